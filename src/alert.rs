@@ -0,0 +1,54 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Alerting rules that surface degraded states, such as a filling filesystem
+//! or snapshots that are no longer taken, as warnings even if a run succeeds.
+
+use crate::{
+    color, find_snapshots, notification::EventKind, output::OutputFormat, size::ByteSize,
+    SnapshotConfig, State,
+};
+use anyhow::Result;
+use std::time::Duration;
+
+impl<'a> State<'a> {
+    /// Evaluate the alerting rules of a snapshot config after processing it
+    /// with `command`, and record a warning for every rule that fires.
+    pub(crate) fn check_alerts(
+        &mut self,
+        snapshot: &'a SnapshotConfig,
+        command: &str,
+    ) -> Result<()> {
+        let mut alerts = vec![];
+        if let Some(threshold) = snapshot.alert_if_free_below {
+            let usage = self.filesystem_usage(snapshot)?;
+            if usage.free < threshold.bytes(usage.size) {
+                alerts.push(format!(
+                    "Only {} free on {}, below `alert_if_free_below` of {}",
+                    ByteSize(usage.free),
+                    snapshot.mount_point.as_ref().unwrap().display(),
+                    threshold
+                ));
+            }
+        }
+        if let Some(max_age) = snapshot.alert_if_no_snapshot_for {
+            let max_age = max_age.into_inner();
+            match find_snapshots(snapshot, &[])?.first() {
+                Some(newest) if newest.age <= max_age => (),
+                Some(newest) => alerts.push(format!(
+                    "Newest snapshot of {} is {} old, beyond `alert_if_no_snapshot_for` of {}",
+                    snapshot.name,
+                    humantime::format_duration(Duration::from_secs(newest.age.as_secs() / 60 * 60)),
+                    humantime::format_duration(max_age)
+                )),
+                None => alerts.push(format!("No snapshots of {} exist", snapshot.name)),
+            }
+        }
+        for message in alerts {
+            if self.output == OutputFormat::Text {
+                println!("{} {}", color::warn("Alert:"), message);
+            }
+            self.record_event(EventKind::Warning, &snapshot.name, command, message);
+        }
+        Ok(())
+    }
+}
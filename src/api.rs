@@ -0,0 +1,453 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! A small HTTP API served by the daemon, such that dashboards and home
+//! automation systems can list snapshots, query the status and metrics, and
+//! trigger runs.
+//!
+//! Every request must carry the configured token as `Authorization: Bearer
+//! <token>`. Requests are handled one at a time by the daemon itself, between
+//! scheduled runs.
+
+use crate::{
+    metrics::MetricsFile, notify::Notifier, output::SnapshotStatus, scrub::ScrubFile,
+    status::StatusFile, Config, SnapshotConfig, State,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::io::{AsRawFd, RawFd},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// How long a client may take to send its request, and to accept the
+/// response.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest request line and headers that are read.
+const MAX_HEAD: u64 = 16 * 1024;
+
+/// The largest request body that is read, which only serves to drain it.
+const MAX_BODY: u64 = 64 * 1024;
+
+/// Where and how the daemon serves its HTTP API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiConfig {
+    /// The address to listen on, such as `127.0.0.1:8484`.
+    pub listen: String,
+    /// The token clients must present.
+    pub token: Option<String>,
+    /// A file containing the token clients must present, such that it can be
+    /// kept out of the config file.
+    pub token_file: Option<PathBuf>,
+}
+
+impl ApiConfig {
+    /// Make sure the API is authenticated.
+    pub fn validate(&self) -> Result<()> {
+        if self.token.is_some() == self.token_file.is_some() {
+            bail!("Exactly one of `token` and `token_file` must be set");
+        }
+        Ok(())
+    }
+
+    /// Determine the token clients must present.
+    fn token(&self) -> Result<String> {
+        let token = match (&self.token, &self.token_file) {
+            (Some(token), _) => token.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read API token from {}", path.display()))?,
+            (None, None) => bail!("No API token configured"),
+        };
+        let token = token.trim().to_owned();
+        if token.is_empty() {
+            bail!("The API token is empty");
+        }
+        Ok(token)
+    }
+}
+
+/// An HTTP request, reduced to what the API looks at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// The method, such as `GET`.
+    pub method: String,
+    /// The path, without the query string.
+    pub path: String,
+    /// The token presented in the `Authorization` header, if any.
+    pub token: Option<String>,
+}
+
+impl Request {
+    /// Read a request from a client. The body is drained and ignored.
+    pub fn read(reader: impl Read) -> Result<Self> {
+        let mut reader = BufReader::new(reader).take(MAX_HEAD);
+        let mut line = String::new();
+        read_head_line(&mut reader, &mut line)?;
+        let mut parts = line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method.to_owned(), target),
+            _ => bail!("Malformed request line `{}`", line.trim()),
+        };
+        let path = target.split('?').next().unwrap_or_default().to_owned();
+
+        let mut token = None;
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            if read_head_line(&mut reader, &mut header)? == 0 {
+                break;
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = match header.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => bail!("Malformed header `{}`", header),
+            };
+            if name.eq_ignore_ascii_case("authorization") {
+                token = value
+                    .strip_prefix("Bearer ")
+                    .map(|token| token.trim().to_owned());
+            } else if name.eq_ignore_ascii_case("content-length") {
+                length = value
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid content length `{}`", value))?;
+            }
+        }
+        reader.set_limit(length.min(MAX_BODY));
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        Ok(Self {
+            method,
+            path,
+            token,
+        })
+    }
+
+    /// Check whether the request presents the given token. Compares in
+    /// constant time, such that the token cannot be guessed byte by byte.
+    pub fn is_authorized(&self, token: &str) -> bool {
+        match &self.token {
+            Some(given) if given.len() == token.len() => {
+                given
+                    .bytes()
+                    .zip(token.bytes())
+                    .fold(0, |acc, (a, b)| acc | (a ^ b))
+                    == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Read a line of the request line or headers, which must end before the
+/// reader's limit is reached.
+fn read_head_line(reader: &mut impl BufRead, line: &mut String) -> Result<usize> {
+    let read = reader.read_line(line)?;
+    if read > 0 && !line.ends_with('\n') {
+        bail!("Request header too large or incomplete");
+    }
+    Ok(read)
+}
+
+/// A client connection that fails reads once a deadline has passed, such that
+/// a client sending its request slowly cannot hold up the daemon.
+struct DeadlineStream<'s> {
+    /// The connection to the client.
+    stream: &'s TcpStream,
+    /// When the request must have been read.
+    deadline: Instant,
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "The client took too long to send its request",
+            ));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+/// An HTTP response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    /// The status code.
+    pub status: u16,
+    /// The media type of the body.
+    pub content_type: &'static str,
+    /// The body.
+    pub body: String,
+}
+
+impl Response {
+    /// Create a JSON response.
+    fn json<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_string_pretty(value) {
+            Ok(body) => Self {
+                status: 200,
+                content_type: "application/json",
+                body,
+            },
+            Err(e) => Self::error(500, &format!("{:#}", e)),
+        }
+    }
+
+    /// Create a plain text response.
+    fn text(body: String) -> Self {
+        Self {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body,
+        }
+    }
+
+    /// Create an error response with a JSON body describing the error.
+    fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::json!({ "error": message }).to_string(),
+        }
+    }
+
+    /// Write the response to a client.
+    fn write(&self, mut writer: impl Write) -> Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            400 => "Bad Request",
+            _ => "Internal Server Error",
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n\
+             Content-Type: {}\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\
+             \r\n\
+             {}",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The outcome of a run triggered through the API.
+#[derive(Debug, Serialize)]
+struct RunOutcome<'b> {
+    /// The actions performed.
+    actions: &'b [crate::output::Action],
+    /// The error the run failed with, if any.
+    error: Option<String>,
+}
+
+/// The listening socket of the API.
+pub struct Server {
+    /// The socket accepting clients.
+    listener: TcpListener,
+    /// The token clients must present.
+    token: String,
+}
+
+impl Server {
+    /// Start listening for clients.
+    pub fn bind(config: &ApiConfig) -> Result<Self> {
+        let token = config.token()?;
+        let listener = TcpListener::bind(&config.listen)
+            .with_context(|| format!("Failed to listen on {}", config.listen))?;
+        info!("Serving the API on {}", config.listen);
+        Ok(Self { listener, token })
+    }
+
+    /// The socket to wait on for clients.
+    pub fn fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// Accept a client that is waiting to connect.
+    pub fn accept(&self) -> Option<TcpStream> {
+        match self.listener.accept() {
+            Ok((stream, peer)) => {
+                debug!("API client connected from {}", peer);
+                Some(stream)
+            }
+            Err(e) => {
+                warn!("Failed to accept API client: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl<'a> State<'a> {
+    /// Read a request from a client, handle it, and write the response.
+    pub fn serve_api_client(
+        &mut self,
+        server: &Server,
+        stream: TcpStream,
+        snapshots: &[&'a SnapshotConfig],
+        config: &Config,
+        notifier: &Notifier,
+    ) {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        let result = stream
+            .set_write_timeout(Some(CLIENT_TIMEOUT))
+            .map_err(Into::into)
+            .and_then(|_| {
+                Request::read(DeadlineStream {
+                    stream: &stream,
+                    deadline,
+                })
+            });
+        let response = match result {
+            Ok(request) => {
+                debug!("API request {} {}", request.method, request.path);
+                self.handle_api_request(&request, &server.token, snapshots, config, notifier)
+            }
+            Err(e) => Response::error(400, &format!("{:#}", e)),
+        };
+        if let Err(e) = response.write(&stream) {
+            warn!("Failed to respond to API client: {:#}", e);
+        }
+    }
+
+    /// Handle a request to the API.
+    ///
+    /// - `GET /snapshots` lists the snapshots of every config.
+    /// - `GET /status` summarizes every config and the outcome of its last run.
+    /// - `GET /metrics` returns the metrics in the Prometheus text format.
+    /// - `POST /snapshots/<name>/take`, `.../rotate`, or `.../run` takes
+    ///   and/or rotates the snapshots of a config right away.
+    pub fn handle_api_request(
+        &mut self,
+        request: &Request,
+        token: &str,
+        snapshots: &[&'a SnapshotConfig],
+        config: &Config,
+        notifier: &Notifier,
+    ) -> Response {
+        if !request.is_authorized(token) {
+            return Response::error(401, "Missing or invalid token");
+        }
+        let segments: Vec<&str> = request
+            .path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let result = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["snapshots"]) => snapshots
+                .iter()
+                .map(|snapshot| self.list_snapshots(snapshot))
+                .collect::<Result<Vec<_>>>()
+                .map(|lists| Response::json(&lists)),
+            ("GET", ["status"]) => self
+                .current_statuses(snapshots, config)
+                .map(|statuses| Response::json(&statuses)),
+            ("GET", ["metrics"]) => MetricsFile::load(config.state_dir())
+                .map(|metrics| Response::text(metrics.render_textfile())),
+            ("POST", ["snapshots", name, command @ ("take" | "rotate" | "run")]) => {
+                match snapshots.iter().find(|snapshot| snapshot.name == *name) {
+                    Some(&snapshot) => {
+                        self.run_now(&[snapshot], command, config, notifier)
+                            .map(|failure| {
+                                Response::json(&RunOutcome {
+                                    actions: &self.actions,
+                                    error: failure.map(|e| format!("{:#}", e)),
+                                })
+                            })
+                    }
+                    None => Ok(Response::error(
+                        404,
+                        &format!("No snapshot config named `{}`", name),
+                    )),
+                }
+            }
+            (_, ["snapshots"] | ["status"] | ["metrics"])
+            | (_, ["snapshots", _, "take" | "rotate" | "run"]) => {
+                Ok(Response::error(405, "Method not allowed"))
+            }
+            _ => Ok(Response::error(404, "Not found")),
+        };
+        result.unwrap_or_else(|e| Response::error(500, &format!("{:#}", e)))
+    }
+
+    /// Summarize every config and the outcome of its last run.
+    pub(crate) fn current_statuses(
+        &mut self,
+        snapshots: &[&'a SnapshotConfig],
+        config: &Config,
+    ) -> Result<Vec<SnapshotStatus>> {
+        let status = StatusFile::load(config.state_dir())?;
+        let scrubs = ScrubFile::load(config.state_dir())?;
+        snapshots
+            .iter()
+            .map(|snapshot| {
+                let last_scrub = snapshot
+                    .mount_point
+                    .as_ref()
+                    .and_then(|mount_point| scrubs.filesystems.get(mount_point));
+                self.snapshot_status(snapshot, status.snapshots.get(&snapshot.name), last_scrub)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Fixture;
+
+    #[test]
+    fn api_rejects_oversized_headers() {
+        let raw = format!(
+            "GET /status HTTP/1.1\r\nX-Junk: {}\r\n\r\n",
+            "x".repeat(32 * 1024)
+        );
+        assert!(Request::read(raw.as_bytes()).is_err());
+        assert!(Request::read("GET /status HTTP/1.1".as_bytes()).is_err());
+        let raw = "GET /status HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody";
+        assert_eq!(Request::read(raw.as_bytes()).unwrap().path, "/status");
+    }
+
+    #[test]
+    fn api_requires_token_and_routes_requests() {
+        let fixture = Fixture::new("api", "");
+        fixture.add_snapshots(&[1]);
+        let (mut state, _) = fixture.state();
+        let snapshots = [fixture.snapshot()];
+        let notifier = Notifier::from_env();
+        let mut handle = |raw: &str| {
+            let request = Request::read(raw.as_bytes()).unwrap();
+            state.handle_api_request(&request, "secret", &snapshots, &fixture.config, &notifier)
+        };
+        assert_eq!(handle("GET /snapshots HTTP/1.1\r\n\r\n").status, 401);
+        assert_eq!(
+            handle("GET /snapshots HTTP/1.1\r\nAuthorization: Bearer wrong\r\n\r\n").status,
+            401
+        );
+        let response =
+            handle("GET /snapshots?x=1 HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n");
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("\"name\": \"data\""));
+        let response = handle("DELETE /status HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n");
+        assert_eq!(response.status, 405);
+        let response =
+            handle("POST /snapshots/other/take HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n");
+        assert_eq!(response.status, 404);
+    }
+}
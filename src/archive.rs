@@ -0,0 +1,365 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Archival of raw `btrfs send` streams to files in a directory or an
+//! S3-compatible bucket.
+//!
+//! Each archive contains an `index.toml` which lists the archived streams in
+//! the order they were written, together with their parent snapshot and the
+//! files they are split into. A snapshot can be restored by concatenating the
+//! files of each stream in its chain, starting at the full stream without a
+//! parent, decrypting and decompressing them if needed, and feeding them into
+//! `btrfs receive`.
+
+use crate::{
+    color, command_failure,
+    output::ActionKind,
+    replicate::{Compression, Encryption, ReplicateConfig},
+    run, spawn_pipeline, wait_pipeline, SnapshotConfig, State,
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Timelike as _};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    process::{Child, ChildStdin, Command, Stdio},
+};
+
+/// The name of the index file within an archive.
+const INDEX_FILE: &str = "index.toml";
+
+/// The list of send streams stored in an archive.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ArchiveIndex {
+    /// The archived streams, oldest first.
+    #[serde(default)]
+    pub streams: Vec<ArchivedStream>,
+}
+
+/// A single send stream stored in an archive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchivedStream {
+    /// The name of the snapshot contained in the stream.
+    pub name: String,
+    /// The snapshot the stream is incremental to, or `None` for a full stream.
+    pub parent: Option<String>,
+    /// The files the stream is split into, in order.
+    pub files: Vec<String>,
+    /// The compression applied to the stream, if any.
+    pub compression: Option<Compression>,
+    /// The encryption applied to the stream after compression, if any.
+    pub encryption: Option<Encryption>,
+    /// The total size of the stream in bytes, after compression and
+    /// encryption.
+    pub size: u64,
+    /// When the stream was archived.
+    pub time: DateTime<Local>,
+}
+
+impl ArchiveIndex {
+    /// The names of the archived snapshots.
+    pub fn names(&self) -> impl Iterator<Item = String> + '_ {
+        self.streams.iter().map(|s| s.name.clone())
+    }
+}
+
+/// A directory or bucket that holds archived send streams.
+pub struct Archive<'a> {
+    config: &'a ReplicateConfig,
+}
+
+impl<'a> Archive<'a> {
+    pub fn new(config: &'a ReplicateConfig) -> Self {
+        Self { config }
+    }
+
+    /// Load the archive's index. Returns an empty index if the archive does
+    /// not contain one yet.
+    pub fn load_index(&self) -> Result<ArchiveIndex> {
+        let buf = match self.read(INDEX_FILE)? {
+            Some(x) => x,
+            None => return Ok(Default::default()),
+        };
+        toml::de::from_str(&buf).context("Failed to parse archive index")
+    }
+
+    /// Write the archive's index.
+    fn save_index(&self, index: &ArchiveIndex) -> Result<()> {
+        let mut file = self.create(INDEX_FILE)?;
+        file.write_all(toml::ser::to_string(index)?.as_bytes())?;
+        file.finish()
+    }
+
+    /// Read a file from the archive, or `None` if it does not exist.
+    fn read(&self, name: &str) -> Result<Option<String>> {
+        match &self.config.bucket {
+            Some(bucket) => {
+                let url = format!("{}/{}", bucket.trim_end_matches('/'), name);
+                let mut cmd = self.aws();
+                cmd.arg("ls").arg(&url);
+                let listing = cmd
+                    .output()
+                    .with_context(|| format!("Failed to execute {:?}", cmd))?;
+                let stdout = String::from_utf8_lossy(&listing.stdout);
+
+                // `aws s3 ls` exits with 1 and prints nothing if no object
+                // matches. Anything else, such as missing permissions, must
+                // not pass for an empty archive.
+                let not_found = listing.status.code() == Some(1)
+                    && listing.stderr.is_empty()
+                    && stdout.trim().is_empty();
+                if !listing.status.success() && !not_found {
+                    return Err(command_failure(&cmd, &listing))
+                        .with_context(|| format!("Listing {} failed", url));
+                }
+                let suffix = format!(" {}", name);
+                if !stdout
+                    .lines()
+                    .any(|line| line.trim_end().ends_with(&suffix))
+                {
+                    return Ok(None);
+                }
+                Ok(Some(run(self.aws().arg("cp").arg(&url).arg("-"))?))
+            }
+            None => {
+                let path = self.config.target_dir().join(name);
+                if !path.exists() {
+                    return Ok(None);
+                }
+                let mut buf = String::new();
+                File::open(&path)?.read_to_string(&mut buf)?;
+                Ok(Some(buf))
+            }
+        }
+    }
+
+    /// Create a file in the archive.
+    fn create(&self, name: &str) -> Result<Sink> {
+        match &self.config.bucket {
+            Some(bucket) => {
+                let url = format!("{}/{}", bucket.trim_end_matches('/'), name);
+                let mut cmd = self.aws();
+                cmd.arg("cp").arg("-").arg(&url);
+                let mut child = cmd
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("Failed to execute {:?}", cmd))?;
+                let stdin = child.stdin.take().unwrap();
+                Ok(Sink::Upload(Box::new(Upload { cmd, child, stdin })))
+            }
+            None => {
+                let path = self.config.target_dir().join(name);
+                let file = File::create(&path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                Ok(Sink::File(file))
+            }
+        }
+    }
+
+    /// Remove the files of a snapshot's send stream. This cleans up after an
+    /// interrupted transfer, which may have left some of the files behind
+    /// without recording them in the index.
+    pub fn remove_files(&self, name: &str) -> Result<()> {
+        let prefix = format!("{}.btrfs", name);
+        match &self.config.bucket {
+            Some(bucket) => {
+                run(self
+                    .aws()
+                    .arg("rm")
+                    .arg(format!("{}/", bucket.trim_end_matches('/')))
+                    .arg("--recursive")
+                    .arg("--exclude")
+                    .arg("*")
+                    .arg("--include")
+                    .arg(format!("{}*", prefix)))?;
+            }
+            None => {
+                for entry in std::fs::read_dir(self.config.target_dir())? {
+                    let entry = entry?;
+                    if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                        debug!("Removing partial {}", entry.path().display());
+                        std::fs::remove_file(entry.path())?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Create an `aws s3` command for the configured bucket.
+    fn aws(&self) -> Command {
+        let mut cmd = Command::new("aws");
+        if let Some(endpoint) = &self.config.endpoint {
+            cmd.arg("--endpoint-url").arg(endpoint);
+        }
+        cmd.arg("s3");
+        cmd
+    }
+
+    /// Write the output of a send pipeline into the archive, splitting it into
+    /// chunks if configured, and record it in the index.
+    fn write_stream(
+        &self,
+        name: &str,
+        parent: Option<&Path>,
+        cmds: &mut [&mut Command],
+    ) -> Result<u64> {
+        let mut index = self.load_index()?;
+        self.remove_files(name)?;
+        let compression = self.config.compression();
+        let encryption = self.config.encrypt.as_ref().map(|e| e.tool);
+        let chunk_size = self.config.chunk_size.map(|s| s.bytes()).unwrap_or(0);
+        let mut children = spawn_pipeline(cmds, false)?;
+        let mut stdout = children.last_mut().unwrap().stdout.take().unwrap();
+
+        // Copy the stream into one or more files.
+        let mut files = Vec::new();
+        let mut size = 0;
+        let mut buf = vec![0; 1 << 20];
+        let mut sink: Option<Sink> = None;
+        let mut written = 0;
+        loop {
+            let n = stdout.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            let mut data = &buf[..n];
+            while !data.is_empty() {
+                if sink.is_none() {
+                    let mut file = format!("{}.btrfs", name);
+                    if let Some(compression) = compression {
+                        file.push('.');
+                        file.push_str(compression.extension());
+                    }
+                    if let Some(encryption) = encryption {
+                        file.push('.');
+                        file.push_str(encryption.extension());
+                    }
+                    if chunk_size > 0 {
+                        file.push_str(&format!(".{:03}", files.len()));
+                    }
+                    debug!("Writing {}", file);
+                    sink = Some(self.create(&file)?);
+                    files.push(file);
+                    written = 0;
+                }
+                let n = if chunk_size > 0 {
+                    std::cmp::min(data.len() as u64, chunk_size - written) as usize
+                } else {
+                    data.len()
+                };
+                sink.as_mut().unwrap().write_all(&data[..n])?;
+                written += n as u64;
+                size += n as u64;
+                data = &data[n..];
+                if chunk_size > 0 && written == chunk_size {
+                    sink.take().unwrap().finish()?;
+                }
+            }
+        }
+        if let Some(sink) = sink {
+            sink.finish()?;
+        }
+        wait_pipeline(cmds, children, None)?;
+
+        // Record the stream in the index.
+        index.streams.push(ArchivedStream {
+            name: name.to_owned(),
+            parent: parent
+                .and_then(|p| p.file_name())
+                .map(|p| p.to_string_lossy().into_owned()),
+            files,
+            compression,
+            encryption,
+            size,
+            time: Local::now().with_nanosecond(0).unwrap(),
+        });
+        self.save_index(&index)?;
+        Ok(size)
+    }
+}
+
+/// A file being written into an archive.
+enum Sink {
+    /// A local file.
+    File(File),
+    /// An upload to object storage.
+    Upload(Box<Upload>),
+}
+
+/// An upload to object storage, fed through the stdin of a command.
+struct Upload {
+    cmd: Command,
+    child: Child,
+    stdin: ChildStdin,
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Sink::File(file) => file.write(buf),
+            Sink::Upload(upload) => upload.stdin.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::File(file) => file.flush(),
+            Sink::Upload(upload) => upload.stdin.flush(),
+        }
+    }
+}
+
+impl Sink {
+    /// Finish writing the file.
+    fn finish(self) -> Result<()> {
+        match self {
+            Sink::File(file) => Ok(file.sync_all()?),
+            Sink::Upload(upload) => {
+                let Upload {
+                    mut cmd,
+                    child,
+                    stdin,
+                } = *upload;
+                drop(stdin);
+                wait_pipeline(&[&mut cmd], vec![child], None).map(|_| ())
+            }
+        }
+    }
+}
+
+impl<'a> State<'a> {
+    /// Archive a snapshot's send stream.
+    pub(crate) fn archive_snapshot(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        replicate: &ReplicateConfig,
+        path: &Path,
+        parent: Option<&Path>,
+        mut send: Command,
+    ) -> Result<()> {
+        let mut filters = replicate.filters();
+        let mut cmds: Vec<_> = std::iter::once(&mut send)
+            .chain(filters.iter_mut())
+            .collect();
+        self.report_action(snapshot, ActionKind::Send, path, &cmds);
+        if self.dry_run {
+            if self.output == crate::OutputFormat::Text {
+                let cmds: Vec<_> = cmds.iter().map(|cmd| format!("{:?}", cmd)).collect();
+                println!(
+                    "{}",
+                    color::dim(format!("{} > {}", cmds.join(" | "), replicate.describe()))
+                );
+            }
+            return Ok(());
+        }
+        let name = path.file_name().unwrap().to_string_lossy();
+        let size = Archive::new(replicate).write_stream(&name, parent, &mut cmds)?;
+        self.metrics
+            .record_action(&snapshot.name, ActionKind::Send, size);
+        Ok(())
+    }
+}
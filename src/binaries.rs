@@ -0,0 +1,120 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Locating the external programs the tool runs, such that it works where
+//! they are not in the default `PATH`, as under systemd, in containers, and
+//! on NixOS.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// Where to find external programs. Programs without a configured path are
+/// looked up in the extra directories first, and then in `PATH`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Binaries {
+    /// The path of `btrfs`.
+    pub btrfs: Option<PathBuf>,
+    /// The path of `mount`.
+    pub mount: Option<PathBuf>,
+    /// The path of `umount`.
+    pub umount: Option<PathBuf>,
+    /// The path of `ssh`.
+    pub ssh: Option<PathBuf>,
+    /// Directories searched for programs before those in `PATH`.
+    #[serde(default)]
+    pub path: Vec<PathBuf>,
+}
+
+impl Binaries {
+    /// Locate a program. Returns the configured path of the program, the
+    /// program in one of the extra directories, or the name itself, such that
+    /// it is looked up in `PATH`.
+    pub fn locate(&self, name: &str) -> PathBuf {
+        let configured = match name {
+            "btrfs" => self.btrfs.as_ref(),
+            "mount" => self.mount.as_ref(),
+            "umount" => self.umount.as_ref(),
+            "ssh" => self.ssh.as_ref(),
+            _ => None,
+        };
+        if let Some(path) = configured {
+            return path.clone();
+        }
+        self.path
+            .iter()
+            .map(|dir| dir.join(name))
+            .find(|path| is_executable(path))
+            .unwrap_or_else(|| PathBuf::from(name))
+    }
+}
+
+/// The program locations in effect, set once from the config.
+static BINARIES: OnceLock<Binaries> = OnceLock::new();
+
+/// Use `binaries` to locate the programs run from now on. The extra
+/// directories are also put in front of `PATH`, such that programs without a
+/// configurable path, such as hooks, find them as well.
+pub fn init(binaries: Binaries) {
+    if !binaries.path.is_empty() {
+        let current = env::var_os("PATH").unwrap_or_default();
+        let dirs = binaries
+            .path
+            .iter()
+            .cloned()
+            .chain(env::split_paths(&current).filter(|dir| !dir.as_os_str().is_empty()));
+        match env::join_paths(dirs) {
+            Ok(path) => env::set_var("PATH", path),
+            Err(e) => warn!("Cannot add the configured directories to `PATH`: {}", e),
+        }
+    }
+    BINARIES.set(binaries).ok();
+}
+
+/// Get the path under which to run a program on the local host.
+pub fn program(name: &str) -> PathBuf {
+    match BINARIES.get() {
+        Some(binaries) => binaries.locate(name),
+        None => PathBuf::from(name),
+    }
+}
+
+/// Check whether a path is an executable file.
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Fixture;
+
+    #[test]
+    fn binaries_located_from_config() {
+        use std::os::unix::fs::PermissionsExt;
+        let fixture = Fixture::new(
+            "binaries",
+            "[binaries]\nbtrfs = \"/opt/btrfs-progs/bin/btrfs\"\npath = [\"/nonexistent\"]",
+        );
+        let bin = fixture.dir.join("bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        std::fs::write(bin.join("mount"), "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(bin.join("mount"), std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        std::fs::write(bin.join("umount"), "").unwrap();
+        let mut binaries = fixture.config.binaries.clone();
+        binaries.path.push(bin.clone());
+        assert_eq!(
+            binaries.locate("btrfs"),
+            PathBuf::from("/opt/btrfs-progs/bin/btrfs")
+        );
+        assert_eq!(binaries.locate("mount"), bin.join("mount"));
+        assert_eq!(binaries.locate("umount"), PathBuf::from("umount"));
+        assert_eq!(binaries.locate("ssh"), PathBuf::from("ssh"));
+    }
+}
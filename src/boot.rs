@@ -0,0 +1,35 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Taking snapshots early at boot, which catch the state of a subvolume right
+//! after an unclean shutdown or before the day's changes.
+
+use crate::pkg::HookFile;
+use std::path::PathBuf;
+
+/// The tag of snapshots taken at boot, whose retention rules are configured
+/// in the `boot` tag.
+pub const BOOT_TAG: &str = "boot";
+
+/// Generate the systemd unit that runs `command` with `take --boot` once local
+/// filesystems are mounted, before the regular services start.
+pub fn unit(command: &str) -> HookFile {
+    HookFile {
+        path: PathBuf::from("/etc/systemd/system/btrfs-snapshot-boot.service"),
+        content: format!(
+            "[Unit]\n\
+             Description=Take btrfs snapshots at boot\n\
+             DefaultDependencies=no\n\
+             After=local-fs.target\n\
+             Before=sysinit.target shutdown.target\n\
+             Conflicts=shutdown.target\n\
+             \n\
+             [Service]\n\
+             Type=oneshot\n\
+             ExecStart={} take --boot\n\
+             \n\
+             [Install]\n\
+             WantedBy=sysinit.target\n",
+            command
+        ),
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Regenerating the boot menu entries of snapshots of the root subvolume, such
+//! that old snapshots can be booted directly.
+
+use crate::{privilege, SnapshotConfig, State};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+/// The grub-btrfs script that regenerates the snapshot submenu.
+const GRUB_BTRFS_SCRIPT: &str = "/etc/grub.d/41_snapshots-btrfs";
+
+/// How to refresh the boot menu after snapshots were taken or deleted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bootloader {
+    /// Regenerate the snapshot submenu of grub-btrfs.
+    GrubBtrfs,
+    /// Run a shell command, e.g. one that writes systemd-boot entries.
+    Command(String),
+}
+
+impl Bootloader {
+    /// The command that refreshes the boot menu.
+    fn command(&self, snapshot: &SnapshotConfig) -> Command {
+        match self {
+            Bootloader::GrubBtrfs => privilege::command(GRUB_BTRFS_SCRIPT),
+            Bootloader::Command(command) => {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c")
+                    .arg(command)
+                    .env("BTRFS_SNAPSHOT_NAME", &snapshot.name);
+                cmd
+            }
+        }
+    }
+}
+
+impl<'a> State<'a> {
+    /// Refresh the boot menu of a snapshot config, which is called after its
+    /// snapshots were taken or deleted.
+    pub fn refresh_bootloader(
+        &self,
+        snapshot: &SnapshotConfig,
+        bootloader: &Bootloader,
+    ) -> Result<()> {
+        debug!("Refreshing boot menu of {}", snapshot.name);
+        let output = self
+            .maybe_run_pipeline(&mut [&mut bootloader.command(snapshot)])
+            .with_context(|| format!("Refreshing the boot menu of {} failed", snapshot.name))?;
+        trace!("Boot menu refresh output: {}", output);
+        Ok(())
+    }
+}
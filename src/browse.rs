@@ -0,0 +1,74 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Looking around in a snapshot.
+
+use crate::{privilege, resolve_snapshot, SnapshotConfig, State, MANAGED_MOUNT_DIR};
+use anyhow::{bail, Context, Result};
+use std::{path::Path, process::Command};
+
+impl<'a> State<'a> {
+    /// Make a snapshot of a snapshot config, given by name or path,
+    /// accessible. Without `shell`, the snapshot's volume is left mounted and
+    /// its path printed. With `shell`, the snapshot is bind-mounted read-only
+    /// at a temporary location, and a shell is started there. The snapshot is
+    /// unmounted again once the shell exits.
+    pub fn browse_snapshot(
+        &mut self,
+        snapshot: &'a SnapshotConfig,
+        name: &str,
+        shell: bool,
+    ) -> Result<()> {
+        debug!("Browse {} of {}", name, snapshot.name);
+        if let (Some(host), true) = (privilege::remote(), shell) {
+            bail!(
+                "Cannot open a shell in the snapshots of {} on {}; `--shell` only works locally",
+                snapshot.name,
+                host
+            );
+        }
+        if !shell {
+            self.keep_mounted = true;
+        }
+        self.mount_snapshot_fs(snapshot)?;
+        let path = resolve_snapshot(snapshot, name)?;
+        if !shell {
+            println!("{}", path.display());
+            return Ok(());
+        }
+        let target = Path::new(MANAGED_MOUNT_DIR).join("browse").join(format!(
+            "{}-{}",
+            snapshot.name,
+            path.file_name().unwrap().to_string_lossy()
+        ));
+        let shell = std::env::var_os("SHELL").unwrap_or_else(|| "/bin/sh".into());
+        let mut cmd = Command::new(shell);
+        cmd.current_dir(&target)
+            .env("BTRFS_SNAPSHOT_PATH", &path)
+            .env("BTRFS_SNAPSHOT_NAME", &snapshot.name);
+        if self.dry_run {
+            println!(
+                "Would mount {} at {} and start {:?} there",
+                path.display(),
+                target.display(),
+                cmd
+            );
+            return Ok(());
+        }
+
+        self.executor
+            .mount(&target, Some(&path.to_string_lossy()), Some("bind,ro"))
+            .with_context(|| format!("Mounting {} failed", path.display()))?;
+        println!(
+            "Browsing {} at {}; exit the shell to unmount it",
+            path.display(),
+            target.display()
+        );
+        let status = cmd
+            .status()
+            .with_context(|| format!("Failed to start {:?}", cmd));
+        self.executor.unmount(&target, false)?;
+        std::fs::remove_dir(&target).ok();
+        status?;
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Persistent record of the snapshots taken and where they were sent, which
+//! knows more about each snapshot than its directory name does.
+
+use crate::{subvolume, SnapshotConfig, State};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Timelike as _};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The name of the file within the state directory that holds the catalog.
+const CATALOG_FILE: &str = "catalog.json";
+
+/// The snapshots known to have been taken.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Catalog {
+    /// The snapshots, keyed by their path.
+    #[serde(default)]
+    pub snapshots: IndexMap<PathBuf, CatalogEntry>,
+    /// The UUIDs that subvolumes had before a restore replaced them, keyed by
+    /// the path of the subvolume. Snapshots taken before the restore are
+    /// snapshots of one of these.
+    #[serde(default)]
+    pub former_uuids: IndexMap<PathBuf, Vec<String>>,
+}
+
+/// What is known about a single snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    /// The name of the snapshot config the snapshot belongs to.
+    pub config: String,
+    /// When the snapshot was taken.
+    pub created: DateTime<Local>,
+    /// The tag the snapshot was taken with.
+    pub tag: Option<String>,
+    /// The generation at which the snapshot was created. Only recorded for
+    /// configs that skip unchanged subvolumes.
+    pub generation: Option<u64>,
+    /// The replication target the snapshot was sent to.
+    pub sent: Option<SentRecord>,
+}
+
+/// A completed transfer of a snapshot to a replication target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentRecord {
+    /// The description of the target, as in `replicate.describe()`.
+    pub target: String,
+    /// When the transfer completed.
+    pub time: DateTime<Local>,
+    /// The size of the send stream in bytes.
+    pub bytes: u64,
+}
+
+impl Catalog {
+    /// Load the catalog from a state directory. Returns an empty catalog if
+    /// the file does not exist yet.
+    pub fn load(state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(CATALOG_FILE);
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        debug!("Loading catalog {}", path.display());
+        let buf = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read catalog from {}", path.display()))?;
+        serde_json::from_str(&buf)
+            .with_context(|| format!("Failed to parse catalog from {}", path.display()))
+    }
+
+    /// Write the catalog into a state directory.
+    pub fn save(&self, state_dir: &Path) -> Result<()> {
+        let path = state_dir.join(CATALOG_FILE);
+        debug!("Saving catalog {}", path.display());
+        std::fs::create_dir_all(state_dir)
+            .with_context(|| format!("Failed to create state dir {}", state_dir.display()))?;
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write catalog to {}", path.display()))
+    }
+
+    /// Look up a snapshot.
+    pub fn get(&self, path: &Path) -> Option<&CatalogEntry> {
+        self.snapshots.get(path)
+    }
+
+    /// Record a snapshot that was just taken in a run at `time`.
+    pub fn record_taken(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        path: &Path,
+        time: DateTime<Local>,
+        tag: Option<&str>,
+        generation: Option<u64>,
+    ) {
+        self.snapshots.insert(
+            path.to_owned(),
+            CatalogEntry {
+                config: snapshot.name.clone(),
+                created: time.with_nanosecond(0).unwrap(),
+                tag: tag.map(String::from),
+                generation,
+                sent: None,
+            },
+        );
+    }
+
+    /// Record that a snapshot was sent to a replication target.
+    pub fn record_sent(&mut self, path: &Path, target: String, bytes: u64) {
+        if let Some(entry) = self.snapshots.get_mut(path) {
+            entry.sent = Some(SentRecord {
+                target,
+                time: Local::now().with_nanosecond(0).unwrap(),
+                bytes,
+            });
+        }
+    }
+
+    /// Record that a subvolume with `uuid` was replaced by a restore, such
+    /// that the snapshots taken of it are still recognized as its snapshots.
+    pub fn record_restored(&mut self, subvolume: &Path, uuid: String) {
+        let uuids = self.former_uuids.entry(subvolume.to_owned()).or_default();
+        if !uuids.contains(&uuid) {
+            uuids.push(uuid);
+        }
+    }
+
+    /// Get the UUIDs a subvolume had before it was restored.
+    pub fn former_uuids(&self, subvolume: &Path) -> &[String] {
+        self.former_uuids
+            .get(subvolume)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Forget a snapshot that was deleted.
+    pub fn forget(&mut self, path: &Path) {
+        self.snapshots.shift_remove(path);
+    }
+
+    /// Follow a snapshot that was renamed.
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+        if let Some(entry) = self.snapshots.shift_remove(from) {
+            self.snapshots.insert(to.to_owned(), entry);
+        }
+    }
+
+    /// Forget the snapshots of a snapshot config that no longer exist, such as
+    /// ones deleted by hand, given the `existing` entries of its snapshot
+    /// directory.
+    pub fn forget_missing(&mut self, name: &str, existing: &[PathBuf]) {
+        self.snapshots
+            .retain(|path, entry| entry.config != name || existing.contains(path));
+    }
+
+    /// Check whether a snapshot is known to have been sent to a replication
+    /// target. Snapshots taken before the catalog existed are assumed to have
+    /// been sent if they exist on the target.
+    pub fn is_sent_to(&self, path: &Path, target: &str) -> bool {
+        match self.snapshots.get(path) {
+            Some(entry) => entry
+                .sent
+                .as_ref()
+                .is_some_and(|sent| sent.target == target),
+            None => true,
+        }
+    }
+
+    /// Take over the entries of a snapshot config from a separate worker, such
+    /// as one processing another filesystem concurrently.
+    pub fn merge(&mut self, other: &Catalog, name: &str) {
+        self.snapshots.retain(|_, entry| entry.config != name);
+        self.snapshots.extend(
+            other
+                .snapshots
+                .iter()
+                .filter(|(_, entry)| entry.config == name)
+                .map(|(path, entry)| (path.clone(), entry.clone())),
+        );
+    }
+}
+
+impl<'a> State<'a> {
+    /// Check whether a snapshot config's subvolume has been modified since a
+    /// snapshot of it was taken. Uses the generation recorded in the catalog
+    /// if there is one, rather than querying the snapshot.
+    pub fn changed_since(&self, snapshot: &SnapshotConfig, path: &Path) -> Result<bool> {
+        match self.catalog.get(path).and_then(|entry| entry.generation) {
+            Some(generation) => {
+                let source = subvolume::show(snapshot.subvolume())?;
+                trace!(
+                    "Source generation {}, snapshot created at generation {} according to catalog",
+                    source.generation,
+                    generation
+                );
+                Ok(source.generation > generation)
+            }
+            None => subvolume::changed_since(snapshot.subvolume(), path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forget_missing_keeps_listed_snapshots() {
+        let entry = |config: &str| CatalogEntry {
+            config: config.to_string(),
+            created: Local::now(),
+            tag: None,
+            generation: None,
+            sent: None,
+        };
+        let mut catalog = Catalog::default();
+        for (path, config) in [("/s/a", "data"), ("/s/b", "data"), ("/t/c", "home")] {
+            catalog.snapshots.insert(PathBuf::from(path), entry(config));
+        }
+        catalog.forget_missing("data", &[PathBuf::from("/s/b")]);
+        let paths: Vec<_> = catalog
+            .snapshots
+            .keys()
+            .map(|p| p.to_str().unwrap())
+            .collect();
+        assert_eq!(paths, ["/s/b", "/t/c"]);
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Validating snapshot configs against the system they run on.
+
+use crate::{
+    mounts::{find_mount, read_mounts},
+    output::{Check, CheckReport},
+    SnapshotConfig,
+};
+use std::{
+    ffi::CString,
+    os::unix::{ffi::OsStrExt as _, fs::MetadataExt as _},
+    path::Path,
+};
+
+/// The inode number of the root directory of every btrfs subvolume.
+const SUBVOLUME_INODE: u64 = 256;
+
+/// Check a snapshot config for problems that would make it fail at runtime.
+pub fn check_snapshot(snapshot: &SnapshotConfig) -> CheckReport {
+    let mut checks = Vec::new();
+    let mut check = |ok: bool, message: String| checks.push(Check { ok, message });
+    let mounts = read_mounts();
+    let mount_point = snapshot.mount_point.as_ref().unwrap();
+    let subvolume = snapshot.subvolume();
+    let snapshot_dir = snapshot.snapshot_dir.as_ref().unwrap();
+
+    // The mount point is usually listed in fstab and mounted on demand, so
+    // only check that it exists. Mount points of devices are created when
+    // mounting them.
+    check(
+        mount_point.is_dir() || snapshot.device.is_some(),
+        format!("mount point {} exists", mount_point.display()),
+    );
+
+    // The subvolume of a pulled config lives on the source host, so only the
+    // snapshot dir can be checked locally.
+    if snapshot.pull.is_some() {
+        check(
+            snapshot_dir.is_dir() && is_writable(snapshot_dir),
+            format!("snapshot dir {} is writable", snapshot_dir.display()),
+        );
+    } else {
+        // Check that the subvolume is a btrfs subvolume.
+        match std::fs::metadata(subvolume) {
+            Ok(meta) => {
+                let fs_type = find_mount(&mounts, subvolume).map(|m| m.fs_type.as_str());
+                check(
+                    fs_type == Some("btrfs") && meta.ino() == SUBVOLUME_INODE,
+                    format!("subvolume {} is a btrfs subvolume", subvolume.display()),
+                );
+            }
+            Err(e) => check(
+                false,
+                format!("subvolume {} exists ({})", subvolume.display(), e),
+            ),
+        }
+
+        // Check that the snapshot dir can hold snapshots of the subvolume.
+        if snapshot_dir.is_dir() {
+            let source = |path| find_mount(&mounts, path).map(|m| m.source.as_str());
+            check(
+                source(subvolume).is_some() && source(subvolume) == source(snapshot_dir),
+                format!(
+                    "snapshot dir {} is on the same filesystem as {}",
+                    snapshot_dir.display(),
+                    subvolume.display()
+                ),
+            );
+            check(
+                is_writable(snapshot_dir),
+                format!("snapshot dir {} is writable", snapshot_dir.display()),
+            );
+        } else {
+            check(
+                false,
+                format!("snapshot dir {} exists", snapshot_dir.display()),
+            );
+        }
+    }
+
+    // Check that names generated from the format can be parsed again.
+    let format = snapshot.format.as_ref().unwrap();
+    match snapshot.naming() {
+        Ok(naming) => {
+            let now = chrono::Local::now();
+            let roundtrip = naming
+                .render(now, None, 1)
+                .map(|name| naming.parse(&name).is_some());
+            check(
+                matches!(roundtrip, Ok(true)),
+                format!("names generated from format `{}` can be parsed", format),
+            );
+        }
+        Err(e) => check(false, format!("format `{}` is valid ({:#})", format, e)),
+    }
+
+    CheckReport {
+        name: snapshot.name.clone(),
+        checks,
+    }
+}
+
+/// Check whether the current user can create files in a directory.
+fn is_writable(dir: &Path) -> bool {
+    let path = match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
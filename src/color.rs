@@ -0,0 +1,93 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Coloring human-readable output on terminals.
+
+use ansi_term::{Colour, Style};
+use anyhow::{bail, Result};
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Whether output is colored.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// When to color output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color output if stdout is a terminal and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    /// Always color output.
+    Always,
+    /// Never color output.
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => bail!("Unknown color choice `{}`", s),
+        }
+    }
+}
+
+impl ColorChoice {
+    /// Decide whether to color output.
+    pub fn enabled(self) -> bool {
+        match self {
+            Self::Auto => {
+                atty::is(atty::Stream::Stdout)
+                    && std::env::var_os("NO_COLOR").is_none()
+                    && std::env::var("TERM").map_or(true, |term| term != "dumb")
+            }
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// Enable or disable colored output.
+pub fn init(choice: ColorChoice) {
+    ENABLED.store(choice.enabled(), Ordering::Relaxed);
+}
+
+/// Apply a style to some text if output is colored.
+fn paint(style: Style, text: impl Display) -> String {
+    match ENABLED.load(Ordering::Relaxed) {
+        true => style.paint(text.to_string()).to_string(),
+        false => text.to_string(),
+    }
+}
+
+/// Color text that reports a snapshot being taken or kept.
+pub fn keep(text: impl Display) -> String {
+    paint(Colour::Green.bold(), text)
+}
+
+/// Color text that reports a snapshot being deleted.
+pub fn delete(text: impl Display) -> String {
+    paint(Colour::Red.bold(), text)
+}
+
+/// Color text that reports a snapshot being sent.
+pub fn send(text: impl Display) -> String {
+    paint(Colour::Cyan.bold(), text)
+}
+
+/// Color text that reports something skipped or otherwise worth a second
+/// look.
+pub fn warn(text: impl Display) -> String {
+    paint(Colour::Yellow.bold(), text)
+}
+
+/// Color secondary text, such as the commands printed in a dry run.
+pub fn dim(text: impl Display) -> String {
+    paint(Style::new().dimmed(), text)
+}
@@ -0,0 +1,222 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Running as a long-lived process that takes snapshots on a schedule, and
+//! serves requests to take them right away.
+
+use crate::{
+    api::Server, catalog::Catalog, dbus::Bus, hold::HoldFile, inhibit::Inhibitor, lock::Lock,
+    metrics::MetricsFile, notify::Notifier, output, schedule::Schedule, status::StatusFile, Config,
+    SnapshotConfig, State,
+};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local};
+use std::{os::unix::io::RawFd, time::Duration};
+
+/// The longest time to sleep at once, such that changes of the system clock
+/// and suspends are noticed in time.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+impl<'a> State<'a> {
+    /// Take and rotate snapshots whenever their schedule is due, and serve
+    /// the HTTP API and D-Bus service if configured. Only returns if none of
+    /// the snapshots has a schedule and there is nothing to serve.
+    pub fn run_daemon(
+        &mut self,
+        snapshots: Vec<&'a SnapshotConfig>,
+        config: &Config,
+    ) -> Result<()> {
+        let scheduled: Vec<(&'a SnapshotConfig, &'a Schedule)> = snapshots
+            .iter()
+            .copied()
+            .filter_map(|snapshot| match &snapshot.schedule {
+                Some(schedule) => Some((snapshot, schedule)),
+                None => {
+                    warn!("Not scheduling {}; no `schedule` configured", snapshot.name);
+                    None
+                }
+            })
+            .collect();
+        let server = config.api.as_ref().map(Server::bind).transpose()?;
+        let mut bus = match &config.dbus {
+            Some(dbus) => Some(Bus::connect(dbus.bus)?),
+            None => None,
+        };
+        let serving = server.is_some() || bus.is_some();
+        if scheduled.is_empty() && !serving {
+            bail!("No snapshots have a `schedule` configured");
+        }
+
+        let notifier = Notifier::from_env();
+        let now = Local::now();
+        let mut next: Vec<Option<DateTime<Local>>> = scheduled
+            .iter()
+            .map(|(_, schedule)| schedule.next_after(now))
+            .collect();
+        for ((snapshot, schedule), next) in scheduled.iter().zip(&next) {
+            match next {
+                Some(next) => info!(
+                    "Scheduling {} `{}`, next at {}",
+                    snapshot.name, schedule, next
+                ),
+                None => warn!("Schedule `{}` of {} is never due", schedule, snapshot.name),
+            }
+        }
+
+        notifier.ready();
+
+        loop {
+            notifier.keepalive();
+            let now = Local::now();
+            let due = next.iter().flatten().min().copied();
+            if due.is_none() && !serving {
+                bail!("None of the schedules is ever due");
+            }
+            if due.is_none_or(|due| due > now) {
+                let wait = match due {
+                    Some(due) => {
+                        trace!("Sleeping until {}", due);
+                        notifier.status(&format!("Waiting until {}", due.format("%F %T %:z")));
+                        (due - now).to_std().unwrap_or_default()
+                    }
+                    None => {
+                        notifier.status("Waiting for requests");
+                        MAX_SLEEP
+                    }
+                };
+                let max_sleep = notifier.keepalive_interval().unwrap_or(MAX_SLEEP);
+                let wait = wait.min(max_sleep).min(MAX_SLEEP);
+                let fds: Vec<_> = server
+                    .iter()
+                    .map(Server::fd)
+                    .chain(bus.iter().map(Bus::fd))
+                    .collect();
+                let ready = wait_readable(&fds, wait);
+                if let Some(server) = &server {
+                    if ready.contains(&server.fd()) {
+                        if let Some(stream) = server.accept() {
+                            self.serve_api_client(server, stream, &snapshots, config, &notifier);
+                        }
+                    }
+                }
+                if let Some(bus) = &mut bus {
+                    if ready.contains(&bus.fd()) {
+                        self.serve_dbus_message(bus, &snapshots, config, &notifier)?;
+                    }
+                }
+                self.publish_events(config, bus.as_mut());
+                continue;
+            }
+
+            // Process all snapshots that are due. They share the timestamp of
+            // their names, as in a regular run.
+            let due: Vec<_> = scheduled
+                .iter()
+                .zip(&next)
+                .filter(|(_, next)| matches!(next, Some(next) if *next <= now))
+                .map(|((snapshot, _), _)| *snapshot)
+                .collect();
+            self.run_now(&due, "run", config, &notifier)?;
+            self.publish_events(config, bus.as_mut());
+            for ((_, schedule), next) in scheduled.iter().zip(&mut next) {
+                if matches!(next, Some(next) if *next <= now) {
+                    *next = schedule.next_after(now);
+                }
+            }
+        }
+    }
+
+    /// Take and rotate snapshots right away, as the `take`, `rotate`, or `run`
+    /// command would, while holding the lock. Manual runs in the meantime are
+    /// waited for. Failures of individual snapshots are logged and recorded
+    /// as events, and the first one is returned once all snapshots have been
+    /// processed.
+    pub(crate) fn run_now(
+        &mut self,
+        snapshots: &[&'a SnapshotConfig],
+        command: &str,
+        config: &Config,
+        notifier: &Notifier,
+    ) -> Result<Option<anyhow::Error>> {
+        let state_dir = config.state_dir();
+        let _lock = match self.dry_run {
+            false => Some(Lock::acquire(config.lock_file(), true)?),
+            true => None,
+        };
+        self.now = Some(Local::now());
+        self.actions.clear();
+        self.holds = HoldFile::load(state_dir)?;
+        self.metrics = MetricsFile::load(state_dir)?;
+        self.catalog = Catalog::load(state_dir)?;
+        let mut status = StatusFile::load(state_dir)?;
+        let _inhibitor = match self.dry_run {
+            false => Inhibitor::acquire("Taking and rotating snapshots"),
+            true => None,
+        };
+        let mut failure = None;
+        for snapshot in snapshots {
+            info!("Running `{}` for {}", command, snapshot.name);
+            notifier.status(&format!("Running `{}` for {}", command, snapshot.name));
+            notifier.keepalive();
+            let first_action = self.actions.len();
+            let result = self.pinged(snapshot, |state| {
+                state.process_snapshot(snapshot, command != "rotate", command != "take")
+            });
+            if !self.dry_run {
+                status.record(&snapshot.name, command, &result);
+                status.save(state_dir)?;
+                self.catalog.save(state_dir)?;
+                self.metrics.record_result(&snapshot.name, &result);
+                self.metrics
+                    .save(state_dir, config.metrics_file.as_deref())?;
+            }
+            match result {
+                Ok(()) => self.record_success(&snapshot.name, command, first_action),
+                Err(e) => {
+                    error!("Snapshot {} failed: {:#}", snapshot.name, e);
+                    self.record_failure(&snapshot.name, command, &e);
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        output::print_actions(self.output, &self.actions)?;
+        if let Err(e) = self.unmount() {
+            error!("{:#}", e);
+        }
+        Ok(failure)
+    }
+
+    /// Emit the recorded events as D-Bus signals, and send notifications
+    /// about them.
+    fn publish_events(&mut self, config: &Config, bus: Option<&mut Bus>) {
+        if let Some(bus) = bus {
+            for event in &self.events {
+                if let Err(e) = bus.emit_event(event) {
+                    warn!("{:#}", e);
+                }
+            }
+        }
+        self.send_notifications(&config.notify);
+    }
+}
+
+/// Wait up to `timeout` for any of the file descriptors to become readable,
+/// and return the ones that are.
+fn wait_readable(fds: &[RawFd], timeout: Duration) -> Vec<RawFd> {
+    let mut polls: Vec<_> = fds
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    if unsafe { libc::poll(polls.as_mut_ptr(), polls.len() as libc::nfds_t, millis) } <= 0 {
+        return vec![];
+    }
+    polls
+        .iter()
+        .filter(|poll| poll.revents != 0)
+        .map(|poll| poll.fd)
+        .collect()
+}
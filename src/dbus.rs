@@ -0,0 +1,797 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Exposing the daemon as a D-Bus service, such that desktop applets can show
+//! the status of snapshots and offer to take one right away.
+//!
+//! The wire protocol is spoken directly over the bus socket, covering just
+//! what the service needs: the `EXTERNAL` authentication, claiming the
+//! service name, answering method calls with string arguments, and emitting
+//! signals. Like the service manager notifications, this avoids depending on
+//! a D-Bus library.
+
+use crate::{
+    notification::{Event, EventKind},
+    notify::Notifier,
+    pkg::HookFile,
+    Config, SnapshotConfig, State,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryInto,
+    io::{Read, Write},
+    os::{
+        linux::net::SocketAddrExt as _,
+        unix::{
+            io::{AsRawFd, RawFd},
+            net::{SocketAddr, UnixStream},
+        },
+    },
+    path::PathBuf,
+};
+
+/// The well-known name of the service.
+pub const NAME: &str = "org.btrfs_snapshot";
+
+/// The object path of the service.
+pub const PATH: &str = "/org/btrfs_snapshot";
+
+/// The interface with the methods and signals of the service.
+pub const INTERFACE: &str = "org.btrfs_snapshot.Snapshots";
+
+/// The introspection data of the service object.
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.btrfs_snapshot.Snapshots">
+    <method name="ListConfigs">
+      <arg name="configs" type="as" direction="out"/>
+    </method>
+    <method name="GetStatus">
+      <arg name="status" type="s" direction="out"/>
+    </method>
+    <method name="TakeSnapshot">
+      <arg name="config" type="s" direction="in"/>
+    </method>
+    <method name="Trigger">
+      <arg name="config" type="s" direction="in"/>
+      <arg name="command" type="s" direction="in"/>
+    </method>
+    <signal name="Finished">
+      <arg name="config" type="s"/>
+      <arg name="command" type="s"/>
+      <arg name="summary" type="s"/>
+    </signal>
+    <signal name="Warning">
+      <arg name="config" type="s"/>
+      <arg name="command" type="s"/>
+      <arg name="message" type="s"/>
+    </signal>
+    <signal name="Failed">
+      <arg name="config" type="s"/>
+      <arg name="command" type="s"/>
+      <arg name="error" type="s"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="data" type="s" direction="out"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+</node>
+"#;
+
+/// Which bus to offer the service on.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BusKind {
+    /// The system bus, for a daemon running as root.
+    #[default]
+    System,
+    /// The session bus of the user running the daemon.
+    Session,
+}
+
+/// How the daemon offers its D-Bus service.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DbusConfig {
+    /// The bus to connect to.
+    #[serde(default)]
+    pub bus: BusKind,
+    /// The group whose members may trigger snapshots on the system bus. Only
+    /// root may trigger snapshots otherwise, while everyone may query them.
+    pub group: Option<String>,
+}
+
+/// Generate the system bus policy that lets the daemon own its name, and the
+/// configured group call its methods.
+pub fn policy(config: &DbusConfig) -> HookFile {
+    let group = match &config.group {
+        Some(group) => format!(
+            "  <policy group=\"{}\">\n\
+             \x20   <allow send_destination=\"{}\"/>\n\
+             \x20 </policy>\n",
+            group, NAME
+        ),
+        None => String::new(),
+    };
+    let allow = |attrs: &str| format!("    <allow send_destination=\"{}\" {}/>\n", NAME, attrs);
+    HookFile {
+        path: PathBuf::from(format!("/etc/dbus-1/system.d/{}.conf", NAME)),
+        content: format!(
+            "<!DOCTYPE busconfig PUBLIC \"-//freedesktop//DTD D-BUS Bus Configuration 1.0//EN\"\n \
+             \"http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd\">\n\
+             <busconfig>\n\
+             \x20 <policy user=\"root\">\n\
+             \x20   <allow own=\"{name}\"/>\n\
+             \x20   <allow send_destination=\"{name}\"/>\n\
+             \x20 </policy>\n\
+             {group}\
+             \x20 <policy context=\"default\">\n\
+             {introspect}{peer}{list}{status}\
+             \x20 </policy>\n\
+             </busconfig>\n",
+            name = NAME,
+            group = group,
+            introspect = allow("send_interface=\"org.freedesktop.DBus.Introspectable\""),
+            peer = allow("send_interface=\"org.freedesktop.DBus.Peer\""),
+            list = allow(&format!(
+                "send_interface=\"{}\" send_member=\"ListConfigs\"",
+                INTERFACE
+            )),
+            status = allow(&format!(
+                "send_interface=\"{}\" send_member=\"GetStatus\"",
+                INTERFACE
+            )),
+        ),
+    }
+}
+
+/// The type of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// A method call.
+    Call = 1,
+    /// The reply to a method call.
+    Return = 2,
+    /// An error in response to a method call.
+    Error = 3,
+    /// A signal.
+    Signal = 4,
+}
+
+/// A value in the body of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// A string, with signature `s`.
+    Str(String),
+    /// An unsigned integer, with signature `u`.
+    U32(u32),
+    /// An array of strings, with signature `as`.
+    StrArray(Vec<String>),
+}
+
+impl Value {
+    /// The signature of the value.
+    fn signature(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "s",
+            Value::U32(_) => "u",
+            Value::StrArray(_) => "as",
+        }
+    }
+}
+
+/// A message sent over the bus, reduced to the header fields the service
+/// looks at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// The type of the message.
+    pub kind: MessageKind,
+    /// The serial number assigned by the sender.
+    pub serial: u32,
+    /// The object the message is sent to or emitted from.
+    pub path: Option<String>,
+    /// The interface of the method or signal.
+    pub interface: Option<String>,
+    /// The name of the method or signal.
+    pub member: Option<String>,
+    /// The name of the error.
+    pub error_name: Option<String>,
+    /// The serial of the message this message replies to.
+    pub reply_serial: Option<u32>,
+    /// The name of the connection the message is sent to.
+    pub destination: Option<String>,
+    /// The unique name of the connection that sent the message.
+    pub sender: Option<String>,
+    /// The values in the body. Parsing stops at the first value of a type
+    /// other than `s`, `u`, and `as`.
+    pub body: Vec<Value>,
+}
+
+impl Message {
+    /// Create a message without any header fields.
+    pub fn new(kind: MessageKind) -> Self {
+        Self {
+            kind,
+            serial: 0,
+            path: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            reply_serial: None,
+            destination: None,
+            sender: None,
+            body: vec![],
+        }
+    }
+
+    /// Create a method call to the message bus itself.
+    fn bus_call(member: &str, body: Vec<Value>) -> Self {
+        Self {
+            path: Some("/org/freedesktop/DBus".to_owned()),
+            interface: Some("org.freedesktop.DBus".to_owned()),
+            member: Some(member.to_owned()),
+            destination: Some("org.freedesktop.DBus".to_owned()),
+            body,
+            ..Self::new(MessageKind::Call)
+        }
+    }
+
+    /// Create the reply to a method call.
+    fn reply_to(call: &Message, body: Vec<Value>) -> Self {
+        Self {
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            body,
+            ..Self::new(MessageKind::Return)
+        }
+    }
+
+    /// Create an error in response to a method call.
+    fn error_for(call: &Message, name: &str, message: &str) -> Self {
+        Self {
+            error_name: Some(name.to_owned()),
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            body: vec![Value::Str(message.to_owned())],
+            ..Self::new(MessageKind::Error)
+        }
+    }
+
+    /// Create a signal of the service.
+    pub fn signal(member: &str, body: Vec<Value>) -> Self {
+        Self {
+            path: Some(PATH.to_owned()),
+            interface: Some(INTERFACE.to_owned()),
+            member: Some(member.to_owned()),
+            body,
+            ..Self::new(MessageKind::Signal)
+        }
+    }
+
+    /// Get a string argument of a method call.
+    fn str_arg(&self, index: usize) -> Option<&str> {
+        match self.body.get(index) {
+            Some(Value::Str(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Encode the message in little-endian byte order.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Writer::default();
+        for value in &self.body {
+            body.value(value);
+        }
+        let signature: String = self.body.iter().map(Value::signature).collect();
+
+        let mut out = Writer::default();
+        out.buf
+            .extend_from_slice(&[b'l', self.kind as u8, 0, PROTOCOL_VERSION]);
+        out.u32(body.buf.len() as u32);
+        out.u32(self.serial);
+        out.array(8, |out| {
+            let mut field = |code: u8, signature: &str, value: &dyn Fn(&mut Writer)| {
+                out.align(8);
+                out.buf.push(code);
+                out.signature(signature);
+                value(out);
+            };
+            if let Some(path) = &self.path {
+                field(1, "o", &|out| out.string(path));
+            }
+            if let Some(interface) = &self.interface {
+                field(2, "s", &|out| out.string(interface));
+            }
+            if let Some(member) = &self.member {
+                field(3, "s", &|out| out.string(member));
+            }
+            if let Some(name) = &self.error_name {
+                field(4, "s", &|out| out.string(name));
+            }
+            if let Some(serial) = self.reply_serial {
+                field(5, "u", &|out| out.u32(serial));
+            }
+            if let Some(destination) = &self.destination {
+                field(6, "s", &|out| out.string(destination));
+            }
+            if !signature.is_empty() {
+                field(8, "g", &|out| out.signature(&signature));
+            }
+        });
+        out.align(8);
+        out.buf.extend_from_slice(&body.buf);
+        out.buf
+    }
+
+    /// Decode a complete message.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let mut r = Reader::new(buf)?;
+        r.pos = 1;
+        let kind = match r.u8()? {
+            1 => MessageKind::Call,
+            2 => MessageKind::Return,
+            3 => MessageKind::Error,
+            4 => MessageKind::Signal,
+            kind => bail!("Unknown message type {}", kind),
+        };
+        r.pos = 8;
+        let serial = r.u32()?;
+        let fields_end = 16 + r.u32()? as usize;
+        let mut message = Self {
+            serial,
+            ..Self::new(kind)
+        };
+        let mut signature = String::new();
+        while r.pos < fields_end {
+            r.align(8);
+            let code = r.u8()?;
+            let field_signature = r.signature()?;
+            match (code, field_signature.as_str()) {
+                (1, "o") => message.path = Some(r.string()?),
+                (2, "s") => message.interface = Some(r.string()?),
+                (3, "s") => message.member = Some(r.string()?),
+                (4, "s") => message.error_name = Some(r.string()?),
+                (5, "u") => message.reply_serial = Some(r.u32()?),
+                (6, "s") => message.destination = Some(r.string()?),
+                (7, "s") => message.sender = Some(r.string()?),
+                (8, "g") => signature = r.signature()?,
+                (_, "s" | "o") => drop(r.string()?),
+                (_, "g") => drop(r.signature()?),
+                (_, "u") => drop(r.u32()?),
+                (code, sig) => bail!("Unexpected header field {} of type `{}`", code, sig),
+            }
+        }
+        r.pos = fields_end;
+        r.align(8);
+        let mut chars = signature.chars().peekable();
+        while let Some(c) = chars.next() {
+            let value = match c {
+                's' => Value::Str(r.string()?),
+                'u' => Value::U32(r.u32()?),
+                'a' if chars.peek() == Some(&'s') => {
+                    chars.next();
+                    let end = r.u32()? as usize;
+                    r.align(4);
+                    let end = r.pos + end;
+                    let mut items = vec![];
+                    while r.pos < end {
+                        items.push(r.string()?);
+                    }
+                    Value::StrArray(items)
+                }
+                _ => break,
+            };
+            message.body.push(value);
+        }
+        Ok(message)
+    }
+}
+
+/// The major version of the wire protocol.
+const PROTOCOL_VERSION: u8 = 1;
+
+/// Marshals values in little-endian byte order.
+#[derive(Default)]
+struct Writer {
+    /// The bytes written so far, starting at an 8-byte boundary.
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    /// Pad with zeros to a multiple of `n` bytes.
+    fn align(&mut self, n: usize) {
+        while !self.buf.len().is_multiple_of(n) {
+            self.buf.push(0);
+        }
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.buf.push(value.len() as u8);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    /// Write an array whose elements are aligned to `align` bytes.
+    fn array(&mut self, align: usize, elements: impl FnOnce(&mut Self)) {
+        self.u32(0);
+        let length_at = self.buf.len() - 4;
+        self.align(align);
+        let start = self.buf.len();
+        elements(self);
+        let length = (self.buf.len() - start) as u32;
+        self.buf[length_at..length_at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Str(s) => self.string(s),
+            Value::U32(n) => self.u32(*n),
+            Value::StrArray(items) => self.array(4, |out| {
+                for item in items {
+                    out.string(item);
+                }
+            }),
+        }
+    }
+}
+
+/// Unmarshals values in either byte order.
+struct Reader<'b> {
+    /// The complete message.
+    buf: &'b [u8],
+    /// The position of the next value.
+    pos: usize,
+    /// Whether the message is in big-endian byte order.
+    big_endian: bool,
+}
+
+impl<'b> Reader<'b> {
+    fn new(buf: &'b [u8]) -> Result<Self> {
+        let big_endian = match buf.first() {
+            Some(b'l') => false,
+            Some(b'B') => true,
+            _ => bail!("Unknown byte order in message"),
+        };
+        Ok(Self {
+            buf,
+            pos: 0,
+            big_endian,
+        })
+    }
+
+    fn align(&mut self, n: usize) {
+        self.pos = self.pos.next_multiple_of(n);
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'b [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| anyhow!("Truncated message"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.align(4);
+        let bytes = self.bytes(4)?.try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        let s = String::from_utf8(self.bytes(len)?.to_vec())?;
+        self.pos += 1;
+        Ok(s)
+    }
+
+    fn signature(&mut self) -> Result<String> {
+        let len = self.u8()? as usize;
+        let s = String::from_utf8(self.bytes(len)?.to_vec())?;
+        self.pos += 1;
+        Ok(s)
+    }
+}
+
+/// A connection to a message bus.
+pub struct Bus {
+    /// The socket connected to the bus.
+    stream: UnixStream,
+    /// The serial of the last message sent.
+    serial: u32,
+}
+
+impl Bus {
+    /// Connect to a bus, authenticate, and claim the service name.
+    pub fn connect(kind: BusKind) -> Result<Self> {
+        let (var, default) = match kind {
+            BusKind::System => (
+                "DBUS_SYSTEM_BUS_ADDRESS",
+                Some("unix:path=/var/run/dbus/system_bus_socket"),
+            ),
+            BusKind::Session => ("DBUS_SESSION_BUS_ADDRESS", None),
+        };
+        let address = std::env::var(var)
+            .ok()
+            .or_else(|| default.map(str::to_owned))
+            .ok_or_else(|| anyhow!("No session bus; `{}` is not set", var))?;
+        let addr = parse_address(&address)?;
+        let mut stream = UnixStream::connect_addr(&addr)
+            .with_context(|| format!("Failed to connect to D-Bus at `{}`", address))?;
+
+        // Authenticate as the user we are running as.
+        let uid = unsafe { libc::geteuid() }.to_string();
+        let hex: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+        stream.write_all(format!("\0AUTH EXTERNAL {}\r\n", hex).as_bytes())?;
+        let line = read_line(&mut stream)?;
+        if !line.starts_with("OK ") {
+            bail!("D-Bus rejected authentication: {}", line);
+        }
+        stream.write_all(b"BEGIN\r\n")?;
+
+        let mut bus = Self { stream, serial: 0 };
+        bus.call(Message::bus_call("Hello", vec![]))?;
+        let reply = bus.call(Message::bus_call(
+            "RequestName",
+            vec![Value::Str(NAME.to_owned()), Value::U32(4)],
+        ))?;
+        match reply.body.first() {
+            Some(Value::U32(1 | 4)) => (),
+            _ => bail!("The D-Bus name {} is already taken", NAME),
+        }
+        info!("Offering the D-Bus service {}", NAME);
+        Ok(bus)
+    }
+
+    /// The socket to wait on for messages.
+    pub fn fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+
+    /// Send a message, assigning it the next serial.
+    pub fn send(&mut self, mut message: Message) -> Result<u32> {
+        self.serial += 1;
+        message.serial = self.serial;
+        self.stream
+            .write_all(&message.encode())
+            .context("Failed to send D-Bus message")?;
+        Ok(self.serial)
+    }
+
+    /// Read the next message.
+    pub fn receive(&mut self) -> Result<Message> {
+        let mut buf = vec![0; 16];
+        self.stream
+            .read_exact(&mut buf)
+            .context("Lost connection to D-Bus")?;
+        let mut r = Reader::new(&buf)?;
+        r.pos = 4;
+        let body_len = r.u32()? as usize;
+        r.pos = 12;
+        let fields_len = r.u32()? as usize;
+        let len = (16 + fields_len).next_multiple_of(8) + body_len;
+        buf.resize(len, 0);
+        self.stream
+            .read_exact(&mut buf[16..])
+            .context("Lost connection to D-Bus")?;
+        Message::decode(&buf)
+    }
+
+    /// Call a method and wait for its reply. Other messages received in the
+    /// meantime are dropped, which is fine while setting up the connection.
+    fn call(&mut self, message: Message) -> Result<Message> {
+        let serial = self.send(message)?;
+        loop {
+            let reply = self.receive()?;
+            if reply.reply_serial != Some(serial) {
+                continue;
+            }
+            if reply.kind == MessageKind::Error {
+                bail!(
+                    "D-Bus call failed: {}: {}",
+                    reply.error_name.as_deref().unwrap_or("unknown error"),
+                    reply.str_arg(0).unwrap_or_default()
+                );
+            }
+            return Ok(reply);
+        }
+    }
+
+    /// Emit an event as a signal.
+    pub fn emit_event(&mut self, event: &Event) -> Result<()> {
+        let member = match event.kind {
+            EventKind::Success => "Finished",
+            EventKind::Warning => "Warning",
+            EventKind::Failure => "Failed",
+        };
+        self.send(Message::signal(
+            member,
+            vec![
+                Value::Str(event.snapshot.clone()),
+                Value::Str(event.command.clone()),
+                Value::Str(event.message.clone()),
+            ],
+        ))?;
+        Ok(())
+    }
+}
+
+/// Parse the first `unix:path=...` or `unix:abstract=...` address in a D-Bus
+/// server address.
+fn parse_address(address: &str) -> Result<SocketAddr> {
+    for entry in address.split(';') {
+        let params = match entry.strip_prefix("unix:") {
+            Some(params) => params,
+            None => continue,
+        };
+        for param in params.split(',') {
+            if let Some(path) = param.strip_prefix("path=") {
+                return Ok(SocketAddr::from_pathname(unescape(path)?)?);
+            }
+            if let Some(name) = param.strip_prefix("abstract=") {
+                return Ok(SocketAddr::from_abstract_name(unescape(name)?)?);
+            }
+        }
+    }
+    bail!("Unsupported D-Bus address `{}`", address)
+}
+
+/// Undo the percent-encoding of a value in a D-Bus address.
+fn unescape(value: &str) -> Result<String> {
+    let mut out = Vec::new();
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+            let hex = std::str::from_utf8(&hex)?;
+            out.push(
+                u8::from_str_radix(hex, 16)
+                    .map_err(|_| anyhow!("Invalid escape `%{}` in D-Bus address", hex))?,
+            );
+        } else {
+            out.push(b);
+        }
+    }
+    Ok(String::from_utf8(out)?)
+}
+
+/// Read a line of the authentication protocol, without reading past it.
+fn read_line(stream: &mut UnixStream) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while !line.ends_with(b"\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            bail!("D-Bus closed the connection during authentication");
+        }
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_owned())
+}
+
+impl<'a> State<'a> {
+    /// Handle the next message from the bus. Triggered runs are carried out
+    /// after replying, such that callers do not time out; their outcome is
+    /// emitted as signals.
+    pub fn serve_dbus_message(
+        &mut self,
+        bus: &mut Bus,
+        snapshots: &[&'a SnapshotConfig],
+        config: &Config,
+        notifier: &Notifier,
+    ) -> Result<()> {
+        let call = bus.receive()?;
+        if call.kind != MessageKind::Call {
+            return Ok(());
+        }
+        let member = call.member.as_deref().unwrap_or_default();
+        debug!("D-Bus call {} from {:?}", member, call.sender);
+        let mut trigger = None;
+        let reply = match (call.interface.as_deref(), member) {
+            (Some("org.freedesktop.DBus.Introspectable") | None, "Introspect") => {
+                Message::reply_to(&call, vec![Value::Str(INTROSPECTION.to_owned())])
+            }
+            (Some("org.freedesktop.DBus.Peer") | None, "Ping") => Message::reply_to(&call, vec![]),
+            (Some(INTERFACE) | None, "ListConfigs") => Message::reply_to(
+                &call,
+                vec![Value::StrArray(
+                    snapshots.iter().map(|s| s.name.clone()).collect(),
+                )],
+            ),
+            (Some(INTERFACE) | None, "GetStatus") => {
+                match self
+                    .current_statuses(snapshots, config)
+                    .and_then(|statuses| Ok(serde_json::to_string(&statuses)?))
+                {
+                    Ok(json) => Message::reply_to(&call, vec![Value::Str(json)]),
+                    Err(e) => Message::error_for(
+                        &call,
+                        "org.freedesktop.DBus.Error.Failed",
+                        &format!("{:#}", e),
+                    ),
+                }
+            }
+            (Some(INTERFACE) | None, "TakeSnapshot" | "Trigger") => {
+                let name = call.str_arg(0).unwrap_or_default();
+                let command = match member {
+                    "TakeSnapshot" => Some("take"),
+                    _ => call.str_arg(1),
+                };
+                let selected: Vec<_> = snapshots
+                    .iter()
+                    .copied()
+                    .filter(|snapshot| name.is_empty() || snapshot.name == name)
+                    .collect();
+                match command {
+                    Some(command @ ("take" | "rotate" | "run")) if !selected.is_empty() => {
+                        trigger = Some((selected, command));
+                        Message::reply_to(&call, vec![])
+                    }
+                    Some("take" | "rotate" | "run") => Message::error_for(
+                        &call,
+                        "org.btrfs_snapshot.Error.UnknownConfig",
+                        &format!("No snapshot config named `{}`", name),
+                    ),
+                    _ => Message::error_for(
+                        &call,
+                        "org.freedesktop.DBus.Error.InvalidArgs",
+                        "The command must be `take`, `rotate`, or `run`",
+                    ),
+                }
+            }
+            _ => Message::error_for(
+                &call,
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                &format!("No method `{}`", member),
+            ),
+        };
+        bus.send(reply)?;
+        if let Some((selected, command)) = trigger {
+            self.run_now(&selected, command, config, notifier)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dbus_messages_round_trip() {
+        let mut message = Message::signal(
+            "Finished",
+            vec![
+                Value::Str("data".to_string()),
+                Value::U32(7),
+                Value::StrArray(vec!["a".to_string(), "bc".to_string()]),
+            ],
+        );
+        message.serial = 3;
+        message.destination = Some(":1.42".to_string());
+        let encoded = message.encode();
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+    }
+}
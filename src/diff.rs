@@ -0,0 +1,189 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Finding the files that changed between two snapshots, by dumping the
+//! metadata-only send stream from one to the other.
+
+use crate::{
+    output::{ChangeKind, ChangedPath, SnapshotDiff},
+    privilege, resolve_snapshot, SnapshotConfig, State,
+};
+use anyhow::{Context, Result};
+use indexmap::IndexMap;
+use std::path::PathBuf;
+
+impl<'a> State<'a> {
+    /// Determine the paths created, modified, and deleted between two
+    /// snapshots of a snapshot config, given by name or path.
+    pub fn diff_snapshots(
+        &mut self,
+        snapshot: &'a SnapshotConfig,
+        from: &str,
+        to: &str,
+    ) -> Result<SnapshotDiff> {
+        debug!("Diff {} and {} of {}", from, to, snapshot.name);
+        self.mount_snapshot_fs(snapshot)?;
+        let from = resolve_snapshot(snapshot, from)?;
+        let to = resolve_snapshot(snapshot, to)?;
+        let mut send = privilege::command("btrfs");
+        send.arg("send")
+            .arg("--quiet")
+            .arg("--no-data")
+            .arg("-p")
+            .arg(&from)
+            .arg(&to);
+        let mut dump = privilege::command("btrfs");
+        dump.arg("receive").arg("--dump");
+        let output = self
+            .executor
+            .run(&mut [&mut send, &mut dump])
+            .with_context(|| format!("Comparing {} and {} failed", from.display(), to.display()))?;
+        Ok(SnapshotDiff {
+            name: snapshot.name.clone(),
+            from,
+            to,
+            changes: parse_dump(&output),
+        })
+    }
+}
+
+/// Summarize the output of `btrfs receive --dump` as the paths that were
+/// created, modified, or deleted. Renamed paths count as deleted at their old
+/// location and created at their new one. Changes to timestamps alone are
+/// ignored, since every change to a directory's contents also touches it.
+pub fn parse_dump(dump: &str) -> Vec<ChangedPath> {
+    let mut prefix = String::new();
+    let mut changes: IndexMap<String, ChangeKind> = IndexMap::new();
+    // Files are created under a temporary name and renamed into place.
+    let mut temporary: Vec<String> = vec![];
+    for line in dump.lines() {
+        let (command, rest) = match line.split_once(char::is_whitespace) {
+            Some(x) => x,
+            None => continue,
+        };
+        let (path, rest) = split_escaped(rest.trim_start());
+        if command == "snapshot" {
+            prefix = format!("{}/", path);
+            continue;
+        }
+        let path = match path.strip_prefix(&prefix) {
+            Some(path) => path.to_owned(),
+            None => continue,
+        };
+        let dest = || {
+            rest.split_once("dest=")
+                .map(|(_, dest)| split_escaped(dest).0)
+                .and_then(|dest| dest.strip_prefix(&prefix).map(String::from))
+        };
+        match command {
+            "mkfile" | "mkdir" | "mknod" | "mkfifo" | "mksock" | "symlink" => {
+                temporary.push(path);
+            }
+            "rename" => {
+                let dest = match dest() {
+                    Some(dest) => dest,
+                    None => continue,
+                };
+                if let Some(index) = temporary.iter().position(|t| *t == path) {
+                    temporary.remove(index);
+                } else {
+                    record(&mut changes, path, ChangeKind::Deleted);
+                }
+                record(&mut changes, dest, ChangeKind::Created);
+            }
+            "link" => {
+                if let Some(dest) = dest() {
+                    record(&mut changes, dest, ChangeKind::Created);
+                }
+            }
+            "unlink" | "rmdir" => record(&mut changes, path, ChangeKind::Deleted),
+            "write" | "update_extent" | "clone" | "truncate" | "chmod" | "chown" | "set_xattr"
+            | "remove_xattr" | "fallocate" | "fileattr"
+                if !temporary.contains(&path) =>
+            {
+                record(&mut changes, path, ChangeKind::Modified)
+            }
+            _ => (),
+        }
+    }
+    changes
+        .into_iter()
+        .map(|(path, kind)| ChangedPath {
+            kind,
+            path: PathBuf::from(path),
+        })
+        .collect()
+}
+
+/// Record a change to a path, merging it with earlier changes to it.
+fn record(changes: &mut IndexMap<String, ChangeKind>, path: String, kind: ChangeKind) {
+    use ChangeKind::*;
+    match (changes.get(&path).copied(), kind) {
+        (None, kind) => {
+            changes.insert(path, kind);
+        }
+        (Some(Created), Deleted) => {
+            changes.shift_remove(&path);
+        }
+        (Some(Deleted), Created) => {
+            changes.insert(path, Modified);
+        }
+        (Some(Created), _) | (Some(_), Modified) => (),
+        (Some(_), kind) => {
+            changes.insert(path, kind);
+        }
+    }
+}
+
+/// Split off the first whitespace-separated field of a line of
+/// `btrfs receive --dump`, undoing the backslash escapes within it.
+fn split_escaped(s: &str) -> (String, &str) {
+    let mut field = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, c)) => field.push(c),
+                None => field.push('\\'),
+            },
+            c if c.is_whitespace() => return (field, &s[i..]),
+            c => field.push(c),
+        }
+    }
+    (field, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_parses_dump() {
+        use ChangeKind::*;
+        let dump = "\
+snapshot        ./snap2                         uuid=a transid=8 parent_uuid=b parent_transid=7
+utimes          ./snap2/                        atime=x mtime=x ctime=x
+mkfile          ./snap2/o258-8-0
+rename          ./snap2/o258-8-0                dest=./snap2/new\\ file
+update_extent   ./snap2/new\\ file                offset=0 len=4
+update_extent   ./snap2/changed                 offset=0 len=4
+unlink          ./snap2/gone
+rmdir           ./snap2/old
+rename          ./snap2/a                       dest=./snap2/b
+";
+        let change = |kind, path: &str| ChangedPath {
+            kind,
+            path: PathBuf::from(path),
+        };
+        assert_eq!(
+            parse_dump(dump),
+            vec![
+                change(Created, "new file"),
+                change(Modified, "changed"),
+                change(Deleted, "gone"),
+                change(Deleted, "old"),
+                change(Deleted, "a"),
+                change(Created, "b"),
+            ]
+        );
+    }
+}
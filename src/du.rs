@@ -0,0 +1,54 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Reporting how much space each snapshot occupies, to tell which ones are
+//! worth deleting.
+
+use crate::{
+    find_snapshots,
+    output::{SnapshotSize, SnapshotUsage},
+    qgroup, SnapshotConfig, State,
+};
+use anyhow::{bail, Context, Result};
+
+impl<'a> State<'a> {
+    /// Measure the referenced and exclusive size of each snapshot of a
+    /// config through quota groups, largest exclusive size first. Quotas are
+    /// enabled first if `enable_quotas` is set.
+    pub fn snapshot_usage(
+        &mut self,
+        snapshot: &'a SnapshotConfig,
+        enable_quotas: bool,
+    ) -> Result<SnapshotUsage> {
+        debug!("Measure snapshot sizes of {}", snapshot.name);
+        self.mount_snapshot_fs(snapshot)?;
+        let mount_point = snapshot.mount_point.as_ref().unwrap();
+        if !qgroup::is_enabled(mount_point) {
+            if !enable_quotas {
+                bail!(
+                    "Quotas are not enabled on {}; pass `--enable-quotas` to enable them, which \
+                     slows down some filesystem operations",
+                    mount_point.display()
+                );
+            }
+            qgroup::enable(mount_point)?;
+        }
+        let mut snapshots = find_snapshots(snapshot, &[])?
+            .into_iter()
+            .map(|entry| {
+                let usage = qgroup::usage(&entry.path)
+                    .with_context(|| format!("Measuring {} failed", entry.path.display()))?;
+                Ok(SnapshotSize {
+                    path: entry.path,
+                    referenced: usage.referenced,
+                    exclusive: usage.exclusive,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        snapshots.sort_by_key(|size| std::cmp::Reverse(size.exclusive));
+        Ok(SnapshotUsage {
+            name: snapshot.name.clone(),
+            exclusive: snapshots.iter().map(|size| size.exclusive).sum(),
+            snapshots,
+        })
+    }
+}
@@ -0,0 +1,590 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Carrying out the operations that mount filesystems and take, delete, and
+//! send snapshots.
+
+use crate::{
+    command_lines, ioctl, mounts, privilege, run, run_pipeline, run_pipeline_counted, subvolume,
+    timeout::timeouts, RotationPlan,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Carries out the operations that modify the system. Taking and rotating
+/// snapshots goes through this trait, such that the logic can be exercised
+/// against a `MockExecutor` without root privileges or a btrfs filesystem.
+/// Executors are shared among the threads that process snapshot configs on
+/// different filesystems concurrently.
+pub trait Executor: Send + Sync {
+    /// Determine the type of the filesystem mounted at a mount point, such as
+    /// `btrfs`, or `None` if nothing is mounted there.
+    fn mounted_fs_type(&self, mount_point: &Path) -> Result<Option<String>>;
+
+    /// Mount a device at a mount point, or the filesystem configured for the
+    /// mount point in fstab if no device is given.
+    fn mount(&self, mount_point: &Path, device: Option<&str>, options: Option<&str>) -> Result<()>;
+
+    /// Unmount a filesystem. A lazy unmount detaches the filesystem right
+    /// away, even if it is busy, and cleans up once it is no longer in use.
+    fn unmount(&self, mount_point: &Path, lazy: bool) -> Result<()>;
+
+    /// Snapshot a subvolume.
+    fn snapshot(&self, source: &Path, target: &Path, readonly: bool) -> Result<()>;
+
+    /// Delete a subvolume.
+    fn delete(&self, path: &Path) -> Result<()>;
+
+    /// Mark a subvolume as read-only or writable.
+    fn set_readonly(&self, path: &Path, readonly: bool) -> Result<()>;
+
+    /// Move a subvolume to another path on the same filesystem.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Determine the UUID of a subvolume.
+    fn subvolume_uuid(&self, path: &Path) -> Result<Option<String>>;
+
+    /// Make sure that `path` is a snapshot of the subvolume at `source`, or of
+    /// an earlier subvolume at `source` with one of the `former_uuids`, and
+    /// that it is read-only if `readonly` is set.
+    fn verify_snapshot(
+        &self,
+        path: &Path,
+        source: &Path,
+        former_uuids: &[String],
+        readonly: bool,
+    ) -> Result<()>;
+
+    /// Make sure that `path` is a snapshot that was completely received from
+    /// another host.
+    fn verify_received(&self, path: &Path) -> Result<()>;
+
+    /// Send a snapshot through a pipeline of commands, and return the number
+    /// of bytes that flowed into the last command.
+    fn send(&self, cmds: &mut [&mut Command]) -> Result<u64>;
+
+    /// Execute a pipeline of commands, such as a hook, and return the stdout of
+    /// the last one.
+    fn run(&self, cmds: &mut [&mut Command]) -> Result<String>;
+
+    /// Execute a pipeline of commands like `run`, but kill it if it does not
+    /// finish within `timeout`.
+    fn run_timed(&self, cmds: &mut [&mut Command], _timeout: Option<Duration>) -> Result<String> {
+        self.run(cmds)
+    }
+
+    /// Wait before retrying an operation that failed.
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+
+    /// Delete the snapshots marked for deletion by a rotation plan.
+    fn apply(&self, plan: &RotationPlan) -> Result<()> {
+        for path in &plan.delete {
+            self.delete(path)
+                .with_context(|| format!("Deleting snapshot {} failed", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl<E: Executor + ?Sized> Executor for Arc<E> {
+    fn mounted_fs_type(&self, mount_point: &Path) -> Result<Option<String>> {
+        (**self).mounted_fs_type(mount_point)
+    }
+
+    fn mount(&self, mount_point: &Path, device: Option<&str>, options: Option<&str>) -> Result<()> {
+        (**self).mount(mount_point, device, options)
+    }
+
+    fn unmount(&self, mount_point: &Path, lazy: bool) -> Result<()> {
+        (**self).unmount(mount_point, lazy)
+    }
+
+    fn snapshot(&self, source: &Path, target: &Path, readonly: bool) -> Result<()> {
+        (**self).snapshot(source, target, readonly)
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        (**self).delete(path)
+    }
+
+    fn set_readonly(&self, path: &Path, readonly: bool) -> Result<()> {
+        (**self).set_readonly(path, readonly)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        (**self).rename(from, to)
+    }
+
+    fn subvolume_uuid(&self, path: &Path) -> Result<Option<String>> {
+        (**self).subvolume_uuid(path)
+    }
+
+    fn verify_snapshot(
+        &self,
+        path: &Path,
+        source: &Path,
+        former_uuids: &[String],
+        readonly: bool,
+    ) -> Result<()> {
+        (**self).verify_snapshot(path, source, former_uuids, readonly)
+    }
+
+    fn verify_received(&self, path: &Path) -> Result<()> {
+        (**self).verify_received(path)
+    }
+
+    fn send(&self, cmds: &mut [&mut Command]) -> Result<u64> {
+        (**self).send(cmds)
+    }
+
+    fn run(&self, cmds: &mut [&mut Command]) -> Result<String> {
+        (**self).run(cmds)
+    }
+
+    fn run_timed(&self, cmds: &mut [&mut Command], timeout: Option<Duration>) -> Result<String> {
+        (**self).run_timed(cmds, timeout)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        (**self).sleep(duration)
+    }
+}
+
+impl Default for Box<dyn Executor> {
+    fn default() -> Self {
+        Box::new(SystemExecutor)
+    }
+}
+
+/// Carries out operations on the actual system, using btrfs ioctls for
+/// subvolumes and running commands for everything else. With `--sudo`, the
+/// subvolumes are handled by running `btrfs` through `sudo` instead.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemExecutor;
+
+impl Executor for SystemExecutor {
+    fn mounted_fs_type(&self, mount_point: &Path) -> Result<Option<String>> {
+        if privilege::remote().is_some() {
+            // `findmnt` fails if nothing is mounted there.
+            let fs_type = run(privilege::command("findmnt")
+                .arg("-n")
+                .arg("-o")
+                .arg("FSTYPE")
+                .arg("--mountpoint")
+                .arg(mount_point))
+            .ok();
+            return Ok(fs_type
+                .map(|t| t.trim().to_owned())
+                .filter(|t| !t.is_empty()));
+        }
+        let mounts = mounts::try_read_mounts().context("Checking mounts failed")?;
+        Ok(mounts::mount_at(&mounts, mount_point).map(|m| m.fs_type.clone()))
+    }
+
+    fn mount(&self, mount_point: &Path, device: Option<&str>, options: Option<&str>) -> Result<()> {
+        let mut cmd = privilege::command("mount");
+        if let Some(options) = options {
+            cmd.arg("-o").arg(options);
+        }
+        if let Some(device) = device {
+            if privilege::remote().is_some() || !mount_point.exists() {
+                run(privilege::command("mkdir").arg("-p").arg(mount_point)).with_context(|| {
+                    format!("Failed to create mount point {}", mount_point.display())
+                })?;
+            }
+            cmd.arg(device);
+        }
+        let result = run_pipeline(&mut [cmd.arg(mount_point)], timeouts().mount)
+            .with_context(|| format!("Mounting {} failed", mount_point.display()));
+        privilege::explain(
+            result,
+            "mounting",
+            Some("add the `user` option to its fstab entry"),
+        )?;
+        Ok(())
+    }
+
+    fn unmount(&self, mount_point: &Path, lazy: bool) -> Result<()> {
+        let mut cmd = privilege::command("umount");
+        if lazy {
+            cmd.arg("--lazy");
+        }
+        let result = run_pipeline(&mut [cmd.arg(mount_point)], timeouts().mount)
+            .with_context(|| format!("Unmounting {} failed", mount_point.display()));
+        privilege::explain(result, "unmounting", None)?;
+        Ok(())
+    }
+
+    fn snapshot(&self, source: &Path, target: &Path, readonly: bool) -> Result<()> {
+        if let Some(dir) = target.parent() {
+            subvolume::check_same_filesystem(source, dir)?;
+        }
+        if privilege::via_commands() {
+            run(&mut subvolume::snapshot_command(source, target, readonly))?;
+            return Ok(());
+        }
+        privilege::explain(
+            ioctl::create_snapshot(source, target, readonly),
+            "snapshotting",
+            Some("own the subvolume and its snapshot dir"),
+        )
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        if privilege::via_commands() {
+            run(&mut subvolume::delete_command(path))?;
+            return Ok(());
+        }
+        if privilege::is_root() {
+            return ioctl::delete_subvolume(path);
+        }
+
+        // Unprivileged users may only delete writable subvolumes, and only on
+        // filesystems mounted with `user_subvol_rm_allowed`.
+        let readonly = ioctl::is_readonly(path)?;
+        if readonly {
+            privilege::explain(
+                ioctl::set_readonly(path, false),
+                "deleting snapshots",
+                Some("own the snapshots"),
+            )?;
+        }
+        let result = ioctl::delete_subvolume(path);
+        if result.is_err() && readonly {
+            ioctl::set_readonly(path, true).ok();
+        }
+        privilege::explain(
+            result,
+            "deleting snapshots",
+            Some("mount the filesystem with `user_subvol_rm_allowed`"),
+        )
+    }
+
+    fn set_readonly(&self, path: &Path, readonly: bool) -> Result<()> {
+        if privilege::via_commands() {
+            run(&mut subvolume::readonly_command(path, readonly))?;
+            return Ok(());
+        }
+        privilege::explain(
+            ioctl::set_readonly(path, readonly),
+            "changing the read-only flag",
+            Some("own the subvolume"),
+        )
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        if privilege::via_commands() {
+            run(privilege::command("mv").arg(from).arg(to))?;
+            return Ok(());
+        }
+        std::fs::rename(from, to)
+            .with_context(|| format!("Failed to move {} to {}", from.display(), to.display()))
+    }
+
+    fn subvolume_uuid(&self, path: &Path) -> Result<Option<String>> {
+        Ok(subvolume::show(path)?.uuid)
+    }
+
+    fn verify_snapshot(
+        &self,
+        path: &Path,
+        source: &Path,
+        former_uuids: &[String],
+        readonly: bool,
+    ) -> Result<()> {
+        subvolume::verify_snapshot(path, source, former_uuids, readonly)
+    }
+
+    fn verify_received(&self, path: &Path) -> Result<()> {
+        subvolume::verify_received(path)
+    }
+
+    fn send(&self, cmds: &mut [&mut Command]) -> Result<u64> {
+        privilege::explain(
+            run_pipeline_counted(cmds, timeouts().send),
+            "sending snapshots",
+            None,
+        )
+    }
+
+    fn run(&self, cmds: &mut [&mut Command]) -> Result<String> {
+        run_pipeline(cmds, None)
+    }
+
+    fn run_timed(&self, cmds: &mut [&mut Command], timeout: Option<Duration>) -> Result<String> {
+        run_pipeline(cmds, timeout)
+    }
+}
+
+/// An operation recorded by a `MockExecutor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// A filesystem was mounted.
+    Mount(PathBuf),
+    /// A filesystem was unmounted.
+    Unmount(PathBuf),
+    /// A subvolume was snapshotted.
+    Snapshot {
+        /// The subvolume that was snapshotted.
+        source: PathBuf,
+        /// The path of the new snapshot.
+        target: PathBuf,
+        /// Whether the snapshot is read-only.
+        readonly: bool,
+    },
+    /// A subvolume was deleted.
+    Delete(PathBuf),
+    /// A subvolume was marked as read-only or writable.
+    SetReadonly(PathBuf, bool),
+    /// A subvolume was moved to another path.
+    Rename(PathBuf, PathBuf),
+    /// A snapshot was sent through a pipeline of commands.
+    Send(Vec<Vec<String>>),
+    /// A pipeline of commands was executed.
+    Run(Vec<Vec<String>>),
+}
+
+/// Records operations instead of carrying them out. Clones share the same
+/// record, such that one clone can be handed to a `State` and the other used
+/// to inspect what happened.
+#[derive(Debug, Default, Clone)]
+pub struct MockExecutor {
+    /// The operations performed so far.
+    operations: Arc<Mutex<Vec<Operation>>>,
+    /// The mount points reported as mounted.
+    mounted: Arc<Mutex<Vec<PathBuf>>>,
+    /// The paths reported as not being snapshots of their subvolume.
+    foreign: Arc<Mutex<Vec<PathBuf>>>,
+    /// The mount points that are busy, with the number of attempts to unmount
+    /// them that still fail. Lazy unmounts always succeed.
+    busy: Arc<Mutex<HashMap<PathBuf, u32>>>,
+    /// The UUID and parent UUID of the subvolumes whose identity is tracked.
+    /// Snapshots of tracked subvolumes are tracked as well.
+    subvolumes: Arc<Mutex<HashMap<PathBuf, MockSubvolume>>>,
+    /// The number of UUIDs handed out to new snapshots.
+    uuids: Arc<AtomicU64>,
+    /// The environment variables set on the first command of every pipeline
+    /// run.
+    environments: Arc<Mutex<Vec<HashMap<String, String>>>>,
+    /// The outputs of commands run, keyed by a subcommand such as
+    /// `filesystem usage`, and returned in order.
+    outputs: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+/// The identity of a subvolume tracked by a `MockExecutor`.
+#[derive(Debug, Clone)]
+struct MockSubvolume {
+    /// The UUID of the subvolume.
+    uuid: String,
+    /// The UUID of the subvolume this one is a snapshot of.
+    parent_uuid: Option<String>,
+}
+
+impl MockExecutor {
+    /// Create a mock that reports the given mount points as mounted.
+    pub fn with_mounted(mount_points: impl IntoIterator<Item = PathBuf>) -> Self {
+        let mock = Self::default();
+        mock.mounted.lock().unwrap().extend(mount_points);
+        mock
+    }
+
+    /// Track the identity of a subvolume, such that snapshots are only
+    /// verified if their parent UUID matches.
+    pub fn add_subvolume(&self, path: impl Into<PathBuf>, uuid: &str, parent_uuid: Option<&str>) {
+        let subvolume = MockSubvolume {
+            uuid: uuid.to_owned(),
+            parent_uuid: parent_uuid.map(String::from),
+        };
+        self.subvolumes
+            .lock()
+            .unwrap()
+            .insert(path.into(), subvolume);
+    }
+
+    /// Report a mount point as busy, such that the next `attempts` attempts to
+    /// unmount it fail.
+    pub fn add_busy(&self, mount_point: impl Into<PathBuf>, attempts: u32) {
+        self.busy
+            .lock()
+            .unwrap()
+            .insert(mount_point.into(), attempts);
+    }
+
+    /// Report a path as not being a snapshot of its subvolume.
+    pub fn add_foreign(&self, path: impl Into<PathBuf>) {
+        self.foreign.lock().unwrap().push(path.into());
+    }
+
+    /// Report `output` as the stdout of the next command that contains
+    /// `command`, e.g. `filesystem usage`.
+    pub fn add_output(&self, command: &str, output: &str) {
+        self.outputs
+            .lock()
+            .unwrap()
+            .push((command.to_owned(), output.to_owned()));
+    }
+
+    /// Get the operations performed so far.
+    pub fn operations(&self) -> Vec<Operation> {
+        self.operations.lock().unwrap().clone()
+    }
+
+    /// Get the environment variables set on the first command of every
+    /// pipeline run so far.
+    pub fn environments(&self) -> Vec<HashMap<String, String>> {
+        self.environments.lock().unwrap().clone()
+    }
+
+    /// Record an operation.
+    fn record(&self, operation: Operation) {
+        self.operations.lock().unwrap().push(operation);
+    }
+}
+
+impl Executor for MockExecutor {
+    fn mounted_fs_type(&self, mount_point: &Path) -> Result<Option<String>> {
+        let mounted = self.mounted.lock().unwrap();
+        Ok(mounted
+            .iter()
+            .any(|m| m == mount_point)
+            .then(|| "btrfs".to_string()))
+    }
+
+    fn mount(
+        &self,
+        mount_point: &Path,
+        _device: Option<&str>,
+        _options: Option<&str>,
+    ) -> Result<()> {
+        self.mounted.lock().unwrap().push(mount_point.to_owned());
+        self.record(Operation::Mount(mount_point.to_owned()));
+        Ok(())
+    }
+
+    fn unmount(&self, mount_point: &Path, lazy: bool) -> Result<()> {
+        if let Some(attempts) = self.busy.lock().unwrap().get_mut(mount_point) {
+            if !lazy && *attempts > 0 {
+                *attempts -= 1;
+                bail!("umount: {}: target is busy", mount_point.display());
+            }
+        }
+        self.mounted.lock().unwrap().retain(|m| m != mount_point);
+        self.record(Operation::Unmount(mount_point.to_owned()));
+        Ok(())
+    }
+
+    fn snapshot(&self, source: &Path, target: &Path, readonly: bool) -> Result<()> {
+        let mut subvolumes = self.subvolumes.lock().unwrap();
+        if let Some(parent) = subvolumes.get(source) {
+            let snapshot = MockSubvolume {
+                uuid: format!("snapshot-{}", self.uuids.fetch_add(1, Ordering::SeqCst)),
+                parent_uuid: Some(parent.uuid.clone()),
+            };
+            subvolumes.insert(target.to_owned(), snapshot);
+        }
+        self.record(Operation::Snapshot {
+            source: source.to_owned(),
+            target: target.to_owned(),
+            readonly,
+        });
+        Ok(())
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        self.subvolumes.lock().unwrap().remove(path);
+        self.record(Operation::Delete(path.to_owned()));
+        Ok(())
+    }
+
+    fn set_readonly(&self, path: &Path, readonly: bool) -> Result<()> {
+        self.record(Operation::SetReadonly(path.to_owned(), readonly));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut subvolumes = self.subvolumes.lock().unwrap();
+        if let Some(subvolume) = subvolumes.remove(from) {
+            subvolumes.insert(to.to_owned(), subvolume);
+        }
+        self.record(Operation::Rename(from.to_owned(), to.to_owned()));
+        Ok(())
+    }
+
+    fn subvolume_uuid(&self, path: &Path) -> Result<Option<String>> {
+        let subvolumes = self.subvolumes.lock().unwrap();
+        Ok(subvolumes.get(path).map(|subvolume| subvolume.uuid.clone()))
+    }
+
+    fn verify_snapshot(
+        &self,
+        path: &Path,
+        source: &Path,
+        former_uuids: &[String],
+        _readonly: bool,
+    ) -> Result<()> {
+        let subvolumes = self.subvolumes.lock().unwrap();
+        let is_snapshot = match subvolumes.get(path) {
+            Some(subvolume) => subvolume.parent_uuid.as_ref().is_some_and(|parent| {
+                subvolumes.get(source).map(|source| &source.uuid) == Some(parent)
+                    || former_uuids.contains(parent)
+            }),
+            None => true,
+        };
+        if !is_snapshot || self.foreign.lock().unwrap().iter().any(|p| p == path) {
+            bail!(
+                "{} is not a snapshot of {}",
+                path.display(),
+                source.display()
+            );
+        }
+        Ok(())
+    }
+
+    fn verify_received(&self, path: &Path) -> Result<()> {
+        if self.foreign.lock().unwrap().iter().any(|p| p == path) {
+            bail!("{} is not a received snapshot", path.display());
+        }
+        Ok(())
+    }
+
+    fn send(&self, cmds: &mut [&mut Command]) -> Result<u64> {
+        self.record(Operation::Send(command_lines(cmds)));
+        Ok(0)
+    }
+
+    fn run(&self, cmds: &mut [&mut Command]) -> Result<String> {
+        let lines = command_lines(cmds);
+        let line = lines.last().map(|line| line.join(" ")).unwrap_or_default();
+        let environment = cmds
+            .first()
+            .into_iter()
+            .flat_map(|cmd| cmd.get_envs())
+            .filter_map(|(key, value)| {
+                let value = value?.to_string_lossy().into_owned();
+                Some((key.to_string_lossy().into_owned(), value))
+            })
+            .collect();
+        self.environments.lock().unwrap().push(environment);
+        self.record(Operation::Run(lines));
+        let mut outputs = self.outputs.lock().unwrap();
+        match outputs
+            .iter()
+            .position(|(command, _)| line.contains(command))
+        {
+            Some(index) => Ok(outputs.remove(index).1),
+            None => Ok(String::new()),
+        }
+    }
+
+    fn sleep(&self, _duration: Duration) {}
+}
@@ -0,0 +1,258 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Exit codes that tell wrapper scripts and systemd why a run failed, and the
+//! structured error reports printed in JSON output.
+
+use crate::output::{ActionKind, OutputFormat};
+use serde::Serialize;
+use std::{fmt, path::PathBuf, sync::OnceLock};
+
+/// The format in which errors are reported, set once from the command line.
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+/// Report errors in `format` from now on.
+pub fn init(format: OutputFormat) {
+    FORMAT.set(format).ok();
+}
+
+/// The code the tool exits with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Everything succeeded.
+    Success = 0,
+    /// The configuration or command line is invalid.
+    ConfigError = 1,
+    /// Some snapshot configs were processed successfully, but one failed.
+    PartialFailure = 2,
+    /// No snapshot config could be processed successfully.
+    Failure = 3,
+    /// Another instance holds the lock.
+    LockBusy = 4,
+}
+
+/// An error that makes the tool exit with a specific code. Errors without an
+/// explicit code exit with `ExitCode::Failure`.
+#[derive(Debug)]
+pub struct ExitError {
+    /// The code to exit with.
+    pub code: ExitCode,
+    /// The underlying error.
+    pub error: anyhow::Error,
+    /// Further errors that occurred alongside the underlying one.
+    pub others: Vec<anyhow::Error>,
+}
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for ExitError {}
+
+/// Assign an exit code to the error of a result.
+pub trait WithExitCode<T> {
+    /// Make the error exit the tool with `code`.
+    fn exit_code(self, code: ExitCode) -> anyhow::Result<T>;
+}
+
+impl<T> WithExitCode<T> for anyhow::Result<T> {
+    fn exit_code(self, code: ExitCode) -> anyhow::Result<T> {
+        self.map_err(|error| {
+            ExitError {
+                code,
+                error,
+                others: vec![],
+            }
+            .into()
+        })
+    }
+}
+
+/// A command that exited with a non-zero code. Attached as context to the
+/// error carrying the command's stderr.
+#[derive(Debug, Clone)]
+pub struct CommandFailure {
+    /// The program and its arguments.
+    pub command: Vec<String>,
+    /// The code the command exited with, or `None` if it was killed.
+    pub exit_code: Option<i32>,
+    /// What the command printed to stderr.
+    pub stderr: String,
+}
+
+impl fmt::Display for CommandFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Command")?;
+        for arg in &self.command {
+            write!(f, " {:?}", arg)?;
+        }
+        write!(f, " failed with exit code {}", self.exit_code.unwrap_or(0))
+    }
+}
+
+/// Marks an error as having occurred while performing an action on a
+/// snapshot. Displays exactly like the error it wraps.
+#[derive(Debug)]
+pub struct ActionFailure {
+    /// What was done to the snapshot.
+    pub operation: ActionKind,
+    /// The path of the snapshot.
+    pub path: PathBuf,
+    /// The underlying error.
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for ActionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for ActionFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// Marks an error as having occurred while processing a snapshot config.
+/// Displays exactly like the error it wraps.
+#[derive(Debug)]
+pub struct ConfigFailure {
+    /// The name of the snapshot config.
+    pub config: String,
+    /// The underlying error.
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for ConfigFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for ConfigFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// What kind of failure an error is, such that tooling can branch on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The configuration or command line is invalid.
+    Config,
+    /// Another instance holds the lock.
+    LockBusy,
+    /// The tool lacks the privileges to do something.
+    Permission,
+    /// A command exited with a non-zero code.
+    Command,
+    /// Anything else.
+    Other,
+}
+
+/// A failure, as reported in JSON output.
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    /// What kind of failure this is.
+    pub kind: ErrorKind,
+    /// The action that failed, if the failure occurred in one.
+    pub operation: Option<ActionKind>,
+    /// The snapshot config being processed, if any.
+    pub config: Option<String>,
+    /// The snapshot the failed action was performed on, if any.
+    pub path: Option<PathBuf>,
+    /// The command that failed, if any.
+    pub command: Option<Vec<String>>,
+    /// The code the failed command exited with.
+    pub exit_code: Option<i32>,
+    /// What the failed command printed to stderr.
+    pub stderr: Option<String>,
+    /// The error message and its causes, outermost first.
+    pub messages: Vec<String>,
+}
+
+impl ErrorReport {
+    /// Describe an error that makes the tool exit with `code`.
+    pub fn new(error: &anyhow::Error, code: ExitCode) -> Self {
+        let action = find::<ActionFailure>(error);
+        let command = find::<CommandFailure>(error);
+        let kind = match code {
+            ExitCode::ConfigError => ErrorKind::Config,
+            ExitCode::LockBusy => ErrorKind::LockBusy,
+            _ if crate::privilege::is_permission_error(error) => ErrorKind::Permission,
+            _ if command.is_some() => ErrorKind::Command,
+            _ => ErrorKind::Other,
+        };
+        Self {
+            kind,
+            operation: action.map(|a| a.operation),
+            config: find::<ConfigFailure>(error).map(|c| c.config.clone()),
+            path: action.map(|a| a.path.clone()),
+            command: command.map(|c| c.command.clone()),
+            exit_code: command.and_then(|c| c.exit_code),
+            stderr: command.map(|c| c.stderr.clone()),
+            messages: error.chain().map(|cause| cause.to_string()).collect(),
+        }
+    }
+}
+
+/// Find an error or context of type `T` in an error, looking through the
+/// errors wrapped by `ConfigFailure` and `ActionFailure`.
+fn find<T>(error: &anyhow::Error) -> Option<&T>
+where
+    T: fmt::Display + fmt::Debug + Send + Sync + 'static,
+{
+    error
+        .downcast_ref::<T>()
+        .or_else(|| {
+            error
+                .downcast_ref::<ConfigFailure>()
+                .and_then(|c| find(&c.error))
+        })
+        .or_else(|| {
+            error
+                .downcast_ref::<ActionFailure>()
+                .and_then(|a| find(&a.error))
+        })
+}
+
+/// The report printed when a run fails in JSON output.
+#[derive(Debug, Serialize)]
+struct FailureReport {
+    /// The code the tool exits with.
+    exit_code: i32,
+    /// Every error that occurred.
+    errors: Vec<ErrorReport>,
+}
+
+/// Print an error and determine the code to exit with.
+pub fn report(error: anyhow::Error) -> ExitCode {
+    let (code, error, others) = match error.downcast::<ExitError>() {
+        Ok(e) => (e.code, e.error, e.others),
+        Err(error) => (ExitCode::Failure, error, vec![]),
+    };
+    if FORMAT.get() == Some(&OutputFormat::Json) {
+        let report = FailureReport {
+            exit_code: code as i32,
+            errors: std::iter::once(&error)
+                .chain(&others)
+                .map(|e| ErrorReport::new(e, code))
+                .collect(),
+        };
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => {
+                println!("{}", json);
+                return code;
+            }
+            Err(e) => eprintln!("Error: Failed to report error as JSON: {}", e),
+        }
+    }
+    for e in others {
+        error!("{:?}", e);
+    }
+    eprintln!("Error: {:?}", error);
+    code
+}
@@ -0,0 +1,94 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Finding and deleting subvolumes in snapshot directories that belong to no
+//! config, such as the snapshots left behind by a renamed config.
+
+use crate::{
+    init, list_dir, output::Orphan, subvolume, trash::TRASH_DIR, Config, SnapshotConfig, State,
+};
+use anyhow::{bail, Context, Result};
+
+impl<'a> State<'a> {
+    /// Find the subvolumes in the snapshot directories of the given configs
+    /// whose names match the format of no config using that directory.
+    pub fn find_orphans(
+        &mut self,
+        config: &'a Config,
+        snapshots: &[&'a SnapshotConfig],
+    ) -> Result<Vec<Orphan>> {
+        let mut orphans = vec![];
+        let mut seen = vec![];
+        for &snapshot in snapshots {
+            let dir = snapshot.snapshot_dir.as_deref().unwrap();
+            if seen.contains(&dir) {
+                continue;
+            }
+            seen.push(dir);
+            self.mount_snapshot_fs(snapshot)?;
+            debug!("Looking for orphaned snapshots in {}", dir.display());
+
+            // Every config that keeps its snapshots in the same directory
+            // lays claim to the names matching its format.
+            let namings = config
+                .snapshots
+                .values()
+                .filter(|other| other.snapshot_dir.as_deref() == Some(dir))
+                .map(|other| other.naming())
+                .collect::<Result<Vec<_>>>()?;
+            let mut paths = vec![];
+            for path in list_dir(dir)
+                .with_context(|| format!("Failed to read snapshot dir {}", dir.display()))?
+            {
+                let name = path.file_name().unwrap().to_string_lossy();
+                if name == TRASH_DIR
+                    || namings.iter().any(|naming| naming.parse(&name).is_some())
+                    || !subvolume::is_subvolume(&path)
+                {
+                    continue;
+                }
+                paths.push(path);
+            }
+            paths.sort();
+            orphans.extend(paths.into_iter().map(|path| Orphan {
+                config: snapshot.name.clone(),
+                path,
+            }));
+        }
+        Ok(orphans)
+    }
+
+    /// Delete orphaned snapshots, after asking for confirmation unless `yes`
+    /// is set.
+    pub fn delete_orphans(&mut self, config: &'a Config, orphans: &[Orphan]) -> Result<()> {
+        if orphans.is_empty() {
+            return Ok(());
+        }
+        if !self.dry_run && !self.yes {
+            confirm_orphans(orphans.len())?;
+        }
+        for orphan in orphans {
+            let snapshot = &config.snapshots[&orphan.config];
+            self.delete_nested(snapshot, &orphan.path)?;
+            self.delete_subvolume(snapshot, &orphan.path)
+                .with_context(|| {
+                    format!(
+                        "Deleting orphaned snapshot {} failed",
+                        orphan.path.display()
+                    )
+                })?;
+        }
+        Ok(())
+    }
+}
+
+/// Ask whether to delete orphaned snapshots.
+fn confirm_orphans(count: usize) -> Result<()> {
+    let question = format!("Deleting {} orphaned snapshot(s) cannot be undone", count);
+    if !atty::is(atty::Stream::Stdin) {
+        bail!("{}; pass `--yes` to delete them anyway", question);
+    }
+    if !init::confirm(&format!("{}. Continue?", question), false)? {
+        bail!("Deleting orphaned snapshots was not confirmed");
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! An append-only log of every action performed on a snapshot, which proves
+//! when snapshots were taken, removed, and sent.
+
+use crate::{output::ActionKind, SnapshotConfig};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Timelike as _};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// The name of the file within the state directory that holds the history.
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// The history file, holding one JSON object per line.
+#[derive(Debug, Clone)]
+pub struct History {
+    /// The path of the history file.
+    path: PathBuf,
+}
+
+/// A single action in the history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// When the action finished.
+    pub time: DateTime<Local>,
+    /// The name of the snapshot config.
+    pub config: String,
+    /// What was done to the snapshot.
+    pub action: ActionKind,
+    /// The path of the snapshot subvolume.
+    pub path: PathBuf,
+    /// Whether the action succeeded.
+    pub success: bool,
+    /// The error message if the action failed.
+    pub error: Option<String>,
+}
+
+impl History {
+    /// Refer to the history file in a state directory.
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join(HISTORY_FILE),
+        }
+    }
+
+    /// Append the outcome of an action to the history. Each entry is written
+    /// with a single write to a file opened for appending, such that entries
+    /// of concurrent workers do not interleave.
+    pub fn record(
+        &self,
+        snapshot: &SnapshotConfig,
+        kind: ActionKind,
+        path: &Path,
+        result: Result<(), &anyhow::Error>,
+    ) -> Result<()> {
+        let entry = HistoryEntry {
+            time: Local::now().with_nanosecond(0).unwrap(),
+            config: snapshot.name.clone(),
+            action: kind,
+            path: path.to_owned(),
+            success: result.is_ok(),
+            error: result.err().map(|e| format!("{:#}", e)),
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create state dir {}", dir.display()))?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to append to history {}", self.path.display()))
+    }
+
+    /// Read all entries of the history, oldest first. Returns no entries if
+    /// the file does not exist yet.
+    pub fn read(&self) -> Result<Vec<HistoryEntry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        debug!("Loading history {}", self.path.display());
+        let buf = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read history from {}", self.path.display()))?;
+        buf.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                serde_json::from_str(line).with_context(|| {
+                    format!(
+                        "Failed to parse line {} of history {}",
+                        index + 1,
+                        self.path.display()
+                    )
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Pinning individual snapshots such that rotation never deletes them.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Timelike as _};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The name of the file within the state directory that holds the pins.
+const HOLD_FILE: &str = "holds.toml";
+
+/// The snapshots that are currently held.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct HoldFile {
+    /// The held snapshots, keyed by their canonical path.
+    #[serde(default)]
+    pub holds: IndexMap<PathBuf, Hold>,
+}
+
+/// A single held snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hold {
+    /// When the hold was placed.
+    pub time: DateTime<Local>,
+}
+
+impl HoldFile {
+    /// Load the hold file from a state directory. Returns no holds if the file
+    /// does not exist yet.
+    pub fn load(state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(HOLD_FILE);
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        debug!("Loading holds {}", path.display());
+        let buf = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read holds from {}", path.display()))?;
+        toml::de::from_str(&buf)
+            .with_context(|| format!("Failed to parse holds from {}", path.display()))
+    }
+
+    /// Write the hold file into a state directory.
+    pub fn save(&self, state_dir: &Path) -> Result<()> {
+        let path = state_dir.join(HOLD_FILE);
+        debug!("Saving holds {}", path.display());
+        std::fs::create_dir_all(state_dir)
+            .with_context(|| format!("Failed to create state dir {}", state_dir.display()))?;
+        std::fs::write(&path, toml::ser::to_string(self)?)
+            .with_context(|| format!("Failed to write holds to {}", path.display()))
+    }
+
+    /// Place a hold on a snapshot. Returns false if it was already held.
+    pub fn hold(&mut self, path: &Path) -> Result<bool> {
+        let path = std::fs::canonicalize(path)
+            .with_context(|| format!("Snapshot {} does not exist", path.display()))?;
+        if self.holds.contains_key(&path) {
+            return Ok(false);
+        }
+        self.holds.insert(
+            path,
+            Hold {
+                time: Local::now().with_nanosecond(0).unwrap(),
+            },
+        );
+        Ok(true)
+    }
+
+    /// Release the hold on a snapshot. Returns false if it was not held.
+    pub fn release(&mut self, path: &Path) -> bool {
+        self.holds.shift_remove(&canonical(path)).is_some()
+    }
+
+    /// Check whether a snapshot is held.
+    pub fn is_held(&self, path: &Path) -> bool {
+        self.holds.contains_key(&canonical(path))
+    }
+}
+
+/// Resolve a path to its canonical form, or leave it as is if it does not
+/// exist anymore.
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
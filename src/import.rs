@@ -0,0 +1,758 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Importing the configs and snapshots of snapper and btrbk, such that a
+//! system can switch over without losing its existing snapshots.
+
+use crate::{
+    init::{confirm, DEFAULT_FORMAT},
+    mounts::{find_mount, read_mounts},
+    output::OutputFormat,
+    pkg, SnapshotConfig, State,
+};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone as _, Utc};
+use regex::Regex;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// The directory snapper keeps its configs in.
+pub const SNAPPER_CONFIG_DIR: &str = "/etc/snapper/configs";
+
+/// Where btrbk keeps its config.
+pub const BTRBK_CONFIG: &str = "/etc/btrbk/btrbk.conf";
+
+/// The directory within a snapshotted subvolume that snapper keeps its
+/// numbered snapshots in.
+const SNAPPER_SNAPSHOT_DIR: &str = ".snapshots";
+
+/// A snapper config, as far as it has an equivalent in btrfs-snapshot.
+struct SnapperConfig {
+    /// The name of the config, which is the name of its file.
+    name: String,
+    /// The path of the snapshotted subvolume.
+    subvolume: PathBuf,
+    /// The timeline limits, as `(key, count)` pairs of the corresponding
+    /// `keep_*` option.
+    limits: Vec<(&'static str, usize)>,
+    /// The settings that have no equivalent, as `KEY=VALUE` lines.
+    ignored: Vec<String>,
+}
+
+/// The kind of a snapper snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SnapperKind {
+    /// A snapshot on its own, such as a timeline snapshot.
+    Single,
+    /// A snapshot before a transaction.
+    Pre,
+    /// A snapshot after a transaction, paired with a `Pre` snapshot.
+    Post,
+}
+
+/// A numbered snapper snapshot.
+struct SnapperSnapshot {
+    /// The number of the snapshot, which is the name of its directory.
+    number: u64,
+    /// When the snapshot was taken.
+    date: DateTime<Utc>,
+    /// The kind of the snapshot.
+    kind: SnapperKind,
+    /// The number of the `Pre` snapshot a `Post` snapshot is paired with.
+    pre_num: Option<u64>,
+}
+
+/// Read the snapper configs in `snapper_dir` and write an equivalent config
+/// to `path`. In a dry run the configuration is printed instead.
+pub fn import_snapper(path: &Path, snapper_dir: &Path, dry_run: bool) -> Result<()> {
+    confirm_overwrite(path, dry_run)?;
+    let configs = read_snapper_configs(snapper_dir)?;
+    if configs.is_empty() {
+        bail!("No snapper configs found in {}", snapper_dir.display());
+    }
+    write_config(path, &render_config(&configs), dry_run)
+}
+
+/// Read a btrbk config and write an equivalent config to `path`. In a dry run
+/// the configuration is printed instead.
+pub fn import_btrbk(path: &Path, btrbk_config: &Path, dry_run: bool) -> Result<()> {
+    confirm_overwrite(path, dry_run)?;
+    let buf = std::fs::read_to_string(btrbk_config)
+        .with_context(|| format!("Failed to read btrbk config {}", btrbk_config.display()))?;
+    let config = parse_btrbk_config(&buf)
+        .with_context(|| format!("Failed to parse btrbk config {}", btrbk_config.display()))?;
+    if config.volumes.iter().all(|v| v.subvolumes.is_empty()) {
+        bail!("No subvolumes found in {}", btrbk_config.display());
+    }
+    write_config(path, &render_btrbk_config(&config), dry_run)
+}
+
+/// Ask before overwriting an existing config.
+fn confirm_overwrite(path: &Path, dry_run: bool) -> Result<()> {
+    if path.exists() && !dry_run && !confirm(&format!("Overwrite {}?", path.display()), false)? {
+        bail!("Not overwriting existing config {}", path.display());
+    }
+    Ok(())
+}
+
+/// Write a generated config to `path`, or print it in a dry run.
+fn write_config(path: &Path, config: &str, dry_run: bool) -> Result<()> {
+    if dry_run {
+        print!("{}", config);
+        return Ok(());
+    }
+    std::fs::write(path, config)
+        .with_context(|| format!("Failed to write config to {}", path.display()))?;
+    println!("Wrote config to {}", path.display());
+    println!("Run `btrfs-snapshot check-config` to verify it");
+    Ok(())
+}
+
+/// Read all snapper configs in a directory, sorted by name.
+fn read_snapper_configs(dir: &Path) -> Result<Vec<SnapperConfig>> {
+    let mut paths = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read snapper configs from {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+    let mut configs = vec![];
+    for path in paths {
+        let buf = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read snapper config {}", path.display()))?;
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        configs.push(
+            parse_snapper_config(&name, &buf)
+                .with_context(|| format!("Failed to parse snapper config {}", path.display()))?,
+        );
+    }
+    Ok(configs)
+}
+
+/// Parse the shell variable assignments of a snapper config.
+fn parse_snapper_config(name: &str, buf: &str) -> Result<SnapperConfig> {
+    let mut vars = HashMap::new();
+    for line in buf.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            vars.insert(key.trim(), value.trim().trim_matches('"'));
+        }
+    }
+    let subvolume = match vars.get("SUBVOLUME") {
+        Some(subvolume) => PathBuf::from(subvolume),
+        None => bail!("Config has no SUBVOLUME"),
+    };
+    let timeline = vars.get("TIMELINE_CREATE") != Some(&"no");
+    let mut limits = vec![];
+    let mut ignored = vec![];
+    for (var, key) in [
+        ("TIMELINE_LIMIT_HOURLY", "keep_hourly"),
+        ("TIMELINE_LIMIT_DAILY", "keep_daily"),
+        ("TIMELINE_LIMIT_WEEKLY", "keep_weekly"),
+        ("TIMELINE_LIMIT_MONTHLY", "keep_monthly"),
+        ("TIMELINE_LIMIT_YEARLY", "keep_yearly"),
+    ] {
+        if let Some(value) = vars.get(var) {
+            match limit(value) {
+                Some(count) if timeline => limits.push((key, count)),
+                _ => ignored.push(format!("{}={}", var, value)),
+            }
+        }
+    }
+    for var in ["NUMBER_LIMIT", "NUMBER_LIMIT_IMPORTANT"] {
+        if let Some(value) = vars.get(var) {
+            ignored.push(format!("{}={}", var, value));
+        }
+    }
+    Ok(SnapperConfig {
+        name: name.to_owned(),
+        subvolume,
+        limits,
+        ignored,
+    })
+}
+
+/// Parse a snapper limit, which is either a number or a range such as `2-10`
+/// of which the upper bound applies.
+fn limit(value: &str) -> Option<usize> {
+    value.rsplit('-').next()?.parse().ok()
+}
+
+/// Generate the configuration file.
+fn render_config(configs: &[SnapperConfig]) -> String {
+    let quote = |s: &str| toml::Value::String(s.to_owned()).to_string();
+    let path = |p: &Path| quote(&p.to_string_lossy());
+    let mounts = read_mounts();
+    let mut out = String::new();
+    writeln!(
+        out,
+        "# Generated by `btrfs-snapshot import snapper`. See the"
+    )
+    .unwrap();
+    writeln!(out, "# example configuration for all available options.").unwrap();
+    writeln!(out, "format = {}", quote(DEFAULT_FORMAT)).unwrap();
+    for config in configs {
+        let mount_point = find_mount(&mounts, &config.subvolume)
+            .map(|m| m.mount_point.clone())
+            .unwrap_or_else(|| config.subvolume.clone());
+        writeln!(out).unwrap();
+        writeln!(out, "[snapshots.{}]", key(&config.name)).unwrap();
+        writeln!(out, "mount_point = {}", path(&mount_point)).unwrap();
+        writeln!(out, "subvolume = {}", path(&config.subvolume)).unwrap();
+        writeln!(
+            out,
+            "snapshot_dir = {}",
+            path(&config.subvolume.join(SNAPPER_SNAPSHOT_DIR))
+        )
+        .unwrap();
+        for (key, count) in &config.limits {
+            writeln!(out, "{} = {}", key, count).unwrap();
+        }
+        for line in &config.ignored {
+            writeln!(out, "# Not imported: {}", line).unwrap();
+        }
+    }
+    out
+}
+
+/// Read the numbered snapshots in a snapper snapshot directory. Directories
+/// without an `info.xml` or a `snapshot` subvolume are skipped.
+fn read_snapper_snapshots(dir: &Path) -> Result<Vec<SnapperSnapshot>> {
+    let mut snapshots = vec![];
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read snapshot dir {}", dir.display()))?
+    {
+        let path = entry?.path();
+        let number = match path.file_name().and_then(|n| n.to_str()?.parse().ok()) {
+            Some(number) => number,
+            None => continue,
+        };
+        let info = path.join("info.xml");
+        if !info.exists() || !path.join("snapshot").exists() {
+            continue;
+        }
+        let buf = std::fs::read_to_string(&info)
+            .with_context(|| format!("Failed to read {}", info.display()))?;
+        snapshots.push(
+            parse_snapper_info(number, &buf)
+                .with_context(|| format!("Failed to parse {}", info.display()))?,
+        );
+    }
+    snapshots.sort_by_key(|snapshot| snapshot.number);
+    Ok(snapshots)
+}
+
+/// Parse the `info.xml` of a snapper snapshot.
+fn parse_snapper_info(number: u64, buf: &str) -> Result<SnapperSnapshot> {
+    let field = |name: &str| {
+        Regex::new(&format!("<{0}>([^<]*)</{0}>", name))
+            .unwrap()
+            .captures(buf)
+            .map(|c| c[1].trim().to_owned())
+    };
+    let date = match field("date") {
+        Some(date) => NaiveDateTime::parse_from_str(&date, "%Y-%m-%d %H:%M:%S")
+            .with_context(|| format!("Invalid date `{}`", date))?,
+        None => bail!("Snapshot has no date"),
+    };
+    let kind = match field("type").as_deref() {
+        Some("pre") => SnapperKind::Pre,
+        Some("post") => SnapperKind::Post,
+        _ => SnapperKind::Single,
+    };
+    Ok(SnapperSnapshot {
+        number,
+        date: Utc.from_utc_datetime(&date),
+        kind,
+        pre_num: field("pre_num").and_then(|n| n.parse().ok()),
+    })
+}
+
+/// Quote a config name for use as a TOML key if it is not a bare key.
+fn key(name: &str) -> String {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        name.to_owned()
+    } else {
+        toml::Value::String(name.to_owned()).to_string()
+    }
+}
+
+/// A parsed btrbk config. Options are kept as the raw text following their
+/// key, and are looked up from the innermost section outwards.
+#[derive(Debug, Default)]
+struct BtrbkConfig {
+    /// The global options.
+    options: HashMap<String, String>,
+    /// The `volume` sections.
+    volumes: Vec<BtrbkVolume>,
+}
+
+/// A `volume` section of a btrbk config.
+#[derive(Debug, Default)]
+struct BtrbkVolume {
+    /// The path of the volume.
+    path: PathBuf,
+    /// The options of the volume.
+    options: HashMap<String, String>,
+    /// The targets of all subvolumes of the volume.
+    targets: Vec<BtrbkTarget>,
+    /// The `subvolume` sections.
+    subvolumes: Vec<BtrbkSubvolume>,
+}
+
+/// A `subvolume` section of a btrbk config.
+#[derive(Debug, Default)]
+struct BtrbkSubvolume {
+    /// The path of the subvolume, relative to the volume.
+    path: PathBuf,
+    /// The options of the subvolume.
+    options: HashMap<String, String>,
+    /// The targets of the subvolume.
+    targets: Vec<BtrbkTarget>,
+}
+
+/// A `target` section of a btrbk config.
+#[derive(Debug, Default)]
+struct BtrbkTarget {
+    /// The type of the target, e.g. `send-receive` or `raw`.
+    kind: String,
+    /// The path of the target, possibly an `ssh://` URL.
+    path: String,
+    /// The options of the target.
+    options: HashMap<String, String>,
+}
+
+/// Parse a btrbk config. Sections are not delimited by indentation but by the
+/// `volume`, `subvolume`, and `target` lines that open them.
+fn parse_btrbk_config(buf: &str) -> Result<BtrbkConfig> {
+    let mut config = BtrbkConfig::default();
+    // Whether the most recent section was a target, and whether it belongs
+    // to a subvolume rather than the volume.
+    let mut in_target = false;
+    let mut in_subvolume = false;
+    for (index, line) in buf.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = match line.split_once(char::is_whitespace) {
+            Some((key, value)) => (key, value.trim()),
+            None => (line, ""),
+        };
+        let volume = config.volumes.last_mut();
+        match (key, volume) {
+            ("volume", _) => {
+                config.volumes.push(BtrbkVolume {
+                    path: PathBuf::from(value),
+                    ..Default::default()
+                });
+                in_target = false;
+                in_subvolume = false;
+            }
+            ("subvolume", Some(volume)) => {
+                volume.subvolumes.push(BtrbkSubvolume {
+                    path: PathBuf::from(value),
+                    ..Default::default()
+                });
+                in_target = false;
+                in_subvolume = true;
+            }
+            ("target", Some(volume)) => {
+                let mut words = value.split_whitespace();
+                let (kind, path) = match (words.next(), words.next()) {
+                    (Some(kind), Some(path)) => (kind, path),
+                    (Some(path), None) => ("send-receive", path),
+                    _ => bail!("Line {}: `target` needs a path", index + 1),
+                };
+                let target = BtrbkTarget {
+                    kind: kind.to_owned(),
+                    path: path.to_owned(),
+                    ..Default::default()
+                };
+                match (in_subvolume, volume.subvolumes.last_mut()) {
+                    (true, Some(subvolume)) => subvolume.targets.push(target),
+                    _ => volume.targets.push(target),
+                }
+                in_target = true;
+            }
+            ("subvolume" | "target", None) => {
+                bail!("Line {}: `{}` outside of a `volume`", index + 1, key)
+            }
+            (_, volume) => {
+                let options = match volume {
+                    None => &mut config.options,
+                    Some(volume) if in_target => {
+                        let targets = match (in_subvolume, volume.subvolumes.last_mut()) {
+                            (true, Some(subvolume)) => &mut subvolume.targets,
+                            _ => &mut volume.targets,
+                        };
+                        &mut targets.last_mut().unwrap().options
+                    }
+                    Some(volume) => match (in_subvolume, volume.subvolumes.last_mut()) {
+                        (true, Some(subvolume)) => &mut subvolume.options,
+                        _ => &mut volume.options,
+                    },
+                };
+                options.insert(key.to_owned(), value.to_owned());
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// Translate a btrbk retention policy such as `14d 4w *m` into `keep_*`
+/// counts. Returns the terms without an equivalent separately.
+fn btrbk_keep_counts(policy: &str) -> (Vec<(&'static str, usize)>, Vec<String>) {
+    let mut counts = vec![];
+    let mut ignored = vec![];
+    for term in policy.split_whitespace() {
+        let (count, unit) = term.split_at(term.len().saturating_sub(1));
+        let key = match unit {
+            "h" => "keep_hourly",
+            "d" => "keep_daily",
+            "w" => "keep_weekly",
+            "m" => "keep_monthly",
+            "y" => "keep_yearly",
+            _ => {
+                ignored.push(term.to_owned());
+                continue;
+            }
+        };
+        match count.parse() {
+            Ok(count) => counts.push((key, count)),
+            Err(_) => ignored.push(term.to_owned()),
+        }
+    }
+    (counts, ignored)
+}
+
+/// Approximate a btrbk retention policy such as `20d 10w` by spacings, with
+/// each period spacing the snapshots once the previous periods have passed.
+/// Snapshots older than all periods are kept. Returns `None` if the policy
+/// has terms without an equivalent.
+fn btrbk_spacings(policy: &str) -> Option<Vec<(String, String)>> {
+    let mut spacings = vec![];
+    let mut age = 0;
+    for term in policy.split_whitespace() {
+        let (count, unit) = term.split_at(term.len().saturating_sub(1));
+        let (hours, name) = match unit {
+            "h" => (1, "hour"),
+            "d" => (24, "day"),
+            "w" => (24 * 7, "week"),
+            "m" => (24 * 30, "month"),
+            "y" => (24 * 365, "year"),
+            _ => return None,
+        };
+        let count: u64 = count.parse().ok()?;
+        spacings.push((format!("{} hours", age.max(hours)), format!("1 {}", name)));
+        age += count * hours;
+    }
+    Some(spacings)
+}
+
+/// Map a btrbk `timestamp_format` to a snapshot name format. btrbk names
+/// snapshots `<snapshot_name>.<timestamp>`.
+fn btrbk_format(timestamp_format: Option<&str>) -> &'static str {
+    match timestamp_format {
+        Some("long") => "{config}.%Y%m%dT%H%M%S",
+        Some("long-iso") => "{config}.%Y%m%dT%H%M%S%z",
+        _ => "{config}.%Y%m%dT%H%M",
+    }
+}
+
+/// Generate the configuration file for a btrbk config.
+fn render_btrbk_config(config: &BtrbkConfig) -> String {
+    let quote = |s: &str| toml::Value::String(s.to_owned()).to_string();
+    let path = |p: &Path| quote(&p.to_string_lossy());
+    let mut out = String::new();
+    writeln!(out, "# Generated by `btrfs-snapshot import btrbk`. See the").unwrap();
+    writeln!(out, "# example configuration for all available options.").unwrap();
+    writeln!(
+        out,
+        "format = {}",
+        quote(btrbk_format(
+            config.options.get("timestamp_format").map(String::as_str)
+        ))
+    )
+    .unwrap();
+    for volume in &config.volumes {
+        for subvolume in &volume.subvolumes {
+            let option = |key: &str| {
+                subvolume
+                    .options
+                    .get(key)
+                    .or_else(|| volume.options.get(key))
+                    .or_else(|| config.options.get(key))
+                    .map(String::as_str)
+            };
+            let name = option("snapshot_name")
+                .map(String::from)
+                .unwrap_or_else(|| {
+                    subvolume
+                        .path
+                        .file_name()
+                        .map(|n| n.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| String::from("root"))
+                });
+            writeln!(out).unwrap();
+            writeln!(out, "[snapshots.{}]", key(&name)).unwrap();
+            writeln!(out, "mount_point = {}", path(&volume.path)).unwrap();
+            writeln!(
+                out,
+                "subvolume = {}",
+                path(&volume.path.join(&subvolume.path))
+            )
+            .unwrap();
+            let snapshot_dir = match option("snapshot_dir") {
+                Some(dir) => volume.path.join(dir),
+                None => volume.path.clone(),
+            };
+            writeln!(out, "snapshot_dir = {}", path(&snapshot_dir)).unwrap();
+            if let Some(format) = subvolume
+                .options
+                .get("timestamp_format")
+                .or_else(|| volume.options.get("timestamp_format"))
+            {
+                writeln!(out, "format = {}", quote(btrbk_format(Some(format)))).unwrap();
+            }
+            let mut ignored = vec![];
+            if let Some(policy) = option("snapshot_preserve") {
+                let (counts, rest) = btrbk_keep_counts(policy);
+                for (key, count) in counts {
+                    writeln!(out, "{} = {}", key, count).unwrap();
+                }
+                if !rest.is_empty() {
+                    ignored.push(format!("snapshot_preserve {}", rest.join(" ")));
+                }
+            }
+            for key in ["snapshot_preserve_min", "snapshot_create"] {
+                if let Some(value) = option(key) {
+                    ignored.push(format!("{} {}", key, value));
+                }
+            }
+
+            // Only a single target per config can be replicated to.
+            let mut targets = subvolume.targets.iter().chain(&volume.targets);
+            if let Some(target) = targets.next() {
+                let target_option = |key: &str| {
+                    target
+                        .options
+                        .get(key)
+                        .map(String::as_str)
+                        .or_else(|| option(key))
+                };
+                writeln!(out).unwrap();
+                writeln!(out, "[snapshots.{}.replicate]", key(&name)).unwrap();
+                if target.kind == "raw" {
+                    writeln!(out, "type = \"archive\"").unwrap();
+                } else if target.kind != "send-receive" {
+                    ignored.push(format!("target {} {}", target.kind, target.path));
+                }
+                match target.path.strip_prefix("ssh://") {
+                    Some(rest) => {
+                        let (host, dir) = match rest.find('/') {
+                            Some(slash) => rest.split_at(slash),
+                            None => (rest, "/"),
+                        };
+                        let user = target_option("ssh_user").unwrap_or("root");
+                        writeln!(out, "host = {}", quote(&format!("{}@{}", user, host))).unwrap();
+                        writeln!(out, "target_dir = {}", quote(dir)).unwrap();
+                        if let Some(identity) = target_option("ssh_identity") {
+                            writeln!(out, "ssh_options = [\"-i\", {}]", quote(identity)).unwrap();
+                        }
+                    }
+                    None => writeln!(out, "target_dir = {}", quote(&target.path)).unwrap(),
+                }
+                if let Some(policy) = target_option("target_preserve") {
+                    match btrbk_spacings(policy) {
+                        Some(spacings) if !spacings.is_empty() => {
+                            let spacings: Vec<_> = spacings
+                                .iter()
+                                .map(|(age, spacing)| {
+                                    format!("{} = {}", quote(age), quote(spacing))
+                                })
+                                .collect();
+                            writeln!(out, "spacings = {{ {} }}", spacings.join(", ")).unwrap();
+                        }
+                        _ => ignored.push(format!("target_preserve {}", policy)),
+                    }
+                }
+            }
+            for target in targets {
+                ignored.push(format!("target {} {}", target.kind, target.path));
+            }
+            for line in &ignored {
+                writeln!(out, "# Not imported: {}", line).unwrap();
+            }
+        }
+    }
+    out
+}
+
+impl<'a> State<'a> {
+    /// Rename the numbered snapper snapshots in the snapshot dir of a config
+    /// into the configured format. Snapshots before and after a transaction
+    /// are tagged `pre-pkg` and `post-pkg`, and named as a pair. Returns the
+    /// number of adopted snapshots.
+    pub fn adopt_snapper(&mut self, snapshot: &'a SnapshotConfig) -> Result<usize> {
+        debug!("Adopt snapper snapshots of {}", snapshot.name);
+        self.mount_snapshot_fs(snapshot)?;
+        let dir = snapshot.snapshot_dir.as_ref().unwrap();
+        if !dir.exists() {
+            return Ok(0);
+        }
+        let naming = snapshot.naming()?;
+        let snapshots = read_snapper_snapshots(dir)?;
+        let dates: HashMap<u64, DateTime<Utc>> = snapshots
+            .iter()
+            .map(|snapshot| (snapshot.number, snapshot.date))
+            .collect();
+
+        // Work out all new names before renaming anything, as in
+        // `migrate_format`.
+        let mut renames: Vec<(PathBuf, PathBuf)> = vec![];
+        let mut targets = HashSet::new();
+        for entry in &snapshots {
+            let (tag, date) = match entry.kind {
+                SnapperKind::Single => (None, entry.date),
+                SnapperKind::Pre => (Some(pkg::PRE_TAG), entry.date),
+                SnapperKind::Post => (
+                    Some(pkg::POST_TAG),
+                    entry
+                        .pre_num
+                        .and_then(|n| dates.get(&n).copied())
+                        .unwrap_or(entry.date),
+                ),
+            };
+            let path = dir.join(entry.number.to_string()).join("snapshot");
+            let target = dir.join(naming.render(date.with_timezone(&Local), tag, entry.number)?);
+            if target.exists() || !targets.insert(target.clone()) {
+                bail!(
+                    "Cannot rename {} to {}, since another snapshot already has that name",
+                    path.display(),
+                    target.display()
+                );
+            }
+            renames.push((path, target));
+        }
+
+        for (path, target) in &renames {
+            if self.output == OutputFormat::Text {
+                println!(
+                    "Adopting snapshot {} as {}",
+                    path.display(),
+                    target.display()
+                );
+            }
+            if self.dry_run {
+                let mut cmd = Command::new("mv");
+                cmd.arg(path).arg(target);
+                self.print_commands(&[&mut cmd]);
+                continue;
+            }
+            self.executor
+                .rename(path, target)
+                .with_context(|| format!("Adopting snapshot {} failed", path.display()))?;
+        }
+        Ok(renames.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::Operation, tests::Fixture, Config};
+
+    #[test]
+    fn import_adopts_snapper_snapshots() {
+        let fixture = Fixture::new("import", "");
+        let dir = fixture.snapshot().snapshot_dir.clone().unwrap();
+        let add = |number: u64, kind: &str, date: &str, extra: &str| {
+            let path = dir.join(number.to_string());
+            std::fs::create_dir_all(path.join("snapshot")).unwrap();
+            std::fs::write(
+                path.join("info.xml"),
+                format!(
+                    "<?xml version=\"1.0\"?>\n<snapshot>\n  <type>{}</type>\n  \
+                     <num>{}</num>\n  <date>{}</date>\n{}</snapshot>\n",
+                    kind, number, date, extra
+                ),
+            )
+            .unwrap();
+            path.join("snapshot")
+        };
+        let single = add(1, "single", "2021-03-01 10:00:00", "");
+        let pre = add(2, "pre", "2021-03-02 10:00:00", "");
+        let post = add(3, "post", "2021-03-02 10:05:00", "  <pre_num>2</pre_num>\n");
+
+        let (mut state, mock) = fixture.state();
+        assert_eq!(state.adopt_snapper(fixture.snapshot()).unwrap(), 3);
+        let naming = fixture.snapshot().naming().unwrap();
+        let ops = mock.operations();
+        let targets: Vec<_> = ops
+            .iter()
+            .map(|op| match op {
+                Operation::Rename(from, to) => {
+                    let parsed = naming
+                        .parse(to.file_name().unwrap().to_str().unwrap())
+                        .unwrap();
+                    (from.clone(), parsed.date, parsed.tag)
+                }
+                op => panic!("unexpected operation {:?}", op),
+            })
+            .collect();
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets[0].0, single);
+        assert_eq!(targets[0].2, None);
+        assert_eq!(targets[1].0, pre);
+        assert_eq!(targets[1].2.as_deref(), Some(pkg::PRE_TAG));
+        assert_eq!(targets[2].0, post);
+        assert_eq!(targets[2].2.as_deref(), Some(pkg::POST_TAG));
+        assert_eq!(targets[1].1, targets[2].1);
+    }
+
+    #[test]
+    fn import_translates_btrbk_config() {
+        let fixture = Fixture::new("btrbk", "");
+        let btrbk = fixture.dir.join("btrbk.conf");
+        std::fs::write(
+            &btrbk,
+            "snapshot_preserve 14d 4w\n\
+             snapshot_dir snapshots\n\
+             volume /mnt/pool\n  \
+               subvolume home\n    \
+                 snapshot_name home_snap\n    \
+                 target ssh://backup/srv/home\n      \
+                   target_preserve 20d 10w\n",
+        )
+        .unwrap();
+        let path = fixture.dir.join("imported.toml");
+        import_btrbk(&path, &btrbk, false).unwrap();
+        let config = Config::load(&path).unwrap();
+        let snapshot = &config.snapshots["home_snap"];
+        assert_eq!(snapshot.subvolume(), Path::new("/mnt/pool/home"));
+        assert_eq!(
+            snapshot.snapshot_dir.as_deref(),
+            Some(Path::new("/mnt/pool/snapshots"))
+        );
+        assert_eq!(snapshot.keep.keep_daily, Some(14));
+        assert_eq!(snapshot.keep.keep_weekly, Some(4));
+        let naming = snapshot.naming().unwrap();
+        assert!(naming.parse("home_snap.20210301T1000").is_some());
+        let replicate = snapshot.replicate.as_ref().unwrap();
+        assert_eq!(replicate.host.as_deref(), Some("root@backup"));
+        assert_eq!(
+            replicate.target_dir.as_deref(),
+            Some(Path::new("/srv/home"))
+        );
+        assert_eq!(replicate.spacings.as_ref().unwrap().len(), 2);
+    }
+}
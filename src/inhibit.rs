@@ -0,0 +1,52 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Preventing the system from sleeping or shutting down while snapshots are
+//! being taken, deleted, or sent.
+
+use std::process::{Child, Command, Stdio};
+
+/// A logind inhibitor lock that blocks sleep and shutdown as long as it is
+/// alive.
+///
+/// The lock is held by a `systemd-inhibit` process running `cat` on a pipe.
+/// Closing the pipe ends the process and releases the lock, which also happens
+/// if we exit unexpectedly.
+pub struct Inhibitor {
+    /// The `systemd-inhibit` process holding the lock.
+    child: Child,
+}
+
+impl Inhibitor {
+    /// Block sleep and shutdown, giving `why` as the reason. Returns `None`
+    /// if no lock could be taken, e.g. on systems without systemd.
+    pub fn acquire(why: &str) -> Option<Self> {
+        let child = Command::new("systemd-inhibit")
+            .arg("--what=sleep:shutdown")
+            .arg("--mode=block")
+            .arg(format!("--who={}", crate_name!()))
+            .arg(format!("--why={}", why))
+            .arg("cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        match child {
+            Ok(child) => {
+                debug!("Inhibiting sleep and shutdown: {}", why);
+                Some(Self { child })
+            }
+            Err(e) => {
+                debug!("Cannot inhibit sleep and shutdown: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Inhibitor {
+    fn drop(&mut self) {
+        debug!("Releasing inhibitor lock");
+        drop(self.child.stdin.take());
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Interactively generating a starter configuration.
+
+use crate::{
+    ioctl,
+    mounts::{read_mounts, Mount},
+};
+use anyhow::{bail, Context, Result};
+use indexmap::IndexSet;
+use std::{
+    fmt::Write as _,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+/// The default format of snapshot names.
+pub const DEFAULT_FORMAT: &str = "%Y_%m_%d_%H%M%z";
+
+/// A subvolume that may be snapshotted.
+struct Candidate {
+    /// The name of the snapshot config.
+    name: String,
+    /// The mount point through which the subvolume is reached.
+    mount_point: PathBuf,
+    /// The path of the subvolume.
+    subvolume: PathBuf,
+    /// Where the snapshots are placed by default.
+    snapshot_dir: PathBuf,
+    /// Whether the subvolume is proposed for snapshotting by default.
+    default: bool,
+}
+
+/// Detect the btrfs subvolumes on the system, ask which of them to snapshot,
+/// and write a starter configuration to `path`. In a dry run the
+/// configuration is printed instead.
+pub fn init(path: &Path, dry_run: bool) -> Result<()> {
+    if path.exists() && !dry_run && !confirm(&format!("Overwrite {}?", path.display()), false)? {
+        bail!("Not overwriting existing config {}", path.display());
+    }
+
+    // Find the subvolumes that could be snapshotted.
+    let mounts: Vec<Mount> = read_mounts()
+        .into_iter()
+        .filter(|m| m.fs_type == "btrfs")
+        .collect();
+    if mounts.is_empty() {
+        bail!("No mounted btrfs filesystems found");
+    }
+    let sources: IndexSet<&str> = mounts.iter().map(|m| m.source.as_str()).collect();
+    let mut candidates = Vec::new();
+    for source in sources {
+        let fs_mounts: Vec<&Mount> = mounts.iter().filter(|m| m.source == source).collect();
+        println!("Found btrfs filesystem on {}", source);
+        candidates.extend(find_candidates(&fs_mounts));
+    }
+
+    // Ask which subvolumes to snapshot and where to put the snapshots.
+    let mut chosen: Vec<Candidate> = Vec::new();
+    for mut candidate in candidates {
+        if !confirm(
+            &format!("Snapshot subvolume {}?", candidate.subvolume.display()),
+            candidate.default,
+        )? {
+            continue;
+        }
+        let mut name = ask("  Name", &candidate.name)?;
+        while chosen.iter().any(|c| c.name == name) || !is_valid_name(&name) {
+            println!("  Names must be unique and only contain letters, digits, `-`, and `_`");
+            name = ask("  Name", &format!("{}-{}", candidate.name, chosen.len()))?;
+        }
+        candidate.name = name;
+        candidate.snapshot_dir = PathBuf::from(ask(
+            "  Snapshot directory",
+            &candidate.snapshot_dir.to_string_lossy(),
+        )?);
+        chosen.push(candidate);
+    }
+    if chosen.is_empty() {
+        bail!("No subvolumes selected");
+    }
+
+    // Ask how long to keep snapshots.
+    let hourly = confirm("Keep hourly snapshots of the last few hours?", true)?;
+    let max_age = loop {
+        let age = ask(
+            "Delete snapshots older than (`never` to keep them)",
+            "1year",
+        )?;
+        if age == "never" {
+            break None;
+        }
+        match humantime::parse_duration(&age) {
+            Ok(_) => break Some(age),
+            Err(e) => println!("  Invalid duration `{}`: {}", age, e),
+        }
+    };
+
+    let config = render_config(&chosen, hourly, max_age.as_deref());
+    if dry_run {
+        print!("{}", config);
+        return Ok(());
+    }
+    std::fs::write(path, config)
+        .with_context(|| format!("Failed to write config to {}", path.display()))?;
+    println!("Wrote config to {}", path.display());
+    println!("Run `btrfs-snapshot check-config` to verify it");
+    Ok(())
+}
+
+/// Determine the subvolumes of a filesystem that could be snapshotted. If the
+/// top-level subvolume is mounted, all subvolumes are reachable through it and
+/// the snapshots are placed next to them. Otherwise only the mounted
+/// subvolumes are offered, with the snapshots placed inside them.
+fn find_candidates(mounts: &[&Mount]) -> Vec<Candidate> {
+    let top_level = mounts.iter().find(|m| m.root == Path::new("/"));
+    let listed = top_level.and_then(|top| {
+        ioctl::nested_subvolumes(&top.mount_point)
+            .map_err(|e| {
+                warn!(
+                    "Cannot list subvolumes of {}: {:#}",
+                    top.mount_point.display(),
+                    e
+                )
+            })
+            .ok()
+            .map(|list| (top, list))
+    });
+
+    if let Some((top, list)) = listed {
+        return list
+            .iter()
+            .filter_map(|path| path.to_str())
+            .filter(|path| {
+                !path
+                    .split('/')
+                    .any(|part| part.starts_with('.') || part.contains("snapshot"))
+            })
+            .map(|path| {
+                let name = subvolume_name(path);
+                Candidate {
+                    mount_point: top.mount_point.clone(),
+                    subvolume: top.mount_point.join(path),
+                    snapshot_dir: top.mount_point.join("snapshots").join(&name),
+                    default: mounts
+                        .iter()
+                        .any(|m| m.root.strip_prefix("/").ok() == Some(Path::new(path))),
+                    name,
+                }
+            })
+            .collect();
+    }
+
+    mounts
+        .iter()
+        .filter(|m| m.root != Path::new("/"))
+        .map(|m| Candidate {
+            name: subvolume_name(&m.mount_point.to_string_lossy()),
+            mount_point: m.mount_point.clone(),
+            subvolume: m.mount_point.clone(),
+            snapshot_dir: m.mount_point.join(".snapshots"),
+            default: true,
+        })
+        .collect()
+}
+
+/// Derive a snapshot config name from the path of a subvolume or its mount
+/// point, e.g. `@home` or `/home` become `home`, and `/` becomes `root`.
+fn subvolume_name(path: &str) -> String {
+    let name: String = path
+        .trim_matches('/')
+        .trim_start_matches('@')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let name = name.trim_matches('-');
+    if name.is_empty() {
+        String::from("root")
+    } else {
+        name.to_owned()
+    }
+}
+
+/// Check whether a name can be used as a snapshot config name.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Generate the configuration file.
+fn render_config(snapshots: &[Candidate], hourly: bool, max_age: Option<&str>) -> String {
+    let quote = |s: &str| toml::Value::String(s.to_owned()).to_string();
+    let mut out = String::new();
+    writeln!(out, "# Generated by `btrfs-snapshot init`. See the example").unwrap();
+    writeln!(out, "# configuration for all available options.").unwrap();
+    writeln!(out, "format = {}", quote(DEFAULT_FORMAT)).unwrap();
+    if let Some(max_age) = max_age {
+        writeln!(out, "max_age = {}", quote(max_age)).unwrap();
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "[spacings]").unwrap();
+    if hourly {
+        writeln!(
+            out,
+            "\"3 hour\" = \"1 hour\"  # keep hourly snapshots after 3 hours"
+        )
+        .unwrap();
+    }
+    writeln!(
+        out,
+        "\"1 day\" = \"1 day\"  # keep daily snapshots after the first day"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "\"1 week\" = \"1 week\"  # keep weekly snapshots after the first week"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "\"1 month\" = \"1 month\"  # keep monthly snapshots after a month"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "\"3 months\" = \"3 months\"  # keep quarterly snapshots after 3 months"
+    )
+    .unwrap();
+    for snapshot in snapshots {
+        writeln!(out).unwrap();
+        writeln!(out, "[snapshots.{}]", snapshot.name).unwrap();
+        let path = |p: &Path| quote(&p.to_string_lossy());
+        writeln!(out, "mount_point = {}", path(&snapshot.mount_point)).unwrap();
+        writeln!(out, "subvolume = {}", path(&snapshot.subvolume)).unwrap();
+        writeln!(out, "snapshot_dir = {}", path(&snapshot.snapshot_dir)).unwrap();
+    }
+    out
+}
+
+/// Ask a question on the terminal. Returns `default` if the answer is empty or
+/// there is no more input.
+fn ask(question: &str, default: &str) -> Result<String> {
+    if default.is_empty() {
+        print!("{}: ", question);
+    } else {
+        print!("{} [{}]: ", question, default);
+    }
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    if std::io::stdin().lock().read_line(&mut line)? == 0 {
+        println!();
+    }
+    let answer = line.trim();
+    Ok(if answer.is_empty() {
+        default.to_owned()
+    } else {
+        answer.to_owned()
+    })
+}
+
+/// Ask a yes/no question on the terminal.
+pub(crate) fn confirm(question: &str, default: bool) -> Result<bool> {
+    loop {
+        let hint = if default { "Y/n" } else { "y/N" };
+        let answer = ask(&format!("{} [{}]", question, hint), "")?;
+        match answer.to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("  Please answer `y` or `n`"),
+        }
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Creating, deleting, and listing btrfs subvolumes directly through the
+//! kernel's ioctl interface, without going through `btrfs-progs`.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    convert::TryInto,
+    ffi::OsStr,
+    fs::File,
+    io,
+    os::unix::{ffi::OsStrExt as _, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+/// The ioctl type shared by all btrfs ioctls.
+const BTRFS_IOCTL_MAGIC: u64 = 0x94;
+
+/// The maximum length of a subvolume name in `VolArgs`.
+const BTRFS_PATH_NAME_MAX: usize = 4087;
+/// The maximum length of a subvolume name in `VolArgsV2`.
+const BTRFS_SUBVOL_NAME_MAX: usize = 4039;
+/// The maximum length of a path returned by `BTRFS_IOC_INO_LOOKUP`.
+const BTRFS_INO_LOOKUP_PATH_MAX: usize = 4080;
+/// The size of the result buffer of `BTRFS_IOC_TREE_SEARCH`.
+const BTRFS_SEARCH_ARGS_BUFSIZE: usize = 4096 - std::mem::size_of::<SearchKey>();
+
+/// The subvolume flag that marks it as read-only.
+const BTRFS_SUBVOL_RDONLY: u64 = 1 << 1;
+/// The tree that holds the root items and references of all subvolumes.
+const BTRFS_ROOT_TREE_OBJECTID: u64 = 1;
+/// The inode number of the root directory of every subvolume.
+pub(crate) const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+/// The key type of the reference from a subvolume to a nested subvolume.
+const BTRFS_ROOT_REF_KEY: u32 = 156;
+
+/// Whether the architecture uses the legacy encoding of ioctl numbers rather
+/// than the generic one.
+const LEGACY_IOC: bool = cfg!(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc",
+    target_arch = "sparc64"
+));
+/// The direction bit of ioctls that pass data to the kernel.
+const IOC_WRITE: u64 = if LEGACY_IOC { 4 } else { 1 };
+/// The direction bit of ioctls that pass data from the kernel.
+const IOC_READ: u64 = 2;
+/// The position of the direction bits in an ioctl number.
+const IOC_DIRSHIFT: u64 = if LEGACY_IOC { 29 } else { 30 };
+
+/// Compute the number of a btrfs ioctl, like the kernel's `_IOC` macro.
+const fn ioc(dir: u64, nr: u64, size: usize) -> u64 {
+    (dir << IOC_DIRSHIFT) | ((size as u64) << 16) | (BTRFS_IOCTL_MAGIC << 8) | nr
+}
+
+const BTRFS_IOC_SNAP_DESTROY: u64 = ioc(IOC_WRITE, 15, std::mem::size_of::<VolArgs>());
+const BTRFS_IOC_TREE_SEARCH: u64 = ioc(IOC_READ | IOC_WRITE, 17, std::mem::size_of::<SearchArgs>());
+const BTRFS_IOC_INO_LOOKUP: u64 = ioc(
+    IOC_READ | IOC_WRITE,
+    18,
+    std::mem::size_of::<InoLookupArgs>(),
+);
+const BTRFS_IOC_SNAP_CREATE_V2: u64 = ioc(IOC_WRITE, 23, std::mem::size_of::<VolArgsV2>());
+const BTRFS_IOC_SUBVOL_GETFLAGS: u64 = ioc(IOC_READ, 25, std::mem::size_of::<u64>());
+const BTRFS_IOC_SUBVOL_SETFLAGS: u64 = ioc(IOC_WRITE, 26, std::mem::size_of::<u64>());
+const BTRFS_IOC_FS_INFO: u64 = ioc(IOC_READ, 31, std::mem::size_of::<FsInfoArgs>());
+
+/// The arguments of `BTRFS_IOC_SNAP_DESTROY`.
+#[repr(C)]
+struct VolArgs {
+    fd: i64,
+    name: [u8; BTRFS_PATH_NAME_MAX + 1],
+}
+
+/// The arguments of `BTRFS_IOC_SNAP_CREATE_V2`.
+#[repr(C)]
+struct VolArgsV2 {
+    fd: i64,
+    transid: u64,
+    flags: u64,
+    unused: [u64; 4],
+    name: [u8; BTRFS_SUBVOL_NAME_MAX + 1],
+}
+
+/// The arguments of `BTRFS_IOC_INO_LOOKUP`.
+#[repr(C)]
+struct InoLookupArgs {
+    treeid: u64,
+    objectid: u64,
+    name: [u8; BTRFS_INO_LOOKUP_PATH_MAX],
+}
+
+/// The result of `BTRFS_IOC_FS_INFO`, of which only the filesystem UUID is
+/// of interest.
+#[repr(C)]
+struct FsInfoArgs {
+    max_id: u64,
+    num_devices: u64,
+    fsid: [u8; 16],
+    rest: [u8; 992],
+}
+
+/// The range of keys searched by `BTRFS_IOC_TREE_SEARCH`.
+#[repr(C)]
+struct SearchKey {
+    tree_id: u64,
+    min_objectid: u64,
+    max_objectid: u64,
+    min_offset: u64,
+    max_offset: u64,
+    min_transid: u64,
+    max_transid: u64,
+    min_type: u32,
+    max_type: u32,
+    nr_items: u32,
+    unused: u32,
+    unused1: u64,
+    unused2: u64,
+    unused3: u64,
+    unused4: u64,
+}
+
+/// The arguments of `BTRFS_IOC_TREE_SEARCH`.
+#[repr(C)]
+struct SearchArgs {
+    key: SearchKey,
+    buf: [u8; BTRFS_SEARCH_ARGS_BUFSIZE],
+}
+
+// The kernel encodes the argument sizes in the ioctl numbers, so make sure the
+// structs above match its layout.
+const _: () = assert!(std::mem::size_of::<VolArgs>() == 4096);
+const _: () = assert!(std::mem::size_of::<VolArgsV2>() == 4096);
+const _: () = assert!(std::mem::size_of::<InoLookupArgs>() == 4096);
+const _: () = assert!(std::mem::size_of::<FsInfoArgs>() == 1024);
+const _: () = assert!(std::mem::size_of::<SearchKey>() == 104);
+const _: () = assert!(std::mem::size_of::<SearchArgs>() == 4096);
+
+/// The size of the header preceding each item returned by
+/// `BTRFS_IOC_TREE_SEARCH`.
+const SEARCH_HEADER_SIZE: usize = 32;
+/// The size of a `btrfs_root_ref` item, excluding the name that follows it.
+const ROOT_REF_SIZE: usize = 18;
+
+/// Perform an ioctl on a file.
+fn ioctl<T>(file: &File, request: u64, args: &mut T) -> io::Result<()> {
+    if unsafe { libc::ioctl(file.as_raw_fd(), request as _, args as *mut T) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Open a directory to perform ioctls on.
+fn open_dir(path: &Path) -> Result<File> {
+    File::open(path).with_context(|| format!("Failed to open {}", path.display()))
+}
+
+/// Split a path into its parent directory and final component.
+fn split_path(path: &Path) -> Result<(&Path, &OsStr)> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok((
+            if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            },
+            name,
+        )),
+        _ => bail!("Path {} does not name a subvolume", path.display()),
+    }
+}
+
+/// Copy a subvolume name into a zero-terminated buffer.
+fn copy_name(name: &OsStr, buf: &mut [u8]) -> Result<()> {
+    let name = name.as_bytes();
+    if name.len() >= buf.len() || name.contains(&0) {
+        bail!("Invalid subvolume name {:?}", name);
+    }
+    buf[..name.len()].copy_from_slice(name);
+    Ok(())
+}
+
+/// Determine the UUID of the btrfs filesystem that `path` lives on. Fails if
+/// it is on another kind of filesystem.
+pub fn filesystem_id(path: &Path) -> Result<[u8; 16]> {
+    let dir = open_dir(path)?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(dir.as_raw_fd(), &mut stat) } < 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to stat filesystem of {}", path.display()));
+    }
+    if stat.f_type != libc::BTRFS_SUPER_MAGIC {
+        bail!("{} is not on a btrfs filesystem", path.display());
+    }
+    let mut args = FsInfoArgs {
+        max_id: 0,
+        num_devices: 0,
+        fsid: [0; 16],
+        rest: [0; 992],
+    };
+    ioctl(&dir, BTRFS_IOC_FS_INFO, &mut args)
+        .with_context(|| format!("Failed to get filesystem info of {}", path.display()))?;
+    Ok(args.fsid)
+}
+
+/// Snapshot the subvolume at `source` into a new subvolume at `target`.
+pub fn create_snapshot(source: &Path, target: &Path, readonly: bool) -> Result<()> {
+    let (parent, name) = split_path(target)?;
+    let source_dir = open_dir(source)?;
+    let parent_dir = open_dir(parent)?;
+    let mut args = VolArgsV2 {
+        fd: source_dir.as_raw_fd() as i64,
+        transid: 0,
+        flags: if readonly { BTRFS_SUBVOL_RDONLY } else { 0 },
+        unused: [0; 4],
+        name: [0; BTRFS_SUBVOL_NAME_MAX + 1],
+    };
+    copy_name(name, &mut args.name)?;
+    ioctl(&parent_dir, BTRFS_IOC_SNAP_CREATE_V2, &mut args).with_context(|| {
+        format!(
+            "Failed to snapshot {} into {}",
+            source.display(),
+            target.display()
+        )
+    })
+}
+
+/// Delete the subvolume at `path`.
+pub fn delete_subvolume(path: &Path) -> Result<()> {
+    let (parent, name) = split_path(path)?;
+    let parent_dir = open_dir(parent)?;
+    let mut args = VolArgs {
+        fd: 0,
+        name: [0; BTRFS_PATH_NAME_MAX + 1],
+    };
+    copy_name(name, &mut args.name)?;
+    ioctl(&parent_dir, BTRFS_IOC_SNAP_DESTROY, &mut args)
+        .with_context(|| format!("Failed to delete subvolume {}", path.display()))
+}
+
+/// Check whether the subvolume at `path` is read-only.
+pub fn is_readonly(path: &Path) -> Result<bool> {
+    let dir = open_dir(path)?;
+    let mut flags = 0u64;
+    ioctl(&dir, BTRFS_IOC_SUBVOL_GETFLAGS, &mut flags)
+        .with_context(|| format!("Failed to get flags of subvolume {}", path.display()))?;
+    Ok(flags & BTRFS_SUBVOL_RDONLY != 0)
+}
+
+/// Mark the subvolume at `path` as read-only or writable.
+pub fn set_readonly(path: &Path, readonly: bool) -> Result<()> {
+    let dir = open_dir(path)?;
+    let mut flags = 0u64;
+    ioctl(&dir, BTRFS_IOC_SUBVOL_GETFLAGS, &mut flags)
+        .with_context(|| format!("Failed to get flags of subvolume {}", path.display()))?;
+    if readonly {
+        flags |= BTRFS_SUBVOL_RDONLY;
+    } else {
+        flags &= !BTRFS_SUBVOL_RDONLY;
+    }
+    ioctl(&dir, BTRFS_IOC_SUBVOL_SETFLAGS, &mut flags)
+        .with_context(|| format!("Failed to set flags of subvolume {}", path.display()))
+}
+
+/// Find the subvolumes nested anywhere within the subvolume at `path`, as
+/// paths relative to it. Parents are sorted before the subvolumes nested
+/// within them.
+pub fn nested_subvolumes(path: &Path) -> Result<Vec<PathBuf>> {
+    let dir = open_dir(path)?;
+    let tree = lookup(&dir, 0, BTRFS_FIRST_FREE_OBJECTID)
+        .with_context(|| format!("Failed to look up subvolume {}", path.display()))?
+        .0;
+    let mut nested = Vec::new();
+    let mut pending = vec![(tree, PathBuf::new())];
+    while let Some((tree, prefix)) = pending.pop() {
+        for (child, dirid, name) in child_refs(&dir, tree)
+            .with_context(|| format!("Failed to list subvolumes below {}", path.display()))?
+        {
+            let dir_path = lookup(&dir, tree, dirid)
+                .with_context(|| format!("Failed to locate subvolume {}", name))?
+                .1;
+            let rel = prefix.join(dir_path).join(name);
+            nested.push(rel.clone());
+            pending.push((child, rel));
+        }
+    }
+    nested.sort();
+    Ok(nested)
+}
+
+/// Look up the path of an inode within a subvolume tree, relative to the
+/// subvolume's root. A `tree` of 0 refers to the subvolume that `dir` is in.
+/// Returns the tree the inode was found in and its path.
+fn lookup(dir: &File, tree: u64, inode: u64) -> io::Result<(u64, PathBuf)> {
+    let mut args = InoLookupArgs {
+        treeid: tree,
+        objectid: inode,
+        name: [0; BTRFS_INO_LOOKUP_PATH_MAX],
+    };
+    ioctl(dir, BTRFS_IOC_INO_LOOKUP, &mut args)?;
+    let len = args.name.iter().position(|&b| b == 0).unwrap_or(0);
+    let path = Path::new(OsStr::from_bytes(&args.name[..len]));
+    Ok((args.treeid, path.to_owned()))
+}
+
+/// Find the subvolumes directly nested within a subvolume tree. Returns the
+/// tree of each nested subvolume, the inode of the directory that contains
+/// it, and its name.
+fn child_refs(dir: &File, tree: u64) -> io::Result<Vec<(u64, u64, String)>> {
+    let mut args = SearchArgs {
+        key: SearchKey {
+            tree_id: BTRFS_ROOT_TREE_OBJECTID,
+            min_objectid: tree,
+            max_objectid: tree,
+            min_offset: 0,
+            max_offset: u64::MAX,
+            min_transid: 0,
+            max_transid: u64::MAX,
+            min_type: BTRFS_ROOT_REF_KEY,
+            max_type: BTRFS_ROOT_REF_KEY,
+            nr_items: 0,
+            unused: 0,
+            unused1: 0,
+            unused2: 0,
+            unused3: 0,
+            unused4: 0,
+        },
+        buf: [0; BTRFS_SEARCH_ARGS_BUFSIZE],
+    };
+    let u64_at = |buf: &[u8], at: usize| u64::from_ne_bytes(buf[at..at + 8].try_into().unwrap());
+    let u32_at = |buf: &[u8], at: usize| u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap());
+    let mut refs = Vec::new();
+    loop {
+        args.key.nr_items = u32::MAX;
+        ioctl(dir, BTRFS_IOC_TREE_SEARCH, &mut args)?;
+        if args.key.nr_items == 0 {
+            break;
+        }
+
+        // Each item consists of a header followed by a `btrfs_root_ref`,
+        // which is followed by the name of the nested subvolume.
+        let mut at = 0;
+        let mut last_offset = 0;
+        for _ in 0..args.key.nr_items {
+            let header = &args.buf[at..at + SEARCH_HEADER_SIZE];
+            let (offset, kind, len) = (
+                u64_at(header, 16),
+                u32_at(header, 24),
+                u32_at(header, 28) as usize,
+            );
+            let item = &args.buf[at + SEARCH_HEADER_SIZE..at + SEARCH_HEADER_SIZE + len];
+            at += SEARCH_HEADER_SIZE + len;
+            last_offset = offset;
+            if kind != BTRFS_ROOT_REF_KEY || len < ROOT_REF_SIZE {
+                continue;
+            }
+            let dirid = u64::from_le_bytes(item[0..8].try_into().unwrap());
+            let name_len = u16::from_le_bytes(item[16..18].try_into().unwrap()) as usize;
+            let name = &item[ROOT_REF_SIZE..(ROOT_REF_SIZE + name_len).min(len)];
+            refs.push((offset, dirid, String::from_utf8_lossy(name).into_owned()));
+        }
+
+        // Continue the search after the last item found.
+        match last_offset.checked_add(1) {
+            Some(next) => args.key.min_offset = next,
+            None => break,
+        }
+    }
+    Ok(refs)
+}
@@ -0,0 +1,169 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Logging to the systemd journal with structured fields.
+//!
+//! Log messages and actions on snapshots are sent to journald using its native
+//! protocol. Actions carry the fields `SNAPSHOT_NAME`, `SUBVOLUME`,
+//! `SNAPSHOT_PATH`, `ACTION`, and `RESULT`, such that they can be filtered with
+//! e.g. `journalctl -t btrfs-snapshot SNAPSHOT_NAME=home`.
+
+use crate::{output::ActionKind, SnapshotConfig};
+use anyhow::Result;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    os::unix::net::UnixDatagram,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The socket on which journald accepts native protocol messages.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Whether log messages are sent to the journal.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Check whether the journal is available and our standard error is
+/// connected to it, as is the case for systemd services.
+pub fn is_connected() -> bool {
+    std::env::var_os("JOURNAL_STREAM").is_some() && Path::new(JOURNAL_SOCKET).exists()
+}
+
+/// Send log messages to the journal instead of standard error. Messages are
+/// filtered according to `RUST_LOG`, as with the regular logger, unless
+/// `level` overrides the level of our own messages.
+pub fn init(level: Option<LevelFilter>) -> Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    let mut filter = env_logger::filter::Builder::from_env("RUST_LOG");
+    if let Some(level) = level {
+        filter.filter_module(env!("CARGO_CRATE_NAME"), level);
+    }
+    let filter = filter.build();
+    log::set_max_level(filter.filter());
+    log::set_boxed_logger(Box::new(JournalLogger { socket, filter }))?;
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Record the outcome of an action on a snapshot in the journal, if log
+/// messages are sent there.
+pub fn log_action(
+    snapshot: &SnapshotConfig,
+    kind: ActionKind,
+    path: &Path,
+    result: Result<(), &anyhow::Error>,
+    dry_run: bool,
+) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let (verb, action) = match kind {
+        ActionKind::Take => ("Taking", "take"),
+        ActionKind::Delete => ("Dropping", "delete"),
+        ActionKind::Trash => ("Trashing", "trash"),
+        ActionKind::Send => ("Sending", "send"),
+        ActionKind::Restore => ("Restoring", "restore"),
+        ActionKind::Pull => ("Pulling", "pull"),
+    };
+    let (message, priority, outcome) = match result {
+        Ok(()) if dry_run => (
+            format!("{} snapshot {} (dry run)", verb, path.display()),
+            Level::Info,
+            "dry-run",
+        ),
+        Ok(()) => (
+            format!("{} snapshot {}", verb, path.display()),
+            Level::Info,
+            "success",
+        ),
+        Err(e) => (
+            format!("{} snapshot {} failed: {:#}", verb, path.display(), e),
+            Level::Error,
+            "failure",
+        ),
+    };
+    let subvolume = snapshot.subvolume().to_string_lossy();
+    let path = path.to_string_lossy();
+    send(&[
+        ("MESSAGE", &message),
+        ("PRIORITY", priority_of(priority)),
+        ("SYSLOG_IDENTIFIER", crate_name!()),
+        ("SNAPSHOT_NAME", &snapshot.name),
+        ("SUBVOLUME", &subvolume),
+        ("SNAPSHOT_PATH", &path),
+        ("ACTION", action),
+        ("RESULT", outcome),
+    ]);
+}
+
+/// A logger that sends messages to the journal.
+struct JournalLogger {
+    /// The socket through which messages are sent.
+    socket: UnixDatagram,
+    /// Which messages to log.
+    filter: env_logger::filter::Filter,
+}
+
+impl Log for JournalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let message = record.args().to_string();
+        let line = record.line().map(|l| l.to_string()).unwrap_or_default();
+        send_on(
+            &self.socket,
+            &[
+                ("MESSAGE", &message),
+                ("PRIORITY", priority_of(record.level())),
+                ("SYSLOG_IDENTIFIER", crate_name!()),
+                ("CODE_MODULE", record.module_path().unwrap_or_default()),
+                ("CODE_FILE", record.file().unwrap_or_default()),
+                ("CODE_LINE", &line),
+            ],
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// The syslog priority of a log level.
+fn priority_of(level: Level) -> &'static str {
+    match level {
+        Level::Error => "3",
+        Level::Warn => "4",
+        Level::Info => "6",
+        Level::Debug | Level::Trace => "7",
+    }
+}
+
+/// Send a message with the given fields to the journal.
+fn send(fields: &[(&str, &str)]) {
+    match UnixDatagram::unbound() {
+        Ok(socket) => send_on(&socket, fields),
+        Err(e) => eprintln!("Cannot log to journal: {}", e),
+    }
+}
+
+/// Send a message with the given fields to the journal through a socket.
+fn send_on(socket: &UnixDatagram, fields: &[(&str, &str)]) {
+    let mut buf = Vec::new();
+    for (name, value) in fields.iter().filter(|(_, value)| !value.is_empty()) {
+        buf.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            // Values with newlines are prefixed with their length instead.
+            buf.push(b'\n');
+            buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buf.push(b'=');
+        }
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+    if let Err(e) = socket.send_to(&buf, JOURNAL_SOCKET) {
+        eprintln!("Cannot log to journal: {}", e);
+    }
+}
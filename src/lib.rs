@@ -0,0 +1,2904 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! A library to create rotating btrfs subvolume snapshots.
+//!
+//! The library exposes the building blocks of the `btrfs-snapshot` tool, such
+//! that its retention logic can be embedded in other tools. A [`Config`] is
+//! read from a file or constructed directly, the existing snapshots of a
+//! snapshot config are gathered into a [`SnapshotSet`], a [`RotationPlan`]
+//! decides which of them to delete, and an [`Executor`] carries out the
+//! deletion:
+//!
+//! ```no_run
+//! use btrfs_snapshot::{Config, Executor, RotationPlan, SnapshotSet, SystemExecutor};
+//! use std::path::Path;
+//!
+//! let config = Config::load(Path::new("/etc/btrfs-snapshot.toml"))?;
+//! for snapshot in config.snapshots.values() {
+//!     let set = SnapshotSet::read(snapshot)?;
+//!     let plan = RotationPlan::new(snapshot, &set, None)?;
+//!     SystemExecutor.apply(&plan)?;
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! [`State`] ties these together with hooks, holds, replication, metrics, and
+//! notifications, as used by the command line tool.
+
+#[macro_use]
+extern crate clap;
+#[macro_use]
+extern crate log;
+
+pub mod alert;
+pub mod api;
+pub mod archive;
+pub mod binaries;
+pub mod boot;
+pub mod bootloader;
+pub mod browse;
+pub mod catalog;
+pub mod check;
+pub mod color;
+pub mod daemon;
+pub mod dbus;
+pub mod diff;
+pub mod du;
+pub mod executor;
+pub mod exit;
+pub mod gc;
+pub mod history;
+pub mod hold;
+pub mod import;
+pub mod inhibit;
+pub mod init;
+pub mod ioctl;
+pub mod journal;
+pub mod lock;
+pub mod luks;
+pub mod metrics;
+pub mod migrate;
+pub mod mounts;
+pub mod naming;
+pub mod notification;
+pub mod notify;
+pub mod output;
+pub mod overrides;
+pub mod parallel;
+pub mod ping;
+pub mod pkg;
+pub mod plan;
+pub mod privilege;
+pub mod prune;
+pub mod pull;
+pub mod qgroup;
+pub mod quiesce;
+pub mod replicate;
+pub mod restore;
+pub mod retention;
+pub mod schedule;
+pub mod scrub;
+pub mod select;
+pub mod size;
+pub mod space;
+pub mod status;
+pub mod subvolume;
+pub mod timeout;
+pub mod timezone;
+pub mod trash;
+pub mod user;
+pub mod verify;
+
+pub use crate::{
+    executor::{Executor, MockExecutor, SystemExecutor},
+    plan::{RotationPlan, SnapshotSet},
+};
+
+use crate::{
+    api::ApiConfig,
+    catalog::Catalog,
+    dbus::DbusConfig,
+    history::History,
+    hold::HoldFile,
+    luks::LuksConfig,
+    metrics::MetricsFile,
+    naming::Naming,
+    notification::{Event, EventKind, NotificationConfig},
+    output::{
+        Action, ActionKind, ListedSnapshot, OutputFormat, SnapshotList, SnapshotPlan,
+        SnapshotStatus, SpacingRule,
+    },
+    pull::PullConfig,
+    quiesce::QuiesceConfig,
+    replicate::ReplicateConfig,
+    retention::{parse_snapshots, sort_spacings, KeepCounts, SnapshotEntry, Spacings, TagConfig},
+    schedule::Schedule,
+    scrub::{ScrubConfig, ScrubStatus},
+    size::ByteSize,
+    status::RunStatus,
+    timezone::TimeZone,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use indexmap::IndexMap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::Read,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+
+/// The configuration of the snapshots to take, rotate, and replicate.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// The directory where persistent state such as the last run status is
+    /// kept.
+    pub state_dir: Option<PathBuf>,
+    /// The file locked while snapshots are modified, such that concurrent
+    /// runs do not interfere with each other.
+    pub lock_file: Option<PathBuf>,
+    /// The file to export metrics to in the Prometheus text format, e.g. in
+    /// the directory of the node_exporter textfile collector.
+    pub metrics_file: Option<PathBuf>,
+    /// The maximum number of filesystems to process concurrently. Defaults
+    /// to processing all of them at once.
+    pub jobs: Option<usize>,
+    /// Where to send notifications about failed runs.
+    #[serde(default)]
+    pub notify: NotificationConfig,
+    /// Where the daemon serves its HTTP API.
+    pub api: Option<ApiConfig>,
+    /// How the daemon offers its D-Bus service.
+    pub dbus: Option<DbusConfig>,
+    /// How long external commands may take before they are killed.
+    #[serde(default)]
+    pub timeouts: timeout::Timeouts,
+    /// Where to find the external programs that are run.
+    #[serde(default)]
+    pub binaries: binaries::Binaries,
+    /// Additional files with snapshot configs. The file name may contain `*`
+    /// and `?` wildcards. Relative paths are resolved against the directory
+    /// of the main config file.
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
+    /// The common configuration bits for snapshots.
+    #[serde(flatten)]
+    pub generic: SnapshotConfig,
+    /// The per-snapshot configuration.
+    #[serde(default)]
+    pub snapshots: IndexMap<String, SnapshotConfig>,
+}
+
+/// The configuration of a single snapshot config, with the generic
+/// configuration already filled in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotConfig {
+    /// The name of the snapshot config.
+    #[serde(skip)]
+    pub name: String,
+    /// The name of the config this config was split from if it lists multiple
+    /// subvolumes, or the same as `name` otherwise.
+    #[serde(skip)]
+    pub group: String,
+    /// Whether the snapshot config is in effect. Disabled configs are skipped
+    /// unless they are selected explicitly with `--snapshot`.
+    pub enabled: Option<bool>,
+    /// The mount point of the btrfs volume.
+    pub mount_point: Option<PathBuf>,
+    /// The device to mount at the mount point, such as `/dev/sdb1`,
+    /// `UUID=...`, or `LABEL=...`, instead of relying on an fstab entry.
+    /// Defaults the mount point to a location managed by the tool.
+    pub device: Option<String>,
+    /// The options to mount the volume with, such as `subvol=/,compress=zstd`.
+    pub mount_options: Option<String>,
+    /// Leave the volume mounted after the run if it had to be mounted.
+    pub keep_mounted: Option<bool>,
+    /// Lazily detach the volume if it is still busy after retrying to
+    /// unmount it.
+    pub lazy_unmount: Option<bool>,
+    /// The LUKS container to open before mounting the volume, and to close
+    /// after unmounting it.
+    pub luks: Option<LuksConfig>,
+    /// How often to scrub the volume.
+    pub scrub: Option<ScrubConfig>,
+    /// The format to use for snapshot names. A chrono format string that may
+    /// contain the variables `{hostname}`, `{config}`, `{tag}`, and `{seq}`.
+    pub format: Option<String>,
+    /// The time zone in which snapshot names are generated, and in which
+    /// names without a UTC offset are parsed. Defaults to the local zone.
+    pub timezone: Option<TimeZone>,
+    /// The subvolume or subvolumes to snapshot.
+    pub subvolume: Option<Subvolumes>,
+    /// The directory where snapshots are stored.
+    pub snapshot_dir: Option<PathBuf>,
+    /// A list of spacing between snapshots for snapshots of a given age.
+    pub spacings: Option<Spacings>,
+    /// Align spacings of whole hours, days, weeks, months, and years to the
+    /// calendar, keeping the first snapshot after each boundary.
+    pub align_spacings: Option<bool>,
+    /// The number of hourly, daily, weekly, monthly, and yearly snapshots to
+    /// keep. Replaces `spacings` if any count is given.
+    #[serde(flatten)]
+    pub keep: KeepCounts,
+    /// Never delete snapshots if fewer than this many would remain.
+    pub keep_min: Option<usize>,
+    /// Delete the oldest snapshots beyond this many.
+    pub keep_max: Option<usize>,
+    /// Ask for confirmation before a rotation deletes more than this many
+    /// snapshots at once, and refuse if there is no terminal to ask on.
+    pub confirm_delete_above: Option<usize>,
+    /// Delete the oldest snapshots until the space used exclusively by the
+    /// snapshots falls below this size. Enables quotas on the filesystem.
+    pub max_total_size: Option<ByteSize>,
+    /// Delete snapshots older than this, unless `keep_min` spares them.
+    pub max_age: Option<humantime_serde::Serde<Duration>>,
+    /// Move rotated snapshots into a `.trash` directory instead of deleting
+    /// them, and only delete them for good once they have been there for this
+    /// long.
+    pub trash_grace: Option<humantime_serde::Serde<Duration>>,
+    /// Whether snapshots are taken read-only. Defaults to true.
+    pub readonly: Option<bool>,
+    /// Also snapshot the subvolumes nested within `subvolume`, placing them
+    /// at the corresponding paths within the snapshot.
+    pub recursive: Option<bool>,
+    /// Do not take a new snapshot if the subvolume has not been modified since
+    /// the newest existing snapshot.
+    pub skip_unchanged: Option<bool>,
+    /// Do not take a new snapshot if the newest existing snapshot is younger
+    /// than this.
+    pub min_interval: Option<humantime_serde::Serde<Duration>>,
+    /// What to do if the name of a new snapshot is already taken.
+    pub on_collision: Option<naming::CollisionPolicy>,
+    /// Do not take a new snapshot if the filesystem has less free space than
+    /// this, as a size or a percentage of the filesystem size.
+    pub min_free: Option<space::MinFree>,
+    /// What to do if the filesystem has less free space than `min_free`.
+    pub min_free_action: Option<space::MinFreeAction>,
+    /// Delete the least valuable snapshots after rotating until the
+    /// filesystem has at least this much free space, as a size or a
+    /// percentage of the filesystem size.
+    pub free_target: Option<space::MinFree>,
+    /// Wait for btrfs to clean up deleted snapshots after rotating, and
+    /// report how much space they released.
+    pub sync_deletions: Option<bool>,
+    /// Warn after processing the config if the filesystem has less free space
+    /// than this, as a size or a percentage of the filesystem size.
+    pub alert_if_free_below: Option<space::MinFree>,
+    /// Warn after processing the config if the newest snapshot is older than
+    /// this.
+    pub alert_if_no_snapshot_for: Option<humantime_serde::Serde<Duration>>,
+    /// When the daemon takes and rotates snapshots.
+    pub schedule: Option<Schedule>,
+    /// A shell command to run before taking a snapshot.
+    pub pre_hook: Option<String>,
+    /// A shell command to run after taking a snapshot.
+    pub post_hook: Option<String>,
+    /// A shell command to run after every action performed on a snapshot.
+    pub action_hook: Option<String>,
+    /// The user that hooks and quiesce commands run as.
+    pub user: Option<String>,
+    /// The group that hooks and quiesce commands run as.
+    #[serde(rename = "group")]
+    pub user_group: Option<String>,
+    /// How to refresh the boot menu after snapshots were taken or deleted,
+    /// for configs of the root subvolume.
+    pub bootloader: Option<bootloader::Bootloader>,
+    /// How to quiesce an application while the snapshot is taken.
+    pub quiesce: Option<QuiesceConfig>,
+    /// The URL of a dead man's switch such as healthchecks.io, which is
+    /// pinged when processing starts, succeeds, or fails.
+    pub ping_url: Option<String>,
+    /// The retention rules for snapshots taken with a tag. Tagged snapshots
+    /// without rules follow the same rules as untagged ones, but are rotated
+    /// separately.
+    #[serde(default)]
+    pub tags: IndexMap<String, TagConfig>,
+    /// Where to replicate snapshots to.
+    pub replicate: Option<ReplicateConfig>,
+    /// Where to pull snapshots from, turning this into a config for a backup
+    /// server that receives the snapshots of `subvolume` on another host.
+    pub pull: Option<PullConfig>,
+}
+
+/// A config file included from the main config.
+#[derive(Debug, Deserialize)]
+struct IncludedConfig {
+    /// The per-snapshot configuration.
+    #[serde(default)]
+    snapshots: IndexMap<String, SnapshotConfig>,
+}
+
+/// One or more subvolumes to snapshot under a single config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Subvolumes {
+    /// A single subvolume, whose snapshots go directly into `snapshot_dir`.
+    Single(PathBuf),
+    /// Multiple subvolumes, whose snapshots go into a subdirectory of
+    /// `snapshot_dir` each.
+    Multiple(Vec<PathBuf>),
+}
+
+impl Config {
+    /// Get the file locked while snapshots are modified.
+    pub fn lock_file(&self) -> &Path {
+        self.lock_file
+            .as_deref()
+            .unwrap_or_else(|| Path::new("/run/btrfs-snapshot.lock"))
+    }
+
+    /// Get the directory where persistent state is kept.
+    pub fn state_dir(&self) -> &Path {
+        self.state_dir
+            .as_deref()
+            .unwrap_or_else(|| Path::new("/var/lib/btrfs-snapshot"))
+    }
+
+    /// Treat all configured paths as relative to `root`, such as an
+    /// installation that a rescue system mounted at `/mnt`. The lock file and
+    /// the mount points the tool manages for devices stay on the running
+    /// system.
+    pub fn reroot(&mut self, root: &Path) {
+        let reroot = |path: &mut PathBuf| *path = reroot_path(root, path);
+        self.state_dir = Some(reroot_path(root, self.state_dir()));
+        self.metrics_file.iter_mut().for_each(reroot);
+        for s in self.snapshots.values_mut() {
+            s.mount_point.iter_mut().for_each(reroot);
+            s.snapshot_dir.iter_mut().for_each(reroot);
+            match &mut s.subvolume {
+                Some(Subvolumes::Single(path)) => reroot(path),
+                Some(Subvolumes::Multiple(paths)) => paths.iter_mut().for_each(reroot),
+                None => (),
+            }
+            if let Some(luks) = &mut s.luks {
+                luks.keyfile.iter_mut().for_each(reroot);
+            }
+            if let Some(replicate) = &mut s.replicate {
+                replicate.mount_point.iter_mut().for_each(reroot);
+                if let Some(luks) = &mut replicate.luks {
+                    luks.keyfile.iter_mut().for_each(reroot);
+                }
+                if replicate.host.is_none() {
+                    replicate.target_dir.iter_mut().for_each(reroot);
+                }
+            }
+        }
+    }
+}
+
+impl SnapshotConfig {
+    /// Get the naming scheme of snapshots.
+    pub fn naming(&self) -> Result<Naming> {
+        Naming::new(
+            self.format.as_ref().unwrap(),
+            &self.group,
+            self.timezone.clone().unwrap_or_default(),
+        )
+    }
+
+    /// Get the subvolume to snapshot. Configs with multiple subvolumes are
+    /// split up by `read_config`, so there is always exactly one.
+    pub fn subvolume(&self) -> &Path {
+        match self.subvolume.as_ref().unwrap() {
+            Subvolumes::Single(path) => path,
+            Subvolumes::Multiple(_) => unreachable!("multiple subvolumes not split up"),
+        }
+    }
+
+    /// Split a config with multiple subvolumes into one config per subvolume.
+    /// The snapshots of each subvolume are stored in a subdirectory of
+    /// `snapshot_dir` named after the subvolume.
+    fn split_subvolumes(self) -> Result<Vec<SnapshotConfig>> {
+        let paths = match self.subvolume.as_ref().unwrap() {
+            Subvolumes::Single(_) => return Ok(vec![self]),
+            Subvolumes::Multiple(paths) => paths.clone(),
+        };
+        let mut configs: Vec<SnapshotConfig> = Vec::new();
+        for path in paths {
+            let subdir = match path.file_name() {
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => String::from("root"),
+            };
+            let name = format!("{}/{}", self.name, subdir);
+            if configs.iter().any(|c| c.name == name) {
+                bail!(
+                    "Snapshot {} has multiple subvolumes named `{}`",
+                    self.name,
+                    subdir
+                );
+            }
+            let mut config = self.clone();
+            config.name = name;
+            config.snapshot_dir = Some(self.snapshot_dir.as_ref().unwrap().join(&subdir));
+            config.replicate = self.replicate.as_ref().map(|r| r.for_subdir(&subdir));
+            config.pull = self.pull.as_ref().map(|p| p.for_subdir(&subdir));
+            config.subvolume = Some(Subvolumes::Single(path));
+            configs.push(config);
+        }
+        Ok(configs)
+    }
+
+    /// Get the configured spacings as `(age, spacing)` pairs, sorted by
+    /// ascending age.
+    pub fn sorted_spacings(&self) -> Vec<(Duration, Duration)> {
+        sort_spacings(self.spacings.as_ref().unwrap())
+    }
+}
+
+impl Config {
+    /// Read a configuration file.
+    pub fn load(path: &Path) -> Result<Config> {
+        Self::load_with_overrides(path, &[], None)
+    }
+
+    /// Read a configuration file, with some values overridden from the
+    /// command line. If `root` is given, absolute paths, including those of
+    /// included files, are taken relative to it, as in `reroot`.
+    pub fn load_with_overrides(
+        path: &Path,
+        overrides: &[overrides::Override],
+        root: Option<&Path>,
+    ) -> Result<Config> {
+        debug!("Loading config {}", path.display());
+        let mut buf = String::new();
+        File::open(path)?.read_to_string(&mut buf)?;
+        let mut cfg: Config = toml::de::from_str(&buf)?;
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        for pattern in &cfg.include {
+            let mut pattern = expand_path(pattern)?;
+            if let Some(root) = root {
+                pattern = reroot_path(root, &pattern);
+            }
+            for file in expand_include(&base.join(pattern))? {
+                debug!("Loading included config {}", file.display());
+                let buf = std::fs::read_to_string(&file).with_context(|| {
+                    format!("Failed to read included config {}", file.display())
+                })?;
+                let included: IncludedConfig = toml::de::from_str(&buf).with_context(|| {
+                    format!("Failed to parse included config {}", file.display())
+                })?;
+                for (name, s) in included.snapshots {
+                    if cfg.snapshots.contains_key(&name) {
+                        bail!(
+                            "Snapshot {} in {} is already defined elsewhere",
+                            name,
+                            file.display()
+                        );
+                    }
+                    cfg.snapshots.insert(name, s);
+                }
+            }
+        }
+        let mut cfg = cfg.resolve(overrides)?;
+        if let Some(root) = root {
+            cfg.reroot(root);
+        }
+        Ok(cfg)
+    }
+
+    /// Create a configuration for a single subvolume without a config file,
+    /// for quick manual snapshots. The config is named after the subvolume,
+    /// and the mount point is the one the subvolume lies on.
+    pub fn adhoc(
+        subvolume: &Path,
+        snapshot_dir: &Path,
+        format: &str,
+        overrides: &[overrides::Override],
+    ) -> Result<Config> {
+        let subvolume = std::fs::canonicalize(subvolume)
+            .with_context(|| format!("Cannot find subvolume {}", subvolume.display()))?;
+        let mounts = mounts::read_mounts();
+        let mount_point = mounts::find_mount(&mounts, &subvolume)
+            .map(|m| m.mount_point.clone())
+            .ok_or_else(|| anyhow!("Cannot find the mount point of {}", subvolume.display()))?;
+        let name = subvolume
+            .file_name()
+            .map_or_else(|| "root".into(), |name| name.to_string_lossy());
+        let mut snapshot = toml::value::Table::new();
+        snapshot.insert("mount_point".into(), toml::Value::try_from(&mount_point)?);
+        snapshot.insert("subvolume".into(), toml::Value::try_from(&subvolume)?);
+        snapshot.insert("snapshot_dir".into(), toml::Value::try_from(snapshot_dir)?);
+        snapshot.insert("format".into(), format.into());
+        let mut snapshots = toml::value::Table::new();
+        snapshots.insert(name.into_owned(), snapshot.into());
+        let mut cfg = toml::value::Table::new();
+        cfg.insert("snapshots".into(), snapshots.into());
+        let cfg: Config = toml::Value::from(cfg).try_into()?;
+        cfg.resolve(overrides)
+    }
+
+    /// Apply overrides from the command line, copy the generic config into
+    /// the snapshot configs, and check the result.
+    fn resolve(self, overrides: &[overrides::Override]) -> Result<Config> {
+        let mut cfg = self;
+        if !overrides.is_empty() {
+            let mut value = toml::Value::try_from(&cfg)?;
+            for o in overrides {
+                debug!("Overriding `{}` with {}", o.key.join("."), o.value);
+                o.apply(&mut value)?;
+            }
+            cfg = value
+                .try_into()
+                .context("Invalid config after applying `--set`")?;
+        }
+        if cfg.generic.spacings.is_none() {
+            cfg.generic.spacings = Some(Default::default());
+        }
+
+        // Copy details from the generic config into the snapshots.
+        let mut snapshots = std::mem::take(&mut cfg.snapshots);
+        for (name, s) in &mut snapshots {
+            s.name = name.clone();
+            s.group = name.clone();
+            if s.mount_point.is_none() {
+                s.mount_point = cfg.generic.mount_point.clone();
+            }
+            if s.device.is_none() {
+                s.device = cfg.generic.device.clone();
+            }
+            if s.mount_options.is_none() {
+                s.mount_options = cfg.generic.mount_options.clone();
+            }
+            if s.keep_mounted.is_none() {
+                s.keep_mounted = cfg.generic.keep_mounted;
+            }
+            if s.lazy_unmount.is_none() {
+                s.lazy_unmount = cfg.generic.lazy_unmount;
+            }
+            if s.luks.is_none() {
+                s.luks = cfg.generic.luks.clone();
+            }
+            if s.scrub.is_none() {
+                s.scrub = cfg.generic.scrub.clone();
+            }
+            if s.format.is_none() {
+                s.format = cfg.generic.format.clone();
+            }
+            if s.timezone.is_none() {
+                s.timezone = cfg.generic.timezone.clone();
+            }
+            if s.subvolume.is_none() {
+                s.subvolume = cfg.generic.subvolume.clone();
+            }
+            if s.snapshot_dir.is_none() {
+                s.snapshot_dir = cfg.generic.snapshot_dir.clone();
+            }
+            if s.spacings.is_none() {
+                s.spacings = cfg.generic.spacings.clone();
+            }
+            if s.align_spacings.is_none() {
+                s.align_spacings = cfg.generic.align_spacings;
+            }
+            s.keep.inherit(&cfg.generic.keep);
+            for (tag, config) in &cfg.generic.tags {
+                s.tags.entry(tag.clone()).or_insert_with(|| config.clone());
+            }
+            if s.keep_min.is_none() {
+                s.keep_min = cfg.generic.keep_min;
+            }
+            if s.keep_max.is_none() {
+                s.keep_max = cfg.generic.keep_max;
+            }
+            if s.confirm_delete_above.is_none() {
+                s.confirm_delete_above = cfg.generic.confirm_delete_above;
+            }
+            if s.max_total_size.is_none() {
+                s.max_total_size = cfg.generic.max_total_size;
+            }
+            if s.max_age.is_none() {
+                s.max_age = cfg.generic.max_age;
+            }
+            if s.trash_grace.is_none() {
+                s.trash_grace = cfg.generic.trash_grace;
+            }
+            if s.enabled.is_none() {
+                s.enabled = cfg.generic.enabled;
+            }
+            if s.readonly.is_none() {
+                s.readonly = cfg.generic.readonly;
+            }
+            if s.recursive.is_none() {
+                s.recursive = cfg.generic.recursive;
+            }
+            if s.skip_unchanged.is_none() {
+                s.skip_unchanged = cfg.generic.skip_unchanged;
+            }
+            if s.min_interval.is_none() {
+                s.min_interval = cfg.generic.min_interval;
+            }
+            if s.on_collision.is_none() {
+                s.on_collision = cfg.generic.on_collision;
+            }
+            if s.min_free.is_none() {
+                s.min_free = cfg.generic.min_free;
+            }
+            if s.min_free_action.is_none() {
+                s.min_free_action = cfg.generic.min_free_action;
+            }
+            if s.free_target.is_none() {
+                s.free_target = cfg.generic.free_target;
+            }
+            if s.sync_deletions.is_none() {
+                s.sync_deletions = cfg.generic.sync_deletions;
+            }
+            if s.alert_if_free_below.is_none() {
+                s.alert_if_free_below = cfg.generic.alert_if_free_below;
+            }
+            if s.alert_if_no_snapshot_for.is_none() {
+                s.alert_if_no_snapshot_for = cfg.generic.alert_if_no_snapshot_for;
+            }
+            if s.schedule.is_none() {
+                s.schedule = cfg.generic.schedule.clone();
+            }
+            if s.pre_hook.is_none() {
+                s.pre_hook = cfg.generic.pre_hook.clone();
+            }
+            if s.post_hook.is_none() {
+                s.post_hook = cfg.generic.post_hook.clone();
+            }
+            if s.action_hook.is_none() {
+                s.action_hook = cfg.generic.action_hook.clone();
+            }
+            if s.user.is_none() && s.user_group.is_none() {
+                s.user = cfg.generic.user.clone();
+                s.user_group = cfg.generic.user_group.clone();
+            }
+            if s.bootloader.is_none() {
+                s.bootloader = cfg.generic.bootloader.clone();
+            }
+            if s.ping_url.is_none() {
+                s.ping_url = cfg.generic.ping_url.clone();
+            }
+            if s.quiesce.is_none() {
+                s.quiesce = cfg.generic.quiesce.clone();
+            }
+
+            // Expand environment variables and `~` in paths.
+            let expand = |path: &mut Option<PathBuf>| -> Result<()> {
+                if let Some(path) = path {
+                    *path = expand_path(path)
+                        .with_context(|| format!("Snapshot {} has an invalid path", name))?;
+                }
+                Ok(())
+            };
+            expand(&mut s.mount_point)?;
+            expand(&mut s.snapshot_dir)?;
+            match &mut s.subvolume {
+                Some(Subvolumes::Single(path)) => *path = expand_path(path)?,
+                Some(Subvolumes::Multiple(paths)) => {
+                    for path in paths {
+                        *path = expand_path(path)?;
+                    }
+                }
+                None => (),
+            }
+
+            // Mount devices at a managed location unless told otherwise, and
+            // resolve relative paths within it.
+            if let Some(device) = &s.device {
+                let mount_point = s
+                    .mount_point
+                    .get_or_insert_with(|| managed_mount_point(device))
+                    .clone();
+                let resolve = |path: &mut PathBuf| *path = mount_point.join(&*path);
+                if let Some(path) = &mut s.snapshot_dir {
+                    resolve(path);
+                }
+                match &mut s.subvolume {
+                    Some(Subvolumes::Single(path)) => resolve(path),
+                    Some(Subvolumes::Multiple(paths)) => paths.iter_mut().for_each(resolve),
+                    None => (),
+                }
+            }
+
+            // Check that we have enough information.
+            if s.mount_point.is_none() {
+                bail!("Snapshot {} has no `mount_point` config", name);
+            }
+            if s.format.is_none() {
+                bail!("Snapshot {} has no `format` config", name);
+            }
+            if s.subvolume.is_none() {
+                bail!("Snapshot {} has no `subvolume` config", name);
+            }
+            if s.snapshot_dir.is_none() {
+                bail!("Snapshot {} has no `snapshot_dir` config", name);
+            }
+            s.naming()
+                .with_context(|| format!("Snapshot {} has an invalid `format`", name))?;
+            if let (Some(min), Some(max)) = (s.keep_min, s.keep_max) {
+                if min > max {
+                    bail!(
+                        "Snapshot {} has `keep_min` ({}) greater than `keep_max` ({})",
+                        name,
+                        min,
+                        max
+                    );
+                }
+            }
+            if let Some(replicate) = &s.replicate {
+                if s.readonly == Some(false) {
+                    bail!(
+                        "Snapshot {} cannot be replicated since `readonly` is false",
+                        name
+                    );
+                }
+                if s.recursive == Some(true) {
+                    bail!(
+                        "Snapshot {} cannot be replicated since `recursive` is set",
+                        name
+                    );
+                }
+                replicate.validate().with_context(|| {
+                    format!("Snapshot {} has an invalid `replicate` config", name)
+                })?;
+            }
+            if s.pull.is_some() {
+                if s.recursive == Some(true) {
+                    bail!(
+                        "Snapshot {} cannot be pulled since `recursive` is set",
+                        name
+                    );
+                }
+                if s.replicate.is_some() {
+                    bail!("Snapshot {} cannot both pull and replicate snapshots", name);
+                }
+            }
+        }
+
+        if cfg.jobs == Some(0) {
+            bail!("`jobs` must be at least 1");
+        }
+        if let Some(api) = &cfg.api {
+            api.validate().context("Invalid `api` config")?;
+        }
+        if let Some(email) = &cfg.notify.email {
+            if email.to.is_empty() {
+                bail!("Notification emails need at least one recipient in `to`");
+            }
+        }
+
+        // Split up configs that snapshot multiple subvolumes.
+        for (_, s) in snapshots {
+            for s in s.split_subvolumes()? {
+                cfg.snapshots.insert(s.name.clone(), s);
+            }
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// Expand a leading `~` to the home directory, and `$VAR` and `${VAR}` to the
+/// value of the environment variable.
+fn expand_path(path: &Path) -> Result<PathBuf> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow!("Path {} is not valid UTF-8", path.display()))?;
+    let var = |name: &str| {
+        std::env::var(name).with_context(|| {
+            format!(
+                "Environment variable `{}` in path `{}` is not set",
+                name, path
+            )
+        })
+    };
+    let mut expanded = String::new();
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        expanded.push_str(&var("HOME")?);
+        rest = &rest[1..];
+    }
+    let re = Regex::new(r"\$(?:\{([A-Za-z_][A-Za-z0-9_]*)\}|([A-Za-z_][A-Za-z0-9_]*))").unwrap();
+    let mut last = 0;
+    for cap in re.captures_iter(rest) {
+        let m = cap.get(0).unwrap();
+        expanded.push_str(&rest[last..m.start()]);
+        expanded.push_str(&var(cap.get(1).or_else(|| cap.get(2)).unwrap().as_str())?);
+        last = m.end();
+    }
+    expanded.push_str(&rest[last..]);
+    Ok(PathBuf::from(expanded))
+}
+
+/// Translate a pattern with `*` and `?` wildcards into a regular expression
+/// that matches whole file names.
+fn wildcard_regex(pattern: &str) -> Regex {
+    Regex::new(&format!(
+        "^{}$",
+        regex::escape(pattern)
+            .replace("\\*", "[^/]*")
+            .replace("\\?", "[^/]")
+    ))
+    .unwrap()
+}
+
+/// Find the files matching an include pattern, sorted by name. Only the file
+/// name may contain wildcards. A pattern without wildcards must name an
+/// existing file.
+fn expand_include(pattern: &Path) -> Result<Vec<PathBuf>> {
+    let name = pattern.file_name().unwrap_or_default().to_string_lossy();
+    if !name.contains(&['*', '?'][..]) {
+        return Ok(vec![pattern.to_owned()]);
+    }
+    let re = wildcard_regex(&name);
+    let dir = pattern.parent().unwrap_or_else(|| Path::new("."));
+    let mut files = Vec::new();
+    if dir.is_dir() {
+        for entry in std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read directory {}", dir.display()))?
+        {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if !file_name.starts_with('.') && re.is_match(&file_name) {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The state of a run that takes, rotates, and replicates snapshots.
+#[derive(Default)]
+pub struct State<'a> {
+    /// Whether to only print btrfs commands rather than executing them.
+    pub dry_run: bool,
+    /// The format in which results are printed.
+    pub output: OutputFormat,
+    /// The actions performed or planned so far.
+    pub actions: Vec<Action>,
+    /// The disks mounted explicitly by us, and how to unmount each. Only
+    /// LUKS containers opened by us are listed in the settings.
+    pub manual_mounts: IndexMap<&'a Path, MountSettings<'a>>,
+    /// The snapshots protected from rotation.
+    pub holds: HoldFile,
+    /// The metrics about snapshot operations.
+    pub metrics: MetricsFile,
+    /// The record of the snapshots taken and where they were sent.
+    pub catalog: Catalog,
+    /// The log that every action is appended to, if any.
+    pub history: Option<History>,
+    /// The events that have not been notified about yet.
+    pub events: Vec<Event>,
+    /// The time of this run, fixed when the invocation or daemon run starts,
+    /// or else when the first snapshot is taken. All snapshots of a run are
+    /// named after and cataloged with this time, such that the snapshots of
+    /// different configs can be correlated.
+    pub now: Option<chrono::DateTime<chrono::Local>>,
+    /// The tag to take new snapshots with.
+    pub tag: Option<String>,
+    /// Whether to print why each snapshot is kept or deleted when rotating.
+    pub explain: bool,
+    /// How much space to reclaim when rotating, on top of what is free.
+    pub free_up: Option<size::ByteSize>,
+    /// Whether to wait for deleted snapshots to be cleaned up after rotating.
+    pub sync_deletions: bool,
+    /// Whether to delete snapshots without asking for confirmation.
+    pub yes: bool,
+    /// Whether to leave all disks mounted after the run.
+    pub keep_mounted: bool,
+    /// Carries out the operations that modify the system.
+    pub executor: Box<dyn Executor>,
+}
+
+impl<'a> State<'a> {
+    /// Take a new snapshot and/or rotate the existing ones.
+    pub fn process_snapshot(
+        &mut self,
+        snapshot: &'a SnapshotConfig,
+        take: bool,
+        rotate: bool,
+    ) -> Result<()> {
+        let first_action = self.actions.len();
+        if take {
+            self.timed(snapshot, "take", |state| state.take_snapshot(snapshot))?;
+        }
+        if rotate {
+            self.timed(snapshot, "rotate", |state| state.rotate_snapshot(snapshot))?;
+        }
+
+        // Only bother the bootloader if the set of snapshots changed.
+        if let Some(bootloader) = &snapshot.bootloader {
+            let changed = self.actions[first_action..].iter().any(|action| {
+                matches!(
+                    action.action,
+                    ActionKind::Take | ActionKind::Delete | ActionKind::Trash
+                )
+            });
+            if changed {
+                self.refresh_bootloader(snapshot, bootloader)?;
+            }
+        }
+
+        let command = match (take, rotate) {
+            (true, false) => "take",
+            (false, true) => "rotate",
+            _ => "run",
+        };
+        self.check_alerts(snapshot, command)?;
+        Ok(())
+    }
+
+    fn take_snapshot(&mut self, snapshot: &'a SnapshotConfig) -> Result<()> {
+        if snapshot.pull.is_some() {
+            debug!("Not taking snapshot of {}; it is pulled", snapshot.name);
+            return Ok(());
+        }
+        debug!("Take snapshot of {}", snapshot.name);
+        self.mount_snapshot_fs(snapshot)?;
+
+        // Skip the snapshot if the newest one is too recent, or if nothing was
+        // written since the newest one. Tagged snapshots are taken on purpose
+        // and never skipped.
+        if self.tag.is_none()
+            && (snapshot.min_interval.is_some() || snapshot.skip_unchanged == Some(true))
+        {
+            if let Some(newest) = find_snapshots(snapshot, &[])?.first() {
+                let reason = match snapshot.min_interval {
+                    Some(min) if newest.age < min.into_inner() => Some(format!(
+                        "{} is younger than {}",
+                        newest.path.display(),
+                        humantime::format_duration(min.into_inner())
+                    )),
+                    _ if snapshot.skip_unchanged == Some(true)
+                        && !self.changed_since(snapshot, &newest.path)? =>
+                    {
+                        Some(format!("unchanged since {}", newest.path.display()))
+                    }
+                    _ => None,
+                };
+                if let Some(reason) = reason {
+                    if self.output == OutputFormat::Text {
+                        println!(
+                            "{} {}; {}",
+                            color::warn("Skipping snapshot of"),
+                            snapshot.subvolume().display(),
+                            reason
+                        );
+                    }
+                    return Ok(());
+                }
+            }
+        }
+
+        // Make room for the snapshot, or skip it if there is too little.
+        if let Some(min_free) = snapshot.min_free {
+            if !self.ensure_free_space(snapshot, min_free)? {
+                return Ok(());
+            }
+        }
+
+        // Construct the snapshot directory.
+        let naming = snapshot.naming()?;
+        let mut path = snapshot.snapshot_dir.clone().unwrap();
+        if !self.dry_run {
+            create_dir_all(&path)
+                .with_context(|| format!("Failed to create snapshot dir {}", path.display()))?;
+        }
+        let seq = if naming.has_seq() {
+            find_snapshots(snapshot, &[])?
+                .iter()
+                .filter_map(|entry| entry.seq)
+                .max()
+                .map_or(1, |seq| seq + 1)
+        } else {
+            0
+        };
+        let run_time = *self.now.get_or_insert_with(chrono::Local::now);
+        let now = self.pkg_pair_time(snapshot)?.unwrap_or(run_time);
+        path.push(naming.render(now, self.tag.as_deref(), seq)?);
+
+        // Deal with an existing snapshot of the same name, e.g. one taken
+        // earlier within the same minute.
+        let existing = list_dir(snapshot.snapshot_dir.as_ref().unwrap())?;
+        if existing.contains(&path) {
+            match snapshot.on_collision.unwrap_or_default() {
+                naming::CollisionPolicy::Error => bail!(
+                    "Snapshot {} already exists; set `on_collision` to \"skip\" or \"suffix\" \
+                     to take snapshots more than once per name",
+                    path.display()
+                ),
+                naming::CollisionPolicy::Skip => {
+                    if self.output == OutputFormat::Text {
+                        println!(
+                            "{} {}; {} already exists",
+                            color::warn("Skipping snapshot of"),
+                            snapshot.subvolume().display(),
+                            path.display()
+                        );
+                    }
+                    return Ok(());
+                }
+                naming::CollisionPolicy::Suffix => {
+                    for suffix in 1.. {
+                        path.set_file_name(naming.render_with_suffix(
+                            now,
+                            self.tag.as_deref(),
+                            seq,
+                            Some(suffix),
+                        )?);
+                        if !existing.contains(&path) {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Take the snapshot.
+        if let Some(hook) = &snapshot.pre_hook {
+            self.run_hook(snapshot, "pre_hook", hook, ActionKind::Take, &path)?;
+        }
+        // Recursive snapshots are made read-only only once the nested
+        // subvolumes have been placed inside them.
+        let readonly = snapshot.readonly != Some(false);
+        let recursive = snapshot.recursive == Some(true);
+        let take = |state: &mut Self| {
+            state
+                .take_subvolume(
+                    snapshot,
+                    snapshot.subvolume(),
+                    &path,
+                    readonly && !recursive,
+                )
+                .with_context(|| format!("Taking snapshot {} failed", path.display()))?;
+            if recursive {
+                state.take_nested(snapshot, &path, readonly)?;
+            }
+            Ok(())
+        };
+        match &snapshot.quiesce {
+            Some(quiesce) => self.quiesced(snapshot, quiesce, take)?,
+            None => take(self)?,
+        };
+        if !self.dry_run {
+            let generation = match snapshot.skip_unchanged {
+                Some(true) => Some(subvolume::show(&path)?.gen_at_creation),
+                _ => None,
+            };
+            self.catalog.forget_missing(&snapshot.name, &existing);
+            self.catalog
+                .record_taken(snapshot, &path, run_time, self.tag.as_deref(), generation);
+        }
+        if let Some(hook) = &snapshot.post_hook {
+            self.run_hook(snapshot, "post_hook", hook, ActionKind::Take, &path)?;
+        }
+
+        Ok(())
+    }
+
+    fn rotate_snapshot(&mut self, snapshot: &'a SnapshotConfig) -> Result<()> {
+        debug!("Rotate snapshots for {}", snapshot.name);
+        self.mount_snapshot_fs(snapshot)?;
+        let set = SnapshotSet::read(snapshot)?;
+        let plan = self.rotation_plan(snapshot, &set, !self.dry_run)?;
+        if self.explain && self.output == OutputFormat::Text {
+            output::print_explanation(&SnapshotPlan::new(snapshot, &set, &plan));
+        }
+        if let Some(max) = snapshot.confirm_delete_above {
+            if plan.delete.len() > max && !self.dry_run && !self.yes {
+                confirm_deletion(snapshot, plan.delete.len(), max)?;
+            }
+        }
+
+        // Make sure every marked path is one of our snapshots before touching
+        // any of them.
+        let readonly = snapshot.readonly != Some(false);
+        let mut plan = plan;
+        plan.delete
+            .retain(|file| self.is_own_snapshot(snapshot, "rotate", file, readonly));
+
+        // Measure the free space to aim for before deleting anything, such
+        // that the regular rotation counts towards it.
+        let free_target = self.free_space_target(snapshot)?;
+        let first_action = self.actions.len();
+        let free_before = match self.sync_deletions || snapshot.sync_deletions == Some(true) {
+            true if !self.dry_run => Some(self.free_space(snapshot)?),
+            _ => None,
+        };
+
+        // Delete the marked snapshots, or move them into the trash and delete
+        // the ones that have been there long enough.
+        if let Some(grace) = snapshot.trash_grace {
+            self.empty_trash(snapshot, grace.into_inner())?;
+            for file in &plan.delete {
+                self.trash_snapshot(snapshot, file)?;
+            }
+        } else {
+            for file in &plan.delete {
+                if snapshot.recursive == Some(true) {
+                    self.delete_nested(snapshot, file)?;
+                }
+                self.delete_subvolume(snapshot, file)
+                    .with_context(|| format!("Deleting snapshot {} failed", file.display()))?;
+            }
+        }
+
+        // Delete further snapshots until enough space is free.
+        if let Some(required) = free_target {
+            self.free_up(snapshot, required)?;
+        }
+
+        // Wait for the space of the deleted snapshots to actually be freed.
+        if let Some(free_before) = free_before {
+            if self.actions[first_action..]
+                .iter()
+                .any(|action| action.action == ActionKind::Delete)
+            {
+                self.sync_deletions(snapshot, free_before)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decide which snapshots of a set to delete, sparing held ones. Limiting
+    /// the total size requires quotas to measure the space used exclusively
+    /// by each snapshot; they are enabled if `enable_quotas` is set, and the
+    /// limit is ignored otherwise.
+    fn rotation_plan(
+        &self,
+        snapshot: &SnapshotConfig,
+        set: &SnapshotSet,
+        enable_quotas: bool,
+    ) -> Result<RotationPlan> {
+        let mut measure_size = false;
+        if snapshot.max_total_size.is_some() {
+            let mount_point = snapshot.mount_point.as_ref().unwrap();
+            if !qgroup::is_enabled(mount_point) {
+                if enable_quotas {
+                    qgroup::enable(mount_point)?;
+                } else {
+                    warn!(
+                        "Ignoring `max_total_size` of {} since quotas are not enabled on {}",
+                        snapshot.name,
+                        mount_point.display()
+                    );
+                }
+            }
+            measure_size = qgroup::is_enabled(mount_point);
+        }
+        let exclusive = |path: &Path| Ok(qgroup::usage(path)?.exclusive);
+        let mut plan = RotationPlan::new(
+            snapshot,
+            set,
+            match measure_size {
+                true => Some(&exclusive),
+                false => None,
+            },
+        )?;
+        let holds = &self.holds;
+        plan.spare(|path| {
+            let held = holds.is_held(path);
+            if held {
+                debug!("  Keeping {} since it is held", path.display());
+            }
+            held
+        });
+        Ok(plan)
+    }
+
+    /// Snapshot the subvolumes nested within a config's subvolume into a new
+    /// writable snapshot, then make the snapshots read-only if requested.
+    fn take_nested(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        path: &Path,
+        readonly: bool,
+    ) -> Result<()> {
+        let source = snapshot.subvolume();
+        let snapshot_dir = snapshot.snapshot_dir.as_ref().unwrap();
+        let nested: Vec<_> = subvolume::nested(source)?
+            .into_iter()
+            .filter(|rel| !source.join(rel).starts_with(snapshot_dir))
+            .collect();
+        for rel in &nested {
+            // Nested subvolumes show up as empty directories in the snapshot.
+            let target = path.join(rel);
+            if !self.dry_run {
+                std::fs::remove_dir(&target).with_context(|| {
+                    format!("Failed to remove placeholder {}", target.display())
+                })?;
+            }
+            self.take_subvolume(snapshot, &source.join(rel), &target, false)
+                .with_context(|| format!("Taking snapshot {} failed", target.display()))?;
+        }
+        if readonly {
+            for rel in nested.iter().rev() {
+                self.set_readonly(&path.join(rel), true)?;
+            }
+            self.set_readonly(path, true)?;
+        }
+        Ok(())
+    }
+
+    /// Delete the subvolumes nested within a recursive snapshot, such that
+    /// the snapshot itself can be deleted.
+    fn delete_nested(&mut self, snapshot: &SnapshotConfig, path: &Path) -> Result<()> {
+        let nested = subvolume::nested(path)?;
+        if nested.is_empty() {
+            return Ok(());
+        }
+        self.set_readonly(path, false)?;
+        for rel in &nested {
+            self.set_readonly(&path.join(rel), false)?;
+        }
+        for rel in nested.iter().rev() {
+            let target = path.join(rel);
+            self.delete_subvolume(snapshot, &target)
+                .with_context(|| format!("Deleting snapshot {} failed", target.display()))?;
+        }
+        Ok(())
+    }
+
+    /// List the existing snapshots of a snapshot config.
+    pub fn list_snapshots(&mut self, snapshot: &'a SnapshotConfig) -> Result<SnapshotList> {
+        debug!("List snapshots for {}", snapshot.name);
+        self.mount_snapshot_fs(snapshot)?;
+        let spacings = snapshot.sorted_spacings();
+        let entries = find_snapshots(snapshot, &spacings)?;
+        Ok(SnapshotList {
+            name: snapshot.name.clone(),
+            snapshots: entries
+                .into_iter()
+                .map(|entry| ListedSnapshot {
+                    date: entry.date,
+                    held: self.holds.is_held(&entry.path),
+                    sent_bytes: self
+                        .catalog
+                        .get(&entry.path)
+                        .and_then(|entry| entry.sent.as_ref())
+                        .map(|sent| sent.bytes),
+                    tag: entry.tag,
+                    path: entry.path,
+                    age: entry.age,
+                    rule: entry.rule.map(|rule| SpacingRule {
+                        age: spacings[rule].0,
+                        spacing: spacings[rule].1,
+                    }),
+                })
+                .collect(),
+        })
+    }
+
+    /// Decide which of the existing snapshots of a snapshot config the next
+    /// rotation keeps and which it deletes, without deleting anything.
+    pub fn plan_snapshots(&mut self, snapshot: &'a SnapshotConfig) -> Result<SnapshotPlan> {
+        debug!("Plan rotation for {}", snapshot.name);
+        self.mount_snapshot_fs(snapshot)?;
+        let set = SnapshotSet::read(snapshot)?;
+        let plan = self.rotation_plan(snapshot, &set, false)?;
+        Ok(SnapshotPlan::new(snapshot, &set, &plan))
+    }
+
+    /// Summarize the existing snapshots of a snapshot config.
+    pub fn snapshot_status(
+        &mut self,
+        snapshot: &'a SnapshotConfig,
+        last_run: Option<&RunStatus>,
+        last_scrub: Option<&ScrubStatus>,
+    ) -> Result<SnapshotStatus> {
+        debug!("Status of {}", snapshot.name);
+        self.mount_snapshot_fs(snapshot)?;
+        let entries = find_snapshots(snapshot, &snapshot.sorted_spacings())?;
+        Ok(SnapshotStatus {
+            name: snapshot.name.clone(),
+            count: entries.len(),
+            newest: entries.first().map(|e| e.date),
+            oldest: entries.last().map(|e| e.date),
+            last_run: last_run.cloned(),
+            last_scrub: last_scrub.cloned(),
+        })
+    }
+
+    /// Run a hook command configured for a snapshot. Hooks are only printed
+    /// in a dry run, and killed if they exceed the hook timeout.
+    fn run_hook(
+        &self,
+        snapshot: &SnapshotConfig,
+        which: &str,
+        hook: &str,
+        action: ActionKind,
+        path: &Path,
+    ) -> Result<()> {
+        debug!("Running {} of {}", which, snapshot.name);
+        let mut cmd = self.hook_command(snapshot, hook, action, path)?;
+        let output = if self.dry_run {
+            self.print_commands(&[&mut cmd]);
+            Ok(String::new())
+        } else {
+            self.executor
+                .run_timed(&mut [&mut cmd], timeout::timeouts().hook)
+        }
+        .with_context(|| format!("Running `{}` of {} failed", which, snapshot.name))?;
+        trace!("{} output: {}", which, output);
+        Ok(())
+    }
+
+    /// Assemble the command running a hook as the configured user, exposing
+    /// what it is run for as environment variables.
+    fn hook_command(
+        &self,
+        snapshot: &SnapshotConfig,
+        hook: &str,
+        action: ActionKind,
+        path: &Path,
+    ) -> Result<Command> {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(hook)
+            .env("BTRFS_SNAPSHOT_CONFIG", &snapshot.name)
+            .env("BTRFS_SNAPSHOT_NAME", path.file_name().unwrap_or_default())
+            .env("BTRFS_SNAPSHOT_SUBVOLUME", snapshot.subvolume())
+            .env("BTRFS_SNAPSHOT_PATH", path)
+            .env("BTRFS_SNAPSHOT_ACTION", action.name());
+        if let Some(run_as) =
+            user::RunAs::resolve(snapshot.user.as_deref(), snapshot.user_group.as_deref())
+                .with_context(|| format!("Invalid `user` or `group` of {}", snapshot.name))?
+        {
+            run_as.apply(&mut cmd);
+        }
+        Ok(cmd)
+    }
+
+    /// Mount a disk if it is not yet mounted.
+    fn mount_if_needed(
+        &mut self,
+        mount_point: &'a Path,
+        mut settings: MountSettings<'a>,
+    ) -> Result<()> {
+        // No need to mount twice.
+        if self.manual_mounts.contains_key(mount_point) {
+            return Ok(());
+        }
+
+        // Check if the disk is not already mounted.
+        if let Some(fs_type) = self.executor.mounted_fs_type(mount_point)? {
+            trace!("Already mounted {}", mount_point.display());
+            return check_fs_type(mount_point, &fs_type, settings.require_btrfs);
+        }
+
+        // Open the encrypted container holding the disk, which then provides
+        // the device to mount unless another one is configured.
+        let opened = match settings.luks {
+            Some(luks) => self.open_luks(luks)?,
+            None => false,
+        };
+        let mapped = settings.luks.map(|luks| luks.mapped_device());
+        let device = settings
+            .device
+            .map(String::from)
+            .or_else(|| mapped.map(|path| path.to_string_lossy().into_owned()));
+
+        // Actually mount the disk.
+        debug!("Mounting {}", mount_point.display());
+        let result = self
+            .executor
+            .mount(mount_point, device.as_deref(), settings.options);
+        if !opened {
+            settings.luks = None;
+        }
+        if let (Err(_), Some(luks)) = (&result, settings.luks) {
+            self.close_luks(luks).ok();
+        }
+        result?;
+        if settings.keep_mounted || self.keep_mounted {
+            debug!("Leaving {} mounted after the run", mount_point.display());
+        } else {
+            self.manual_mounts.insert(mount_point, settings);
+        }
+
+        // Make sure the right filesystem was mounted, e.g. in case the fstab
+        // entry points at another disk.
+        match self.executor.mounted_fs_type(mount_point)? {
+            Some(fs_type) => check_fs_type(mount_point, &fs_type, settings.require_btrfs),
+            None => bail!(
+                "Nothing is mounted at {} after mounting it",
+                mount_point.display()
+            ),
+        }
+    }
+
+    /// Mount the filesystem of a snapshot config if it is not mounted yet.
+    fn mount_snapshot_fs(&mut self, snapshot: &'a SnapshotConfig) -> Result<()> {
+        self.mount_if_needed(
+            snapshot.mount_point.as_ref().unwrap(),
+            MountSettings {
+                device: snapshot.device.as_deref(),
+                options: snapshot.mount_options.as_deref(),
+                luks: snapshot.luks.as_ref(),
+                keep_mounted: snapshot.keep_mounted == Some(true),
+                lazy_unmount: snapshot.lazy_unmount == Some(true),
+                require_btrfs: true,
+            },
+        )
+    }
+
+    /// Unmount all the manually mounted disks. Disks that are busy, e.g.
+    /// because a file indexer or udisks still looks at them, are retried with
+    /// increasing delays and lazily detached if configured. Returns the first
+    /// error after trying all disks.
+    pub fn unmount(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for (mount_point, settings) in std::mem::take(&mut self.manual_mounts) {
+            debug!("Unmounting {}", mount_point.display());
+            let mut delay = UNMOUNT_RETRY_DELAY;
+            let mut outcome = self.executor.unmount(mount_point, false);
+            for _ in 0..UNMOUNT_RETRIES {
+                match &outcome {
+                    Err(e) if is_busy(e) => (),
+                    _ => break,
+                }
+                info!(
+                    "{} is busy; retrying to unmount in {}",
+                    mount_point.display(),
+                    humantime::format_duration(delay)
+                );
+                self.executor.sleep(delay);
+                delay *= 2;
+                outcome = self.executor.unmount(mount_point, false);
+            }
+            if settings.lazy_unmount && outcome.as_ref().is_err_and(is_busy) {
+                warn!(
+                    "{} is still busy; detaching it lazily",
+                    mount_point.display()
+                );
+                outcome = self.executor.unmount(mount_point, true);
+            }
+            if let (Ok(()), Some(luks)) = (&outcome, settings.luks) {
+                outcome = self.close_luks(luks);
+            }
+            if let Err(e) = outcome {
+                if result.is_ok() {
+                    result = Err(e);
+                } else {
+                    error!("{:#}", e);
+                }
+            }
+        }
+        result
+    }
+
+    /// Snapshot a subvolume as part of a snapshot config.
+    fn take_subvolume(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        source: &Path,
+        target: &Path,
+        readonly: bool,
+    ) -> Result<()> {
+        let mut cmd = subvolume::snapshot_command(source, target, readonly);
+        self.perform_with(
+            snapshot,
+            ActionKind::Take,
+            target,
+            &mut [&mut cmd],
+            |exec, _| exec.snapshot(source, target, readonly).map(|_| 0),
+        )
+    }
+
+    /// Make sure that `path` is one of the snapshots of a config, either
+    /// taken of its subvolume or received from the source host it pulls from.
+    fn verify_own_snapshot(
+        &self,
+        snapshot: &SnapshotConfig,
+        path: &Path,
+        readonly: bool,
+    ) -> Result<()> {
+        match snapshot.pull {
+            Some(_) => self.executor.verify_received(path),
+            None => self.executor.verify_snapshot(
+                path,
+                snapshot.subvolume(),
+                self.catalog.former_uuids(snapshot.subvolume()),
+                readonly,
+            ),
+        }
+    }
+
+    /// Check whether `path` is one of the snapshots of a config before it is
+    /// deleted automatically. Paths that are not are spared with a warning,
+    /// such that they do not hold up the deletion of the others.
+    fn is_own_snapshot(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        command: &str,
+        path: &Path,
+        readonly: bool,
+    ) -> bool {
+        match self.verify_own_snapshot(snapshot, path, readonly) {
+            Ok(()) => true,
+            Err(e) => {
+                let message = format!("Not deleting {}: {:#}", path.display(), e);
+                warn!("{}", message);
+                self.record_event(EventKind::Warning, &snapshot.name, command, message);
+                false
+            }
+        }
+    }
+
+    /// Delete a subvolume as part of a snapshot config.
+    fn delete_subvolume(&mut self, snapshot: &SnapshotConfig, path: &Path) -> Result<()> {
+        let mut cmd = subvolume::delete_command(path);
+        self.perform_with(
+            snapshot,
+            ActionKind::Delete,
+            path,
+            &mut [&mut cmd],
+            |exec, _| exec.delete(path).map(|_| 0),
+        )
+    }
+
+    /// Perform an action on a snapshot, reporting it in the configured output
+    /// format.
+    fn perform(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        kind: ActionKind,
+        path: &Path,
+        cmd: &mut Command,
+    ) -> Result<()> {
+        self.perform_pipeline(snapshot, kind, path, &mut [cmd])
+    }
+
+    /// Perform an action on a snapshot that is implemented as a pipeline of
+    /// commands.
+    fn perform_pipeline(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        kind: ActionKind,
+        path: &Path,
+        cmds: &mut [&mut Command],
+    ) -> Result<()> {
+        self.perform_with(snapshot, kind, path, cmds, |exec, cmds| {
+            if matches!(kind, ActionKind::Send | ActionKind::Pull) && cmds.len() > 1 {
+                exec.send(cmds)
+            } else {
+                exec.run(cmds).map(|_| 0)
+            }
+        })
+    }
+
+    /// Perform an action on a snapshot, reporting the commands that implement
+    /// it in the configured output format. The action itself is carried out by
+    /// `f`, which returns the number of bytes sent.
+    fn perform_with(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        kind: ActionKind,
+        path: &Path,
+        cmds: &mut [&mut Command],
+        f: impl FnOnce(&dyn Executor, &mut [&mut Command]) -> Result<u64>,
+    ) -> Result<()> {
+        self.report_action(snapshot, kind, path, cmds);
+        let result = if self.dry_run {
+            self.print_commands(cmds);
+            Ok(0)
+        } else {
+            f(&*self.executor, cmds).map_err(|error| {
+                exit::ActionFailure {
+                    operation: kind,
+                    path: path.to_owned(),
+                    error,
+                }
+                .into()
+            })
+        };
+        if let (Ok(bytes), false) = (&result, self.dry_run) {
+            self.metrics.record_action(&snapshot.name, kind, *bytes);
+            match (kind, &snapshot.replicate) {
+                (ActionKind::Delete | ActionKind::Trash, _) => self.catalog.forget(path),
+                (ActionKind::Send, Some(replicate)) => {
+                    self.catalog.record_sent(path, replicate.describe(), *bytes)
+                }
+                _ => (),
+            }
+        }
+        journal::log_action(
+            snapshot,
+            kind,
+            path,
+            result.as_ref().map(|_| ()),
+            self.dry_run,
+        );
+        if let (Some(history), false) = (&self.history, self.dry_run) {
+            if let Err(e) = history.record(snapshot, kind, path, result.as_ref().map(|_| ())) {
+                warn!("{:#}", e);
+            }
+        }
+        result?;
+        if let Some(hook) = &snapshot.action_hook {
+            self.run_hook(snapshot, "action_hook", hook, kind, path)?;
+        }
+        Ok(())
+    }
+
+    /// Report an action on a snapshot in the configured output format.
+    fn report_action(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        kind: ActionKind,
+        path: &Path,
+        cmds: &[&mut Command],
+    ) {
+        if self.output == OutputFormat::Text {
+            match kind {
+                ActionKind::Take => {
+                    println!("{} {}", color::keep("Taking snapshot"), path.display())
+                }
+                ActionKind::Delete => {
+                    println!("{} {}", color::delete("Dropping snapshot"), path.display())
+                }
+                ActionKind::Trash => {
+                    println!("{} {}", color::delete("Trashing snapshot"), path.display())
+                }
+                ActionKind::Send => {
+                    println!("{} {}", color::send("Sending snapshot"), path.display())
+                }
+                ActionKind::Restore => {
+                    println!("{} {}", color::keep("Restoring subvolume"), path.display())
+                }
+                ActionKind::Pull => {
+                    println!("{} {}", color::send("Pulling snapshot"), path.display())
+                }
+            }
+        }
+        self.actions.push(Action {
+            snapshot: snapshot.name.clone(),
+            action: kind,
+            path: path.to_owned(),
+            commands: command_lines(cmds),
+            dry_run: self.dry_run,
+        });
+    }
+
+    /// Mark a subvolume as read-only or writable, or only print the
+    /// equivalent command in a dry run.
+    fn set_readonly(&self, path: &Path, readonly: bool) -> Result<()> {
+        if self.dry_run {
+            self.print_commands(&[&mut subvolume::readonly_command(path, readonly)]);
+            return Ok(());
+        }
+        self.executor.set_readonly(path, readonly)
+    }
+
+    fn maybe_run_pipeline(&self, cmds: &mut [&mut Command]) -> Result<String> {
+        if self.dry_run {
+            self.print_commands(cmds);
+            Ok(String::new())
+        } else {
+            self.executor.run(cmds)
+        }
+    }
+
+    /// Print a pipeline of commands in a dry run, if the output is
+    /// human-readable.
+    fn print_commands(&self, cmds: &[&mut Command]) {
+        if self.output == OutputFormat::Text {
+            let cmds: Vec<_> = cmds.iter().map(|cmd| format!("{:?}", cmd)).collect();
+            println!("{}", color::dim(cmds.join(" | ")));
+        }
+    }
+}
+
+/// Ask whether to go ahead with a rotation that deletes more snapshots than
+/// `confirm_delete_above` allows. Fails if the answer is no or if there is no
+/// terminal to ask on.
+fn confirm_deletion(snapshot: &SnapshotConfig, count: usize, max: usize) -> Result<()> {
+    // Configs processed concurrently must not ask at the same time.
+    static PROMPT: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let question = format!(
+        "Rotating {} would delete {} snapshots, more than `confirm_delete_above` = {}",
+        snapshot.name, count, max
+    );
+    if !atty::is(atty::Stream::Stdin) {
+        bail!(
+            "{}; see `btrfs-snapshot plan` and pass `--yes` to delete them anyway",
+            question
+        );
+    }
+    let _prompt = PROMPT.lock().unwrap();
+    if !init::confirm(&format!("{}. Continue?", question), false)? {
+        bail!(
+            "Deleting {} snapshots of {} was not confirmed",
+            count,
+            snapshot.name
+        );
+    }
+    Ok(())
+}
+
+/// Get the program and arguments of each command in a pipeline.
+fn command_lines(cmds: &[&mut Command]) -> Vec<Vec<String>> {
+    cmds.iter()
+        .map(|cmd| {
+            std::iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect()
+        })
+        .collect()
+}
+
+/// Find the existing snapshots for a snapshot config, sorted by descending
+/// date.
+fn find_snapshots(
+    snapshot: &SnapshotConfig,
+    spacings: &[(Duration, Duration)],
+) -> Result<Vec<SnapshotEntry>> {
+    let dir = snapshot.snapshot_dir.as_ref().unwrap();
+    let files: Vec<_> = list_dir(dir)?
+        .into_iter()
+        .filter(|file| file.file_name() != Some(trash::TRASH_DIR.as_ref()))
+        .collect();
+    parse_snapshots(files, &snapshot.naming()?, spacings)
+}
+
+/// List the entries of a directory, on the remote host if there is one.
+/// Returns no entries if the directory does not exist.
+fn list_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    if privilege::remote().is_some() {
+        let output = run(privilege::command("sh")
+            .arg("-c")
+            .arg("[ ! -d \"$1\" ] || ls -1A \"$1\"")
+            .arg("sh")
+            .arg(dir))
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?;
+        return Ok(output.lines().map(|name| dir.join(name)).collect());
+    }
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?
+    {
+        files.push(entry?.path());
+    }
+    Ok(files)
+}
+
+/// Create a directory and its parents, on the remote host if there is one.
+fn create_dir_all(dir: &Path) -> Result<()> {
+    if privilege::remote().is_some() {
+        run(privilege::command("mkdir").arg("-p").arg(dir))?;
+        return Ok(());
+    }
+    std::fs::create_dir_all(dir).map_err(Into::into)
+}
+
+/// How to mount a disk that is not mounted yet, and what to do with it after
+/// the run.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MountSettings<'a> {
+    /// The device to mount instead of relying on an fstab entry.
+    pub device: Option<&'a str>,
+    /// The options to mount the device with.
+    pub options: Option<&'a str>,
+    /// The LUKS container to open before mounting and close after unmounting.
+    /// Provides the device to mount if none is given.
+    pub luks: Option<&'a LuksConfig>,
+    /// Whether to leave the disk mounted after the run.
+    pub keep_mounted: bool,
+    /// Whether to lazily detach the disk if it stays busy.
+    pub lazy_unmount: bool,
+    /// Whether the disk must hold a btrfs filesystem, rather than any
+    /// filesystem that can store archives.
+    pub require_btrfs: bool,
+}
+
+/// Make sure a disk mounted at a mount point holds a btrfs filesystem, if
+/// required.
+fn check_fs_type(mount_point: &Path, fs_type: &str, require_btrfs: bool) -> Result<()> {
+    if require_btrfs && fs_type != "btrfs" {
+        bail!(
+            "{} is mounted with filesystem type {} rather than btrfs",
+            mount_point.display(),
+            fs_type
+        );
+    }
+    Ok(())
+}
+
+/// How often to retry unmounting a busy disk.
+const UNMOUNT_RETRIES: u32 = 5;
+
+/// How long to wait before retrying to unmount a busy disk the first time. The
+/// delay doubles with every retry.
+const UNMOUNT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Check whether unmounting failed because the disk is busy.
+fn is_busy(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|cause| cause.to_string().contains("busy"))
+}
+
+/// The directory within which devices are mounted if no mount point is
+/// configured for them.
+pub const MANAGED_MOUNT_DIR: &str = "/run/btrfs-snapshot";
+
+/// Place an absolute path under an alternate root, such as `/mnt/home` for
+/// `/home` and `/mnt`. Relative paths and paths in the tool's managed mount
+/// directory are left alone.
+fn reroot_path(root: &Path, path: &Path) -> PathBuf {
+    if !path.is_absolute() || path.starts_with(MANAGED_MOUNT_DIR) {
+        return path.to_path_buf();
+    }
+    let mut rerooted = root.to_path_buf();
+    rerooted.extend(
+        path.components()
+            .filter(|c| !matches!(c, std::path::Component::RootDir)),
+    );
+    rerooted
+}
+
+/// Determine where to mount a device that has no configured mount point, such
+/// as `/run/btrfs-snapshot/UUID=1234` for `UUID=1234`.
+fn managed_mount_point(device: &str) -> PathBuf {
+    let name: String = device
+        .trim_start_matches('/')
+        .chars()
+        .map(|c| if c == '/' { '-' } else { c })
+        .collect();
+    Path::new(MANAGED_MOUNT_DIR).join(name)
+}
+
+/// Find one of the snapshots of a snapshot config by name or path. Fails for
+/// anything that is not one of the config's snapshots.
+fn resolve_snapshot(snapshot: &SnapshotConfig, name: &str) -> Result<PathBuf> {
+    let path = match name.contains('/') {
+        true => PathBuf::from(name),
+        false => snapshot.snapshot_dir.as_ref().unwrap().join(name),
+    };
+    find_snapshots(snapshot, &[])?
+        .into_iter()
+        .map(|entry| entry.path)
+        .find(|p| p == &path)
+        .ok_or_else(|| anyhow!("{} is not a snapshot of {}", name, snapshot.name))
+}
+
+/// Execute a `Command` and return its stdout on exit code 0, or a flurry of
+/// appropriate error messages if anything goes wrong.
+fn run(cmd: &mut Command) -> Result<String> {
+    privilege::route(cmd);
+    let output = cmd
+        .output()
+        .with_context(|| format!("Failed to execute {:?}", cmd))?;
+    if !output.status.success() {
+        return Err(command_failure(cmd, &output));
+    }
+    String::from_utf8(output.stdout)
+        .with_context(|| format!("Command {:?} stdout is non-utf8", cmd))
+}
+
+/// Describe a command that exited with a non-zero code. The error carries the
+/// command's stderr, with an `exit::CommandFailure` as context.
+fn command_failure(cmd: &Command, output: &std::process::Output) -> anyhow::Error {
+    let stderr = std::str::from_utf8(&output.stderr)
+        .unwrap_or("<stderr not utf-8>")
+        .trim()
+        .to_owned();
+    let command = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    anyhow!(stderr.clone()).context(exit::CommandFailure {
+        command,
+        exit_code: output.status.code(),
+        stderr,
+    })
+}
+
+/// Execute a `Command` like `run`, but kill it if it does not finish within a
+/// timeout.
+fn run_with_timeout(cmd: &mut Command, timeout: Duration) -> Result<String> {
+    run_pipeline(&mut [cmd], Some(timeout))
+}
+
+/// Execute a pipeline of `Command`s, feeding the stdout of each command into
+/// the stdin of the next, and return the stdout of the last command if all of
+/// them exit with code 0. The pipeline is killed if it does not finish within
+/// `timeout`.
+fn run_pipeline(cmds: &mut [&mut Command], timeout: Option<Duration>) -> Result<String> {
+    if let ([cmd], None) = (&mut *cmds, timeout) {
+        return run(cmd);
+    }
+    let children = spawn_pipeline(cmds, timeout.is_some())?;
+    let watchdog = timeout::Watchdog::start(&children, timeout);
+    let stdout = wait_pipeline(cmds, children, watchdog.as_ref());
+    if let Some(watchdog) = watchdog {
+        watchdog.finish(|| describe_pipeline(cmds))?;
+    }
+    String::from_utf8(stdout?).context("Pipeline stdout is non-utf8")
+}
+
+/// Execute a pipeline of `Command`s like `run_pipeline`, and count the bytes
+/// that flow into the last command.
+fn run_pipeline_counted(cmds: &mut [&mut Command], timeout: Option<Duration>) -> Result<u64> {
+    let (last, init) = cmds.split_last_mut().unwrap();
+    let mut children = spawn_pipeline(init, timeout.is_some())?;
+    let mut source = children.last_mut().unwrap().stdout.take().unwrap();
+    privilege::route(last);
+    if timeout.is_some() {
+        last.process_group(0);
+    }
+    let mut child = last
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to execute {:?}", last))?;
+    let mut sink = child.stdin.take().unwrap();
+    let relay = std::thread::spawn(move || std::io::copy(&mut source, &mut sink));
+    children.push(child);
+    let watchdog = timeout::Watchdog::start(&children, timeout);
+    let output = wait_pipeline(cmds, children, watchdog.as_ref());
+    let bytes = relay.join().unwrap();
+    if let Some(watchdog) = watchdog {
+        watchdog.finish(|| describe_pipeline(cmds))?;
+    }
+    output?;
+    bytes.context("Failed to relay pipeline")
+}
+
+/// Describe a pipeline of `Command`s in error messages.
+fn describe_pipeline(cmds: &[&mut Command]) -> String {
+    match cmds {
+        [cmd] => format!("Command {:?}", cmd),
+        _ => {
+            let cmds: Vec<_> = cmds.iter().map(|cmd| format!("{:?}", cmd)).collect();
+            format!("Pipeline {}", cmds.join(" | "))
+        }
+    }
+}
+
+/// Spawn a pipeline of `Command`s, feeding the stdout of each command into
+/// the stdin of the next. The stdout of the last command is piped such that
+/// the caller can consume it. With `grouped`, each command is spawned in its
+/// own process group, such that a `Watchdog` can kill it along with the
+/// processes it starts.
+fn spawn_pipeline(cmds: &mut [&mut Command], grouped: bool) -> Result<Vec<Child>> {
+    let mut children: Vec<Child> = Vec::new();
+    for cmd in cmds.iter_mut() {
+        privilege::route(cmd);
+        if let Some(prev) = children.last_mut() {
+            cmd.stdin(Stdio::from(prev.stdout.take().unwrap()));
+        }
+        if grouped {
+            cmd.process_group(0);
+        }
+        let child = cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to execute {:?}", cmd))?;
+        children.push(child);
+    }
+    Ok(children)
+}
+
+/// Wait for a pipeline spawned with `spawn_pipeline` to finish, and return the
+/// stdout of the last command if all of them exit with code 0. Returns an
+/// empty stdout if the caller has already consumed it. The commands are
+/// reaped through the `watchdog` if there is one.
+fn wait_pipeline(
+    cmds: &[&mut Command],
+    mut children: Vec<Child>,
+    watchdog: Option<&timeout::Watchdog>,
+) -> Result<Vec<u8>> {
+    // Drain the output of every command in the background, such that a
+    // command filling its stderr pipe cannot stall the pipeline while another
+    // command is waited for.
+    let drains: Vec<_> = children
+        .iter_mut()
+        .map(|child| (drain(child.stdout.take()), drain(child.stderr.take())))
+        .collect();
+    let mut outputs = Vec::new();
+    for (mut child, (stdout, stderr)) in children.into_iter().zip(drains) {
+        let stdout = stdout.join().unwrap();
+        let stderr = stderr.join().unwrap();
+        let status = match watchdog {
+            Some(watchdog) => watchdog.reap(&mut child),
+            None => child.wait(),
+        };
+        outputs.push(std::process::Output {
+            status: status.context("Failed to wait for pipeline")?,
+            stdout: stdout.context("Failed to read pipeline output")?,
+            stderr: stderr.context("Failed to read pipeline output")?,
+        });
+    }
+    for (cmd, output) in cmds.iter().zip(&outputs) {
+        if !output.status.success() {
+            return Err(command_failure(cmd, output));
+        }
+    }
+    Ok(outputs
+        .pop()
+        .map(|output| output.stdout)
+        .unwrap_or_default())
+}
+
+/// Read a pipe of a command to the end on a separate thread.
+fn drain(
+    pipe: Option<impl Read + Send + 'static>,
+) -> std::thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    std::thread::spawn(move || {
+        let mut data = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut data)?;
+        }
+        Ok(data)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Operation;
+    use chrono::Local;
+
+    /// A scratch directory holding a config file and the snapshot directory of
+    /// a single snapshot config named `data`. Removed when dropped.
+    pub(crate) struct Fixture {
+        pub(crate) dir: PathBuf,
+        pub(crate) config: Config,
+    }
+
+    impl Fixture {
+        /// Create a fixture whose config contains the given extra lines.
+        pub(crate) fn new(name: &str, extra: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "btrfs-snapshot-test-{}-{}",
+                name,
+                std::process::id()
+            ));
+            std::fs::remove_dir_all(&dir).ok();
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("config.toml");
+            std::fs::write(
+                &path,
+                format!(
+                    "mount_point = \"/mnt\"\n\
+                     subvolume = \"/mnt/data\"\n\
+                     snapshot_dir = \"{}\"\n\
+                     format = \"%Y_%m_%d_%H%M%z\"\n\
+                     {}\n\
+                     [snapshots.data]\n",
+                    dir.join("snapshots").display(),
+                    extra
+                ),
+            )
+            .unwrap();
+            let config = Config::load(&path).unwrap();
+            Self { dir, config }
+        }
+
+        /// Get the snapshot config.
+        pub(crate) fn snapshot(&self) -> &SnapshotConfig {
+            &self.config.snapshots["data"]
+        }
+
+        /// Create empty directories in place of snapshots taken the given
+        /// number of hours ago, and return their paths.
+        pub(crate) fn add_snapshots(&self, hours_ago: &[i64]) -> Vec<PathBuf> {
+            let naming = self.snapshot().naming().unwrap();
+            let snapshot_dir = self.snapshot().snapshot_dir.as_ref().unwrap();
+            hours_ago
+                .iter()
+                .map(|&hours| {
+                    let time = Local::now() - chrono::Duration::hours(hours);
+                    let path = snapshot_dir.join(naming.render(time, None, 0).unwrap());
+                    std::fs::create_dir_all(&path).unwrap();
+                    path
+                })
+                .collect()
+        }
+
+        /// Create a state that carries out operations on a mock, with `/mnt`
+        /// already mounted.
+        pub(crate) fn state(&self) -> (State<'_>, MockExecutor) {
+            let mock = MockExecutor::with_mounted(vec![PathBuf::from("/mnt")]);
+            let state = State {
+                executor: Box::new(mock.clone()),
+                ..Default::default()
+            };
+            (state, mock)
+        }
+
+        /// Create a state like `state`, on a 100 GB filesystem whose free
+        /// space is reported as the given number of bytes by successive
+        /// `btrfs filesystem usage` calls.
+        pub(crate) fn state_with_free_space(&self, free: &[u64]) -> (State<'_>, MockExecutor) {
+            let (state, mock) = self.state();
+            for free in free {
+                mock.add_output(
+                    "filesystem usage",
+                    &format!(
+                        "Overall:\n    Device size:    100000000000\n    \
+                         Free (estimated):    {}    (min: 0)\n",
+                        free
+                    ),
+                );
+            }
+            (state, mock)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    #[test]
+    fn take_readonly_snapshot() {
+        let fixture = Fixture::new("take-readonly", "");
+        let (mut state, mock) = fixture.state();
+        state
+            .process_snapshot(fixture.snapshot(), true, false)
+            .unwrap();
+        let ops = mock.operations();
+        assert_eq!(ops.len(), 1);
+        match &ops[0] {
+            Operation::Snapshot {
+                source,
+                target,
+                readonly,
+            } => {
+                assert_eq!(source, Path::new("/mnt/data"));
+                assert_eq!(target.parent(), fixture.snapshot().snapshot_dir.as_deref());
+                assert!(readonly);
+            }
+            op => panic!("unexpected operation {:?}", op),
+        }
+        assert_eq!(state.actions.len(), 1);
+        assert_eq!(state.actions[0].action, ActionKind::Take);
+    }
+
+    #[test]
+    fn take_runs_hooks_around_snapshot() {
+        let fixture = Fixture::new(
+            "take-hooks",
+            "readonly = false\npre_hook = \"echo pre\"\npost_hook = \"echo post\"",
+        );
+        let (mut state, mock) = fixture.state();
+        state
+            .process_snapshot(fixture.snapshot(), true, false)
+            .unwrap();
+        let hook = |cmd: &str| Operation::Run(vec![vec!["sh".into(), "-c".into(), cmd.into()]]);
+        let ops = mock.operations();
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[0], hook("echo pre"));
+        assert!(matches!(
+            ops[1],
+            Operation::Snapshot {
+                readonly: false,
+                ..
+            }
+        ));
+        assert_eq!(ops[2], hook("echo post"));
+    }
+
+    #[test]
+    fn action_hook_runs_after_every_action() {
+        let fixture = Fixture::new("action-hook", "keep_max = 1\naction_hook = \"log\"");
+        let paths = fixture.add_snapshots(&[1, 2]);
+        let (mut state, mock) = fixture.state();
+        state.dry_run = true;
+        state
+            .process_snapshot(fixture.snapshot(), false, true)
+            .unwrap();
+        assert_eq!(mock.operations(), vec![]);
+        state.dry_run = false;
+        state
+            .process_snapshot(fixture.snapshot(), false, true)
+            .unwrap();
+        let hook = Operation::Run(vec![vec!["sh".into(), "-c".into(), "log".into()]]);
+        assert_eq!(
+            mock.operations(),
+            vec![Operation::Delete(paths[1].clone()), hook]
+        );
+        let env = &mock.environments()[0];
+        let file_name = paths[1].file_name().unwrap().to_string_lossy();
+        assert_eq!(env["BTRFS_SNAPSHOT_CONFIG"], "data");
+        assert_eq!(env["BTRFS_SNAPSHOT_ACTION"], "delete");
+        assert_eq!(env["BTRFS_SNAPSHOT_PATH"], paths[1].to_string_lossy());
+        assert_eq!(env["BTRFS_SNAPSHOT_NAME"], file_name);
+    }
+
+    #[test]
+    fn bootloader_refreshed_when_snapshots_change() {
+        let fixture = Fixture::new("bootloader", "bootloader = { command = \"update-boot\" }");
+        fixture.add_snapshots(&[1]);
+        let (mut state, mock) = fixture.state();
+        state
+            .process_snapshot(fixture.snapshot(), false, true)
+            .unwrap();
+        assert!(mock.operations().is_empty());
+        state
+            .process_snapshot(fixture.snapshot(), true, false)
+            .unwrap();
+        let ops = mock.operations();
+        assert_eq!(ops.len(), 2);
+        assert!(matches!(ops[0], Operation::Snapshot { .. }));
+        assert_eq!(
+            ops[1],
+            Operation::Run(vec![vec!["sh".into(), "-c".into(), "update-boot".into()]])
+        );
+    }
+
+    #[test]
+    fn take_mounts_and_unmounts() {
+        let fixture = Fixture::new("take-mount", "");
+        let mock = MockExecutor::default();
+        let mut state = State {
+            executor: Box::new(mock.clone()),
+            ..Default::default()
+        };
+        state
+            .process_snapshot(fixture.snapshot(), true, false)
+            .unwrap();
+        state.unmount().unwrap();
+        let ops = mock.operations();
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[0], Operation::Mount(PathBuf::from("/mnt")));
+        assert!(matches!(ops[1], Operation::Snapshot { .. }));
+        assert_eq!(ops[2], Operation::Unmount(PathBuf::from("/mnt")));
+    }
+
+    #[test]
+    fn busy_disks_unmounted_after_retries() {
+        for (extra, attempts, unmounted) in [
+            ("", 2, true),
+            ("", u32::MAX, false),
+            ("lazy_unmount = true", u32::MAX, true),
+        ] {
+            let fixture = Fixture::new("busy", extra);
+            let mock = MockExecutor::default();
+            mock.add_busy("/mnt", attempts);
+            let mut state = State {
+                executor: Box::new(mock.clone()),
+                ..Default::default()
+            };
+            state
+                .process_snapshot(fixture.snapshot(), true, false)
+                .unwrap();
+            assert_eq!(state.unmount().is_ok(), unmounted);
+            assert_eq!(
+                mock.operations().last() == Some(&Operation::Unmount(PathBuf::from("/mnt"))),
+                unmounted
+            );
+        }
+    }
+
+    #[test]
+    fn luks_opened_and_closed_around_mount() {
+        let fixture = Fixture::new("luks", "luks = { device = \"/dev/sdz\" }");
+        let mock = MockExecutor::default();
+        let mut state = State {
+            executor: Box::new(mock.clone()),
+            ..Default::default()
+        };
+        state
+            .process_snapshot(fixture.snapshot(), true, false)
+            .unwrap();
+        state.unmount().unwrap();
+        let cryptsetup = |args: &[&str]| {
+            Operation::Run(vec![std::iter::once("cryptsetup")
+                .chain(args.iter().copied())
+                .map(String::from)
+                .collect()])
+        };
+        let ops = mock.operations();
+        assert_eq!(ops.len(), 5);
+        assert_eq!(
+            ops[0],
+            cryptsetup(&["open", "--type", "luks", "/dev/sdz", "btrfs-snapshot-sdz"])
+        );
+        assert_eq!(ops[1], Operation::Mount(PathBuf::from("/mnt")));
+        assert!(matches!(ops[2], Operation::Snapshot { .. }));
+        assert_eq!(ops[3], Operation::Unmount(PathBuf::from("/mnt")));
+        assert_eq!(ops[4], cryptsetup(&["close", "btrfs-snapshot-sdz"]));
+    }
+
+    #[test]
+    fn keep_mounted_skips_unmount() {
+        let fixture = Fixture::new("keep-mounted", "keep_mounted = true");
+        let mock = MockExecutor::default();
+        let mut state = State {
+            executor: Box::new(mock.clone()),
+            ..Default::default()
+        };
+        state
+            .process_snapshot(fixture.snapshot(), true, false)
+            .unwrap();
+        state.unmount().unwrap();
+        let ops = mock.operations();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0], Operation::Mount(PathBuf::from("/mnt")));
+        assert!(matches!(ops[1], Operation::Snapshot { .. }));
+    }
+
+    #[test]
+    fn colliding_names_get_suffix() {
+        let fixture = Fixture::new("collision", "on_collision = \"suffix\"");
+        let (mut state, mock) = fixture.state();
+        let run_time = Local::now();
+        state.now = Some(run_time);
+        let naming = fixture.snapshot().naming().unwrap();
+        let dir = fixture.snapshot().snapshot_dir.clone().unwrap();
+        let name = naming.render(run_time, None, 0).unwrap();
+        for existing in [name.clone(), format!("{}-1", name)] {
+            std::fs::create_dir_all(dir.join(existing)).unwrap();
+        }
+        state
+            .process_snapshot(fixture.snapshot(), true, false)
+            .unwrap();
+        assert!(matches!(
+            &mock.operations()[0],
+            Operation::Snapshot { target, .. } if *target == dir.join(format!("{}-2", name))
+        ));
+
+        // Rotation orders snapshots of the same time by their suffix.
+        let entries = find_snapshots(fixture.snapshot(), &[]).unwrap();
+        let suffixes: Vec<_> = entries.iter().map(|entry| entry.suffix).collect();
+        assert_eq!(suffixes, [Some(1), None]);
+        let parsed = naming.parse(&format!("{}-3@manual", name)).unwrap();
+        assert_eq!(
+            (parsed.suffix, parsed.tag.as_deref()),
+            (Some(3), Some("manual"))
+        );
+
+        // Without a policy, a collision is an error.
+        let fixture = Fixture::new("collision-error", "");
+        let naming = fixture.snapshot().naming().unwrap();
+        let dir = fixture.snapshot().snapshot_dir.clone().unwrap();
+        std::fs::create_dir_all(dir.join(naming.render(run_time, None, 0).unwrap())).unwrap();
+        let (mut state, _) = fixture.state();
+        state.now = Some(run_time);
+        let error = state
+            .process_snapshot(fixture.snapshot(), true, false)
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("already exists"));
+    }
+
+    #[test]
+    fn rotate_by_spacing() {
+        let fixture = Fixture::new("rotate-spacing", "[spacings]\n\"30min\" = \"2h\"");
+        let paths = fixture.add_snapshots(&[1, 2, 3, 4, 5]);
+        let (mut state, mock) = fixture.state();
+        state
+            .process_snapshot(fixture.snapshot(), false, true)
+            .unwrap();
+        assert_eq!(
+            mock.operations(),
+            vec![
+                Operation::Delete(paths[1].clone()),
+                Operation::Delete(paths[3].clone()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rotate_beyond_keep_max() {
+        let fixture = Fixture::new("rotate-keep-max", "keep_max = 2");
+        let paths = fixture.add_snapshots(&[1, 2, 3, 4]);
+        let (mut state, mock) = fixture.state();
+        state
+            .process_snapshot(fixture.snapshot(), false, true)
+            .unwrap();
+        assert_eq!(
+            mock.operations(),
+            vec![
+                Operation::Delete(paths[3].clone()),
+                Operation::Delete(paths[2].clone()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rotate_spares_held_snapshots() {
+        let fixture = Fixture::new("rotate-held", "keep_max = 1");
+        let paths = fixture.add_snapshots(&[1, 2, 3]);
+        let (mut state, mock) = fixture.state();
+        state.holds.hold(&paths[2]).unwrap();
+        state
+            .process_snapshot(fixture.snapshot(), false, true)
+            .unwrap();
+        assert_eq!(mock.operations(), vec![Operation::Delete(paths[1].clone())]);
+    }
+
+    #[test]
+    fn rotate_spares_foreign_paths() {
+        let fixture = Fixture::new("rotate-foreign", "keep_max = 1");
+        let paths = fixture.add_snapshots(&[1, 2, 3]);
+        let (mut state, mock) = fixture.state();
+        mock.add_foreign(&paths[2]);
+        state
+            .process_snapshot(fixture.snapshot(), false, true)
+            .unwrap();
+        assert_eq!(mock.operations(), vec![Operation::Delete(paths[1].clone())]);
+        assert_eq!(state.events.len(), 1);
+        assert_eq!(state.events[0].kind, EventKind::Warning);
+    }
+
+    #[test]
+    fn rotate_after_restore_deletes_earlier_snapshots() {
+        let fixture = Fixture::new("restore-rotate", "keep_max = 1");
+        let paths = fixture.add_snapshots(&[1, 2, 3]);
+        let (mut state, mock) = fixture.state();
+        mock.add_subvolume("/mnt/data", "live", None);
+        for (index, path) in paths.iter().enumerate() {
+            mock.add_subvolume(path, &format!("taken-{}", index), Some("live"));
+        }
+        state.yes = true;
+        let name = paths[0].file_name().unwrap().to_str().unwrap();
+        state.restore_snapshot(fixture.snapshot(), name).unwrap();
+        assert_eq!(
+            state.catalog.former_uuids(Path::new("/mnt/data")),
+            ["live".to_string()]
+        );
+        state
+            .process_snapshot(fixture.snapshot(), false, true)
+            .unwrap();
+        let deleted: Vec<_> = mock
+            .operations()
+            .into_iter()
+            .filter_map(|op| match op {
+                Operation::Delete(path) => Some(path),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deleted, vec![paths[2].clone(), paths[1].clone()]);
+        assert!(state.events.is_empty());
+    }
+
+    #[test]
+    fn restore_moves_subvolume_aside() {
+        let fixture = Fixture::new("restore", "");
+        let paths = fixture.add_snapshots(&[1, 2]);
+        let (mut state, mock) = fixture.state();
+        state.yes = true;
+        let name = paths[1].file_name().unwrap().to_str().unwrap();
+        state.restore_snapshot(fixture.snapshot(), name).unwrap();
+        let ops = mock.operations();
+        assert_eq!(ops.len(), 2);
+        match &ops[0] {
+            Operation::Rename(from, to) => {
+                assert_eq!(from, Path::new("/mnt/data"));
+                assert!(to.to_str().unwrap().starts_with("/mnt/data.pre-restore-"));
+            }
+            op => panic!("unexpected operation {:?}", op),
+        }
+        assert_eq!(
+            ops[1],
+            Operation::Snapshot {
+                source: paths[1].clone(),
+                target: PathBuf::from("/mnt/data"),
+                readonly: false,
+            }
+        );
+    }
+
+    #[test]
+    fn catalog_records_taken_and_deleted() {
+        let fixture = Fixture::new("catalog", "keep_max = 1");
+        let paths = fixture.add_snapshots(&[3, 2]);
+        let (mut state, _) = fixture.state();
+        state.tag = Some("manual".to_string());
+        let run_time = Local::now() - chrono::Duration::minutes(30);
+        state.now = Some(run_time);
+        state
+            .process_snapshot(fixture.snapshot(), true, false)
+            .unwrap();
+        let taken: Vec<_> = state.catalog.snapshots.keys().cloned().collect();
+        assert_eq!(taken.len(), 1);
+        let naming = fixture.snapshot().naming().unwrap();
+        let name = naming.render(run_time, Some("manual"), 0).unwrap();
+        assert!(taken[0].ends_with(name));
+        let entry = state.catalog.get(&taken[0]).unwrap();
+        assert_eq!(entry.config, "data");
+        assert_eq!(entry.tag.as_deref(), Some("manual"));
+        assert_eq!(entry.created.timestamp(), run_time.timestamp());
+
+        // Rotating the older snapshot away must not disturb the new entry,
+        // and deleting the new one must drop it from the catalog.
+        state
+            .catalog
+            .record_taken(fixture.snapshot(), &paths[0], Local::now(), None, None);
+        state
+            .process_snapshot(fixture.snapshot(), false, true)
+            .unwrap();
+        assert!(state.catalog.get(&paths[0]).is_none());
+        assert!(state.catalog.get(&taken[0]).is_some());
+    }
+
+    #[test]
+    fn pkg_snapshots_paired_and_rotated_together() {
+        let fixture = Fixture::new("pkg", "keep_max = 1");
+        let naming = fixture.snapshot().naming().unwrap();
+        let dir = fixture.snapshot().snapshot_dir.clone().unwrap();
+        let add = |hours: i64, tag: &str| {
+            let time = Local::now() - chrono::Duration::hours(hours);
+            let path = dir.join(naming.render(time, Some(tag), 0).unwrap());
+            std::fs::create_dir_all(&path).unwrap();
+            (time, path)
+        };
+        let (_, old_pre) = add(2, pkg::PRE_TAG);
+        let (_, old_post) = add(2, pkg::POST_TAG);
+        let (time, _) = add(1, pkg::PRE_TAG);
+
+        // The snapshot after the transaction is named after the unpaired one
+        // before it, and the older pair is deleted as a whole.
+        let (mut state, mock) = fixture.state();
+        state.tag = Some(pkg::POST_TAG.to_string());
+        state
+            .process_snapshot(fixture.snapshot(), true, true)
+            .unwrap();
+        let ops = mock.operations();
+        assert_eq!(ops.len(), 3);
+        match &ops[0] {
+            Operation::Snapshot { target, .. } => assert_eq!(
+                target,
+                &dir.join(naming.render(time, Some(pkg::POST_TAG), 0).unwrap())
+            ),
+            op => panic!("unexpected operation {:?}", op),
+        }
+        assert_eq!(ops[1], Operation::Delete(old_pre));
+        assert_eq!(ops[2], Operation::Delete(old_post));
+    }
+
+    #[test]
+    fn history_records_actions() {
+        let fixture = Fixture::new("history", "");
+        let (mut state, _) = fixture.state();
+        let history = History::new(&fixture.dir);
+        state.history = Some(history.clone());
+        state
+            .process_snapshot(fixture.snapshot(), true, false)
+            .unwrap();
+        let entries = history.read().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].config, "data");
+        assert_eq!(entries[0].action, ActionKind::Take);
+        assert!(entries[0].success);
+    }
+
+    #[test]
+    fn aligned_spacings_keep_first_snapshot_of_day() {
+        let dates = [
+            "2021-03-03T10:00:00+00:00",
+            "2021-03-03T00:10:00+00:00",
+            "2021-03-02T23:00:00+00:00",
+            "2021-03-02T12:00:00+00:00",
+            "2021-03-02T00:30:00+00:00",
+            "2021-03-01T23:00:00+00:00",
+        ];
+        let entries: Vec<_> = dates
+            .iter()
+            .map(|date| SnapshotEntry {
+                date: chrono::DateTime::parse_from_rfc3339(date).unwrap(),
+                path: PathBuf::from(date),
+                age: Duration::from_secs(0),
+                rule: Some(0),
+                tag: None,
+                seq: None,
+                suffix: None,
+            })
+            .collect();
+        let spacings = [(Duration::from_secs(0), Duration::from_secs(86400))];
+        let delete =
+            retention::plan_rotation(&entries, &spacings, true, &mut Default::default()).unwrap();
+        let mut deleted: Vec<_> = delete.keys().map(|path| path.to_str().unwrap()).collect();
+        deleted.sort_unstable();
+        assert_eq!(deleted, [dates[3], dates[2]]);
+    }
+
+    #[test]
+    fn min_free_prunes_oldest_snapshots() {
+        let fixture = Fixture::new(
+            "min-free",
+            "min_free = \"10GB\"\nmin_free_action = \"prune\"",
+        );
+        let paths = fixture.add_snapshots(&[3, 2, 1]);
+        let (mut state, mock) =
+            fixture.state_with_free_space(&[5000000000, 8000000000, 12000000000]);
+        state
+            .process_snapshot(fixture.snapshot(), true, false)
+            .unwrap();
+        let ops: Vec<_> = mock
+            .operations()
+            .into_iter()
+            .filter(|op| !matches!(op, Operation::Run(_)))
+            .collect();
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[0], Operation::Delete(paths[0].clone()));
+        assert_eq!(ops[1], Operation::Delete(paths[1].clone()));
+        assert!(matches!(ops[2], Operation::Snapshot { .. }));
+    }
+
+    #[test]
+    fn alerts_raised_for_low_space_and_stale_snapshots() {
+        let fixture = Fixture::new(
+            "alerts",
+            "alert_if_free_below = \"10%\"\nalert_if_no_snapshot_for = \"2h\"",
+        );
+        fixture.add_snapshots(&[5]);
+        let (mut state, _) = fixture.state_with_free_space(&[5000000000]);
+        state
+            .process_snapshot(fixture.snapshot(), false, true)
+            .unwrap();
+        let alerts: Vec<_> = state
+            .events
+            .iter()
+            .filter(|event| event.kind == EventKind::Warning)
+            .map(|event| event.message.as_str())
+            .collect();
+        assert_eq!(alerts.len(), 2);
+        assert!(alerts[0].contains("below `alert_if_free_below` of 10%"));
+        assert!(alerts[1].contains("is 5h old"));
+    }
+
+    #[test]
+    fn free_target_deletes_least_valuable_snapshots() {
+        let fixture = Fixture::new(
+            "free-target",
+            "free_target = \"10GB\"\n[spacings]\n\"1h\" = \"30min\"",
+        );
+        let paths = fixture.add_snapshots(&[2, 3, 5, 9]);
+        let (mut state, mock) =
+            fixture.state_with_free_space(&[5000000000, 5000000000, 8000000000, 12000000000]);
+        state
+            .process_snapshot(fixture.snapshot(), false, true)
+            .unwrap();
+        let ops: Vec<_> = mock
+            .operations()
+            .into_iter()
+            .filter(|op| !matches!(op, Operation::Run(_)))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                Operation::Delete(paths[1].clone()),
+                Operation::Delete(paths[2].clone())
+            ]
+        );
+    }
+
+    #[test]
+    fn sync_deletions_reports_reclaimed_space() {
+        let fixture = Fixture::new("sync-deletions", "keep_max = 1\nsync_deletions = true");
+        let paths = fixture.add_snapshots(&[2, 1]);
+        let (mut state, mock) = fixture.state_with_free_space(&[5000000000, 8000000000]);
+        state
+            .process_snapshot(fixture.snapshot(), false, true)
+            .unwrap();
+        let ops = mock.operations();
+        let delete = ops
+            .iter()
+            .position(|op| *op == Operation::Delete(paths[0].clone()))
+            .unwrap();
+        assert!(matches!(&ops[delete + 1], Operation::Run(cmds) if cmds[0][1] == "subvolume"));
+        assert_eq!(state.metrics.snapshots["data"].reclaimed_bytes, 3000000000);
+    }
+
+    #[test]
+    fn prune_deletes_matching_snapshots() {
+        let fixture = Fixture::new("prune", "");
+        let paths = fixture.add_snapshots(&[100, 50, 10, 1]);
+        let (mut state, mock) = fixture.state();
+        state.yes = true;
+        let filter = crate::prune::PruneFilter {
+            older_than: Some(Duration::from_secs(24 * 3600)),
+            pattern: Some("*_*".to_string()),
+        };
+        assert_eq!(
+            state.prune_snapshots(fixture.snapshot(), &filter).unwrap(),
+            2
+        );
+        assert_eq!(
+            mock.operations(),
+            vec![
+                Operation::Delete(paths[1].clone()),
+                Operation::Delete(paths[0].clone())
+            ]
+        );
+    }
+
+    #[test]
+    fn prune_refuses_foreign_paths() {
+        let fixture = Fixture::new("prune-foreign", "");
+        let paths = fixture.add_snapshots(&[100, 50, 1]);
+        let (mut state, mock) = fixture.state();
+        state.yes = true;
+        mock.add_foreign(&paths[0]);
+        let filter = crate::prune::PruneFilter {
+            older_than: Some(Duration::from_secs(24 * 3600)),
+            pattern: None,
+        };
+        let error = state
+            .prune_snapshots(fixture.snapshot(), &filter)
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("Refusing to delete"));
+        assert_eq!(mock.operations(), vec![]);
+    }
+
+    #[test]
+    fn paths_rerooted_under_alternate_root() {
+        let mut fixture = Fixture::new("reroot", "");
+        let lock_file = fixture.config.lock_file().to_path_buf();
+        let root = Path::new("/rescue");
+        fixture.config.reroot(root);
+        let config = &fixture.config;
+        let data = &config.snapshots["data"];
+        assert_eq!(data.mount_point.as_deref(), Some(Path::new("/rescue/mnt")));
+        assert_eq!(data.subvolume(), Path::new("/rescue/mnt/data"));
+        assert!(data.snapshot_dir.as_ref().unwrap().starts_with(root));
+        assert_eq!(
+            config.state_dir(),
+            Path::new("/rescue/var/lib/btrfs-snapshot")
+        );
+        assert_eq!(config.lock_file(), lock_file);
+        assert_eq!(reroot_path(root, Path::new("/")), root);
+        let managed = managed_mount_point("UUID=1234");
+        assert_eq!(reroot_path(root, &managed), managed);
+    }
+
+    #[test]
+    fn includes_resolved_under_alternate_root() {
+        let fixture = Fixture::new("reroot-include", "");
+        let root = &fixture.dir;
+        std::fs::create_dir_all(root.join("etc/conf.d")).unwrap();
+        std::fs::write(
+            root.join("etc/conf.d/home.toml"),
+            "[snapshots.home]\nsubvolume = \"/mnt/home\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("etc/main.toml"),
+            "mount_point = \"/mnt\"\n\
+             snapshot_dir = \"/snapshots\"\n\
+             format = \"%Y_%m_%d_%H%M%z\"\n\
+             include = [\"/etc/conf.d/*.toml\"]\n",
+        )
+        .unwrap();
+        let config =
+            Config::load_with_overrides(&root.join("etc/main.toml"), &[], Some(root)).unwrap();
+        let home = &config.snapshots["home"];
+        assert_eq!(home.subvolume(), root.join("mnt/home"));
+        assert_eq!(home.snapshot_dir.as_deref(), Some(&*root.join("snapshots")));
+    }
+
+    #[test]
+    fn adhoc_config_named_after_subvolume() {
+        let fixture = Fixture::new("adhoc", "");
+        let subvolume = fixture.dir.join("home");
+        std::fs::create_dir_all(&subvolume).unwrap();
+        let config =
+            Config::adhoc(&subvolume, Path::new("/snapshots"), "%Y-%m-%d_%H%M", &[]).unwrap();
+        let snapshot = &config.snapshots["home"];
+        assert_eq!(snapshot.subvolume(), subvolume.canonicalize().unwrap());
+        assert!(snapshot.mount_point.is_some());
+        assert_eq!(snapshot.format.as_deref(), Some("%Y-%m-%d_%H%M"));
+    }
+
+    #[test]
+    fn dry_run_performs_nothing() {
+        let fixture = Fixture::new("dry-run", "keep_max = 1");
+        fixture.add_snapshots(&[1, 2]);
+        let (mut state, mock) = fixture.state();
+        state.dry_run = true;
+        state.output = OutputFormat::Json;
+        state
+            .process_snapshot(fixture.snapshot(), true, true)
+            .unwrap();
+        assert_eq!(mock.operations(), vec![]);
+        assert_eq!(state.actions.len(), 2);
+        assert!(state.actions.iter().all(|action| action.dry_run));
+    }
+
+    #[test]
+    fn failures_reported_as_structured_errors() {
+        let fixture = Fixture::new("errors", "");
+        let (mut state, _mock) = fixture.state();
+        let path = fixture.dir.join("snapshots/new");
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo oops >&2; exit 3"]);
+        let error = state
+            .perform_with(
+                fixture.snapshot(),
+                ActionKind::Take,
+                &path,
+                &mut [&mut cmd],
+                |_, cmds| run(cmds[0]).map(|_| 0),
+            )
+            .context("Taking snapshot failed")
+            .unwrap_err();
+        let error = exit::ConfigFailure {
+            config: "data".into(),
+            error,
+        }
+        .into();
+        let report = exit::ErrorReport::new(&error, exit::ExitCode::Failure);
+        assert_eq!(report.kind, exit::ErrorKind::Command);
+        assert_eq!(report.operation, Some(ActionKind::Take));
+        assert_eq!(report.config.as_deref(), Some("data"));
+        assert_eq!(report.path, Some(path));
+        assert_eq!(report.exit_code, Some(3));
+        assert_eq!(report.stderr.as_deref(), Some("oops"));
+        assert_eq!(
+            report.messages,
+            vec![
+                "Taking snapshot failed",
+                r#"Command "sh" "-c" "echo oops >&2; exit 3" failed with exit code 3"#,
+                "oops",
+            ]
+        );
+    }
+
+    #[test]
+    fn failing_config_does_not_stop_others() {
+        let fixture = Fixture::new("keep-going", "[snapshots.home]\nsubvolume = \"/mnt/home\"");
+        let (mut state, _mock) = fixture.state();
+        let snapshots = [fixture.snapshot(), &fixture.config.snapshots["home"]];
+        let outcomes =
+            state.process_parallel(&snapshots, "take", None, |_, snapshot| {
+                match snapshot.name.as_str() {
+                    "data" => bail!("Disk on fire"),
+                    _ => Ok(()),
+                }
+            });
+        let results: Vec<_> = outcomes
+            .iter()
+            .map(|(snapshot, result)| (snapshot.name.as_str(), result.is_ok()))
+            .collect();
+        assert_eq!(results, vec![("data", false), ("home", true)]);
+    }
+
+    #[test]
+    fn stuck_hooks_killed_with_their_children() {
+        let start = std::time::Instant::now();
+        let mut hook = Command::new("sh");
+        hook.arg("-c").arg("sleep 100; true");
+        let error = run_with_timeout(&mut hook, Duration::from_millis(200)).unwrap_err();
+        assert!(error.to_string().contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn noisy_pipelines_do_not_stall() {
+        let mut noisy = Command::new("sh");
+        let mut cat = Command::new("cat");
+        noisy
+            .arg("-c")
+            .arg("head -c 1000000 /dev/zero >&2; echo done");
+        let output = run_pipeline(&mut [&mut noisy, &mut cat], Some(Duration::from_secs(10)));
+        assert_eq!(output.unwrap(), "done\n");
+    }
+
+    #[test]
+    fn hung_commands_killed_after_timeout() {
+        let start = std::time::Instant::now();
+        let mut sleep = Command::new("sleep");
+        let mut cat = Command::new("cat");
+        sleep.arg("10");
+        let error = run_pipeline(
+            &mut [&mut sleep, &mut cat],
+            Some(Duration::from_millis(200)),
+        )
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("timed out after 200ms and was killed"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let mut echo = Command::new("echo");
+        echo.arg("done");
+        let output = run_with_timeout(&mut echo, Duration::from_secs(10)).unwrap();
+        assert_eq!(output, "done\n");
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Preventing concurrent runs from operating on the same snapshots.
+
+use crate::exit::{ExitCode, WithExitCode};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fs::{File, OpenOptions},
+    io::{Seek, Write},
+    os::unix::io::AsRawFd,
+    path::Path,
+};
+
+/// An exclusive lock on a lock file, released when dropped.
+pub struct Lock {
+    /// The locked file. Closing it releases the lock.
+    _file: File,
+}
+
+impl Lock {
+    /// Lock a file. If another process holds the lock, either wait for it to
+    /// be released or fail, depending on `wait`.
+    pub fn acquire(path: &Path, wait: bool) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+        if !try_flock(&file, libc::LOCK_EX | libc::LOCK_NB)
+            .with_context(|| format!("Failed to lock {}", path.display()))?
+        {
+            let holder = std::fs::read_to_string(path).unwrap_or_default();
+            let holder = match holder.trim() {
+                "" => String::new(),
+                pid => format!(" (pid {})", pid),
+            };
+            if !wait {
+                return Err(anyhow!(
+                    "Another btrfs-snapshot process holds the lock {}{}; use --wait to wait for it",
+                    path.display(),
+                    holder
+                ))
+                .exit_code(ExitCode::LockBusy);
+            }
+            info!("Waiting for lock {}{}", path.display(), holder);
+            try_flock(&file, libc::LOCK_EX)
+                .with_context(|| format!("Failed to lock {}", path.display()))?;
+        }
+        debug!("Locked {}", path.display());
+
+        // Record who holds the lock, for the benefit of waiting processes.
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file })
+    }
+}
+
+/// Apply `flock` to a file. Returns `false` if the lock is held elsewhere and
+/// `LOCK_NB` was given.
+fn try_flock(file: &File, operation: libc::c_int) -> Result<bool> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(true);
+        }
+        let error = std::io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => return Ok(false),
+            Some(libc::EINTR) => continue,
+            _ => return Err(error.into()),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Opening LUKS-encrypted disks before mounting them, and closing them again
+//! after unmounting.
+
+use crate::{privilege, State};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// An encrypted LUKS container holding the btrfs volume of a mount point.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LuksConfig {
+    /// The encrypted block device, e.g. `/dev/disk/by-uuid/...`.
+    pub device: PathBuf,
+    /// The file holding the key. `cryptsetup` asks for a passphrase if this is
+    /// omitted.
+    pub keyfile: Option<PathBuf>,
+    /// The name of the opened container in `/dev/mapper`. Defaults to the file
+    /// name of the device, prefixed with `btrfs-snapshot-`.
+    pub name: Option<String>,
+}
+
+impl LuksConfig {
+    /// Get the name of the opened container.
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!(
+                "btrfs-snapshot-{}",
+                self.device
+                    .file_name()
+                    .map(|n| n.to_string_lossy())
+                    .unwrap_or_default()
+            ),
+        }
+    }
+
+    /// Get the decrypted block device of the opened container, which holds
+    /// the btrfs volume.
+    pub fn mapped_device(&self) -> PathBuf {
+        Path::new("/dev/mapper").join(self.name())
+    }
+}
+
+impl<'a> State<'a> {
+    /// Open a LUKS container unless it is open already. Returns whether it was
+    /// opened, and should therefore be closed again after unmounting.
+    pub(crate) fn open_luks(&mut self, luks: &LuksConfig) -> Result<bool> {
+        if luks.mapped_device().exists() {
+            trace!("Already opened {}", luks.device.display());
+            return Ok(false);
+        }
+        debug!("Opening {} as {}", luks.device.display(), luks.name());
+        let mut cmd = privilege::command("cryptsetup");
+        cmd.arg("open").arg("--type").arg("luks");
+        if let Some(keyfile) = &luks.keyfile {
+            cmd.arg("--key-file").arg(keyfile);
+        }
+        cmd.arg(&luks.device).arg(luks.name());
+        self.executor
+            .run(&mut [&mut cmd])
+            .with_context(|| format!("Opening LUKS container {} failed", luks.device.display()))?;
+        Ok(true)
+    }
+
+    /// Close a LUKS container.
+    pub(crate) fn close_luks(&mut self, luks: &LuksConfig) -> Result<()> {
+        debug!("Closing {}", luks.name());
+        let mut cmd = privilege::command("cryptsetup");
+        cmd.arg("close").arg(luks.name());
+        self.executor
+            .run(&mut [&mut cmd])
+            .with_context(|| format!("Closing LUKS container {} failed", luks.name()))?;
+        Ok(())
+    }
+}
@@ -1,383 +1,1095 @@
 // Copyright (c) 2021 Fabian Schuiki
-/// A simple tool to create rotating btrfs subvolume snapshots.
+
+//! A simple tool to create rotating btrfs subvolume snapshots.
 
 #[macro_use]
 extern crate clap;
 #[macro_use]
 extern crate log;
 
-use anyhow::{anyhow, bail, Context, Result};
-use chrono::{DateTime, Timelike as _};
-use clap::Arg;
-use humantime::format_duration;
-use indexmap::{IndexMap, IndexSet};
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-use std::{
-    fs::File,
-    io::Read,
-    path::{Path, PathBuf},
-    process::Command,
-    time::Duration,
+use anyhow::{anyhow, Context, Result};
+use btrfs_snapshot::{
+    binaries, boot,
+    catalog::Catalog,
+    check,
+    color::{self, ColorChoice},
+    dbus,
+    exit::{self, ExitCode, WithExitCode},
+    history::History,
+    hold::HoldFile,
+    import, inhibit, init, journal, lock,
+    metrics::MetricsFile,
+    output::{self, OutputFormat},
+    overrides::Override,
+    pkg, privilege,
+    prune::PruneFilter,
+    scrub::ScrubFile,
+    select::Selector,
+    size::ByteSize,
+    status::StatusFile,
+    timeout, Config, SnapshotConfig, State,
 };
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use env_logger::fmt::WriteStyle;
+use log::LevelFilter;
+use std::path::Path;
 
-fn main() -> Result<()> {
-    pretty_env_logger::init();
+fn main() {
+    let code = match try_main() {
+        Ok(()) => ExitCode::Success,
+        Err(e) => exit::report(e),
+    };
+    std::process::exit(code as i32);
+}
 
+fn try_main() -> Result<()> {
     // Parse the command line arguments.
-    let matches = clap::app_from_crate!("\n")
+    let matches = App::new(crate_name!())
+        .version(crate_version!())
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(crate_description!())
+        .setting(AppSettings::VersionlessSubcommands)
         .arg(
             Arg::with_name("config")
                 .short("c")
                 .long("config")
                 .value_name("FILE")
                 .help("Path to the configuration file")
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .value_name("KEY=VALUE")
+                .help("Override a config value, e.g. `home.snapshot_dir=/mnt/alt` or `format=...`")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .value_name("HOST")
+                .help("Run btrfs and mount commands on a remote host over ssh, e.g. `root@nas`")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("root")
+                .long("root")
+                .value_name("DIR")
+                .help("Treat configured paths as relative to DIR, e.g. an installation mounted by a rescue system")
+                .takes_value(true)
+                .global(true),
         )
         .arg(
             Arg::with_name("dry-run")
                 .short("n")
                 .long("dry-run")
-                .help("Show btrfs operations without executing"),
+                .help("Show btrfs operations without executing")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FORMAT")
+                .help("Format in which results are printed")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .global(true),
         )
         .arg(
-            Arg::with_name("only-rotate")
-                .short("r")
-                .long("rotate")
-                .help("Only rotate snapshots"),
+            Arg::with_name("color")
+                .long("color")
+                .value_name("WHEN")
+                .help("When to color output")
+                .possible_values(&["auto", "always", "never"])
+                .default_value("auto")
+                .global(true),
         )
         .arg(
-            Arg::with_name("only-take")
-                .short("t")
-                .long("take")
-                .help("Only take snapshots"),
+            Arg::with_name("log")
+                .long("log")
+                .value_name("TARGET")
+                .help("Where log messages go; `auto` uses the journal when running as a systemd service")
+                .possible_values(&["auto", "stderr", "journald"])
+                .default_value("auto")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .value_name("N")
+                .help("Process at most N filesystems concurrently")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("no-unmount")
+                .long("no-unmount")
+                .help("Leave disks mounted after the run")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("sudo")
+                .long("sudo")
+                .help("Run privileged btrfs commands through sudo instead of requiring root")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Log more details; repeat for debug (-vv) and trace (-vvv) messages")
+                .multiple(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Log nothing besides the error a run fails with")
+                .conflicts_with("verbose")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("yes")
+                .short("y")
+                .long("yes")
+                .help("Delete snapshots without asking for confirmation")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("wait")
+                .long("wait")
+                .help("Wait for other running instances to finish instead of failing")
+                .overrides_with("no-wait")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("no-wait")
+                .long("no-wait")
+                .help("Fail if another instance is running (default)")
+                .overrides_with("wait")
+                .global(true),
         )
         .arg(
             Arg::with_name("only-snapshot")
                 .short("s")
                 .long("snapshot")
                 .value_name("NAME")
-                .help("Only operate on specific snapshots")
+                .help("Only operate on specific snapshots; accepts globs such as `vm-*` and /regexes/")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("exclude-snapshot")
+                .short("x")
+                .long("exclude-snapshot")
+                .value_name("NAME")
+                .help("Do not operate on specific snapshots; accepts the same patterns as `--snapshot`")
                 .multiple(true)
-                .takes_value(true),
+                .number_of_values(1)
+                .takes_value(true)
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Take new snapshots and rotate old ones (default if no command is given)")
+                .arg(tag_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("take")
+                .about("Take new snapshots")
+                .arg(tag_arg())
+                .arg(
+                    Arg::with_name("reason")
+                        .long("reason")
+                        .value_name("REASON")
+                        .help("Take the snapshots before or after a package manager transaction, as a pair")
+                        .possible_values(&[pkg::PRE_TAG, pkg::POST_TAG])
+                        .conflicts_with("tag"),
+                )
+                .arg(
+                    Arg::with_name("boot")
+                        .long("boot")
+                        .help("Take the snapshots at boot, tagged `boot`; see `boot-unit`")
+                        .conflicts_with_all(&["tag", "reason"]),
+                )
+                .arg(
+                    Arg::with_name("subvolume")
+                        .long("subvolume")
+                        .value_name("PATH")
+                        .help("Snapshot this subvolume without a config file")
+                        .requires("snapshot-dir"),
+                )
+                .arg(
+                    Arg::with_name("snapshot-dir")
+                        .long("snapshot-dir")
+                        .value_name("DIR")
+                        .help("Where to put the snapshot of `--subvolume`")
+                        .requires("subvolume"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("How to name the snapshot of `--subvolume` [default: %Y_%m_%d_%H%M%z]")
+                        .requires("subvolume"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rotate")
+                .about("Delete old snapshots according to the configured spacings")
+                .arg(
+                    Arg::with_name("explain")
+                        .long("explain")
+                        .help("Print why each snapshot is kept or deleted"),
+                )
+                .arg(
+                    Arg::with_name("free-up")
+                        .long("free-up")
+                        .value_name("SIZE")
+                        .help("Also delete the least valuable snapshots until SIZE more is free"),
+                )
+                .arg(
+                    Arg::with_name("sync")
+                        .long("sync")
+                        .help("Wait for btrfs to free the space of deleted snapshots and report it"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("Delete the snapshots matching a filter, e.g. with `--snapshot home`")
+                .arg(
+                    Arg::with_name("older-than")
+                        .long("older-than")
+                        .takes_value(true)
+                        .value_name("AGE")
+                        .help("Only delete snapshots older than this duration, e.g. `90d`"),
+                )
+                .arg(
+                    Arg::with_name("match")
+                        .long("match")
+                        .takes_value(true)
+                        .value_name("GLOB")
+                        .help("Only delete snapshots whose name matches, with `*` and `?` wildcards"),
+                )
+                .group(
+                    ArgGroup::with_name("filter")
+                        .args(&["older-than", "match"])
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("send")
+                .about("Replicate snapshots that do not yet exist on the replication target"),
+        )
+        .subcommand(
+            SubCommand::with_name("pull")
+                .about("Pull snapshots from the source hosts of configs with a `pull` section"),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List existing snapshots with their age and applicable spacing")
+                .arg(
+                    Arg::with_name("paths-only")
+                        .long("paths-only")
+                        .help("Only print the path of each snapshot, one per line"),
+                )
+                .arg(
+                    Arg::with_name("null")
+                        .short("0")
+                        .long("null")
+                        .help("Only print the paths, separated by NUL characters for `xargs -0`"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("du")
+                .about("Show the referenced and exclusive size of each snapshot, using quotas")
+                .arg(
+                    Arg::with_name("enable-quotas")
+                        .long("enable-quotas")
+                        .help("Enable quotas on filesystems that do not have them enabled yet"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("plan")
+                .about("Show which snapshots the next rotation keeps and deletes, and why"),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Summarize existing snapshots and the outcome of the last run"),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate-format")
+                .about("Rename existing snapshots after changing the naming format")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .value_name("FORMAT")
+                        .required(true)
+                        .help("The format the existing snapshots are named in"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .value_name("FORMAT")
+                        .help("The format to rename the snapshots to [default: the configured format]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("List subvolumes in snapshot dirs that match no config, and delete them")
+                .arg(
+                    Arg::with_name("delete")
+                        .long("delete")
+                        .help("Delete the orphaned snapshots after asking for confirmation"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("boot-unit")
+                .about("Print a systemd unit that takes snapshots early at every boot"),
+        )
+        .subcommand(
+            SubCommand::with_name("dbus-policy")
+                .about("Print the system bus policy that lets the daemon offer its D-Bus service"),
+        )
+        .subcommand(
+            SubCommand::with_name("pkg-hooks")
+                .about("Print package manager hooks that take snapshots around every transaction")
+                .arg(
+                    Arg::with_name("MANAGER")
+                        .help("The package manager to generate hooks for")
+                        .possible_values(&["pacman", "apt"])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Show the recorded history of actions performed on snapshots")
+                .arg(
+                    Arg::with_name("action")
+                        .long("action")
+                        .takes_value(true)
+                        .possible_values(&["take", "delete", "trash", "send", "restore", "pull"])
+                        .help("Only show actions of this kind"),
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .takes_value(true)
+                        .value_name("AGE")
+                        .help("Only show actions performed within this duration, e.g. `30d`"),
+                )
+                .arg(
+                    Arg::with_name("failed")
+                        .long("failed")
+                        .help("Only show actions that failed"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("scrub")
+                .about("Scrub the filesystems whose `scrub` interval has passed")
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Scrub every filesystem, regardless of its `scrub` config"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about("Check the configuration for problems and exit non-zero if there are any"),
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("Keep running and take and rotate snapshots according to their schedule"),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Interactively create a starter configuration file"),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Generate a config from the configs of another snapshot tool")
+                .arg(
+                    Arg::with_name("TOOL")
+                        .help("The tool to import from")
+                        .possible_values(&["snapper", "btrbk"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("snapper-dir")
+                        .long("snapper-dir")
+                        .value_name("DIR")
+                        .help("Where snapper keeps its configs")
+                        .default_value(import::SNAPPER_CONFIG_DIR),
+                )
+                .arg(
+                    Arg::with_name("btrbk-config")
+                        .long("btrbk-config")
+                        .value_name("FILE")
+                        .help("Where btrbk keeps its config")
+                        .default_value(import::BTRBK_CONFIG),
+                )
+                .arg(
+                    Arg::with_name("adopt")
+                        .long("adopt")
+                        .help("Rename the existing snapper snapshots into the configured format"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("hold")
+                .about("Protect a snapshot from being deleted by rotation")
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("Path of the snapshot to hold")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("List the paths created, modified, and deleted between two snapshots")
+                .arg(
+                    Arg::with_name("CONFIG")
+                        .help("Name of the snapshot config the snapshots belong to")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("FROM")
+                        .help("Name or path of the older snapshot")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("TO")
+                        .help("Name or path of the newer snapshot")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Compare the files of a snapshot against a newer one to detect corruption")
+                .arg(
+                    Arg::with_name("CONFIG")
+                        .help("Name of the snapshot config the snapshots belong to")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("FROM")
+                        .help("Name or path of the older snapshot [default: the newest snapshot]"),
+                )
+                .arg(
+                    Arg::with_name("TO")
+                        .help("Name or path of the newer snapshot [default: the live subvolume]"),
+                )
+                .arg(
+                    Arg::with_name("sample")
+                        .long("sample")
+                        .takes_value(true)
+                        .value_name("PERCENT")
+                        .help("Compare only a random sample of the files"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("browse")
+                .about("Print the path of a snapshot, or open a shell in it with --shell")
+                .arg(
+                    Arg::with_name("CONFIG")
+                        .help("Name of the snapshot config the snapshot belongs to")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("SNAPSHOT")
+                        .help("Name or path of the snapshot to browse")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("shell")
+                        .long("shell")
+                        .help("Mount the snapshot read-only at a temporary location and start $SHELL there"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Roll a subvolume back to one of its snapshots, keeping its current state aside")
+                .arg(
+                    Arg::with_name("CONFIG")
+                        .help("Name of the snapshot config whose subvolume to restore")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("SNAPSHOT")
+                        .help("Name or path of the snapshot to restore")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("release")
+                .about("Allow a held snapshot to be deleted by rotation again")
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("Path of the snapshot to release")
+                        .required(true),
+                ),
         )
         .get_matches();
 
-    // Determine what to do.
-    let default = !matches.is_present("only-rotate") && !matches.is_present("only-take");
-    let do_rotate = default || matches.is_present("only-rotate");
-    let do_take = default || matches.is_present("only-take");
+    // Determine what to do. Running without a subcommand is equivalent to
+    // `run`, which takes and rotates snapshots.
+    let (command, matches) = match matches.subcommand() {
+        (name, Some(sub)) => (name, sub),
+        _ => ("run", &matches),
+    };
 
-    // Locate and read the configuration file.
-    let config_path = matches
-        .value_of("config")
-        .unwrap_or("/etc/btrfs-snapshot.toml");
-    let config = read_config(config_path)
-        .with_context(|| format!("Failed to read config from {}", config_path))?;
-    trace!("{:#?}", config);
+    // Set up logging. The verbosity flags override `RUST_LOG` for our own
+    // messages.
+    let level = match (
+        matches.is_present("quiet"),
+        matches.occurrences_of("verbose"),
+    ) {
+        (true, _) => Some(LevelFilter::Off),
+        (false, 0) => None,
+        (false, 1) => Some(LevelFilter::Info),
+        (false, 2) => Some(LevelFilter::Debug),
+        (false, _) => Some(LevelFilter::Trace),
+    };
+    let color = value_t!(matches, "color", ColorChoice)?;
+    color::init(color);
+    match matches.value_of("log") {
+        Some("journald") => journal::init(level)?,
+        Some("auto") if journal::is_connected() => journal::init(level)?,
+        _ => {
+            let mut builder = pretty_env_logger::formatted_builder();
+            if let Ok(filters) = std::env::var("RUST_LOG") {
+                builder.parse_filters(&filters);
+            }
+            if let Some(level) = level {
+                builder.filter_module(env!("CARGO_CRATE_NAME"), level);
+            }
+            builder.write_style(match color {
+                ColorChoice::Auto => WriteStyle::Auto,
+                ColorChoice::Always => WriteStyle::Always,
+                ColorChoice::Never => WriteStyle::Never,
+            });
+            builder.try_init()?;
+        }
+    }
+    exit::init(value_t!(matches, "output", OutputFormat)?);
+    privilege::init(matches.is_present("sudo"));
+    if let Some(host) = matches.value_of("host") {
+        privilege::init_remote(host);
+    }
+    if !privilege::is_root() && !privilege::via_commands() {
+        debug!("Running without root privileges");
+    }
 
-    // Do the work.
-    let mut state = State::default();
-    state.dry_run = matches.is_present("dry-run");
-    for snapshot in config.snapshots.values() {
-        if let Some(mut snaps) = matches.values_of("only-snapshot") {
-            if snaps.find(|&x| x == snapshot.name).is_none() {
-                continue;
+    // Locate and read the configuration file.
+    let root = matches.value_of("root").map(Path::new);
+    if let Some(root) = root {
+        if !root.is_dir() {
+            return Err(anyhow!("Root {} is not a directory", root.display()))
+                .exit_code(ExitCode::ConfigError);
+        }
+        if matches.is_present("subvolume") {
+            return Err(anyhow!("`--root` cannot be combined with `--subvolume`"))
+                .exit_code(ExitCode::ConfigError);
+        }
+    }
+    let default_config = match root {
+        Some(root) => root
+            .join("etc/btrfs-snapshot.toml")
+            .to_string_lossy()
+            .into_owned(),
+        None => String::from("/etc/btrfs-snapshot.toml"),
+    };
+    let config_path = matches.value_of("config").unwrap_or(&default_config);
+    if command == "init" {
+        return init::init(Path::new(config_path), matches.is_present("dry-run"));
+    }
+    if command == "import" {
+        let dry_run = matches.is_present("dry-run");
+        let path = Path::new(config_path);
+        match matches.value_of("TOOL").unwrap() {
+            "btrbk" => {
+                // The generated format matches the names btrbk gives its
+                // snapshots, which therefore need no adopting.
+                let btrbk_config = Path::new(matches.value_of("btrbk-config").unwrap());
+                return import::import_btrbk(path, btrbk_config, dry_run);
+            }
+            _ => {
+                let snapper_dir = Path::new(matches.value_of("snapper-dir").unwrap());
+                import::import_snapper(path, snapper_dir, dry_run)?;
             }
         }
-        if do_take {
-            state.take_snapshot(snapshot)?;
+        // Adopting the snapshots needs the written config.
+        if !matches.is_present("adopt") {
+            return Ok(());
         }
-        if do_rotate {
-            state.rotate_snapshot(snapshot)?;
+        if dry_run {
+            println!("Not adopting snapshots in a dry run, since the config was not written");
+            return Ok(());
         }
     }
-    state.unmount()?;
-
-    Ok(())
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
-    /// The common configuration bits for snapshots.
-    #[serde(flatten)]
-    generic: SnapshotConfig,
-    /// The per-snapshot configuration.
-    #[serde(default)]
-    snapshots: IndexMap<String, SnapshotConfig>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct SnapshotConfig {
-    /// The name of the snapshot config.
-    #[serde(skip)]
-    name: String,
-    /// The mount point of the btrfs volume.
-    mount_point: Option<PathBuf>,
-    /// The format to use for snapshot names.
-    format: Option<String>,
-    /// The subvolume to snapshot.
-    subvolume: Option<PathBuf>,
-    /// The directory where snapshots are stored.
-    snapshot_dir: Option<PathBuf>,
-    /// A list of spacing between snapshots for snapshots of a given age.
-    spacings: Option<IndexMap<humantime_serde::Serde<Duration>, humantime_serde::Serde<Duration>>>,
-}
-
-/// Read a configuration file.
-fn read_config(path: &str) -> Result<Config> {
-    debug!("Loading config {}", path);
-    let mut buf = String::new();
-    File::open(path)?.read_to_string(&mut buf)?;
-    let mut cfg: Config = toml::de::from_str(&buf)?;
-    if cfg.generic.spacings.is_none() {
-        cfg.generic.spacings = Some(Default::default());
+    let overrides = matches
+        .values_of("set")
+        .into_iter()
+        .flatten()
+        .map(str::parse)
+        .collect::<Result<Vec<Override>>>()
+        .exit_code(ExitCode::ConfigError)?;
+    let config = match matches.value_of("subvolume") {
+        Some(subvolume) => Config::adhoc(
+            Path::new(subvolume),
+            Path::new(matches.value_of("snapshot-dir").unwrap()),
+            matches.value_of("format").unwrap_or(init::DEFAULT_FORMAT),
+            &overrides,
+        )
+        .context("Invalid ad-hoc config"),
+        None => Config::load_with_overrides(Path::new(config_path), &overrides, root)
+            .with_context(|| format!("Failed to read config from {}", config_path)),
     }
+    .exit_code(ExitCode::ConfigError)?;
+    trace!("{:#?}", config);
+    timeout::init(config.timeouts);
+    binaries::init(config.binaries.clone());
 
-    // Copy details from the generic config into the snapshots.
-    let mut snapshots = std::mem::take(&mut cfg.snapshots);
-    for (name, s) in &mut snapshots {
-        s.name = name.clone();
-        if s.mount_point.is_none() {
-            s.mount_point = cfg.generic.mount_point.clone();
+    // Do the work.
+    let mut state = State {
+        dry_run: matches.is_present("dry-run"),
+        output: value_t!(matches, "output", OutputFormat)?,
+        holds: HoldFile::load(config.state_dir())?,
+        metrics: MetricsFile::load(config.state_dir())?,
+        catalog: Catalog::load(config.state_dir())?,
+        history: Some(History::new(config.state_dir())),
+        now: Some(chrono::Local::now()),
+        ..Default::default()
+    };
+    if let Some(reason) = matches.value_of("reason") {
+        state.tag = Some(reason.to_owned());
+    }
+    if matches.is_present("boot") {
+        state.tag = Some(boot::BOOT_TAG.to_owned());
+    }
+    if let Some(tag) = matches.value_of("tag") {
+        if tag.is_empty()
+            || !tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow!(
+                "Tag `{}` may only contain letters, digits, `-`, and `_`",
+                tag
+            ))
+            .exit_code(ExitCode::ConfigError);
         }
-        if s.format.is_none() {
-            s.format = cfg.generic.format.clone();
+        state.tag = Some(tag.to_owned());
+    }
+    state.explain = matches.is_present("explain");
+    state.sync_deletions = matches.is_present("sync");
+    state.free_up = matches
+        .value_of("free-up")
+        .map(|v| v.parse::<ByteSize>())
+        .transpose()
+        .exit_code(ExitCode::ConfigError)?;
+    state.yes = matches.is_present("yes");
+    state.keep_mounted = matches.is_present("no-unmount");
+    let wait = matches.is_present("wait");
+    let _lock = match command {
+        "run" | "take" | "rotate" | "prune" | "send" | "pull" | "hold" | "release" | "restore"
+        | "migrate-format" | "scrub" | "import"
+            if !state.dry_run =>
+        {
+            Some(lock::Lock::acquire(config.lock_file(), wait)?)
         }
-        if s.subvolume.is_none() {
-            s.subvolume = cfg.generic.subvolume.clone();
+        "gc" if !state.dry_run && matches.is_present("delete") => {
+            Some(lock::Lock::acquire(config.lock_file(), wait)?)
         }
-        if s.snapshot_dir.is_none() {
-            s.snapshot_dir = cfg.generic.snapshot_dir.clone();
+        _ => None,
+    };
+    let selector = Selector::new(
+        matches.values_of("only-snapshot").into_iter().flatten(),
+        matches.values_of("exclude-snapshot").into_iter().flatten(),
+    )
+    .exit_code(ExitCode::ConfigError)?;
+    let snapshots: Vec<_> = config
+        .snapshots
+        .values()
+        .filter(|snapshot| selector.selects(snapshot))
+        .collect();
+    match command {
+        "run" | "take" | "rotate" | "send" | "pull" => {
+            let state_dir = config.state_dir();
+            let mut status = StatusFile::load(state_dir)?;
+            let _inhibitor = match state.dry_run {
+                false => inhibit::Inhibitor::acquire("Taking, deleting, or sending snapshots"),
+                true => None,
+            };
+            let jobs = match matches.value_of("jobs") {
+                Some(jobs) => match jobs.parse() {
+                    Ok(jobs) if jobs > 0 => Some(jobs),
+                    _ => {
+                        return Err(anyhow!("`--jobs` must be a number of at least 1"))
+                            .exit_code(ExitCode::ConfigError)
+                    }
+                },
+                None => config.jobs,
+            };
+            let outcomes =
+                state.process_parallel(
+                    &snapshots,
+                    command,
+                    jobs,
+                    |state, snapshot| match command {
+                        "send" => {
+                            state.timed(snapshot, "send", |state| state.send_snapshots(snapshot))
+                        }
+                        "pull" => {
+                            state.timed(snapshot, "pull", |state| state.pull_snapshots(snapshot))
+                        }
+                        _ => {
+                            state.process_snapshot(snapshot, command != "rotate", command != "take")
+                        }
+                    },
+                );
+            if !state.dry_run {
+                for (snapshot, result) in &outcomes {
+                    status.record(&snapshot.name, command, result);
+                    state.metrics.record_result(&snapshot.name, result);
+                }
+                status.save(state_dir)?;
+                state.catalog.save(state_dir)?;
+                state
+                    .metrics
+                    .save(state_dir, config.metrics_file.as_deref())?;
+            }
+            let scrubbed = match command {
+                "run" => {
+                    let mut scrubs = ScrubFile::load(state_dir)?;
+                    let result = state.scrub_filesystems(&snapshots, &mut scrubs, false);
+                    if !state.dry_run {
+                        scrubs.save(state_dir)?;
+                    }
+                    result
+                }
+                _ => Ok(()),
+            };
+            state.send_notifications(&config.notify);
+            output::print_summary(state.output, &outcomes);
+            let succeeded = outcomes.iter().filter(|(_, result)| result.is_ok()).count();
+            let mut errors = outcomes
+                .into_iter()
+                .filter_map(|(snapshot, result)| {
+                    result.err().map(|error| {
+                        exit::ConfigFailure {
+                            config: snapshot.name.clone(),
+                            error,
+                        }
+                        .into()
+                    })
+                })
+                .chain(scrubbed.err());
+            output::print_actions(state.output, &state.actions)?;
+            if let Some(error) = errors.next() {
+                // Clean up before reporting the failures, since the configs
+                // that succeeded may have mounted or opened something.
+                let others = errors.chain(state.unmount().err()).collect();
+                return Err(exit::ExitError {
+                    code: match succeeded {
+                        0 => ExitCode::Failure,
+                        _ => ExitCode::PartialFailure,
+                    },
+                    error,
+                    others,
+                }
+                .into());
+            }
         }
-        if s.spacings.is_none() {
-            s.spacings = cfg.generic.spacings.clone();
+        "list" => {
+            let lists = snapshots
+                .into_iter()
+                .map(|snapshot| state.list_snapshots(snapshot))
+                .collect::<Result<Vec<_>>>()?;
+            if matches.is_present("null") {
+                output::print_paths(&lists, b'\0')?;
+            } else if matches.is_present("paths-only") {
+                output::print_paths(&lists, b'\n')?;
+            } else {
+                output::print_list(state.output, &lists)?;
+            }
         }
-
-        // Check that we have enough information.
-        if s.mount_point.is_none() {
-            bail!("Snapshot {} has no `mount_point` config", name);
+        "plan" => {
+            let plans = snapshots
+                .into_iter()
+                .map(|snapshot| state.plan_snapshots(snapshot))
+                .collect::<Result<Vec<_>>>()?;
+            output::print_plan(state.output, &plans)?;
         }
-        if s.format.is_none() {
-            bail!("Snapshot {} has no `format` config", name);
+        "scrub" => {
+            let state_dir = config.state_dir();
+            let mut scrubs = ScrubFile::load(state_dir)?;
+            let result =
+                state.scrub_filesystems(&snapshots, &mut scrubs, matches.is_present("force"));
+            if !state.dry_run {
+                scrubs.save(state_dir)?;
+            }
+            state.send_notifications(&config.notify);
+            result?;
         }
-        if s.subvolume.is_none() {
-            bail!("Snapshot {} has no `subvolume` config", name);
+        "status" => {
+            let status = StatusFile::load(config.state_dir())?;
+            let scrubs = ScrubFile::load(config.state_dir())?;
+            let statuses = snapshots
+                .into_iter()
+                .map(|snapshot| {
+                    let last_scrub = snapshot
+                        .mount_point
+                        .as_ref()
+                        .and_then(|mount_point| scrubs.filesystems.get(mount_point));
+                    state.snapshot_status(
+                        snapshot,
+                        status.snapshots.get(&snapshot.name),
+                        last_scrub,
+                    )
+                })
+                .collect::<Result<Vec<_>>>()?;
+            output::print_status(state.output, &statuses)?;
         }
-        if s.snapshot_dir.is_none() {
-            bail!("Snapshot {} has no `snapshot_dir` config", name);
+        "daemon" => state.run_daemon(snapshots, &config)?,
+        "migrate-format" => {
+            let mut renamed = 0;
+            for snapshot in snapshots {
+                renamed += state.migrate_format(
+                    snapshot,
+                    matches.value_of("from").unwrap(),
+                    matches.value_of("to"),
+                )?;
+            }
+            if !state.dry_run {
+                state.holds.save(config.state_dir())?;
+                state.catalog.save(config.state_dir())?;
+            }
+            if renamed == 0 && state.output == OutputFormat::Text {
+                println!("No snapshots named in the old format found");
+            }
         }
-    }
-    cfg.snapshots = snapshots;
-
-    Ok(cfg)
-}
-
-#[derive(Default)]
-struct State<'a> {
-    /// Whether to only print btrfs commands rather than executing them.
-    dry_run: bool,
-    /// The disks mounted explicitly by us.
-    manual_mounts: IndexSet<&'a Path>,
-}
-
-impl<'a> State<'a> {
-    fn take_snapshot(&mut self, snapshot: &'a SnapshotConfig) -> Result<()> {
-        debug!("Take snapshot of {}", snapshot.name);
-        self.mount_if_needed(snapshot.mount_point.as_ref().unwrap())?;
-
-        // Construct the snapshot directory.
-        let format = snapshot.format.as_ref().unwrap();
-        let mut path = snapshot.snapshot_dir.clone().unwrap();
-        path.push(chrono::Local::now().format(format).to_string());
-        println!("Taking snapshot {}", path.display());
-
-        // Take the snapshot.
-        self.maybe_run(
-            Command::new("btrfs")
-                .arg("subvolume")
-                .arg("snapshot")
-                .arg("-r")
-                .arg(snapshot.subvolume.as_ref().unwrap())
-                .arg(&path),
-        )
-        .with_context(|| format!("Taking snapshot {} failed", path.display()))?;
-
-        Ok(())
-    }
-
-    fn rotate_snapshot(&mut self, snapshot: &'a SnapshotConfig) -> Result<()> {
-        debug!("Rotate snapshots for {}", snapshot.name);
-        self.mount_if_needed(snapshot.mount_point.as_ref().unwrap())?;
-
-        // Create an array of snapshot spacings.
-        let mut spacings: Vec<_> = snapshot
-            .spacings
-            .as_ref()
-            .unwrap()
-            .iter()
-            .map(|(age, spacing)| (age.into_inner(), spacing.into_inner()))
-            .collect();
-        spacings.sort_by_key(|&(age, _)| age);
-        trace!("Spacings: {:?}", spacings);
-
-        // Parse the snapshots into proper dates.
-        let now = chrono::Local::now().with_nanosecond(0).unwrap();
-        let format = snapshot.format.as_ref().unwrap();
-        let mut entries = Vec::new();
-        for file in std::fs::read_dir(snapshot.snapshot_dir.as_ref().unwrap())? {
-            let file = file?.path();
-            let name = match file.file_name().and_then(|x| x.to_str()) {
-                Some(x) => x,
-                None => continue,
-            };
-            let date = match DateTime::parse_from_str(name, format) {
-                Ok(x) => x,
-                Err(_) => {
-                    warn!(
-                        "Ignoring snapshot {} because name does not match format `{}`",
-                        file.display(),
-                        format
-                    );
-                    continue;
-                }
+        "import" => {
+            let mut adopted = 0;
+            for snapshot in snapshots {
+                adopted += state.adopt_snapper(snapshot)?;
+            }
+            if state.output == OutputFormat::Text {
+                println!("Adopted {} snapper snapshot(s)", adopted);
+            }
+        }
+        "du" => {
+            let mut usages = snapshots
+                .into_iter()
+                .map(|snapshot| state.snapshot_usage(snapshot, matches.is_present("enable-quotas")))
+                .collect::<Result<Vec<_>>>()?;
+            usages.sort_by_key(|usage| std::cmp::Reverse(usage.exclusive));
+            output::print_usage(state.output, &usages)?;
+        }
+        "prune" => {
+            let filter = PruneFilter {
+                older_than: matches
+                    .value_of("older-than")
+                    .map(|age| {
+                        humantime::parse_duration(age)
+                            .with_context(|| format!("Invalid `--older-than` duration `{}`", age))
+                    })
+                    .transpose()
+                    .exit_code(ExitCode::ConfigError)?,
+                pattern: matches.value_of("match").map(str::to_owned),
             };
-            let age = now.signed_duration_since(date).to_std()?;
-            let rule = spacings
+            for snapshot in snapshots {
+                state.prune_snapshots(snapshot, &filter)?;
+            }
+            output::print_actions(state.output, &state.actions)?;
+        }
+        "gc" => {
+            let orphans = state.find_orphans(&config, &snapshots)?;
+            output::print_orphans(state.output, &orphans)?;
+            if matches.is_present("delete") {
+                state.delete_orphans(&config, &orphans)?;
+            }
+        }
+        "pkg-hooks" => {
+            let manager: pkg::PackageManager = matches.value_of("MANAGER").unwrap().parse()?;
+            print_hook_files(&manager.hooks(&hook_command(config_path, matches)));
+        }
+        "boot-unit" => print_hook_files(&[boot::unit(&hook_command(config_path, matches))]),
+        "dbus-policy" => print_hook_files(&[dbus::policy(
+            config.dbus.as_ref().unwrap_or(&Default::default()),
+        )]),
+        "history" => {
+            let since = matches
+                .value_of("since")
+                .map(|age| {
+                    humantime::parse_duration(age)
+                        .with_context(|| format!("Invalid `--since` duration `{}`", age))
+                })
+                .transpose()
+                .exit_code(ExitCode::ConfigError)?
+                .map(|age| chrono::Local::now() - chrono::Duration::from_std(age).unwrap());
+            let entries: Vec<_> = History::new(config.state_dir())
+                .read()?
+                .into_iter()
+                .filter(|entry| selector.selects_name(&entry.config))
+                .filter(|entry| {
+                    matches
+                        .value_of("action")
+                        .is_none_or(|action| entry.action.name() == action)
+                })
+                .filter(|entry| since.is_none_or(|since| entry.time >= since))
+                .filter(|entry| !matches.is_present("failed") || !entry.success)
+                .collect();
+            output::print_history(state.output, &entries)?;
+        }
+        "check-config" => {
+            let reports: Vec<_> = snapshots.into_iter().map(check::check_snapshot).collect();
+            output::print_check(state.output, &reports)?;
+            let failed = reports
                 .iter()
-                .enumerate()
-                .filter(|(_, &(a, _))| a <= age)
-                .max_by_key(|(_, &(a, _))| a)
-                .map(|(i, _)| i);
-            entries.push((date, file, rule));
+                .flat_map(|report| &report.checks)
+                .filter(|check| !check.ok)
+                .count();
+            if failed > 0 {
+                return Err(anyhow!("Configuration has {} problem(s)", failed))
+                    .exit_code(ExitCode::ConfigError);
+            }
         }
-
-        // Sort the entries by descending date.
-        entries.sort_by_key(|&(d, ..)| d);
-        entries.reverse();
-
-        // Iterate through the entries newest to oldest and mark the ones that
-        // are too close to the previous entry.
-        let mut delete = IndexSet::new();
-        for (rule, &(target_age, target_spacing)) in spacings.iter().enumerate() {
-            trace!(
-                "Purging for rule {}, until age {}, spacing {}",
-                rule,
-                format_duration(target_age),
-                format_duration(target_spacing)
-            );
-            let mut it = entries.iter().zip(entries.iter().skip(1));
-            let mut newest = match it.next() {
-                Some((x, _)) => x,
-                None => return Ok(()),
+        "hold" | "release" => {
+            let path = Path::new(matches.value_of("PATH").unwrap());
+            let changed = match command {
+                "hold" => state.holds.hold(path)?,
+                _ => state.holds.release(path),
             };
-            trace!("  Initial {}", newest.0);
-            for (current, older) in it {
-                if current.2 > Some(rule) {
-                    break;
-                }
-                let applies = current.2 == Some(rule);
-                let spacing = std::cmp::max(
-                    (newest.0).signed_duration_since(current.0).to_std()?,
-                    (current.0).signed_duration_since(older.0).to_std()?,
-                );
-                trace!(
-                    "  {} {}, rule {:?}, spacing {}",
-                    if applies { "Considering" } else { "Skipping" },
-                    current.0,
-                    current.2,
-                    format_duration(spacing)
-                );
-
-                // Drop the snapshot if not adequately spaced.
-                if spacing < target_spacing {
-                    if current.2 == Some(rule) {
-                        delete.insert(&current.1);
-                        debug!("  Dropping {}", current.0);
-                        debug!("    Favoring: {}", newest.0);
-                        debug!("    Spacing:  {}", format_duration(spacing));
-                        debug!("    Intended: {}", format_duration(target_spacing));
-                    }
-                } else {
-                    newest = current;
-                }
+            output::print_hold(
+                state.output,
+                &output::HoldChange {
+                    path: path.to_owned(),
+                    held: command == "hold",
+                    changed,
+                },
+            )?;
+            if changed && !state.dry_run {
+                state.holds.save(config.state_dir())?;
             }
         }
-
-        // Delete the marked snapshots.
-        for file in delete {
-            println!("Dropping snapshot {}", file.display());
-            self.maybe_run(
-                Command::new("btrfs")
-                    .arg("subvolume")
-                    .arg("delete")
-                    .arg(file),
-            )
-            .with_context(|| format!("Deleting snapshot {} failed", file.display()))?;
+        "diff" => {
+            let snapshot = named_snapshot(&config, matches.value_of("CONFIG").unwrap())?;
+            let diff = state.diff_snapshots(
+                snapshot,
+                matches.value_of("FROM").unwrap(),
+                matches.value_of("TO").unwrap(),
+            )?;
+            output::print_diff(state.output, &diff)?;
         }
-
-        Ok(())
-    }
-
-    /// Mount a disk if it is not yet mounted.
-    fn mount_if_needed(&mut self, mount_point: &'a Path) -> Result<()> {
-        // No need to mount twice.
-        if self.manual_mounts.contains(mount_point) {
-            return Ok(());
+        "verify" => {
+            let snapshot = named_snapshot(&config, matches.value_of("CONFIG").unwrap())?;
+            let sample = matches
+                .value_of("sample")
+                .map(|v| match v.parse::<u8>() {
+                    Ok(percent) if (1..=100).contains(&percent) => Ok(percent),
+                    _ => Err(anyhow!("Invalid sample percentage {}", v)),
+                })
+                .transpose()
+                .exit_code(ExitCode::ConfigError)?;
+            let report = state.verify_snapshots(
+                snapshot,
+                matches.value_of("FROM"),
+                matches.value_of("TO"),
+                sample,
+            )?;
+            output::print_verify(state.output, &report)?;
+            if !report.discrepancies.is_empty() {
+                return Err(anyhow!(
+                    "Found {} discrepancies between {} and {}",
+                    report.discrepancies.len(),
+                    report.from.display(),
+                    report.to.display()
+                ));
+            }
         }
-
-        // Check if the disk is not already mounted.
-        let re = Regex::new(r"(?m)^.+? on (.+?) type").unwrap();
-        let mounts = run(&mut Command::new("mount")).context("Checking mounts failed")?;
-        for cap in re.captures_iter(&mounts) {
-            if Path::new(&cap[1]) == mount_point {
-                trace!("Already mounted {}", &cap[1]);
-                return Ok(());
+        "browse" => {
+            let snapshot = named_snapshot(&config, matches.value_of("CONFIG").unwrap())?;
+            state.browse_snapshot(
+                snapshot,
+                matches.value_of("SNAPSHOT").unwrap(),
+                matches.is_present("shell"),
+            )?;
+        }
+        "restore" => {
+            let snapshot = named_snapshot(&config, matches.value_of("CONFIG").unwrap())?;
+            state.restore_snapshot(snapshot, matches.value_of("SNAPSHOT").unwrap())?;
+            if !state.dry_run {
+                state.catalog.save(config.state_dir())?;
             }
         }
-
-        // Actually mount the disk.
-        debug!("Mounting {}", mount_point.display());
-        run(&mut Command::new("mount").arg(mount_point))
-            .with_context(|| format!("Mounting {} failed", mount_point.display()))?;
-        self.manual_mounts.insert(mount_point);
-        Ok(())
+        _ => unreachable!("unhandled subcommand {}", command),
     }
+    state.unmount()?;
 
-    /// Unmount all the manually mounted disks.
-    fn unmount(&mut self) -> Result<()> {
-        for mount_point in std::mem::take(&mut self.manual_mounts) {
-            debug!("Unmounting {}", mount_point.display());
-            run(&mut Command::new("umount").arg(mount_point))
-                .with_context(|| format!("Unmounting {} failed", mount_point.display()))?;
-        }
-        Ok(())
-    }
+    Ok(())
+}
 
-    fn maybe_run(&self, cmd: &mut Command) -> Result<String> {
-        if self.dry_run {
-            println!("{:?}", cmd);
-            Ok(String::new())
-        } else {
-            run(cmd)
+/// Find the snapshot config named on the command line.
+fn named_snapshot<'a>(config: &'a Config, name: &str) -> Result<&'a SnapshotConfig> {
+    config
+        .snapshots
+        .get(name)
+        .ok_or_else(|| anyhow!("No snapshot config named `{}`", name))
+        .exit_code(ExitCode::ConfigError)
+}
+
+/// The command line that hooks and units installed into the system run, with
+/// the current config and snapshot selection.
+fn hook_command(config_path: &str, matches: &ArgMatches) -> String {
+    let program = std::env::current_exe()
+        .map(|exe| exe.display().to_string())
+        .unwrap_or_else(|_| String::from(crate_name!()));
+    let config_path = std::fs::canonicalize(config_path).unwrap_or_else(|_| config_path.into());
+    let names = |arg| {
+        matches
+            .values_of(arg)
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+    };
+    pkg::command_line(
+        &program,
+        &config_path,
+        &names("only-snapshot"),
+        &names("exclude-snapshot"),
+    )
+}
+
+/// Print hook files, each headed by the path it is to be installed at.
+fn print_hook_files(hooks: &[pkg::HookFile]) {
+    for (index, hook) in hooks.iter().enumerate() {
+        if index > 0 {
+            println!();
         }
+        println!("# {}", hook.path.display());
+        print!("{}", hook.content);
     }
 }
 
-/// Execute a `Command` and return its stdout on exit code 0, or a flurry of
-/// appropriate error messages if anything goes wrong.
-fn run(cmd: &mut Command) -> Result<String> {
-    let output = cmd
-        .output()
-        .with_context(|| format!("Failed to execute {:?}", cmd))?;
-    if !output.status.success() {
-        let code = output.status.code().unwrap_or(0);
-        return Err(anyhow!(std::str::from_utf8(&output.stderr)
-            .unwrap_or("<stderr not utf-8>")
-            .trim()
-            .to_owned()))
-        .with_context(|| format!("Command {:?} failed with exit code {}", cmd, code));
-    }
-    String::from_utf8(output.stdout)
-        .with_context(|| format!("Command {:?} stdout is non-utf8", cmd))
+/// The argument to take snapshots with a tag.
+fn tag_arg() -> Arg<'static, 'static> {
+    Arg::with_name("tag")
+        .short("t")
+        .long("tag")
+        .value_name("TAG")
+        .help("Tag the new snapshots, subjecting them to the retention rules of that tag")
+        .takes_value(true)
 }
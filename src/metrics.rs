@@ -0,0 +1,278 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Metrics about snapshot operations, exported as a Prometheus textfile for
+//! the node_exporter textfile collector.
+
+use crate::{output::ActionKind, SnapshotConfig, State};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Write as _, path::Path, time::Instant};
+
+/// The name of the file within the state directory that holds the metrics.
+const METRICS_FILE: &str = "metrics.toml";
+
+/// The metrics of all snapshot configs, persisted across runs such that
+/// counters keep counting up.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MetricsFile {
+    /// The metrics of each snapshot config, keyed by config name.
+    #[serde(default)]
+    pub snapshots: IndexMap<String, SnapshotMetrics>,
+}
+
+/// The metrics of a single snapshot config.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SnapshotMetrics {
+    /// How many snapshots have been taken.
+    #[serde(default)]
+    pub taken: u64,
+    /// How many snapshots have been deleted, locally or on a target.
+    #[serde(default)]
+    pub deleted: u64,
+    /// How many snapshots have been moved into the trash.
+    #[serde(default)]
+    pub trashed: u64,
+    /// How many snapshots have been sent to a replication target.
+    #[serde(default)]
+    pub sent: u64,
+    /// How many bytes have been sent to replication targets.
+    #[serde(default)]
+    pub sent_bytes: u64,
+    /// How many snapshots have been pulled from source hosts.
+    #[serde(default)]
+    pub pulled: u64,
+    /// How many bytes have been received from source hosts.
+    #[serde(default)]
+    pub pulled_bytes: u64,
+    /// How many bytes btrfs has been seen to release after deletions.
+    #[serde(default)]
+    pub reclaimed_bytes: u64,
+    /// When the last run without errors finished.
+    pub last_success: Option<DateTime<Local>>,
+    /// How many seconds the last take, rotate, and send took.
+    #[serde(default)]
+    pub durations: IndexMap<String, f64>,
+}
+
+impl MetricsFile {
+    /// Load the metrics from a state directory. Returns empty metrics if the
+    /// file does not exist yet.
+    pub fn load(state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(METRICS_FILE);
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        debug!("Loading metrics {}", path.display());
+        let buf = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read metrics from {}", path.display()))?;
+        toml::de::from_str(&buf)
+            .with_context(|| format!("Failed to parse metrics from {}", path.display()))
+    }
+
+    /// Write the metrics into a state directory, and export them to a
+    /// Prometheus textfile if one is configured.
+    pub fn save(&self, state_dir: &Path, textfile: Option<&Path>) -> Result<()> {
+        let path = state_dir.join(METRICS_FILE);
+        debug!("Saving metrics {}", path.display());
+        std::fs::create_dir_all(state_dir)
+            .with_context(|| format!("Failed to create state dir {}", state_dir.display()))?;
+        std::fs::write(&path, toml::ser::to_string(self)?)
+            .with_context(|| format!("Failed to write metrics to {}", path.display()))?;
+        if let Some(textfile) = textfile {
+            self.write_textfile(textfile)?;
+        }
+        Ok(())
+    }
+
+    /// Record a successful action on a snapshot.
+    pub fn record_action(&mut self, name: &str, kind: ActionKind, bytes: u64) {
+        let metrics = self.snapshots.entry(name.to_owned()).or_default();
+        match kind {
+            ActionKind::Take => metrics.taken += 1,
+            ActionKind::Delete => metrics.deleted += 1,
+            ActionKind::Trash => metrics.trashed += 1,
+            ActionKind::Send => {
+                metrics.sent += 1;
+                metrics.sent_bytes += bytes;
+            }
+            ActionKind::Restore => (),
+            ActionKind::Pull => {
+                metrics.pulled += 1;
+                metrics.pulled_bytes += bytes;
+            }
+        }
+    }
+
+    /// Record the space released by deleting snapshots.
+    pub fn record_reclaimed(&mut self, name: &str, bytes: u64) {
+        self.snapshots
+            .entry(name.to_owned())
+            .or_default()
+            .reclaimed_bytes += bytes;
+    }
+
+    /// Record the outcome of a run for a snapshot config.
+    pub fn record_result(&mut self, name: &str, result: &Result<()>) {
+        if result.is_ok() {
+            self.snapshots
+                .entry(name.to_owned())
+                .or_default()
+                .last_success = Some(Local::now());
+        }
+    }
+
+    /// Add the counters and durations recorded by a separate worker, such as
+    /// one processing another filesystem concurrently.
+    pub fn merge(&mut self, other: MetricsFile) {
+        for (name, other) in other.snapshots {
+            let metrics = self.snapshots.entry(name).or_default();
+            metrics.taken += other.taken;
+            metrics.deleted += other.deleted;
+            metrics.trashed += other.trashed;
+            metrics.sent += other.sent;
+            metrics.sent_bytes += other.sent_bytes;
+            metrics.pulled += other.pulled;
+            metrics.pulled_bytes += other.pulled_bytes;
+            metrics.reclaimed_bytes += other.reclaimed_bytes;
+            if other.last_success.is_some() {
+                metrics.last_success = other.last_success;
+            }
+            metrics.durations.extend(other.durations);
+        }
+    }
+
+    /// Write the metrics in the Prometheus text format. The file is replaced
+    /// atomically, such that the collector never sees a partial file.
+    fn write_textfile(&self, path: &Path) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.render_textfile())
+            .with_context(|| format!("Failed to write metrics to {:?}", tmp))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to write metrics to {}", path.display()))
+    }
+
+    /// Render the metrics in the Prometheus text format.
+    pub fn render_textfile(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, String)>| {
+            writeln!(out, "# HELP btrfs_snapshot_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE btrfs_snapshot_{} {}", name, kind).unwrap();
+            for (labels, value) in values {
+                writeln!(out, "btrfs_snapshot_{}{{{}}} {}", name, labels, value).unwrap();
+            }
+        };
+        let per_config = |f: &dyn Fn(&SnapshotMetrics) -> Option<String>| {
+            self.snapshots
+                .iter()
+                .filter_map(|(name, m)| Some((format!("config=\"{}\"", escape(name)), f(m)?)))
+                .collect()
+        };
+        metric(
+            "taken_total",
+            "counter",
+            "Number of snapshots taken.",
+            per_config(&|m| Some(m.taken.to_string())),
+        );
+        metric(
+            "deleted_total",
+            "counter",
+            "Number of snapshots deleted.",
+            per_config(&|m| Some(m.deleted.to_string())),
+        );
+        metric(
+            "trashed_total",
+            "counter",
+            "Number of snapshots moved into the trash.",
+            per_config(&|m| Some(m.trashed.to_string())),
+        );
+        metric(
+            "sent_total",
+            "counter",
+            "Number of snapshots sent to replication targets.",
+            per_config(&|m| Some(m.sent.to_string())),
+        );
+        metric(
+            "sent_bytes_total",
+            "counter",
+            "Number of bytes sent to replication targets.",
+            per_config(&|m| Some(m.sent_bytes.to_string())),
+        );
+        metric(
+            "pulled_total",
+            "counter",
+            "Number of snapshots pulled from source hosts.",
+            per_config(&|m| Some(m.pulled.to_string())),
+        );
+        metric(
+            "pulled_bytes_total",
+            "counter",
+            "Number of bytes received from source hosts.",
+            per_config(&|m| Some(m.pulled_bytes.to_string())),
+        );
+        metric(
+            "reclaimed_bytes_total",
+            "counter",
+            "Number of bytes released by deleting snapshots.",
+            per_config(&|m| Some(m.reclaimed_bytes.to_string())),
+        );
+        metric(
+            "last_success_timestamp_seconds",
+            "gauge",
+            "Time of the last run without errors.",
+            per_config(&|m| m.last_success.map(|t| t.timestamp().to_string())),
+        );
+        metric(
+            "duration_seconds",
+            "gauge",
+            "Duration of the last take, rotate, and send operation.",
+            self.snapshots
+                .iter()
+                .flat_map(|(name, m)| {
+                    m.durations.iter().map(move |(operation, seconds)| {
+                        (
+                            format!(
+                                "config=\"{}\",operation=\"{}\"",
+                                escape(name),
+                                escape(operation)
+                            ),
+                            format!("{:.3}", seconds),
+                        )
+                    })
+                })
+                .collect(),
+        );
+        out
+    }
+}
+
+/// Escape a Prometheus label value.
+fn escape(s: &str) -> String {
+    s.replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl<'a> State<'a> {
+    /// Run an operation on a snapshot config and record how long it took.
+    pub fn timed<T>(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        operation: &str,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let result = f(self);
+        if !self.dry_run {
+            self.metrics
+                .snapshots
+                .entry(snapshot.name.clone())
+                .or_default()
+                .durations
+                .insert(operation.to_owned(), start.elapsed().as_secs_f64());
+        }
+        result
+    }
+}
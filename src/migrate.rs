@@ -0,0 +1,135 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Renaming existing snapshots after the naming format of a config changed,
+//! such that rotation keeps recognizing them.
+
+use crate::{
+    list_dir, naming::Naming, output::OutputFormat, trash::TRASH_DIR, SnapshotConfig, State,
+};
+use anyhow::{bail, Context, Result};
+use std::{collections::HashSet, path::PathBuf, process::Command};
+
+impl<'a> State<'a> {
+    /// Rename the snapshots of a config whose names follow the format `from`
+    /// to the format `to`, or the configured format if omitted. Returns the
+    /// number of renamed snapshots.
+    pub fn migrate_format(
+        &mut self,
+        snapshot: &'a SnapshotConfig,
+        from: &str,
+        to: Option<&str>,
+    ) -> Result<usize> {
+        debug!(
+            "Migrate snapshot names of {} from `{}`",
+            snapshot.name, from
+        );
+        self.mount_snapshot_fs(snapshot)?;
+        let zone = snapshot.timezone.clone().unwrap_or_default();
+        let old = Naming::new(from, &snapshot.group, zone.clone())
+            .with_context(|| format!("Invalid format `{}`", from))?;
+        let new = match to {
+            Some(to) => Naming::new(to, &snapshot.group, zone)
+                .with_context(|| format!("Invalid format `{}`", to))?,
+            None => snapshot.naming()?,
+        };
+
+        // Work out all new names before renaming anything, such that a
+        // format that maps several snapshots to the same name is caught
+        // early.
+        let dir = snapshot.snapshot_dir.as_ref().unwrap();
+        let mut renames: Vec<(PathBuf, PathBuf)> = vec![];
+        let mut targets = HashSet::new();
+        let mut entries = list_dir(dir)
+            .with_context(|| format!("Failed to read snapshot dir {}", dir.display()))?;
+        entries.sort();
+        for path in entries.iter().cloned() {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if name == TRASH_DIR || new.parse(&name).is_some() {
+                continue;
+            }
+            let parsed = match old.parse(&name) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            let target = dir.join(new.render_with_suffix(
+                parsed.date.with_timezone(&chrono::Local),
+                parsed.tag.as_deref(),
+                parsed.seq.unwrap_or(0),
+                parsed.suffix,
+            )?);
+            if entries.contains(&target) || !targets.insert(target.clone()) {
+                bail!(
+                    "Cannot rename {} to {}, since another snapshot already has that name",
+                    path.display(),
+                    target.display()
+                );
+            }
+            renames.push((path, target));
+        }
+
+        for (path, target) in &renames {
+            if self.output == OutputFormat::Text {
+                println!(
+                    "Renaming snapshot {} to {}",
+                    path.display(),
+                    target.display()
+                );
+            }
+            if self.dry_run {
+                let mut cmd = Command::new("mv");
+                cmd.arg(path).arg(target);
+                self.print_commands(&[&mut cmd]);
+                continue;
+            }
+            let held = self.holds.release(path);
+            self.executor
+                .rename(path, target)
+                .with_context(|| format!("Renaming snapshot {} failed", path.display()))?;
+            self.catalog.rename(path, target);
+            if held {
+                self.holds.hold(target)?;
+            }
+        }
+        Ok(renames.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{executor::Operation, tests::Fixture};
+
+    #[test]
+    fn migrate_renames_to_new_format() {
+        let fixture = Fixture::new("migrate", "");
+        let paths = fixture.add_snapshots(&[2, 1]);
+        let (mut state, mock) = fixture.state();
+        let renamed = state
+            .migrate_format(
+                fixture.snapshot(),
+                "%Y_%m_%d_%H%M%z",
+                Some("%Y-%m-%dT%H%M%z"),
+            )
+            .unwrap();
+        assert_eq!(renamed, 2);
+        let ops = mock.operations();
+        assert_eq!(ops.len(), 2);
+        for (op, path) in ops.iter().zip(&paths) {
+            match op {
+                Operation::Rename(from, to) => {
+                    assert_eq!(from, path);
+                    let name = to.file_name().unwrap().to_str().unwrap();
+                    assert_eq!(
+                        name,
+                        path.file_name()
+                            .unwrap()
+                            .to_str()
+                            .unwrap()
+                            .replacen('_', "-", 2)
+                            .replacen('_', "T", 1)
+                    );
+                }
+                op => panic!("unexpected operation {:?}", op),
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Information about mounted filesystems.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// A mounted filesystem, as listed in `/proc/self/mountinfo`.
+pub struct Mount {
+    /// The directory within the filesystem that is mounted, which for btrfs
+    /// is the path of the mounted subvolume.
+    pub root: PathBuf,
+    /// Where the filesystem is mounted.
+    pub mount_point: PathBuf,
+    /// The filesystem type, e.g. `btrfs`.
+    pub fs_type: String,
+    /// The device the filesystem is mounted from.
+    pub source: String,
+}
+
+/// Read the mounted filesystems. Returns an empty list if they cannot be
+/// determined.
+pub fn read_mounts() -> Vec<Mount> {
+    try_read_mounts().unwrap_or_else(|e| {
+        warn!("Cannot read mounted filesystems: {:#}", e);
+        Vec::new()
+    })
+}
+
+/// Read the mounted filesystems, failing if they cannot be determined.
+pub fn try_read_mounts() -> Result<Vec<Mount>> {
+    let info = std::fs::read_to_string("/proc/self/mountinfo")
+        .context("Failed to read /proc/self/mountinfo")?;
+    Ok(parse_mountinfo(&info))
+}
+
+/// Parse the contents of `/proc/self/mountinfo`.
+fn parse_mountinfo(info: &str) -> Vec<Mount> {
+    info.lines()
+        .filter_map(|line| {
+            let (left, right) = line.split_once(" - ")?;
+            let mut left = left.split(' ').skip(3);
+            let root = left.next()?;
+            let mount_point = left.next()?;
+            let mut right = right.split(' ');
+            Some(Mount {
+                root: PathBuf::from(unescape(root)),
+                mount_point: PathBuf::from(unescape(mount_point)),
+                fs_type: right.next()?.to_owned(),
+                source: right.next()?.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Undo the octal escaping of spaces and other special characters in
+/// `/proc/self/mountinfo`.
+fn unescape(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        match u8::from_str_radix(rest.get(i + 1..i + 4).unwrap_or_default(), 8) {
+            Ok(c) => {
+                out.push(c as char);
+                rest = &rest[i + 4..];
+            }
+            Err(_) => {
+                out.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Find the filesystem mounted at a mount point. If several filesystems are
+/// mounted on top of each other, the last one is the one that is visible.
+/// Bind mounts are found like any other mount.
+pub fn mount_at<'a>(mounts: &'a [Mount], mount_point: &Path) -> Option<&'a Mount> {
+    let mount_point = std::fs::canonicalize(mount_point).unwrap_or_else(|_| mount_point.into());
+    mounts.iter().rev().find(|m| m.mount_point == mount_point)
+}
+
+/// Find the filesystem a path resides on, which is the mount with the
+/// longest mount point that contains the path.
+pub fn find_mount<'a>(mounts: &'a [Mount], path: &Path) -> Option<&'a Mount> {
+    let path = std::fs::canonicalize(path).ok()?;
+    mounts
+        .iter()
+        .filter(|m| path.starts_with(&m.mount_point))
+        .max_by_key(|m| m.mount_point.as_os_str().len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mountinfo_unescaped_and_last_mount_wins() {
+        assert_eq!(unescape("/mnt/my\\040disk"), "/mnt/my disk");
+        assert_eq!(unescape("a\\tab\\134"), "a\\tab\\");
+        let mounts = parse_mountinfo(
+            "22 1 0:21 / /proc rw - proc proc rw\n\
+             40 1 0:35 /@data /nonexistent/my\\040disk rw - btrfs /dev/sda1 rw\n\
+             41 40 0:36 / /nonexistent/my\\040disk rw - ext4 /dev/sdb1 rw\n",
+        );
+        assert_eq!(mounts.len(), 3);
+        assert_eq!(mounts[1].root, Path::new("/@data"));
+        let mount = mount_at(&mounts, Path::new("/nonexistent/my disk")).unwrap();
+        assert_eq!(
+            (mount.fs_type.as_str(), mount.source.as_str()),
+            ("ext4", "/dev/sdb1")
+        );
+        assert!(mount_at(&mounts, Path::new("/nonexistent")).is_none());
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Generating and parsing snapshot names from name templates.
+//!
+//! A template is a chrono format string that may additionally contain the
+//! variables `{hostname}`, `{config}`, `{tag}`, and `{seq}`. Names that
+//! collide with an existing snapshot may carry a `-1`, `-2`, ... suffix, which
+//! goes before the tag if the template has no `{tag}` variable.
+
+use crate::timezone::TimeZone;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// The separator between the date and the tag in a snapshot name, for
+/// templates without a `{tag}` variable.
+pub const TAG_SEPARATOR: char = '@';
+
+/// The separator used to join the date parts of a name for parsing.
+const DATE_JOINER: &str = "\u{1}";
+
+/// What to do if the name of a new snapshot is already taken, e.g. by a
+/// snapshot taken earlier within the same minute.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    /// Fail to take the snapshot.
+    #[default]
+    Error,
+    /// Skip the snapshot with a message.
+    Skip,
+    /// Append the first free `-1`, `-2`, ... suffix to the name.
+    Suffix,
+}
+
+/// A variable in a name template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Var {
+    /// The name of the host.
+    Hostname,
+    /// The name of the snapshot config.
+    Config,
+    /// The tag the snapshot is taken with, or empty.
+    Tag,
+    /// A number counting up with each snapshot.
+    Seq,
+}
+
+/// A part of a name template.
+#[derive(Debug, Clone)]
+enum Segment {
+    /// A chrono format string.
+    Format(String),
+    /// A variable.
+    Var(Var),
+}
+
+/// The information needed to generate and parse the snapshot names of a
+/// snapshot config.
+#[derive(Debug, Clone)]
+pub struct Naming {
+    /// The template the names follow.
+    template: String,
+    /// The parsed template.
+    segments: Vec<Segment>,
+    /// The regular expression matching names, with one group per segment.
+    regex: Regex,
+    /// The name of the host.
+    hostname: String,
+    /// The name of the snapshot config.
+    config: String,
+    /// The time zone in which names are generated.
+    zone: TimeZone,
+}
+
+/// The details encoded in a snapshot name.
+#[derive(Debug, Clone)]
+pub struct ParsedName {
+    /// The date the snapshot was taken.
+    pub date: DateTime<FixedOffset>,
+    /// The tag the snapshot was taken with.
+    pub tag: Option<String>,
+    /// The sequence number of the snapshot.
+    pub seq: Option<u64>,
+    /// The suffix that tells the snapshot apart from others with the same
+    /// name.
+    pub suffix: Option<u64>,
+}
+
+impl Naming {
+    /// Prepare the naming of a snapshot config.
+    pub fn new(template: &str, config: &str, zone: TimeZone) -> Result<Self> {
+        let segments = parse_template(template)?;
+        let hostname = if segments
+            .iter()
+            .any(|s| matches!(s, Segment::Var(Var::Hostname)))
+        {
+            hostname()?
+        } else {
+            String::new()
+        };
+        let mut pattern = String::from("^");
+        for segment in &segments {
+            let group = match segment {
+                Segment::Format(f) => format_regex(f),
+                Segment::Var(Var::Hostname) => regex::escape(&hostname),
+                Segment::Var(Var::Config) => regex::escape(config),
+                Segment::Var(Var::Tag) => String::from("[A-Za-z0-9_-]*?"),
+                Segment::Var(Var::Seq) => String::from("[0-9]+"),
+            };
+            pattern.push('(');
+            pattern.push_str(&group);
+            pattern.push(')');
+        }
+        pattern.push_str("(?:-([0-9]+))?$");
+        Ok(Self {
+            template: template.to_owned(),
+            regex: Regex::new(&pattern).unwrap(),
+            segments,
+            hostname,
+            config: config.to_owned(),
+            zone,
+        })
+    }
+
+    /// The template the names follow.
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// Whether the names carry a sequence number.
+    pub fn has_seq(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|s| matches!(s, Segment::Var(Var::Seq)))
+    }
+
+    /// Whether names of other hosts or configs can be told apart from the
+    /// names of this config, such that they may share a directory.
+    pub fn is_qualified(&self) -> bool {
+        self.segments.iter().any(|s| {
+            matches!(s, Segment::Var(Var::Hostname)) || matches!(s, Segment::Var(Var::Config))
+        })
+    }
+
+    /// Generate the name of a snapshot.
+    pub fn render(
+        &self,
+        time: DateTime<chrono::Local>,
+        tag: Option<&str>,
+        seq: u64,
+    ) -> Result<String> {
+        self.render_with_suffix(time, tag, seq, None)
+    }
+
+    /// Generate the name of a snapshot that carries a suffix to tell it apart
+    /// from another one with the same name.
+    pub fn render_with_suffix(
+        &self,
+        time: DateTime<chrono::Local>,
+        tag: Option<&str>,
+        seq: u64,
+        suffix: Option<u64>,
+    ) -> Result<String> {
+        let has_tag = self
+            .segments
+            .iter()
+            .any(|s| matches!(s, Segment::Var(Var::Tag)));
+        let mut format = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Format(f) => format.push_str(f),
+                Segment::Var(var) => {
+                    let value = match var {
+                        Var::Hostname => self.hostname.clone(),
+                        Var::Config => self.config.clone(),
+                        Var::Tag => tag.unwrap_or_default().to_owned(),
+                        Var::Seq => seq.to_string(),
+                    };
+                    format.push_str(&value.replace('%', "%%"));
+                }
+            }
+        }
+        let mut name = self.zone.convert(time)?.format(&format).to_string();
+        if let Some(suffix) = suffix {
+            name.push_str(&format!("-{}", suffix));
+        }
+        if let (Some(tag), false) = (tag, has_tag) {
+            name.push(TAG_SEPARATOR);
+            name.push_str(tag);
+        }
+        Ok(name)
+    }
+
+    /// Parse a snapshot name. Returns `None` if the name does not follow the
+    /// template, or belongs to another host or config.
+    pub fn parse(&self, name: &str) -> Option<ParsedName> {
+        if let Some(parsed) = self.parse_exact(name) {
+            return Some(parsed);
+        }
+        let has_tag = self
+            .segments
+            .iter()
+            .any(|s| matches!(s, Segment::Var(Var::Tag)));
+        if has_tag {
+            return None;
+        }
+        let (name, tag) = name.rsplit_once(TAG_SEPARATOR)?;
+        let mut parsed = self.parse_exact(name)?;
+        parsed.tag = Some(tag.to_owned());
+        Some(parsed)
+    }
+
+    /// Parse a name that follows the template exactly.
+    fn parse_exact(&self, name: &str) -> Option<ParsedName> {
+        let caps = self.regex.captures(name)?;
+        let mut date_text = Vec::new();
+        let mut date_format = Vec::new();
+        let mut tag = None;
+        let mut seq = None;
+        for (segment, cap) in self.segments.iter().zip(caps.iter().skip(1)) {
+            let text = cap.map(|m| m.as_str()).unwrap_or_default();
+            match segment {
+                Segment::Format(f) => {
+                    date_text.push(text);
+                    date_format.push(f.as_str());
+                }
+                Segment::Var(Var::Tag) if !text.is_empty() => tag = Some(text.to_owned()),
+                Segment::Var(Var::Seq) => seq = Some(text.parse().ok()?),
+                Segment::Var(_) => (),
+            }
+        }
+        let text = date_text.join(DATE_JOINER);
+        let format = date_format.join(DATE_JOINER);
+        let suffix = match caps.get(self.segments.len() + 1) {
+            Some(m) => Some(m.as_str().parse().ok()?),
+            None => None,
+        };
+        let date = match DateTime::parse_from_str(&text, &format) {
+            Ok(date) => date,
+            Err(_) => {
+                let date = NaiveDateTime::parse_from_str(&text, &format).ok()?;
+                self.zone.resolve(date).ok()?
+            }
+        };
+        Some(ParsedName {
+            date,
+            tag,
+            seq,
+            suffix,
+        })
+    }
+}
+
+/// Split a name template into chrono format strings and variables.
+fn parse_template(template: &str) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| anyhow!("Unterminated `{{` in format `{}`", template))?;
+        if start > 0 {
+            segments.push(Segment::Format(rest[..start].to_owned()));
+        }
+        let var = match &rest[start + 1..end] {
+            "hostname" => Var::Hostname,
+            "config" => Var::Config,
+            "tag" => Var::Tag,
+            "seq" => Var::Seq,
+            other => bail!("Unknown variable `{{{}}}` in format `{}`", other, template),
+        };
+        if segments
+            .iter()
+            .any(|s| matches!(s, Segment::Var(v) if *v == var))
+        {
+            bail!(
+                "Variable `{{{}}}` used twice in format `{}`",
+                &rest[start + 1..end],
+                template
+            );
+        }
+        segments.push(Segment::Var(var));
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Format(rest.to_owned()));
+    }
+    Ok(segments)
+}
+
+/// Translate a chrono format string into a regular expression that matches
+/// the strings it produces, such that the date can be told apart from the
+/// variables around it.
+fn format_regex(format: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            regex.push_str(&regex::escape(&c.to_string()));
+            continue;
+        }
+        // Skip padding and other modifiers.
+        let mut spec = chars.next();
+        while let Some('-' | '_' | '0' | ':' | '.' | '3' | '6' | '9') = spec {
+            spec = chars.next();
+        }
+        regex.push_str(match spec {
+            Some('Y' | 'C' | 'y' | 'm' | 'd' | 'e' | 'H' | 'k' | 'I' | 'l' | 'M' | 'S') => {
+                " ?[0-9]+"
+            }
+            Some('j' | 'U' | 'W' | 'V' | 'G' | 'g' | 'u' | 'w' | 's' | 'f') => "[0-9]+",
+            Some('a' | 'A' | 'b' | 'B' | 'h' | 'p' | 'P') => "[A-Za-z]+",
+            Some('z') => "[+-][0-9:]+",
+            Some('Z') => "[A-Za-z0-9+:-]+",
+            Some('F') => "[0-9]+-[0-9]+-[0-9]+",
+            Some('T') => "[0-9]+:[0-9]+:[0-9]+",
+            Some('R') => "[0-9]+:[0-9]+",
+            Some('D') => "[0-9]+/[0-9]+/[0-9]+",
+            Some('%') => "%",
+            Some('n') => "\\n",
+            Some('t') => "\\t",
+            _ => ".*?",
+        });
+    }
+    regex
+}
+
+/// Determine the name of the host.
+pub fn hostname() -> Result<String> {
+    let name = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .context("Failed to determine hostname")?;
+    Ok(name.trim().to_owned())
+}
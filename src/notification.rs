@@ -0,0 +1,418 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Notifying administrators about the outcome of runs, such that unattended
+//! machines do not fail silently.
+
+use crate::{
+    color,
+    naming::hostname,
+    output::{ActionKind, OutputFormat},
+    run, State,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::Write,
+    os::unix::{fs::MetadataExt as _, process::CommandExt as _},
+    process::{Command, Stdio},
+};
+
+/// Where to send notifications.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct NotificationConfig {
+    /// Send an email summarizing the failures of a run.
+    pub email: Option<EmailConfig>,
+    /// Post events to webhooks.
+    #[serde(default)]
+    pub webhook: Vec<WebhookConfig>,
+    /// Show events as desktop notifications.
+    pub desktop: Option<DesktopConfig>,
+}
+
+/// How to show desktop notifications.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DesktopConfig {
+    /// The kinds of events to show. Defaults to all.
+    #[serde(default = "default_desktop_events")]
+    pub events: Vec<EventKind>,
+}
+
+fn default_desktop_events() -> Vec<EventKind> {
+    vec![EventKind::Success, EventKind::Warning, EventKind::Failure]
+}
+
+/// How to send notification emails.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailConfig {
+    /// The recipients of the email.
+    pub to: Vec<String>,
+    /// The sender of the email. Defaults to `btrfs-snapshot@<hostname>`.
+    pub from: Option<String>,
+    /// The URL of an SMTP relay to send the email through with `curl`, e.g.
+    /// `smtp://mail.example.com:25`. The email is handed to `sendmail` if
+    /// omitted.
+    pub relay: Option<String>,
+}
+
+/// How to post events to a webhook.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// The URL to post events to.
+    pub url: String,
+    /// The payload format the webhook expects.
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// The kinds of events to post. Defaults to warnings and failures.
+    #[serde(default = "default_webhook_events")]
+    pub events: Vec<EventKind>,
+}
+
+fn default_webhook_events() -> Vec<EventKind> {
+    vec![EventKind::Warning, EventKind::Failure]
+}
+
+/// The payload format of a webhook.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    /// A JSON object with all details of the event.
+    #[default]
+    Json,
+    /// A JSON object with a `text` field, as expected by Slack and
+    /// Slack-compatible webhooks.
+    Slack,
+    /// A JSON object with a `text` field, as expected by Matrix webhook
+    /// bridges such as hookshot.
+    Matrix,
+    /// A plain text message with title, priority, and tags in headers, as
+    /// expected by ntfy topic URLs.
+    Ntfy,
+}
+
+/// The kind of an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A snapshot config was processed successfully.
+    Success,
+    /// Something went wrong, but processing continued.
+    Warning,
+    /// Processing a snapshot config failed.
+    Failure,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventKind::Success => write!(f, "success"),
+            EventKind::Warning => write!(f, "warning"),
+            EventKind::Failure => write!(f, "failure"),
+        }
+    }
+}
+
+/// Something that happened during a run that is worth notifying about.
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// What kind of event this is.
+    pub kind: EventKind,
+    /// The name of the snapshot config.
+    pub snapshot: String,
+    /// The command that was executed, e.g. `run` or `send`.
+    pub command: String,
+    /// When the event happened.
+    pub time: DateTime<Local>,
+    /// A description of the event. For failures this includes the failing
+    /// command and its stderr.
+    pub message: String,
+}
+
+impl<'a> State<'a> {
+    /// Record an event to notify about.
+    pub(crate) fn record_event(
+        &mut self,
+        kind: EventKind,
+        snapshot: &str,
+        command: &str,
+        message: String,
+    ) {
+        self.events.push(Event {
+            kind,
+            snapshot: snapshot.to_owned(),
+            command: command.to_owned(),
+            time: Local::now(),
+            message,
+        });
+    }
+
+    /// Record that processing a snapshot config failed.
+    pub fn record_failure(&mut self, snapshot: &str, command: &str, error: &anyhow::Error) {
+        self.record_event(
+            EventKind::Failure,
+            snapshot,
+            command,
+            format!("{:?}", error),
+        );
+    }
+
+    /// Record that processing a snapshot config succeeded, summarizing the
+    /// actions performed since `first_action`.
+    pub fn record_success(&mut self, snapshot: &str, command: &str, first_action: usize) {
+        let count = |kind| {
+            self.actions[first_action..]
+                .iter()
+                .filter(|action| action.action == kind)
+                .count()
+        };
+        let message = format!(
+            "Snapshots taken: {}, deleted: {}, sent: {}",
+            count(ActionKind::Take),
+            count(ActionKind::Delete),
+            count(ActionKind::Send)
+        );
+        self.record_event(EventKind::Success, snapshot, command, message);
+    }
+
+    /// Send notifications about the events recorded so far, and forget them.
+    /// Failures to notify are only logged.
+    pub fn send_notifications(&mut self, config: &NotificationConfig) {
+        let events = std::mem::take(&mut self.events);
+        let failures: Vec<_> = events
+            .iter()
+            .filter(|event| event.kind == EventKind::Failure)
+            .cloned()
+            .collect();
+        if let (Some(email), false) = (&config.email, failures.is_empty()) {
+            if let Err(e) = self.send_email(email, &failures) {
+                warn!("Sending notification email failed: {:#}", e);
+            }
+        }
+        for webhook in &config.webhook {
+            for event in events.iter().filter(|e| webhook.events.contains(&e.kind)) {
+                if let Err(e) = self.post_webhook(webhook, event) {
+                    warn!("Posting to webhook {} failed: {:#}", webhook.url, e);
+                }
+            }
+        }
+        if let Some(desktop) = &config.desktop {
+            for event in events.iter().filter(|e| desktop.events.contains(&e.kind)) {
+                if let Err(e) = self.show_desktop_notification(event) {
+                    warn!("Showing desktop notification failed: {:#}", e);
+                }
+            }
+        }
+    }
+
+    /// Show an event as a desktop notification with `notify-send`. When
+    /// running as root, e.g. from a systemd service, the notification is shown
+    /// in the session of every logged-in user.
+    fn show_desktop_notification(&self, event: &Event) -> Result<()> {
+        let urgency = match event.kind {
+            EventKind::Success => "low",
+            EventKind::Warning => "normal",
+            EventKind::Failure => "critical",
+        };
+        let summary = match event.kind {
+            EventKind::Success => format!("Snapshots of {} updated", event.snapshot),
+            EventKind::Warning => format!("Problem with snapshots of {}", event.snapshot),
+            EventKind::Failure => format!("Snapshots of {} failed", event.snapshot),
+        };
+        let notify_send = || {
+            let mut cmd = Command::new("notify-send");
+            cmd.arg("--app-name")
+                .arg(crate_name!())
+                .arg("--icon")
+                .arg("drive-harddisk")
+                .arg("--urgency")
+                .arg(urgency)
+                .arg(&summary)
+                .arg(&event.message);
+            cmd
+        };
+        let mut cmds = Vec::new();
+        if unsafe { libc::geteuid() } != 0 {
+            cmds.push(notify_send());
+        } else {
+            for entry in std::fs::read_dir("/run/user")? {
+                let dir = entry?.path();
+                let uid = dir
+                    .file_name()
+                    .and_then(|n| n.to_str()?.parse::<u32>().ok());
+                let bus = dir.join("bus");
+                let (uid, meta) = match (uid, std::fs::metadata(&bus)) {
+                    (Some(uid), Ok(meta)) if uid != 0 => (uid, meta),
+                    _ => continue,
+                };
+                let mut cmd = notify_send();
+                cmd.uid(uid).gid(meta.gid()).env(
+                    "DBUS_SESSION_BUS_ADDRESS",
+                    format!("unix:path={}", bus.display()),
+                );
+                cmds.push(cmd);
+            }
+        }
+        for mut cmd in cmds {
+            if self.dry_run {
+                if self.output == OutputFormat::Text {
+                    println!("{}", color::dim(format!("{:?}", cmd)));
+                }
+                continue;
+            }
+            debug!(
+                "Showing {} of {} on the desktop",
+                event.kind, event.snapshot
+            );
+            run(&mut cmd)?;
+        }
+        Ok(())
+    }
+
+    /// Post an event to a webhook.
+    fn post_webhook(&self, config: &WebhookConfig, event: &Event) -> Result<()> {
+        let host = hostname().unwrap_or_else(|_| String::from("localhost"));
+        let title = format!(
+            "{}: {} {} on {}",
+            crate_name!(),
+            event.snapshot,
+            event.kind,
+            host
+        );
+        let text = format!("{}\n{}", title, event.message);
+        let mut cmd = Command::new("curl");
+        cmd.arg("-fsS")
+            .arg("--max-time")
+            .arg("10")
+            .arg("-o")
+            .arg("/dev/null")
+            .arg("-X")
+            .arg("POST");
+        let body = match config.format {
+            WebhookFormat::Json => {
+                cmd.arg("-H").arg("Content-Type: application/json");
+                serde_json::json!({
+                    "host": host,
+                    "kind": event.kind,
+                    "snapshot": event.snapshot,
+                    "command": event.command,
+                    "time": event.time,
+                    "message": event.message,
+                })
+                .to_string()
+            }
+            WebhookFormat::Slack | WebhookFormat::Matrix => {
+                cmd.arg("-H").arg("Content-Type: application/json");
+                serde_json::json!({ "text": text }).to_string()
+            }
+            WebhookFormat::Ntfy => {
+                let (priority, tags) = match event.kind {
+                    EventKind::Success => ("default", "white_check_mark"),
+                    EventKind::Warning => ("high", "warning"),
+                    EventKind::Failure => ("urgent", "rotating_light"),
+                };
+                cmd.arg("-H")
+                    .arg(format!("Title: {}", title))
+                    .arg("-H")
+                    .arg(format!("Priority: {}", priority))
+                    .arg("-H")
+                    .arg(format!("Tags: {}", tags));
+                event.message.clone()
+            }
+        };
+        cmd.arg("--data-binary").arg("@-").arg(&config.url);
+        if self.dry_run {
+            if self.output == OutputFormat::Text {
+                println!("{}", color::dim(format!("{:?}", cmd)));
+            }
+            return Ok(());
+        }
+        debug!(
+            "Posting {} of {} to {}",
+            event.kind, event.snapshot, config.url
+        );
+        run_with_input(&mut cmd, &body)
+    }
+
+    /// Send an email summarizing a list of failures.
+    fn send_email(&self, config: &EmailConfig, failures: &[Event]) -> Result<()> {
+        let host = hostname().unwrap_or_else(|_| String::from("localhost"));
+        let from = config
+            .from
+            .clone()
+            .unwrap_or_else(|| format!("{}@{}", crate_name!(), host));
+        let mut message = String::new();
+        message.push_str(&format!("From: {}\r\n", from));
+        message.push_str(&format!("To: {}\r\n", config.to.join(", ")));
+        message.push_str(&format!(
+            "Subject: {}: {} failure(s) on {}\r\n",
+            crate_name!(),
+            failures.len(),
+            host
+        ));
+        message.push_str(&format!("Date: {}\r\n", Local::now().to_rfc2822()));
+        message.push_str("Content-Type: text/plain; charset=utf-8\r\n\r\n");
+        for failure in failures {
+            message.push_str(&format!(
+                "Snapshot {} failed during `{}` at {}:\r\n\r\n",
+                failure.snapshot,
+                failure.command,
+                failure.time.format("%F %T %:z")
+            ));
+            for line in failure.message.lines() {
+                message.push_str("    ");
+                message.push_str(line);
+                message.push_str("\r\n");
+            }
+            message.push_str("\r\n");
+        }
+
+        let mut cmd = match &config.relay {
+            Some(relay) => {
+                let mut cmd = Command::new("curl");
+                cmd.arg("-sS")
+                    .arg("--url")
+                    .arg(relay)
+                    .arg("--mail-from")
+                    .arg(&from);
+                for to in &config.to {
+                    cmd.arg("--mail-rcpt").arg(to);
+                }
+                cmd.arg("--upload-file").arg("-");
+                cmd
+            }
+            None => {
+                let mut cmd = Command::new("sendmail");
+                cmd.arg("-t").arg("-i");
+                cmd
+            }
+        };
+        if self.dry_run {
+            if self.output == OutputFormat::Text {
+                println!("{}", color::dim(format!("{:?}", cmd)));
+            }
+            return Ok(());
+        }
+        debug!("Sending notification email to {}", config.to.join(", "));
+        run_with_input(&mut cmd, &message)
+    }
+}
+
+/// Execute a command with the given input on stdin.
+fn run_with_input(cmd: &mut Command, input: &str) -> Result<()> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to execute {:?}", cmd))?;
+    child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(String::from_utf8_lossy(&output.stderr)
+            .trim()
+            .to_owned()))
+        .with_context(|| format!("Command {:?} failed", cmd));
+    }
+    Ok(())
+}
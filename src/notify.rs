@@ -0,0 +1,93 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Notifying systemd about the state of the daemon, for services with
+//! `Type=notify` and `WatchdogSec=`.
+
+use std::{
+    os::{
+        linux::net::SocketAddrExt as _,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    time::Duration,
+};
+
+/// A connection to the service manager's notification socket.
+pub struct Notifier {
+    /// The socket to send notifications through, and its address.
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    /// How often the service manager expects a watchdog keepalive.
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Connect to the socket in `NOTIFY_SOCKET`, if any. The variables are
+    /// removed from the environment such that hooks do not inherit them.
+    pub fn from_env() -> Self {
+        let path = std::env::var_os("NOTIFY_SOCKET");
+        let watchdog_usec = std::env::var("WATCHDOG_USEC").ok();
+        let watchdog_pid = std::env::var("WATCHDOG_PID").ok();
+        for var in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+            std::env::remove_var(var);
+        }
+
+        let socket = path.and_then(|path| {
+            let bytes = std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str());
+            let addr = match bytes.strip_prefix(b"@") {
+                Some(name) => SocketAddr::from_abstract_name(name),
+                None => SocketAddr::from_pathname(&path),
+            };
+            let socket = addr.and_then(|addr| Ok((UnixDatagram::unbound()?, addr)));
+            match socket {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    warn!("Cannot connect to notification socket {:?}: {}", path, e);
+                    None
+                }
+            }
+        });
+        let watchdog = watchdog_usec
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| match &watchdog_pid {
+                Some(pid) => pid.parse() == Ok(std::process::id()),
+                None => true,
+            })
+            .map(Duration::from_micros);
+        if let Some(watchdog) = watchdog {
+            debug!("Watchdog expects a keepalive every {:?}", watchdog);
+        }
+        Self { socket, watchdog }
+    }
+
+    /// Send a notification such as `READY=1` to the service manager.
+    pub fn notify(&self, state: &str) {
+        if let Some((socket, addr)) = &self.socket {
+            trace!("Notifying service manager: {}", state);
+            if let Err(e) = socket.send_to_addr(state.as_bytes(), addr) {
+                debug!("Notifying service manager failed: {}", e);
+            }
+        }
+    }
+
+    /// Tell the service manager that the daemon has started up.
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    /// Update the status shown by `systemctl status`.
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    /// Send a watchdog keepalive if the service manager expects them.
+    pub fn keepalive(&self) {
+        if self.watchdog.is_some() {
+            self.notify("WATCHDOG=1");
+        }
+    }
+
+    /// How long to wait at most between two keepalives, which is half the
+    /// watchdog timeout as recommended by systemd.
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|watchdog| watchdog / 2)
+    }
+}
@@ -0,0 +1,684 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Human-readable and machine-readable reporting of results.
+
+use crate::{
+    color, history::HistoryEntry, retention::Reason, scrub::ScrubStatus, size::ByteSize,
+    status::RunStatus, RotationPlan, SnapshotConfig, SnapshotSet,
+};
+use anyhow::{bail, Result};
+use chrono::{DateTime, FixedOffset};
+use humantime::format_duration;
+use serde::{Deserialize, Serialize, Serializer};
+use std::{io::Write, os::unix::ffi::OsStrExt, path::PathBuf, str::FromStr, time::Duration};
+
+/// The format in which results are printed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable text.
+    #[default]
+    Text,
+    /// Machine-readable JSON.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!("Unknown output format `{}`", s),
+        }
+    }
+}
+
+/// The existing snapshots of one snapshot config.
+#[derive(Debug, Serialize)]
+pub struct SnapshotList {
+    /// The name of the snapshot config.
+    pub name: String,
+    /// The snapshots, newest first.
+    pub snapshots: Vec<ListedSnapshot>,
+}
+
+/// A single existing snapshot.
+#[derive(Debug, Serialize)]
+pub struct ListedSnapshot {
+    /// The date parsed from the snapshot name.
+    pub date: DateTime<FixedOffset>,
+    /// The path of the snapshot subvolume.
+    pub path: PathBuf,
+    /// The age of the snapshot.
+    #[serde(rename = "age_seconds", serialize_with = "seconds")]
+    pub age: Duration,
+    /// The tag the snapshot was taken with.
+    pub tag: Option<String>,
+    /// The spacing rule that currently applies to the snapshot.
+    pub rule: Option<SpacingRule>,
+    /// Whether the snapshot is protected from rotation.
+    pub held: bool,
+    /// The size of the send stream if the catalog records the snapshot as
+    /// replicated.
+    pub sent_bytes: Option<u64>,
+}
+
+/// A spacing rule from the config.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SpacingRule {
+    /// The age after which the rule applies.
+    #[serde(rename = "age_seconds", serialize_with = "seconds")]
+    pub age: Duration,
+    /// The spacing the rule enforces between snapshots.
+    #[serde(rename = "spacing_seconds", serialize_with = "seconds")]
+    pub spacing: Duration,
+}
+
+/// The decisions the next rotation makes about the snapshots of one snapshot
+/// config.
+#[derive(Debug, Serialize)]
+pub struct SnapshotPlan {
+    /// The name of the snapshot config.
+    pub name: String,
+    /// The snapshots, newest first.
+    pub snapshots: Vec<PlannedSnapshot>,
+}
+
+/// The decision about a single existing snapshot.
+#[derive(Debug, Serialize)]
+pub struct PlannedSnapshot {
+    /// The date parsed from the snapshot name.
+    pub date: DateTime<FixedOffset>,
+    /// The path of the snapshot subvolume.
+    pub path: PathBuf,
+    /// The age of the snapshot.
+    #[serde(rename = "age_seconds", serialize_with = "seconds")]
+    pub age: Duration,
+    /// Whether the snapshot is deleted.
+    pub delete: bool,
+    /// Why the snapshot is kept or deleted.
+    #[serde(flatten)]
+    pub reason: Reason,
+}
+
+impl SnapshotPlan {
+    /// Describe the decisions of a rotation plan about a set of snapshots.
+    pub fn new(snapshot: &SnapshotConfig, set: &SnapshotSet, plan: &RotationPlan) -> Self {
+        Self {
+            name: snapshot.name.clone(),
+            snapshots: set
+                .entries()
+                .iter()
+                .map(|entry| PlannedSnapshot {
+                    date: entry.date,
+                    path: entry.path.clone(),
+                    age: entry.age,
+                    delete: plan.delete.contains(&entry.path),
+                    reason: plan.reasons.get(&entry.path).cloned().unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The paths that changed between two snapshots of one snapshot config.
+#[derive(Debug, Serialize)]
+pub struct SnapshotDiff {
+    /// The name of the snapshot config.
+    pub name: String,
+    /// The older snapshot.
+    pub from: PathBuf,
+    /// The newer snapshot.
+    pub to: PathBuf,
+    /// The paths that changed, relative to the snapshots.
+    pub changes: Vec<ChangedPath>,
+}
+
+/// A path that changed between two snapshots.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChangedPath {
+    /// How the path changed.
+    pub kind: ChangeKind,
+    /// The path relative to the snapshots.
+    pub path: PathBuf,
+}
+
+/// How a path changed between two snapshots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    /// The path only exists in the newer snapshot.
+    Created,
+    /// The path exists in both snapshots, with different contents or
+    /// attributes.
+    Modified,
+    /// The path only exists in the older snapshot.
+    Deleted,
+}
+
+/// The outcome of comparing the files of a snapshot against a newer snapshot
+/// or the live subvolume.
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    /// The name of the snapshot config.
+    pub name: String,
+    /// The older snapshot.
+    pub from: PathBuf,
+    /// The newer snapshot or the live subvolume.
+    pub to: PathBuf,
+    /// The number of files whose contents were compared.
+    pub compared: usize,
+    /// The number of files not compared because they changed on purpose.
+    pub skipped: usize,
+    /// The files found to differ or to be unreadable.
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+/// A file whose contents should be identical in two places, but are not.
+#[derive(Debug, Serialize)]
+pub struct Discrepancy {
+    /// The path relative to the snapshots.
+    pub path: PathBuf,
+    /// What is wrong with the file.
+    pub problem: String,
+}
+
+/// A subvolume in a snapshot directory whose name matches the format of no
+/// config.
+#[derive(Debug, Serialize)]
+pub struct Orphan {
+    /// The name of the snapshot config whose snapshot directory holds it.
+    pub config: String,
+    /// The path of the subvolume.
+    pub path: PathBuf,
+}
+
+/// The space occupied by the snapshots of one snapshot config.
+#[derive(Debug, Serialize)]
+pub struct SnapshotUsage {
+    /// The name of the snapshot config.
+    pub name: String,
+    /// The total number of bytes used exclusively by the snapshots.
+    pub exclusive: u64,
+    /// The snapshots, largest exclusive size first.
+    pub snapshots: Vec<SnapshotSize>,
+}
+
+/// The space occupied by a single snapshot.
+#[derive(Debug, Serialize)]
+pub struct SnapshotSize {
+    /// The path of the snapshot subvolume.
+    pub path: PathBuf,
+    /// The number of bytes referenced by the snapshot, including ones shared
+    /// with other snapshots or the subvolume.
+    pub referenced: u64,
+    /// The number of bytes used only by this snapshot, which deleting it
+    /// frees.
+    pub exclusive: u64,
+}
+
+/// A summary of the snapshots of one snapshot config.
+#[derive(Debug, Serialize)]
+pub struct SnapshotStatus {
+    /// The name of the snapshot config.
+    pub name: String,
+    /// The number of existing snapshots.
+    pub count: usize,
+    /// The date of the newest snapshot.
+    pub newest: Option<DateTime<FixedOffset>>,
+    /// The date of the oldest snapshot.
+    pub oldest: Option<DateTime<FixedOffset>>,
+    /// The outcome of the last run.
+    pub last_run: Option<RunStatus>,
+    /// The outcome of the last scrub of the config's filesystem.
+    pub last_scrub: Option<ScrubStatus>,
+}
+
+/// The outcome of checking a snapshot config.
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    /// The name of the snapshot config.
+    pub name: String,
+    /// The individual checks performed.
+    pub checks: Vec<Check>,
+}
+
+/// A single check performed on a snapshot config.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    /// Whether the check passed.
+    pub ok: bool,
+    /// What was checked.
+    pub message: String,
+}
+
+/// The outcome of holding or releasing a snapshot.
+#[derive(Debug, Serialize)]
+pub struct HoldChange {
+    /// The path of the snapshot.
+    pub path: PathBuf,
+    /// Whether the snapshot is held now.
+    pub held: bool,
+    /// Whether the hold was placed or released, rather than already being in
+    /// that state.
+    pub changed: bool,
+}
+
+/// The kind of an action performed on a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    /// A new snapshot is taken.
+    Take,
+    /// An existing snapshot is deleted.
+    Delete,
+    /// An existing snapshot is moved into the trash, to be deleted later.
+    Trash,
+    /// An existing snapshot is sent to a replication target.
+    Send,
+    /// A subvolume is restored from an existing snapshot.
+    Restore,
+    /// A snapshot is pulled from a source host.
+    Pull,
+}
+
+impl ActionKind {
+    /// The name of the action, as used in JSON output.
+    pub fn name(self) -> &'static str {
+        match self {
+            ActionKind::Take => "take",
+            ActionKind::Delete => "delete",
+            ActionKind::Trash => "trash",
+            ActionKind::Send => "send",
+            ActionKind::Restore => "restore",
+            ActionKind::Pull => "pull",
+        }
+    }
+}
+
+/// An action performed, or planned in a dry run, on a snapshot.
+#[derive(Debug, Serialize)]
+pub struct Action {
+    /// The name of the snapshot config.
+    pub snapshot: String,
+    /// What is done to the snapshot.
+    pub action: ActionKind,
+    /// The path of the snapshot subvolume.
+    pub path: PathBuf,
+    /// The commands that implement the action, or the equivalent `btrfs`
+    /// commands for actions performed through ioctls. Multiple commands form
+    /// a pipeline.
+    pub commands: Vec<Vec<String>>,
+    /// Whether the command was only printed rather than executed.
+    pub dry_run: bool,
+}
+
+/// Print the existing snapshots of a set of snapshot configs.
+pub fn print_list(format: OutputFormat, lists: &[SnapshotList]) -> Result<()> {
+    if format == OutputFormat::Json {
+        return print_json(&lists);
+    }
+    for list in lists {
+        println!("{}:", list.name);
+        for snapshot in &list.snapshots {
+            let rule = match (&snapshot.tag, snapshot.rule) {
+                (Some(tag), _) => format!("tag {}", tag),
+                (None, Some(rule)) => format!(
+                    "every {} after {}",
+                    format_duration(rule.spacing),
+                    format_duration(rule.age)
+                ),
+                (None, None) => String::from("keep all"),
+            };
+            println!(
+                "  {}  {:>20}  {}  ({}){}{}",
+                snapshot.date,
+                format_duration(snapshot.age).to_string(),
+                snapshot.path.display(),
+                rule,
+                if snapshot.held { "  [held]" } else { "" },
+                match snapshot.sent_bytes {
+                    Some(bytes) => format!("  [sent {}]", ByteSize(bytes)),
+                    None => String::new(),
+                }
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Print the path of every listed snapshot followed by `terminator`, such
+/// that scripts can consume them regardless of the output format.
+pub fn print_paths(lists: &[SnapshotList], terminator: u8) -> Result<()> {
+    write_paths(std::io::stdout().lock(), lists, terminator)
+}
+
+/// Write the path of every listed snapshot followed by `terminator`.
+fn write_paths(mut writer: impl Write, lists: &[SnapshotList], terminator: u8) -> Result<()> {
+    for snapshot in lists.iter().flat_map(|list| &list.snapshots) {
+        writer.write_all(snapshot.path.as_os_str().as_bytes())?;
+        writer.write_all(&[terminator])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Print the paths that changed between two snapshots.
+pub fn print_diff(format: OutputFormat, diff: &SnapshotDiff) -> Result<()> {
+    if format == OutputFormat::Json {
+        return print_json(diff);
+    }
+    for change in &diff.changes {
+        let marker = match change.kind {
+            ChangeKind::Created => color::keep("+"),
+            ChangeKind::Modified => color::warn("M"),
+            ChangeKind::Deleted => color::delete("-"),
+        };
+        println!("{} {}", marker, change.path.display());
+    }
+    Ok(())
+}
+
+/// Print the outcome of verifying a snapshot.
+pub fn print_verify(format: OutputFormat, report: &VerifyReport) -> Result<()> {
+    if format == OutputFormat::Json {
+        return print_json(report);
+    }
+    for discrepancy in &report.discrepancies {
+        println!(
+            "{}  {}: {}",
+            color::delete("FAIL"),
+            discrepancy.path.display(),
+            discrepancy.problem
+        );
+    }
+    println!(
+        "Compared {} file(s) of {} against {}, skipped {} changed file(s)",
+        report.compared,
+        report.from.display(),
+        report.to.display(),
+        report.skipped
+    );
+    Ok(())
+}
+
+/// Print the rotation plan of a set of snapshot configs as a table.
+pub fn print_plan(format: OutputFormat, plans: &[SnapshotPlan]) -> Result<()> {
+    if format == OutputFormat::Json {
+        return print_json(&plans);
+    }
+    for plan in plans {
+        println!("{}:", plan.name);
+        let rows: Vec<_> = plan
+            .snapshots
+            .iter()
+            .map(|snapshot| {
+                [
+                    String::from(if snapshot.delete { "DELETE" } else { "KEEP" }),
+                    snapshot.date.to_string(),
+                    format_duration(snapshot.age).to_string(),
+                    snapshot.path.display().to_string(),
+                    snapshot.reason.rule.clone(),
+                ]
+            })
+            .collect();
+        let header = ["DECISION", "DATE", "AGE", "PATH", "RULE"].map(String::from);
+        let mut widths = [0; 5];
+        for row in std::iter::once(&header).chain(&rows) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        for row in std::iter::once(&header).chain(&rows) {
+            let mut line: Vec<_> = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:<1$}", cell, width))
+                .collect();
+            line[0] = match row[0].as_str() {
+                "KEEP" => color::keep(&line[0]),
+                "DELETE" => color::delete(&line[0]),
+                _ => line[0].clone(),
+            };
+            println!("  {}", line.join("  ").trim_end());
+        }
+        let deleted = plan.snapshots.iter().filter(|s| s.delete).count();
+        println!(
+            "  {} snapshot(s) kept, {} deleted",
+            plan.snapshots.len() - deleted,
+            deleted
+        );
+    }
+    Ok(())
+}
+
+/// Print why each snapshot of a snapshot config is kept or deleted.
+pub fn print_explanation(plan: &SnapshotPlan) {
+    println!("Rotating snapshots of {}:", plan.name);
+    for snapshot in &plan.snapshots {
+        let decision = match snapshot.delete {
+            true => color::delete("DELETE"),
+            false => color::keep("KEEP  "),
+        };
+        println!("  {}  {}", decision, snapshot.path.display());
+        match &snapshot.reason.detail {
+            Some(detail) => println!("          {}: {}", snapshot.reason.rule, detail),
+            None => println!("          {}", snapshot.reason.rule),
+        }
+    }
+}
+
+/// Print a summary of the snapshots of a set of snapshot configs.
+pub fn print_status(format: OutputFormat, statuses: &[SnapshotStatus]) -> Result<()> {
+    if format == OutputFormat::Json {
+        return print_json(&statuses);
+    }
+    for status in statuses {
+        println!("{}:", status.name);
+        println!("  Snapshots: {}", status.count);
+        if let Some(newest) = status.newest {
+            println!("  Newest:    {}", newest);
+        }
+        if let Some(oldest) = status.oldest {
+            println!("  Oldest:    {}", oldest);
+        }
+        match &status.last_run {
+            Some(run) if run.success => {
+                println!("  Last run:  {} `{}` succeeded", run.time, run.command)
+            }
+            Some(run) => println!(
+                "  Last run:  {} `{}` failed: {}",
+                run.time,
+                run.command,
+                run.error.as_deref().unwrap_or("unknown error")
+            ),
+            None => println!("  Last run:  never"),
+        }
+        match &status.last_scrub {
+            Some(scrub) if scrub.success => println!(
+                "  Scrubbed:  {} ({})",
+                scrub.time,
+                scrub.summary.as_deref().unwrap_or("done")
+            ),
+            Some(scrub) => println!(
+                "  Scrubbed:  {} failed: {}",
+                scrub.time,
+                scrub.summary.as_deref().unwrap_or("unknown error")
+            ),
+            None => (),
+        }
+    }
+    Ok(())
+}
+
+/// Print the outcome of checking a set of snapshot configs.
+pub fn print_check(format: OutputFormat, reports: &[CheckReport]) -> Result<()> {
+    if format == OutputFormat::Json {
+        return print_json(&reports);
+    }
+    for report in reports {
+        println!("{}:", report.name);
+        for check in &report.checks {
+            println!(
+                "  {:<4}  {}",
+                if check.ok { "ok" } else { "FAIL" },
+                check.message
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Print the space occupied by the snapshots of a set of snapshot configs.
+pub fn print_usage(format: OutputFormat, usages: &[SnapshotUsage]) -> Result<()> {
+    if format == OutputFormat::Json {
+        return print_json(&usages);
+    }
+    for usage in usages {
+        println!("{}: {} exclusive", usage.name, ByteSize(usage.exclusive));
+        println!("  {:>10}  {:>10}  PATH", "EXCLUSIVE", "REFERENCED");
+        for snapshot in &usage.snapshots {
+            println!(
+                "  {:>10}  {:>10}  {}",
+                ByteSize(snapshot.exclusive).to_string(),
+                ByteSize(snapshot.referenced).to_string(),
+                snapshot.path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Print the outcome of holding or releasing a snapshot.
+pub fn print_hold(format: OutputFormat, change: &HoldChange) -> Result<()> {
+    if format == OutputFormat::Json {
+        return print_json(change);
+    }
+    let path = change.path.display();
+    match (change.held, change.changed) {
+        (true, true) => println!("Holding snapshot {}", path),
+        (true, false) => println!("Snapshot {} is already held", path),
+        (false, true) => println!("Releasing snapshot {}", path),
+        (false, false) => println!("Snapshot {} is not held", path),
+    }
+    Ok(())
+}
+
+/// Print the orphaned snapshots found in snapshot directories.
+pub fn print_orphans(format: OutputFormat, orphans: &[Orphan]) -> Result<()> {
+    if format == OutputFormat::Json {
+        return print_json(&orphans);
+    }
+    if orphans.is_empty() {
+        println!("No orphaned snapshots found");
+    }
+    for orphan in orphans {
+        println!(
+            "{} {} (in snapshot dir of {})",
+            color::warn("Orphaned snapshot"),
+            orphan.path.display(),
+            orphan.config
+        );
+    }
+    Ok(())
+}
+
+/// Print the actions recorded in the history, oldest first.
+pub fn print_history(format: OutputFormat, entries: &[HistoryEntry]) -> Result<()> {
+    if format == OutputFormat::Json {
+        return print_json(&entries);
+    }
+    for entry in entries {
+        let outcome = match &entry.error {
+            None => String::from("ok"),
+            Some(error) => format!("FAILED: {}", error),
+        };
+        println!(
+            "{}  {:<8}  {:<7}  {}  {}",
+            entry.time,
+            entry.config,
+            entry.action.name(),
+            entry.path.display(),
+            outcome
+        );
+    }
+    Ok(())
+}
+
+/// Print the actions performed during a run. Text output is printed as the
+/// actions happen, so this only produces JSON output.
+pub fn print_actions(format: OutputFormat, actions: &[Action]) -> Result<()> {
+    if format == OutputFormat::Json {
+        print_json(&actions)?;
+    }
+    Ok(())
+}
+
+/// Print whether each processed snapshot config succeeded, at the end of a
+/// run. JSON output reports failures as structured errors instead.
+pub fn print_summary(format: OutputFormat, outcomes: &[(&SnapshotConfig, Result<()>)]) {
+    if format == OutputFormat::Json || outcomes.is_empty() {
+        return;
+    }
+    let width = outcomes
+        .iter()
+        .map(|(snapshot, _)| snapshot.name.len())
+        .max()
+        .unwrap_or(0);
+    let failed = outcomes
+        .iter()
+        .filter(|(_, result)| result.is_err())
+        .count();
+    println!(
+        "Summary: {} of {} snapshot config(s) succeeded",
+        outcomes.len() - failed,
+        outcomes.len()
+    );
+    for (snapshot, result) in outcomes {
+        match result {
+            Ok(()) => println!(
+                "  {:<width$}  {}",
+                snapshot.name,
+                color::keep("ok"),
+                width = width
+            ),
+            Err(e) => println!(
+                "  {:<width$}  {}: {:#}",
+                snapshot.name,
+                color::delete("failed"),
+                e,
+                width = width
+            ),
+        }
+    }
+}
+
+/// Print a value as pretty JSON to stdout.
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Serialize a duration as a number of seconds.
+fn seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Fixture;
+
+    #[test]
+    fn paths_written_with_terminator() {
+        let fixture = Fixture::new("paths", "");
+        let paths = fixture.add_snapshots(&[1, 2]);
+        let (mut state, _) = fixture.state();
+        let lists = [state.list_snapshots(fixture.snapshot()).unwrap()];
+        let mut output = vec![];
+        write_paths(&mut output, &lists, b'\0').unwrap();
+        let expected = format!("{}\0{}\0", paths[0].display(), paths[1].display());
+        assert_eq!(output, expected.as_bytes());
+    }
+}
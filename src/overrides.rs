@@ -0,0 +1,107 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Overriding config values from the command line, such as
+//! `--set home.snapshot_dir=/mnt/alt`.
+//!
+//! A key whose first part names a snapshot config only applies to that
+//! config. Any other key is set in the generic config as well as in every
+//! snapshot config that sets it itself, such that it overrides the resolved
+//! value everywhere.
+
+use anyhow::{anyhow, bail, Result};
+use std::str::FromStr;
+use toml::Value;
+
+/// A config value set on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    /// The dotted key to set, split into its parts.
+    pub key: Vec<String>,
+    /// The value to set the key to.
+    pub value: Value,
+}
+
+impl FromStr for Override {
+    type Err = anyhow::Error;
+
+    /// Parse a `KEY=VALUE` pair. The value is parsed as TOML if possible, such
+    /// that `keep_max=5` sets a number, and taken as a string otherwise.
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected `KEY=VALUE` instead of `{}`", s))?;
+        let key: Vec<_> = key.trim().split('.').map(str::to_owned).collect();
+        if key.iter().any(String::is_empty) {
+            bail!("Invalid key in `{}`", s);
+        }
+        let value = value.trim();
+        let value = toml::from_str::<Value>(&format!("value = {}", value))
+            .ok()
+            .and_then(|mut table| table.as_table_mut()?.remove("value"))
+            .unwrap_or_else(|| Value::String(value.to_owned()));
+        Ok(Override { key, value })
+    }
+}
+
+impl Override {
+    /// Apply the override to a config, given as a TOML value.
+    pub fn apply(&self, config: &mut Value) -> Result<()> {
+        let snapshots = config.get_mut("snapshots").and_then(Value::as_table_mut);
+        if let Some(snapshots) = snapshots {
+            if let (Some(snapshot), true) = (snapshots.get_mut(&self.key[0]), self.key.len() > 1) {
+                return set(snapshot, &self.key[1..], self.value.clone());
+            }
+            for (_, snapshot) in snapshots.iter_mut() {
+                if snapshot.get(&self.key[0]).is_some() {
+                    set(snapshot, &self.key, self.value.clone())?;
+                }
+            }
+        }
+        set(config, &self.key, self.value.clone())
+    }
+}
+
+/// Set a dotted key in a table, creating intermediate tables as needed.
+fn set(table: &mut Value, key: &[String], value: Value) -> Result<()> {
+    let table = table
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("Cannot set `{}` in a non-table value", key.join(".")))?;
+    match key {
+        [last] => {
+            table.insert(last.clone(), value);
+            Ok(())
+        }
+        [first, rest @ ..] => set(
+            table
+                .entry(first.clone())
+                .or_insert_with(|| Value::Table(Default::default())),
+            rest,
+            value,
+        ),
+        [] => unreachable!("empty override key"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::Fixture, Config};
+    use std::path::Path;
+
+    #[test]
+    fn overrides_replace_resolved_values() {
+        let fixture = Fixture::new("overrides", "keep_max = 3\n[snapshots.home]\nkeep_max = 7");
+        let overrides: Vec<Override> = vec![
+            "keep_max=5".parse().unwrap(),
+            "home.snapshot_dir=/mnt/alt".parse().unwrap(),
+        ];
+        let config =
+            Config::load_with_overrides(&fixture.dir.join("config.toml"), &overrides, None)
+                .unwrap();
+        let home = &config.snapshots["home"];
+        assert_eq!(home.keep_max, Some(5));
+        assert_eq!(home.snapshot_dir.as_deref(), Some(Path::new("/mnt/alt")));
+        assert_eq!(config.snapshots["data"].keep_max, Some(5));
+        assert_ne!(config.snapshots["data"].snapshot_dir, home.snapshot_dir);
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Processing snapshot configs on different filesystems concurrently.
+
+use crate::{executor::Executor, notification::Event, output::Action, SnapshotConfig, State};
+use anyhow::Result;
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// The outcome of processing a single snapshot config on a worker thread.
+struct Outcome {
+    /// The index of the snapshot config in the processed list.
+    index: usize,
+    /// Whether processing succeeded.
+    result: Result<()>,
+    /// The actions performed for the snapshot config.
+    actions: Vec<Action>,
+    /// The events recorded for the snapshot config.
+    events: Vec<Event>,
+}
+
+impl<'a> State<'a> {
+    /// Process snapshot configs with `f`, pinging their dead man's switches
+    /// and recording the outcome of each for notifications.
+    ///
+    /// Configs that share a mount point, as source or as replication target,
+    /// are processed one after the other. Configs on different filesystems are
+    /// processed concurrently by up to `jobs` threads. A failing config does
+    /// not stop the others, such that every config gets an outcome. Dry runs are processed one config
+    /// at a time, such that the printed commands appear in order.
+    ///
+    /// Returns the result of each processed config, in the given order.
+    pub fn process_parallel(
+        &mut self,
+        snapshots: &[&'a SnapshotConfig],
+        command: &str,
+        jobs: Option<usize>,
+        f: impl Fn(&mut State<'a>, &'a SnapshotConfig) -> Result<()> + Sync,
+    ) -> Vec<(&'a SnapshotConfig, Result<()>)> {
+        let groups = group_by_mount_point(snapshots);
+        let jobs = match self.dry_run {
+            true => 1,
+            false => jobs.unwrap_or(groups.len()),
+        }
+        .clamp(1, groups.len().max(1));
+        debug!(
+            "Processing {} group(s) of snapshot configs with {} thread(s)",
+            groups.len(),
+            jobs
+        );
+
+        // All snapshots of the run share the same name, regardless of which
+        // worker takes them.
+        self.now.get_or_insert_with(chrono::Local::now);
+
+        // Hand each worker its own state that shares the executor, and let
+        // the workers pick groups of configs until none are left.
+        let executor: Arc<dyn Executor> = std::mem::take(&mut self.executor).into();
+        let queue = Mutex::new(groups.into_iter());
+        let workers: Vec<(State<'a>, Vec<Outcome>)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..jobs)
+                .map(|_| {
+                    let mut worker = State {
+                        dry_run: self.dry_run,
+                        output: self.output,
+                        holds: self.holds.clone(),
+                        catalog: self.catalog.clone(),
+                        history: self.history.clone(),
+                        now: self.now,
+                        tag: self.tag.clone(),
+                        explain: self.explain,
+                        yes: self.yes,
+                        keep_mounted: self.keep_mounted,
+                        executor: Box::new(executor.clone()),
+                        ..Default::default()
+                    };
+                    let (queue, f) = (&queue, &f);
+                    scope.spawn(move || {
+                        let mut outcomes = vec![];
+                        loop {
+                            let group = match queue.lock().unwrap().next() {
+                                Some(group) => group,
+                                None => break,
+                            };
+                            for index in group {
+                                let snapshot = snapshots[index];
+                                let result = worker.pinged(snapshot, |state| f(state, snapshot));
+                                match &result {
+                                    Ok(()) => worker.record_success(&snapshot.name, command, 0),
+                                    Err(e) => worker.record_failure(&snapshot.name, command, e),
+                                }
+                                outcomes.push(Outcome {
+                                    index,
+                                    result,
+                                    actions: std::mem::take(&mut worker.actions),
+                                    events: std::mem::take(&mut worker.events),
+                                });
+                            }
+                        }
+                        (worker, outcomes)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("snapshot worker panicked"))
+                .collect()
+        });
+        self.executor = Box::new(executor);
+
+        // Gather what the workers did, in the order of the configs.
+        let mut outcomes = vec![];
+        for (worker, worker_outcomes) in workers {
+            self.manual_mounts.extend(worker.manual_mounts);
+            self.metrics.merge(worker.metrics);
+            for outcome in &worker_outcomes {
+                self.catalog
+                    .merge(&worker.catalog, &snapshots[outcome.index].name);
+            }
+            outcomes.extend(worker_outcomes);
+        }
+        outcomes.sort_by_key(|outcome| outcome.index);
+        outcomes
+            .into_iter()
+            .map(|outcome| {
+                self.actions.extend(outcome.actions);
+                self.events.extend(outcome.events);
+                (snapshots[outcome.index], outcome.result)
+            })
+            .collect()
+    }
+}
+
+/// Split snapshot configs into groups that share no mount point with each
+/// other. Each group lists the indices of its configs in order.
+fn group_by_mount_point(snapshots: &[&SnapshotConfig]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<(Vec<&Path>, Vec<usize>)> = vec![];
+    for (index, snapshot) in snapshots.iter().enumerate() {
+        let mut mount_points: Vec<&Path> = snapshot.mount_point.as_deref().into_iter().collect();
+        if let Some(mount_point) = snapshot
+            .replicate
+            .as_ref()
+            .and_then(|r| r.mount_point.as_deref())
+        {
+            mount_points.push(mount_point);
+        }
+
+        // Merge all groups this config shares a mount point with.
+        let (shared, mut rest): (Vec<_>, Vec<_>) = groups
+            .into_iter()
+            .partition(|(points, _)| points.iter().any(|p| mount_points.contains(p)));
+        let mut indices = vec![];
+        for (points, group) in shared {
+            mount_points.extend(points);
+            indices.extend(group);
+        }
+        indices.push(index);
+        indices.sort_unstable();
+        rest.push((mount_points, indices));
+        groups = rest;
+    }
+    groups.sort_by_key(|(_, indices)| indices[0]);
+    groups.into_iter().map(|(_, indices)| indices).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Fixture;
+
+    #[test]
+    fn configs_sharing_mount_points_grouped() {
+        let fixture = Fixture::new(
+            "groups",
+            "[snapshots.home]\n\
+             mount_point = \"/backup\"\n\
+             [snapshots.usb]\n\
+             mount_point = \"/usb\"\n\
+             replicate = { mount_point = \"/backup\", target_dir = \"/backup/usb\" }\n\
+             [snapshots.other]\n\
+             mount_point = \"/other\"\n\
+             [snapshots.more]\n\
+             mount_point = \"/usb\"",
+        );
+        let snapshots: Vec<_> = fixture.config.snapshots.values().collect();
+        let names: Vec<_> = snapshots.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["home", "usb", "other", "more", "data"]);
+        assert_eq!(
+            group_by_mount_point(&snapshots),
+            vec![vec![0, 1, 3], vec![2], vec![4]]
+        );
+    }
+}
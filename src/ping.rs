@@ -0,0 +1,73 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Pinging a dead man's switch such as healthchecks.io, which raises an alert
+//! if snapshots stop being taken.
+
+use crate::{color, output::OutputFormat, run, SnapshotConfig, State};
+use anyhow::Result;
+use std::process::Command;
+
+/// A signal sent to a dead man's switch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Signal {
+    /// Processing of the snapshot has started.
+    Start,
+    /// Processing of the snapshot has finished successfully.
+    Success,
+    /// Processing of the snapshot has failed.
+    Fail,
+}
+
+impl<'a> State<'a> {
+    /// Run `f` on a snapshot, pinging the snapshot's `ping_url` when it starts
+    /// and when it succeeds or fails.
+    pub fn pinged(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        f: impl FnOnce(&mut Self) -> Result<()>,
+    ) -> Result<()> {
+        let url = match &snapshot.ping_url {
+            Some(url) => url.trim_end_matches('/'),
+            None => return f(self),
+        };
+        self.ping(url, Signal::Start, None);
+        let result = f(self);
+        match &result {
+            Ok(()) => self.ping(url, Signal::Success, None),
+            Err(e) => self.ping(url, Signal::Fail, Some(&format!("{:#}", e))),
+        }
+        result
+    }
+
+    /// Send a signal to a dead man's switch. Failures are only logged, since
+    /// they should not prevent snapshots from being taken.
+    fn ping(&self, url: &str, signal: Signal, message: Option<&str>) {
+        let url = match signal {
+            Signal::Start => format!("{}/start", url),
+            Signal::Success => url.to_owned(),
+            Signal::Fail => format!("{}/fail", url),
+        };
+        let mut cmd = Command::new("curl");
+        cmd.arg("-fsS")
+            .arg("--max-time")
+            .arg("10")
+            .arg("--retry")
+            .arg("3")
+            .arg("-o")
+            .arg("/dev/null");
+        if let Some(message) = message {
+            cmd.arg("--data-raw").arg(message);
+        }
+        cmd.arg(&url);
+        if self.dry_run {
+            if self.output == OutputFormat::Text {
+                println!("{}", color::dim(format!("{:?}", cmd)));
+            }
+            return;
+        }
+        debug!("Pinging {}", url);
+        if let Err(e) = run(&mut cmd) {
+            warn!("Pinging {} failed: {:#}", url, e);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Bracketing package manager transactions with a pair of snapshots, taken
+//! before and after the transaction by hooks of the package manager.
+//!
+//! The snapshot after a transaction is named with the date of the snapshot
+//! before it, such that the two form a pair. Both are rotated together, in a
+//! retention bucket of their own that is configured as the `pkg` tag.
+
+use crate::{
+    find_snapshots, privilege::shell_quote, retention::SnapshotEntry, SnapshotConfig, State,
+};
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use std::path::{Path, PathBuf};
+
+/// The tag of snapshots taken before a package manager transaction.
+pub const PRE_TAG: &str = "pre-pkg";
+
+/// The tag of snapshots taken after a package manager transaction.
+pub const POST_TAG: &str = "post-pkg";
+
+/// The tag whose retention rules apply to the snapshots around package
+/// manager transactions.
+pub const BUCKET: &str = "pkg";
+
+/// A package manager whose transactions can be bracketed by snapshots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageManager {
+    /// The Arch Linux package manager.
+    Pacman,
+    /// The Debian package manager, hooking into every `dpkg` run.
+    Apt,
+}
+
+/// A hook file to install for a package manager.
+#[derive(Debug, Clone)]
+pub struct HookFile {
+    /// Where the package manager expects the file.
+    pub path: PathBuf,
+    /// The content of the file.
+    pub content: String,
+}
+
+impl std::str::FromStr for PackageManager {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pacman" => Ok(PackageManager::Pacman),
+            "apt" => Ok(PackageManager::Apt),
+            _ => Err(anyhow::anyhow!("Unknown package manager `{}`", s)),
+        }
+    }
+}
+
+/// The command line that hooks and units installed into the system run:
+/// `program` with the config at `config_path`, waiting for the lock, and
+/// restricted to the `only` and `exclude` snapshot configs.
+pub fn command_line(program: &str, config_path: &Path, only: &[&str], exclude: &[&str]) -> String {
+    let mut command = format!("{} --config {} --wait", program, config_path.display());
+    for (flag, names) in [("--snapshot", only), ("--exclude-snapshot", exclude)] {
+        for name in names {
+            command.push_str(&format!(" {} {}", flag, shell_quote(name)));
+        }
+    }
+    command
+}
+
+impl PackageManager {
+    /// Generate the hook files that take snapshots around transactions by
+    /// running `command` with `--reason pre-pkg` and `--reason post-pkg`.
+    pub fn hooks(self, command: &str) -> Vec<HookFile> {
+        let take = |tag: &str| format!("{} take --reason {}", command, tag);
+        match self {
+            PackageManager::Pacman => [
+                (PRE_TAG, "00-btrfs-snapshot-pre", "PreTransaction", "before"),
+                (
+                    POST_TAG,
+                    "zz-btrfs-snapshot-post",
+                    "PostTransaction",
+                    "after",
+                ),
+            ]
+            .iter()
+            .map(|&(tag, name, when, description)| HookFile {
+                path: PathBuf::from(format!("/etc/pacman.d/hooks/{}.hook", name)),
+                content: format!(
+                    "[Trigger]\n\
+                     Operation = Install\n\
+                     Operation = Upgrade\n\
+                     Operation = Remove\n\
+                     Type = Package\n\
+                     Target = *\n\
+                     \n\
+                     [Action]\n\
+                     Description = Taking btrfs snapshots {} the transaction...\n\
+                     When = {}\n\
+                     Exec = {}\n",
+                    description,
+                    when,
+                    take(tag)
+                ),
+            })
+            .collect(),
+            PackageManager::Apt => vec![HookFile {
+                path: PathBuf::from("/etc/apt/apt.conf.d/80btrfs-snapshot"),
+                content: format!(
+                    "// Take btrfs snapshots before and after every dpkg run.\n\
+                     DPkg::Pre-Invoke {{ \"{} || true\"; }};\n\
+                     DPkg::Post-Invoke {{ \"{} || true\"; }};\n",
+                    take(PRE_TAG),
+                    take(POST_TAG)
+                ),
+            }],
+        }
+    }
+}
+
+/// Map the tag of a snapshot to the retention bucket it is rotated in.
+pub fn bucket(tag: Option<&str>) -> Option<&str> {
+    match tag {
+        Some(PRE_TAG) | Some(POST_TAG) => Some(BUCKET),
+        tag => tag,
+    }
+}
+
+/// Split snapshots into the ones that are rotated on their own, and the
+/// snapshots taken after a transaction whose snapshot from before the
+/// transaction still exists. The latter are returned together with the path
+/// of their partner, whose fate they share.
+pub fn split_pairs(entries: &[SnapshotEntry]) -> (Vec<SnapshotEntry>, Vec<(&Path, &Path)>) {
+    let mut leaders = vec![];
+    let mut followers = vec![];
+    for entry in entries {
+        let partner = match entry.tag.as_deref() {
+            Some(POST_TAG) => entries
+                .iter()
+                .find(|other| other.tag.as_deref() == Some(PRE_TAG) && other.date == entry.date),
+            _ => None,
+        };
+        match partner {
+            Some(partner) => followers.push((entry.path.as_path(), partner.path.as_path())),
+            None => leaders.push(entry.clone()),
+        }
+    }
+    (leaders, followers)
+}
+
+impl<'a> State<'a> {
+    /// Determine the date to name a snapshot after a transaction with, which
+    /// is the date of the newest snapshot before a transaction if that one
+    /// has no partner yet. Returns `None` for all other snapshots.
+    pub fn pkg_pair_time(&self, snapshot: &SnapshotConfig) -> Result<Option<DateTime<Local>>> {
+        if self.tag.as_deref() != Some(POST_TAG) {
+            return Ok(None);
+        }
+        let entries = find_snapshots(snapshot, &[])?;
+        let pre = match entries
+            .iter()
+            .find(|entry| entry.tag.as_deref() == Some(PRE_TAG))
+        {
+            Some(pre)
+                if !entries.iter().any(|entry| {
+                    entry.tag.as_deref() == Some(POST_TAG) && entry.date >= pre.date
+                }) =>
+            {
+                pre
+            }
+            _ => {
+                debug!("No unpaired `{}` snapshot of {}", PRE_TAG, snapshot.name);
+                return Ok(None);
+            }
+        };
+        debug!("Pairing with {}", pre.path.display());
+        Ok(Some(pre.date.with_timezone(&Local)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_quotes_snapshot_names() {
+        let command = command_line(
+            "/usr/bin/btrfs-snapshot",
+            Path::new("/etc/btrfs-snapshot.toml"),
+            &["root", "it's"],
+            &["o'clock"],
+        );
+        assert_eq!(
+            command,
+            "/usr/bin/btrfs-snapshot --config /etc/btrfs-snapshot.toml --wait \
+             --snapshot 'root' --snapshot 'it'\\''s' --exclude-snapshot 'o'\\''clock'"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Planning which snapshots of a snapshot config to keep and which to delete.
+
+use crate::{
+    find_snapshots, pkg,
+    retention::{
+        assign_rules, limit_age, limit_count, limit_size, parse_snapshots, plan_keep_counts,
+        plan_rotation, sort_spacings, split_by_tag, Reason, Reasons, SnapshotEntry, TagConfig,
+    },
+    SnapshotConfig,
+};
+use anyhow::Result;
+use indexmap::IndexMap;
+use std::path::{Path, PathBuf};
+
+/// A function that measures the space used exclusively by a snapshot.
+pub type SizeFn = dyn Fn(&Path) -> Result<u64>;
+
+/// The existing snapshots of a snapshot config, sorted by descending date.
+#[derive(Clone)]
+pub struct SnapshotSet {
+    /// The snapshots, newest first.
+    entries: Vec<SnapshotEntry>,
+}
+
+impl SnapshotSet {
+    /// Find the existing snapshots in the snapshot directory of a config.
+    pub fn read(snapshot: &SnapshotConfig) -> Result<Self> {
+        Ok(Self {
+            entries: find_snapshots(snapshot, &snapshot.sorted_spacings())?,
+        })
+    }
+
+    /// Gather a set of snapshots from a list of paths, for example as reported
+    /// by a remote host. Paths whose name does not match the snapshot config's
+    /// format are ignored.
+    pub fn from_paths(
+        snapshot: &SnapshotConfig,
+        paths: impl IntoIterator<Item = PathBuf>,
+    ) -> Result<Self> {
+        Ok(Self {
+            entries: parse_snapshots(paths, &snapshot.naming()?, &snapshot.sorted_spacings())?,
+        })
+    }
+
+    /// Get the snapshots, newest first.
+    pub fn entries(&self) -> &[SnapshotEntry] {
+        &self.entries
+    }
+
+    /// Get the number of snapshots.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether there are no snapshots.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// The snapshots to keep and to delete when rotating a snapshot config.
+#[derive(Debug, Clone, Default)]
+pub struct RotationPlan {
+    /// The snapshots to keep.
+    pub keep: Vec<PathBuf>,
+    /// The snapshots to delete, in the order in which they should be deleted.
+    pub delete: Vec<PathBuf>,
+    /// Why each snapshot is kept or deleted.
+    pub reasons: IndexMap<PathBuf, Reason>,
+}
+
+impl RotationPlan {
+    /// Decide which snapshots of a set to delete, according to the spacings,
+    /// keep counts, tag rules, and limits of a snapshot config. `keep_min`
+    /// spares snapshots from every other rule, including `max_age`. The
+    /// `max_total_size` limit is only applied if a function to measure the
+    /// space used exclusively by a snapshot is given.
+    pub fn new(
+        snapshot: &SnapshotConfig,
+        set: &SnapshotSet,
+        exclusive_size: Option<&SizeFn>,
+    ) -> Result<Self> {
+        let spacings = snapshot.sorted_spacings();
+        let entries = set.entries();
+
+        // Snapshots taken after a package manager transaction share the fate
+        // of the one taken before it, and are left out of the rotation.
+        let (leaders, followers) = pkg::split_pairs(entries);
+
+        // Rotate the snapshots of each tag separately, according to the tag's
+        // own rules if it has any.
+        let mut streams = split_by_tag(&leaders);
+        let mut delete = Reasons::new();
+        let mut kept = Reasons::new();
+        for (tag, stream) in &mut streams {
+            let config = tag.and_then(|tag| snapshot.tags.get(tag));
+            let keep = match config {
+                Some(config) if !config.keep.is_empty() => Some(&config.keep),
+                Some(TagConfig {
+                    spacings: Some(_), ..
+                }) => None,
+                _ if snapshot.keep.is_empty() => None,
+                _ => Some(&snapshot.keep),
+            };
+            if let Some(keep) = keep {
+                delete.extend(plan_keep_counts(stream, keep, &mut kept));
+                continue;
+            }
+            let spacings = match config.and_then(|config| config.spacings.as_ref()) {
+                Some(spacings) => {
+                    let spacings = sort_spacings(spacings);
+                    assign_rules(stream, &spacings);
+                    spacings
+                }
+                None => spacings.clone(),
+            };
+            let align = snapshot.align_spacings == Some(true);
+            delete.extend(plan_rotation(stream, &spacings, align, &mut kept)?);
+        }
+        if let (Some(max), Some(exclusive_size)) = (snapshot.max_total_size, exclusive_size) {
+            limit_size(&leaders, &mut delete, max.bytes(), exclusive_size)?;
+        }
+        if let Some(max_age) = snapshot.max_age {
+            limit_age(&leaders, &mut delete, max_age.into_inner());
+        }
+        let before_min = delete.clone();
+        limit_count(&leaders, &mut delete, snapshot.keep_min, snapshot.keep_max);
+        for (path, reason) in before_min {
+            if !delete.contains_key(path) {
+                let min = snapshot.keep_min.unwrap();
+                let reason = Reason::new(format!("keep_min = {}", min)).with_detail(format!(
+                    "spared from `{}` to keep at least {} snapshots",
+                    reason.rule, min
+                ));
+                kept.insert(path, reason);
+            }
+        }
+        for (follower, leader) in followers {
+            let paired = |reason: Option<&Reason>| {
+                let rule = reason
+                    .map(|reason| reason.rule.as_str())
+                    .unwrap_or_default();
+                Reason::new(format!("paired with {}", leader.display()))
+                    .with_detail(format!("follows `{}` of its partner", rule))
+            };
+            match delete.get(leader) {
+                Some(reason) => {
+                    let reason = paired(Some(reason));
+                    delete.insert(follower, reason);
+                }
+                None => {
+                    kept.insert(follower, paired(kept.get(leader)));
+                }
+            }
+        }
+
+        let reasons = entries
+            .iter()
+            .map(|entry| {
+                let path = entry.path.as_path();
+                let reason = delete.get(path).or_else(|| kept.get(path));
+                (path.to_owned(), reason.cloned().unwrap_or_default())
+            })
+            .collect();
+        Ok(Self {
+            keep: entries
+                .iter()
+                .filter(|entry| !delete.contains_key(entry.path.as_path()))
+                .map(|entry| entry.path.clone())
+                .collect(),
+            delete: delete.into_keys().map(Path::to_owned).collect(),
+            reasons,
+        })
+    }
+
+    /// Keep the snapshots marked for deletion for which `spare` returns true,
+    /// for example because they are held.
+    pub fn spare(&mut self, mut spare: impl FnMut(&Path) -> bool) {
+        let (spared, delete): (Vec<_>, _) = std::mem::take(&mut self.delete)
+            .into_iter()
+            .partition(|path| spare(path));
+        self.delete = delete;
+        for path in &spared {
+            let reason = self.reasons.entry(path.clone()).or_default();
+            *reason = Reason::new("held").with_detail(format!(
+                "spared from `{}` by a hold",
+                std::mem::take(&mut reason.rule)
+            ));
+        }
+        self.keep.extend(spared);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Fixture;
+
+    #[test]
+    fn keep_min_spares_snapshots_beyond_max_age() {
+        let fixture = Fixture::new("plan-max-age", "keep_min = 2\nmax_age = \"1day\"");
+        let paths = fixture.add_snapshots(&[30, 40, 50, 60]);
+        let set = SnapshotSet::read(fixture.snapshot()).unwrap();
+        let plan = RotationPlan::new(fixture.snapshot(), &set, None).unwrap();
+        assert_eq!(plan.keep, &paths[..2]);
+        assert_eq!(plan.delete, &paths[2..]);
+        assert_eq!(plan.reasons[&paths[0]].rule, "keep_min = 2");
+        assert_eq!(plan.reasons[&paths[3]].rule, "max_age = 1day");
+    }
+}
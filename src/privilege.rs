@@ -0,0 +1,160 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Running as root, as an unprivileged user, or with privileged commands
+//! wrapped in `sudo`. Privileged commands may also run on a remote host over
+//! `ssh`, such that one machine manages the snapshots of several servers.
+
+use crate::binaries;
+use anyhow::{anyhow, Result};
+use std::{
+    path::PathBuf,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+/// Whether privileged commands are wrapped in `sudo`.
+static SUDO: AtomicBool = AtomicBool::new(false);
+
+/// The host that privileged commands run on, if not the local one.
+static REMOTE: OnceLock<String> = OnceLock::new();
+
+/// The environment variable that marks a command as privileged, such that it
+/// is sent to the remote host when executed.
+const REMOTE_MARKER: &str = "BTRFS_SNAPSHOT_REMOTE";
+
+/// Enable or disable wrapping privileged commands in `sudo`.
+pub fn init(sudo: bool) {
+    SUDO.store(sudo, Ordering::Relaxed);
+}
+
+/// Check whether privileged commands are wrapped in `sudo`. In this mode,
+/// operations that would otherwise use btrfs ioctls run `btrfs` instead.
+pub fn sudo() -> bool {
+    SUDO.load(Ordering::Relaxed)
+}
+
+/// Run privileged commands on a remote host over `ssh`, such as
+/// `root@nas`.
+pub fn init_remote(host: &str) {
+    REMOTE.set(host.to_owned()).ok();
+}
+
+/// Get the remote host that privileged commands run on, if any.
+pub fn remote() -> Option<&'static str> {
+    REMOTE.get().map(String::as_str)
+}
+
+/// Check whether subvolumes are handled by running `btrfs` rather than with
+/// ioctls, which is the case with `sudo` and on a remote host.
+pub fn via_commands() -> bool {
+    sudo() || remote().is_some()
+}
+
+/// Check whether the process runs as root.
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Create a command for a program that may need root privileges, wrapped in
+/// `sudo` if requested. On a remote host, the command is marked such that
+/// `route` sends it there when it is executed, and the program is looked up in
+/// the remote `PATH` rather than where it is configured locally.
+pub fn command(program: &str) -> Command {
+    let program = match remote() {
+        Some(_) => PathBuf::from(program),
+        None => binaries::program(program),
+    };
+    let mut cmd = match sudo() {
+        true => {
+            let mut cmd = Command::new("sudo");
+            cmd.arg(&program);
+            cmd
+        }
+        false => Command::new(&program),
+    };
+    if remote().is_some() {
+        cmd.env(REMOTE_MARKER, "1");
+    }
+    cmd
+}
+
+/// Rewrite a command created by `command` into one that runs it on the remote
+/// host over `ssh`, right before it is executed. Other commands and commands
+/// on the local host are left alone.
+pub(crate) fn route(cmd: &mut Command) {
+    let host = match remote() {
+        Some(host) => host,
+        None => return,
+    };
+    if !cmd.get_envs().any(|(key, _)| key == REMOTE_MARKER) {
+        return;
+    }
+    let envs: Vec<_> = cmd
+        .get_envs()
+        .filter(|&(key, _)| key != REMOTE_MARKER)
+        .filter_map(|(key, value)| Some(format!("{}={}", key.to_str()?, value?.to_str()?)))
+        .collect();
+    let mut line = vec![];
+    if !envs.is_empty() {
+        line.push(String::from("env"));
+        line.extend(envs);
+    }
+    line.extend(
+        std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned()),
+    );
+    let line: Vec<_> = line.iter().map(|arg| shell_quote(arg)).collect();
+    let mut ssh = Command::new(binaries::program("ssh"));
+    ssh.arg("-o")
+        .arg("BatchMode=yes")
+        .arg(host)
+        .arg("--")
+        .arg(line.join(" "));
+    *cmd = ssh;
+}
+
+/// Quote a string such that it is passed verbatim through the remote shell
+/// that `ssh` invokes.
+pub(crate) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Check whether an error was caused by a lack of privileges, either as an
+/// I/O error of an ioctl or as the stderr of a failed command.
+pub(crate) fn is_permission_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied)
+            || cause.to_string().contains("Operation not permitted")
+            || cause.to_string().contains("Permission denied")
+            || cause.to_string().contains("must be superuser")
+    })
+}
+
+/// Explain a failure to do `what` that was caused by a lack of privileges,
+/// instead of leaving the user with a raw `EPERM`. `hint` suggests what else
+/// could make the operation work.
+pub fn explain<T>(result: Result<T>, what: &str, hint: Option<&str>) -> Result<T> {
+    result.map_err(|error| {
+        if (is_root() && remote().is_none()) || !is_permission_error(&error) {
+            return error;
+        }
+        let hint = hint.map(|h| format!("{}; ", h)).unwrap_or_default();
+        let remedy = match (sudo(), remote()) {
+            (true, _) => "check that sudo grants access to btrfs",
+            (false, Some(_)) => "connect as root or pass --sudo",
+            (false, None) => "run as root or pass --sudo",
+        };
+        error.context(anyhow!(
+            "Need CAP_SYS_ADMIN for {}; {}{}",
+            what,
+            hint,
+            remedy
+        ))
+    })
+}
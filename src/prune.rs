@@ -0,0 +1,93 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Deleting the snapshots of a config that match a filter, as a one-off
+//! cleanup outside of the regular rotation.
+
+use crate::{find_snapshots, init, wildcard_regex, SnapshotConfig, State};
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+
+/// Which snapshots of a config to prune. Snapshots must match all given
+/// criteria.
+#[derive(Debug, Default, Clone)]
+pub struct PruneFilter {
+    /// Only snapshots older than this.
+    pub older_than: Option<Duration>,
+    /// Only snapshots whose name matches this pattern, which may contain `*`
+    /// and `?` wildcards.
+    pub pattern: Option<String>,
+}
+
+impl<'a> State<'a> {
+    /// Delete the snapshots of a config that match a filter, after asking for
+    /// confirmation unless `yes` is set. The same safety checks as for
+    /// rotation apply: held snapshots, the newest snapshot, and the newest
+    /// `keep_min` snapshots are spared, every path is verified to be one of
+    /// the config's snapshots, and snapshots are moved into the trash if
+    /// `trash_grace` is set. Returns the number of pruned snapshots.
+    pub fn prune_snapshots(
+        &mut self,
+        snapshot: &'a SnapshotConfig,
+        filter: &PruneFilter,
+    ) -> Result<usize> {
+        if filter.older_than.is_none() && filter.pattern.is_none() {
+            bail!("Refusing to prune all snapshots of {}", snapshot.name);
+        }
+        debug!("Prune snapshots of {} matching {:?}", snapshot.name, filter);
+        self.mount_snapshot_fs(snapshot)?;
+        let re = filter.pattern.as_deref().map(wildcard_regex);
+        let keep = snapshot.keep_min.unwrap_or(0).max(1);
+        let mut paths = vec![];
+        for entry in find_snapshots(snapshot, &[])?.into_iter().skip(keep) {
+            let name = entry.path.file_name().unwrap().to_string_lossy();
+            let matches = filter.older_than.is_none_or(|age| entry.age > age)
+                && re.as_ref().is_none_or(|re| re.is_match(&name));
+            if !matches {
+                continue;
+            }
+            if self.holds.is_held(&entry.path) {
+                info!("Keeping held snapshot {}", entry.path.display());
+                continue;
+            }
+            paths.push(entry.path);
+        }
+        if paths.is_empty() {
+            info!("No snapshots of {} to prune", snapshot.name);
+            return Ok(0);
+        }
+
+        // Make sure every path is one of our snapshots before touching any.
+        let readonly = snapshot.readonly != Some(false);
+        for path in &paths {
+            self.verify_own_snapshot(snapshot, path, readonly)
+                .with_context(|| format!("Refusing to delete {}", path.display()))?;
+        }
+        if !self.dry_run && !self.yes {
+            confirm_prune(snapshot, paths.len())?;
+        }
+        for path in &paths {
+            if snapshot.trash_grace.is_some() {
+                self.trash_snapshot(snapshot, path)?;
+                continue;
+            }
+            if snapshot.recursive == Some(true) {
+                self.delete_nested(snapshot, path)?;
+            }
+            self.delete_subvolume(snapshot, path)
+                .with_context(|| format!("Deleting snapshot {} failed", path.display()))?;
+        }
+        Ok(paths.len())
+    }
+}
+
+/// Ask whether to prune snapshots.
+fn confirm_prune(snapshot: &SnapshotConfig, count: usize) -> Result<()> {
+    let question = format!("Pruning {} snapshot(s) of {}", count, snapshot.name);
+    if !atty::is(atty::Stream::Stdin) {
+        bail!("{}; pass `--yes` to delete them anyway", question);
+    }
+    if !init::confirm(&format!("{}. Continue?", question), false)? {
+        bail!("Pruning snapshots of {} was not confirmed", snapshot.name);
+    }
+    Ok(())
+}
@@ -0,0 +1,350 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Pulling snapshots from source hosts onto a backup server over `ssh`, for
+//! setups where the sources cannot reach the backup server.
+//!
+//! A config with a `pull` section describes the snapshots of a subvolume on
+//! the source host. The backup server receives them into the config's local
+//! `snapshot_dir`, and rotates them there according to the config's spacings.
+
+use crate::{
+    binaries, create_dir_all, find_snapshots,
+    notification::EventKind,
+    output::ActionKind,
+    privilege,
+    replicate::Compression,
+    retention::{parse_snapshots, SnapshotEntry},
+    SnapshotConfig, State,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Where to pull the snapshots of a snapshot config from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PullConfig {
+    /// The SSH source to pull from, e.g. `root@web`.
+    pub host: String,
+    /// The directory on the source host that holds the snapshots of
+    /// `subvolume`, named according to the config's `format`.
+    pub source_dir: PathBuf,
+    /// Take a read-only snapshot of `subvolume` on the source host before
+    /// pulling, for sources that do not take snapshots themselves. The
+    /// snapshots on the source are deleted once pulled, except the newest.
+    #[serde(default)]
+    pub take: bool,
+    /// Additional options passed to `ssh`.
+    #[serde(default)]
+    pub ssh_options: Vec<String>,
+    /// Compress send streams on the source host before they are transferred.
+    pub compress: Option<Compression>,
+    /// Pass `--compressed-data` to `btrfs send`, such that data compressed on
+    /// disk is sent without decompressing it first.
+    #[serde(default)]
+    pub compressed_data: bool,
+}
+
+impl PullConfig {
+    /// Derive the source for one of several subvolumes of a snapshot config,
+    /// whose snapshots are stored in a subdirectory of the source directory.
+    pub fn for_subdir(&self, subdir: &str) -> PullConfig {
+        let mut config = self.clone();
+        config.source_dir = self.source_dir.join(subdir);
+        config
+    }
+
+    /// Create a command that runs on the source host.
+    fn command(&self, program: &str) -> Command {
+        let mut cmd = Command::new(binaries::program("ssh"));
+        cmd.args(&self.ssh_options).arg(&self.host).arg(program);
+        cmd
+    }
+
+    /// A path on the source host as an argument to a command created by
+    /// `command()`.
+    fn path_arg(path: &Path) -> OsString {
+        privilege::shell_quote(&path.to_string_lossy()).into()
+    }
+
+    /// A human-readable description of the source.
+    pub fn describe(&self) -> String {
+        format!("{}:{}", self.host, self.source_dir.display())
+    }
+
+    /// Create the command that lists the entries of the source directory.
+    fn list_command(&self) -> Command {
+        let mut cmd = self.command("ls");
+        cmd.arg("-1")
+            .arg("--")
+            .arg(Self::path_arg(&self.source_dir));
+        cmd
+    }
+
+    /// Create the command that sends a snapshot on the source host, relative
+    /// to a parent snapshot if there is one.
+    fn send_command(&self, path: &Path, parent: Option<&Path>) -> Command {
+        let mut cmd = self.command("btrfs");
+        cmd.arg("send");
+        if self.compressed_data {
+            cmd.arg("--compressed-data");
+        }
+        if let Some(parent) = parent {
+            cmd.arg("-p").arg(Self::path_arg(parent));
+        }
+        cmd.arg(Self::path_arg(path));
+        // The compression runs on the source host, as part of the command
+        // line passed to the remote shell.
+        if let Some(compression) = self.compress {
+            cmd.args(["|", compression.program(), "-q", "-c"]);
+        }
+        cmd
+    }
+}
+
+impl<'a> State<'a> {
+    /// Pull all snapshots from the source host that do not yet exist locally,
+    /// and rotate the local snapshots.
+    ///
+    /// Snapshots are pulled oldest to newest. Each snapshot is sent
+    /// incrementally relative to the next older snapshot that exists on both
+    /// hosts, or in full if there is none. Source snapshots older than the
+    /// newest local one are not pulled again, since the local rotation has
+    /// deliberately dropped them.
+    pub fn pull_snapshots(&mut self, snapshot: &'a SnapshotConfig) -> Result<()> {
+        let pull = match &snapshot.pull {
+            Some(x) => x,
+            None => {
+                debug!("Not pulling {}; no `pull` config", snapshot.name);
+                return Ok(());
+            }
+        };
+        debug!(
+            "Pull snapshots for {} from {}",
+            snapshot.name,
+            pull.describe()
+        );
+        self.mount_snapshot_fs(snapshot)?;
+        let snapshot_dir = snapshot.snapshot_dir.as_ref().unwrap();
+        if !self.dry_run {
+            create_dir_all(snapshot_dir).with_context(|| {
+                format!("Failed to create snapshot dir {}", snapshot_dir.display())
+            })?;
+        }
+        if pull.take {
+            self.take_source_snapshot(snapshot, pull)?;
+        }
+        let sources = self.source_snapshots(snapshot, pull)?;
+        let mut local = find_snapshots(snapshot, &[])?;
+
+        // A previous run may have been interrupted while receiving a snapshot,
+        // leaving behind a partial subvolume. Since snapshots are pulled oldest
+        // to newest, this can only be the newest local snapshot.
+        if let Some(newest) = local.first().map(|entry| entry.path.clone()) {
+            if self.executor.verify_received(&newest).is_err() {
+                let message = format!(
+                    "Snapshot {} is incomplete; pulling it again",
+                    newest.display()
+                );
+                warn!("{}", message);
+                self.record_event(EventKind::Warning, &snapshot.name, "pull", message);
+                self.delete_subvolume(snapshot, &newest).with_context(|| {
+                    format!("Deleting partial snapshot {} failed", newest.display())
+                })?;
+                local.remove(0);
+            }
+        }
+
+        let newest_local = local.first().map(|entry| entry.date);
+        let mut parent = None;
+        for entry in sources.iter().rev() {
+            let target = snapshot_dir.join(entry.path.file_name().unwrap());
+            if local.iter().any(|local| local.path == target) {
+                trace!("Already pulled {}", entry.path.display());
+                parent = Some(&entry.path);
+                continue;
+            }
+            if newest_local.is_some_and(|newest| entry.date <= newest) {
+                trace!("Not pulling {}; rotated away", entry.path.display());
+                continue;
+            }
+            let mut send = pull.send_command(&entry.path, parent.map(PathBuf::as_path));
+            let mut decompress = pull.compress.map(|compression| {
+                let mut cmd = Command::new(compression.program());
+                cmd.arg("-q").arg("-d").arg("-c");
+                cmd
+            });
+            let mut receive = privilege::command("btrfs");
+            receive.arg("receive").arg(snapshot_dir);
+            let mut cmds: Vec<_> = std::iter::once(&mut send)
+                .chain(decompress.as_mut())
+                .chain(std::iter::once(&mut receive))
+                .collect();
+            self.perform_pipeline(snapshot, ActionKind::Pull, &target, &mut cmds)
+                .with_context(|| {
+                    format!(
+                        "Pulling snapshot {} from {} failed",
+                        entry.path.display(),
+                        pull.host
+                    )
+                })?;
+            parent = Some(&entry.path);
+        }
+
+        // The snapshots taken on the source are only needed until they have
+        // been pulled. Keep the newest one as the parent of the next
+        // incremental transfer.
+        if pull.take && parent.is_some() && parent == sources.first().map(|entry| &entry.path) {
+            for entry in &sources[1..] {
+                self.delete_source_snapshot(snapshot, pull, &entry.path)?;
+            }
+        }
+
+        self.rotate_snapshot(snapshot)
+    }
+
+    /// Find the snapshots in the source directory, sorted newest first.
+    fn source_snapshots(
+        &self,
+        snapshot: &SnapshotConfig,
+        pull: &PullConfig,
+    ) -> Result<Vec<SnapshotEntry>> {
+        let output = self
+            .executor
+            .run(&mut [&mut pull.list_command()])
+            .with_context(|| format!("Listing snapshots in {} failed", pull.describe()))?;
+        parse_snapshots(
+            output.lines().map(|name| pull.source_dir.join(name)),
+            &snapshot.naming()?,
+            &[],
+        )
+    }
+
+    /// Delete a snapshot on the source host.
+    fn delete_source_snapshot(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        pull: &PullConfig,
+        path: &Path,
+    ) -> Result<()> {
+        let mut cmd = pull.command("btrfs");
+        cmd.arg("subvolume")
+            .arg("delete")
+            .arg(PullConfig::path_arg(path));
+        self.perform(snapshot, ActionKind::Delete, path, &mut cmd)
+            .with_context(|| {
+                format!(
+                    "Deleting snapshot {} on {} failed",
+                    path.display(),
+                    pull.host
+                )
+            })
+    }
+
+    /// Take a read-only snapshot of the subvolume on the source host.
+    fn take_source_snapshot(&mut self, snapshot: &SnapshotConfig, pull: &PullConfig) -> Result<()> {
+        let naming = snapshot.naming()?;
+        let seq = if naming.has_seq() {
+            self.source_snapshots(snapshot, pull)?
+                .iter()
+                .filter_map(|entry| entry.seq)
+                .max()
+                .map_or(1, |seq| seq + 1)
+        } else {
+            0
+        };
+        let now = *self.now.get_or_insert_with(chrono::Local::now);
+        let path = pull
+            .source_dir
+            .join(naming.render(now, self.tag.as_deref(), seq)?);
+        let mut cmd = pull.command("btrfs");
+        cmd.arg("subvolume")
+            .arg("snapshot")
+            .arg("-r")
+            .arg(PullConfig::path_arg(snapshot.subvolume()))
+            .arg(PullConfig::path_arg(&path));
+        self.perform(snapshot, ActionKind::Take, &path, &mut cmd)
+            .with_context(|| format!("Taking snapshot {} on {} failed", path.display(), pull.host))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::Operation, tests::Fixture};
+    use chrono::Local;
+
+    #[test]
+    fn pull_receives_missing_snapshots() {
+        let mut fixture = Fixture::new("pull", "");
+        fixture.config.snapshots["data"].pull = Some(crate::pull::PullConfig {
+            host: "root@web".to_string(),
+            source_dir: PathBuf::from("/srv/snapshots"),
+            take: false,
+            ssh_options: vec![],
+            compress: None,
+            compressed_data: false,
+        });
+        let naming = fixture.snapshot().naming().unwrap();
+        let name = |hours| {
+            let time = Local::now() - chrono::Duration::hours(hours);
+            naming.render(time, None, 0).unwrap()
+        };
+        let local = fixture.add_snapshots(&[3]);
+        let (mut state, mock) = fixture.state();
+        mock.add_output("ls", &[name(5), name(3), name(1)].join("\n"));
+        state.pull_snapshots(fixture.snapshot()).unwrap();
+        let sends: Vec<_> = mock
+            .operations()
+            .into_iter()
+            .filter_map(|op| match op {
+                Operation::Send(cmds) => Some(cmds),
+                _ => None,
+            })
+            .collect();
+        let source = |hours| format!("'/srv/snapshots/{}'", name(hours));
+        assert_eq!(sends.len(), 1);
+        assert_eq!(sends[0][0][3..], ["send", "-p", &source(3), &source(1)]);
+        assert_eq!(
+            sends[0][1].last().map(String::as_str),
+            local[0].parent().unwrap().to_str()
+        );
+    }
+
+    #[test]
+    fn pull_deletes_taken_source_snapshots() {
+        let mut fixture = Fixture::new("pull-take", "");
+        fixture.config.snapshots["data"].pull = Some(crate::pull::PullConfig {
+            host: "root@web".to_string(),
+            source_dir: PathBuf::from("/srv/snapshots"),
+            take: true,
+            ssh_options: vec![],
+            compress: None,
+            compressed_data: false,
+        });
+        let naming = fixture.snapshot().naming().unwrap();
+        let name = |hours| {
+            let time = Local::now() - chrono::Duration::hours(hours);
+            naming.render(time, None, 0).unwrap()
+        };
+        fixture.add_snapshots(&[3]);
+        let (mut state, mock) = fixture.state();
+        mock.add_output("ls", &[name(5), name(3), name(1)].join("\n"));
+        state.pull_snapshots(fixture.snapshot()).unwrap();
+        let deleted: Vec<_> = mock
+            .operations()
+            .into_iter()
+            .filter_map(|op| match op {
+                Operation::Run(cmds) if cmds[0].contains(&"delete".to_string()) => {
+                    cmds[0].last().cloned()
+                }
+                _ => None,
+            })
+            .collect();
+        let source = |hours| format!("'/srv/snapshots/{}'", name(hours));
+        assert_eq!(deleted, vec![source(3), source(5)]);
+    }
+}
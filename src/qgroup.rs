@@ -0,0 +1,76 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Querying the disk usage of subvolumes through btrfs quota groups.
+
+use crate::{privilege, run};
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+
+/// The disk usage of a subvolume.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    /// The number of bytes referenced by the subvolume, including ones
+    /// shared with other subvolumes.
+    pub referenced: u64,
+    /// The number of bytes used exclusively by the subvolume, which are freed
+    /// when it is deleted.
+    pub exclusive: u64,
+}
+
+/// Check whether quotas are enabled on a btrfs filesystem.
+pub fn is_enabled(mount_point: &Path) -> bool {
+    run(privilege::command("btrfs")
+        .arg("qgroup")
+        .arg("show")
+        .arg(mount_point))
+    .is_ok()
+}
+
+/// Enable quotas on a btrfs filesystem and wait for the initial accounting to
+/// finish.
+pub fn enable(mount_point: &Path) -> Result<()> {
+    info!("Enabling quotas on {}", mount_point.display());
+    let result = run(privilege::command("btrfs")
+        .arg("quota")
+        .arg("enable")
+        .arg(mount_point))
+    .with_context(|| format!("Enabling quotas on {} failed", mount_point.display()));
+    privilege::explain(result, "enabling quotas", None)?;
+    run(privilege::command("btrfs")
+        .arg("quota")
+        .arg("rescan")
+        .arg("-w")
+        .arg(mount_point))
+    .with_context(|| format!("Rescanning quotas on {} failed", mount_point.display()))?;
+    Ok(())
+}
+
+/// Determine the disk usage of a subvolume.
+pub fn usage(path: &Path) -> Result<Usage> {
+    let output = run(privilege::command("btrfs")
+        .arg("qgroup")
+        .arg("show")
+        .arg("--raw")
+        .arg("-f")
+        .arg(path))
+    .with_context(|| format!("Querying qgroup of {} failed", path.display()))?;
+
+    // Look for a line of the form `0/257 16384 16384`, listing the
+    // referenced and exclusive bytes.
+    for line in output.lines() {
+        let mut fields = line.split_whitespace();
+        let id = fields.next().unwrap_or_default();
+        if !id.starts_with("0/") {
+            continue;
+        }
+        let referenced = fields.next().and_then(|x| x.parse().ok());
+        let exclusive = fields.next().and_then(|x| x.parse().ok());
+        if let (Some(referenced), Some(exclusive)) = (referenced, exclusive) {
+            return Ok(Usage {
+                referenced,
+                exclusive,
+            });
+        }
+    }
+    Err(anyhow!("No qgroup found for {}", path.display()))
+}
@@ -0,0 +1,83 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Quiescing applications such as databases while a snapshot is taken.
+
+use crate::{color, output::OutputFormat, run_with_timeout, user::RunAs, SnapshotConfig, State};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{process::Command, time::Duration};
+
+/// How to quiesce an application while a snapshot is taken.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuiesceConfig {
+    /// The shell command that quiesces the application.
+    pub command: String,
+    /// The shell command that resumes the application. Always runs once
+    /// `command` has been started, even if quiescing or the snapshot fails.
+    pub release: String,
+    /// How long each of the commands may take before it is killed.
+    #[serde(with = "humantime_serde", default = "default_timeout")]
+    pub timeout: Duration,
+    /// The user the commands run as, instead of the one of the config.
+    pub user: Option<String>,
+    /// The group the commands run as, instead of the one of the config.
+    pub group: Option<String>,
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+impl<'a> State<'a> {
+    /// Quiesce the application, run `f`, and resume the application again
+    /// regardless of whether quiescing or `f` succeeded.
+    pub(crate) fn quiesced<T>(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        quiesce: &QuiesceConfig,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        debug!("Quiescing {}", snapshot.name);
+        let run_as = match (&quiesce.user, &quiesce.group) {
+            (None, None) => {
+                RunAs::resolve(snapshot.user.as_deref(), snapshot.user_group.as_deref())
+            }
+            (user, group) => RunAs::resolve(user.as_deref(), group.as_deref()),
+        }
+        .with_context(|| format!("Invalid `user` or `group` to quiesce {}", snapshot.name))?;
+        let result = self
+            .run_quiesce_command(&quiesce.command, quiesce.timeout, run_as.as_ref())
+            .with_context(|| format!("Quiescing {} failed", snapshot.name))
+            .and_then(|_| f(self));
+        debug!("Releasing {}", snapshot.name);
+        let released = self
+            .run_quiesce_command(&quiesce.release, quiesce.timeout, run_as.as_ref())
+            .with_context(|| format!("Releasing {} failed", snapshot.name));
+        let value = result?;
+        released?;
+        Ok(value)
+    }
+
+    /// Run one of the quiesce commands, killing it after a timeout.
+    fn run_quiesce_command(
+        &self,
+        command: &str,
+        timeout: Duration,
+        run_as: Option<&RunAs>,
+    ) -> Result<()> {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        if let Some(run_as) = run_as {
+            run_as.apply(&mut cmd);
+        }
+        if self.dry_run {
+            if self.output == OutputFormat::Text {
+                println!("{}", color::dim(format!("{:?}", cmd)));
+            }
+            return Ok(());
+        }
+        let output = run_with_timeout(&mut cmd, timeout)?;
+        trace!("Quiesce output: {}", output);
+        Ok(())
+    }
+}
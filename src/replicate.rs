@@ -0,0 +1,582 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Replication of snapshots to another machine or disk via `btrfs send` and
+//! `btrfs receive`.
+
+use crate::{
+    archive::Archive,
+    binaries, list_dir,
+    luks::LuksConfig,
+    notification::EventKind,
+    output::ActionKind,
+    privilege,
+    retention::{parse_snapshots, plan_rotation, sort_spacings, Spacings},
+    run,
+    size::{ByteRate, ByteSize},
+    MountSettings, SnapshotConfig, State,
+};
+use anyhow::{bail, Context, Result};
+use humantime::format_duration;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+/// Where to replicate the snapshots of a snapshot config to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicateConfig {
+    /// How snapshots are stored on the target.
+    #[serde(default, rename = "type")]
+    pub kind: TargetKind,
+    /// The SSH destination to replicate to, e.g. `root@backup`. Snapshots are
+    /// replicated to a local path if this is omitted.
+    pub host: Option<String>,
+    /// The mount point of a local target's btrfs volume, which is mounted
+    /// if needed.
+    pub mount_point: Option<PathBuf>,
+    /// Leave the target's volume mounted after the run if it had to be
+    /// mounted.
+    #[serde(default)]
+    pub keep_mounted: bool,
+    /// Lazily detach the target's volume if it is still busy after retrying
+    /// to unmount it.
+    #[serde(default)]
+    pub lazy_unmount: bool,
+    /// The LUKS container to open before mounting the target's volume, and to
+    /// close after unmounting it.
+    pub luks: Option<LuksConfig>,
+    /// The directory on the target where snapshots are received or archived.
+    pub target_dir: Option<PathBuf>,
+    /// Additional options passed to `ssh`.
+    #[serde(default)]
+    pub ssh_options: Vec<String>,
+    /// The S3-compatible bucket URL to archive send streams to, e.g.
+    /// `s3://backups/root`.
+    pub bucket: Option<String>,
+    /// The endpoint URL of an S3-compatible object storage other than AWS.
+    pub endpoint: Option<String>,
+    /// Split archived send streams into files of at most this size.
+    pub chunk_size: Option<ByteSize>,
+    /// Compress send streams before they are transferred to a remote host or
+    /// written to an archive.
+    pub compress: Option<Compression>,
+    /// Pass `--compressed-data` to `btrfs send`, such that data compressed on
+    /// disk is sent without decompressing it first.
+    #[serde(default)]
+    pub compressed_data: bool,
+    /// Encrypt archived send streams for the given recipients.
+    pub encrypt: Option<EncryptConfig>,
+    /// Limit the rate at which send streams are transferred, e.g. `10MB/s`.
+    /// Requires `pv`.
+    pub rate_limit: Option<ByteRate>,
+    /// The spacings applied to snapshots on the target, independently of the
+    /// spacings of the local snapshots. Snapshots on the target are kept
+    /// indefinitely if this is omitted.
+    pub spacings: Option<Spacings>,
+    /// How often to retry sending a snapshot after a failed transfer.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// How long to wait before retrying a failed transfer.
+    pub retry_delay: Option<humantime_serde::Serde<Duration>>,
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+/// How snapshots are stored on a replication target.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetKind {
+    /// Snapshots are recreated on the target with `btrfs receive`.
+    #[default]
+    Receive,
+    /// Raw `btrfs send` streams are stored as files.
+    Archive,
+}
+
+/// A compression program that send streams can be piped through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Zstd,
+    Gzip,
+    Xz,
+}
+
+impl Compression {
+    /// The name of the program that implements the compression.
+    pub(crate) fn program(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+        }
+    }
+
+    /// The file extension of compressed files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zst",
+            Compression::Gzip => "gz",
+            Compression::Xz => "xz",
+        }
+    }
+}
+
+/// How archived send streams are encrypted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptConfig {
+    /// The program used for encryption.
+    pub tool: Encryption,
+    /// The public keys or key IDs the streams are encrypted for.
+    pub recipients: Vec<String>,
+}
+
+/// A program that send streams can be encrypted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encryption {
+    Age,
+    Gpg,
+}
+
+impl EncryptConfig {
+    /// Create the command that encrypts a stream from stdin to stdout.
+    fn command(&self) -> Command {
+        let mut cmd;
+        match self.tool {
+            Encryption::Age => {
+                cmd = Command::new("age");
+                for recipient in &self.recipients {
+                    cmd.arg("-r").arg(recipient);
+                }
+            }
+            Encryption::Gpg => {
+                cmd = Command::new("gpg");
+                cmd.arg("--batch").arg("--encrypt");
+                for recipient in &self.recipients {
+                    cmd.arg("--recipient").arg(recipient);
+                }
+            }
+        }
+        cmd
+    }
+}
+
+impl Encryption {
+    /// The file extension of encrypted files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encryption::Age => "age",
+            Encryption::Gpg => "gpg",
+        }
+    }
+}
+
+impl ReplicateConfig {
+    /// Check that the config describes a usable target.
+    pub fn validate(&self) -> Result<()> {
+        match self.kind {
+            TargetKind::Receive => {
+                if self.target_dir.is_none() {
+                    bail!("Replication target has no `target_dir` config");
+                }
+            }
+            TargetKind::Archive => {
+                if self.target_dir.is_none() == self.bucket.is_none() {
+                    bail!("Archive target needs exactly one of `target_dir` or `bucket`");
+                }
+                if self.host.is_some() {
+                    bail!("Archive target does not support `host`");
+                }
+            }
+        }
+        if self.spacings.is_some() && self.kind == TargetKind::Archive {
+            bail!("Archive targets do not support `spacings`");
+        }
+        if let Some(encrypt) = &self.encrypt {
+            if self.kind != TargetKind::Archive {
+                bail!("Encryption is only supported for archive targets");
+            }
+            if encrypt.recipients.is_empty() {
+                bail!("Encryption needs at least one entry in `recipients`");
+            }
+        }
+        Ok(())
+    }
+
+    /// Derive the target for one of several subvolumes of a snapshot config,
+    /// which is stored in a subdirectory of the configured target.
+    pub fn for_subdir(&self, subdir: &str) -> ReplicateConfig {
+        let mut config = self.clone();
+        config.target_dir = self.target_dir.as_ref().map(|dir| dir.join(subdir));
+        config.bucket = self
+            .bucket
+            .as_ref()
+            .map(|bucket| format!("{}/{}", bucket.trim_end_matches('/'), subdir));
+        config
+    }
+
+    /// The directory on the target. Only valid for targets with a
+    /// `target_dir`.
+    pub fn target_dir(&self) -> &Path {
+        self.target_dir.as_ref().unwrap()
+    }
+
+    /// Create a command that runs on the replication target.
+    fn command(&self, program: &str) -> Command {
+        match &self.host {
+            Some(host) => {
+                let mut cmd = Command::new(binaries::program("ssh"));
+                cmd.args(&self.ssh_options).arg(host).arg(program);
+                cmd
+            }
+            None => privilege::command(program),
+        }
+    }
+
+    /// Create the commands that process a send stream between `btrfs send`
+    /// and the target.
+    pub fn filters(&self) -> Vec<Command> {
+        let mut filters = Vec::new();
+        if let Some(compression) = self.compression() {
+            let mut cmd = Command::new(compression.program());
+            cmd.arg("-q").arg("-c");
+            filters.push(cmd);
+        }
+        if let Some(encrypt) = &self.encrypt {
+            filters.push(encrypt.command());
+        }
+        if let Some(rate) = self.rate_limit {
+            let mut cmd = Command::new("pv");
+            cmd.arg("-q")
+                .arg("-L")
+                .arg(rate.bytes_per_second().to_string());
+            filters.push(cmd);
+        }
+        filters
+    }
+
+    /// The compression applied to send streams. Streams received on a local
+    /// disk are never compressed, since they do not cross the network.
+    pub fn compression(&self) -> Option<Compression> {
+        match (self.kind, &self.host) {
+            (TargetKind::Receive, None) => None,
+            _ => self.compress,
+        }
+    }
+
+    /// Create the command that receives a send stream on the target.
+    fn receive_command(&self) -> Command {
+        let target_dir = self.path_arg(self.target_dir());
+        match self.compression() {
+            // The decompression runs on the remote host, as part of the
+            // command line passed to the remote shell.
+            Some(compression) => {
+                let mut cmd = self.command(compression.program());
+                cmd.args(["-q", "-d", "-c", "|", "btrfs", "receive"])
+                    .arg(target_dir);
+                cmd
+            }
+            None => {
+                let mut cmd = self.command("btrfs");
+                cmd.arg("receive").arg(target_dir);
+                cmd
+            }
+        }
+    }
+
+    /// A path on the target as an argument to a command created by
+    /// `command()`.
+    fn path_arg(&self, path: &Path) -> OsString {
+        match self.host {
+            Some(_) => privilege::shell_quote(&path.to_string_lossy()).into(),
+            None => path.into(),
+        }
+    }
+
+    /// A human-readable description of the target.
+    pub fn describe(&self) -> String {
+        match (&self.host, &self.bucket) {
+            (_, Some(bucket)) => bucket.clone(),
+            (Some(host), None) => format!("{}:{}", host, self.target_dir().display()),
+            (None, None) => self.target_dir().display().to_string(),
+        }
+    }
+
+    /// Determine the names of the snapshots already present on the target.
+    fn existing_snapshots(&self) -> Result<HashSet<String>> {
+        let names = if self.kind == TargetKind::Archive {
+            Archive::new(self).load_index()?.names().collect()
+        } else if self.host.is_some() {
+            let output = run(self
+                .command("ls")
+                .arg("-1")
+                .arg("--")
+                .arg(self.path_arg(self.target_dir())))?;
+            output.lines().map(String::from).collect()
+        } else {
+            list_dir(self.target_dir())?
+                .iter()
+                .filter_map(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+                .collect()
+        };
+        Ok(names)
+    }
+
+    /// Create the target directory if it does not exist yet.
+    fn create_target_dir(&self) -> Result<()> {
+        run(self
+            .command("mkdir")
+            .arg("-p")
+            .arg("--")
+            .arg(self.path_arg(self.target_dir())))?;
+        Ok(())
+    }
+
+    /// Check whether a snapshot on a `receive` target is complete. An
+    /// interrupted `btrfs receive` leaves behind a subvolume without a
+    /// received UUID, since that is only set once the entire stream has been
+    /// applied.
+    fn is_complete(&self, name: &str) -> Result<bool> {
+        let output = run(self
+            .command("btrfs")
+            .arg("subvolume")
+            .arg("show")
+            .arg(self.path_arg(&self.target_dir().join(name))))?;
+        Ok(output.lines().any(|line| {
+            let line = line.trim();
+            line.starts_with("Received UUID:") && !line.ends_with('-')
+        }))
+    }
+}
+
+impl<'a> State<'a> {
+    /// Send all snapshots that do not yet exist on the replication target.
+    ///
+    /// Snapshots are sent oldest to newest. Each snapshot is sent
+    /// incrementally relative to the next older snapshot that already exists
+    /// on the target, or in full if there is none.
+    pub fn send_snapshots(&mut self, snapshot: &'a SnapshotConfig) -> Result<()> {
+        let replicate = match &snapshot.replicate {
+            Some(x) => x,
+            None => {
+                debug!("Not replicating {}; no `replicate` config", snapshot.name);
+                return Ok(());
+            }
+        };
+        debug!("Send snapshots for {}", snapshot.name);
+        self.mount_snapshot_fs(snapshot)?;
+        if let Some(mount_point) = &replicate.mount_point {
+            self.mount_if_needed(
+                mount_point,
+                MountSettings {
+                    luks: replicate.luks.as_ref(),
+                    keep_mounted: replicate.keep_mounted,
+                    lazy_unmount: replicate.lazy_unmount,
+                    require_btrfs: replicate.kind == TargetKind::Receive,
+                    ..Default::default()
+                },
+            )?;
+        }
+        if replicate.target_dir.is_some() && !self.dry_run {
+            replicate
+                .create_target_dir()
+                .with_context(|| format!("Creating target dir {} failed", replicate.describe()))?;
+        }
+        let entries = crate::find_snapshots(snapshot, &[])?;
+        let mut existing = replicate
+            .existing_snapshots()
+            .with_context(|| format!("Listing snapshots in {} failed", replicate.describe()))?;
+        trace!("Existing snapshots on target: {:?}", existing);
+
+        // A previous run may have been interrupted while receiving a snapshot,
+        // leaving behind a partial subvolume. Since snapshots are sent oldest
+        // to newest, this can only be the newest snapshot on the target.
+        if replicate.kind == TargetKind::Receive {
+            let newest = entries
+                .iter()
+                .map(|entry| entry.path.file_name().unwrap().to_string_lossy())
+                .find(|name| existing.contains(name.as_ref()));
+            if let Some(name) = newest {
+                if !replicate.is_complete(&name)? {
+                    let message = format!(
+                        "Snapshot {} on {} is incomplete; sending it again",
+                        name,
+                        replicate.describe()
+                    );
+                    warn!("{}", message);
+                    self.record_event(EventKind::Warning, &snapshot.name, "send", message);
+                    self.remove_partial(snapshot, replicate, &name)?;
+                    existing.remove(name.as_ref());
+                }
+            }
+        }
+
+        let mut parent = None;
+        for entry in entries.iter().rev() {
+            let name = entry.path.file_name().unwrap().to_string_lossy();
+            if existing.contains(name.as_ref()) {
+                trace!("Already replicated {}", entry.path.display());
+                // Only base incremental sends on snapshots the catalog knows
+                // to have arrived on this target completely.
+                if self.catalog.is_sent_to(&entry.path, &replicate.describe()) {
+                    parent = Some(&entry.path);
+                }
+                continue;
+            }
+            let mut attempt = 0;
+            loop {
+                let result = self.send_snapshot(
+                    snapshot,
+                    replicate,
+                    &entry.path,
+                    parent.map(PathBuf::as_path),
+                );
+                let error = match result {
+                    Ok(()) => break,
+                    Err(e) => e,
+                };
+                if attempt >= replicate.retries {
+                    return Err(error).with_context(|| {
+                        format!(
+                            "Sending snapshot {} to {} failed",
+                            entry.path.display(),
+                            replicate.describe()
+                        )
+                    });
+                }
+                attempt += 1;
+                let delay = replicate
+                    .retry_delay
+                    .map(|d| d.into_inner())
+                    .unwrap_or_else(|| Duration::from_secs(30));
+                let message = format!(
+                    "Sending snapshot {} failed: {:#}; retrying in {} (attempt {} of {})",
+                    entry.path.display(),
+                    error,
+                    format_duration(delay),
+                    attempt,
+                    replicate.retries
+                );
+                warn!("{}", message);
+                self.record_event(EventKind::Warning, &snapshot.name, "send", message);
+                std::thread::sleep(delay);
+                self.remove_partial(snapshot, replicate, &name)?;
+            }
+            parent = Some(&entry.path);
+        }
+
+        if replicate.spacings.is_some() {
+            self.rotate_target(snapshot, replicate)?;
+        }
+        Ok(())
+    }
+
+    /// Delete snapshots on a replication target according to the target's
+    /// spacings.
+    fn rotate_target(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        replicate: &ReplicateConfig,
+    ) -> Result<()> {
+        debug!(
+            "Rotate snapshots for {} on {}",
+            snapshot.name,
+            replicate.describe()
+        );
+        let spacings = sort_spacings(replicate.spacings.as_ref().unwrap());
+        let files = replicate
+            .existing_snapshots()?
+            .into_iter()
+            .map(|name| replicate.target_dir().join(name));
+        let entries = parse_snapshots(files, &snapshot.naming()?, &spacings)?;
+        let align = snapshot.align_spacings == Some(true);
+        for path in plan_rotation(&entries, &spacings, align, &mut Default::default())?.keys() {
+            self.perform(
+                snapshot,
+                ActionKind::Delete,
+                path,
+                replicate
+                    .command("btrfs")
+                    .arg("subvolume")
+                    .arg("delete")
+                    .arg(replicate.path_arg(path)),
+            )
+            .with_context(|| format!("Deleting snapshot {} failed", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Send a single snapshot to a replication target.
+    fn send_snapshot(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        replicate: &ReplicateConfig,
+        path: &Path,
+        parent: Option<&Path>,
+    ) -> Result<()> {
+        let mut send = privilege::command("btrfs");
+        send.arg("send");
+        if replicate.compressed_data {
+            send.arg("--compressed-data");
+        }
+        if let Some(parent) = parent {
+            send.arg("-p").arg(parent);
+        }
+        send.arg(path);
+        match replicate.kind {
+            TargetKind::Receive => {
+                let mut receive = replicate.receive_command();
+                let mut filters = replicate.filters();
+                let mut cmds: Vec<_> = std::iter::once(&mut send)
+                    .chain(filters.iter_mut())
+                    .chain(std::iter::once(&mut receive))
+                    .collect();
+                self.perform_pipeline(snapshot, ActionKind::Send, path, &mut cmds)?;
+                Ok(())
+            }
+            TargetKind::Archive => self.archive_snapshot(snapshot, replicate, path, parent, send),
+        }
+    }
+
+    /// Remove what an interrupted transfer of a snapshot may have left behind
+    /// on the replication target.
+    fn remove_partial(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        replicate: &ReplicateConfig,
+        name: &str,
+    ) -> Result<()> {
+        match replicate.kind {
+            TargetKind::Receive => {
+                if !replicate.existing_snapshots()?.contains(name) {
+                    return Ok(());
+                }
+                let path = replicate.target_dir().join(name);
+                self.perform(
+                    snapshot,
+                    ActionKind::Delete,
+                    &path,
+                    replicate
+                        .command("btrfs")
+                        .arg("subvolume")
+                        .arg("delete")
+                        .arg(replicate.path_arg(&path)),
+                )
+                .with_context(|| format!("Deleting partial snapshot {} failed", path.display()))?;
+            }
+            TargetKind::Archive => {
+                if !self.dry_run {
+                    Archive::new(replicate).remove_files(name)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
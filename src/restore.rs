@@ -0,0 +1,148 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Rolling a subvolume back to one of its snapshots.
+
+use crate::{
+    init, output::ActionKind, resolve_snapshot, subvolume, OutputFormat, SnapshotConfig, State,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+impl<'a> State<'a> {
+    /// Roll the subvolume of a snapshot config back to one of its snapshots,
+    /// given by name or path. The current subvolume is moved aside rather than
+    /// deleted, and a writable snapshot of the chosen snapshot takes its
+    /// place.
+    pub fn restore_snapshot(&mut self, snapshot: &'a SnapshotConfig, name: &str) -> Result<()> {
+        debug!("Restore {} of {}", name, snapshot.name);
+        self.mount_snapshot_fs(snapshot)?;
+        let subvolume = snapshot.subvolume();
+        let snapshot_dir = snapshot.snapshot_dir.as_ref().unwrap();
+        if snapshot_dir.starts_with(subvolume) {
+            bail!(
+                "Cannot restore {} since its snapshot dir {} lies within it; restore it manually",
+                subvolume.display(),
+                snapshot_dir.display()
+            );
+        }
+
+        // Only restore one of our own snapshots.
+        let source = resolve_snapshot(snapshot, name)?;
+
+        // Make sure the user knows what is about to happen.
+        let now = chrono::Local::now();
+        let aside = PathBuf::from(format!(
+            "{}.pre-restore-{}",
+            subvolume.display(),
+            now.format("%Y_%m_%d_%H%M%S")
+        ));
+        if !self.dry_run && !self.yes {
+            confirm_restore(subvolume, &source, &aside)?;
+        }
+
+        // Move the current subvolume aside, then put a writable snapshot of
+        // the chosen snapshot in its place. Remember the UUID it had, since
+        // the existing snapshots were taken of it.
+        let uuid = match self.dry_run {
+            false => self
+                .executor
+                .subvolume_uuid(subvolume)
+                .with_context(|| format!("Querying subvolume {} failed", subvolume.display()))?,
+            true => None,
+        };
+        self.move_aside(subvolume, &aside)?;
+        self.restore_subvolume(snapshot, &source, subvolume)?;
+        if let Some(uuid) = uuid {
+            self.catalog.record_restored(subvolume, uuid);
+        }
+        if snapshot.recursive == Some(true) {
+            for rel in subvolume::nested(&source)? {
+                let target = subvolume.join(&rel);
+                if !self.dry_run {
+                    std::fs::remove_dir(&target).with_context(|| {
+                        format!("Failed to remove placeholder {}", target.display())
+                    })?;
+                }
+                self.restore_subvolume(snapshot, &source.join(&rel), &target)?;
+            }
+        }
+
+        if self.output == OutputFormat::Text && !self.dry_run {
+            println!();
+            println!(
+                "Restored {} from {}.",
+                subvolume.display(),
+                source.display()
+            );
+            println!("The previous state is kept at {}.", aside.display());
+            println!(
+                "If {} is mounted elsewhere, e.g. as the root filesystem, reboot or remount it \
+                 to switch to the restored state.",
+                subvolume.display()
+            );
+            println!(
+                "Once everything works, delete the previous state with `btrfs subvolume delete{} {}`.",
+                match snapshot.recursive {
+                    Some(true) => " --recursive",
+                    _ => "",
+                },
+                aside.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Put a writable snapshot of a snapshot at the path of the subvolume it
+    /// was taken of.
+    fn restore_subvolume(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        source: &Path,
+        target: &Path,
+    ) -> Result<()> {
+        let mut cmd = subvolume::snapshot_command(source, target, false);
+        self.perform_with(
+            snapshot,
+            ActionKind::Restore,
+            target,
+            &mut [&mut cmd],
+            |exec, _| exec.snapshot(source, target, false).map(|_| 0),
+        )
+        .with_context(|| format!("Restoring {} failed", target.display()))
+    }
+
+    /// Rename a subvolume, or only print the equivalent command in a dry run.
+    fn move_aside(&mut self, path: &Path, aside: &Path) -> Result<()> {
+        if self.output == OutputFormat::Text {
+            println!("Moving {} aside to {}", path.display(), aside.display());
+        }
+        if self.dry_run {
+            self.print_commands(&[&mut Command::new("mv").arg(path).arg(aside)]);
+            return Ok(());
+        }
+        self.executor
+            .rename(path, aside)
+            .with_context(|| format!("Moving {} aside failed", path.display()))
+    }
+}
+
+/// Ask whether to go ahead with a restore. Fails if the answer is no or if
+/// there is no terminal to ask on.
+fn confirm_restore(subvolume: &Path, source: &Path, aside: &Path) -> Result<()> {
+    let question = format!(
+        "Restoring {} from {} moves the current state aside to {}",
+        subvolume.display(),
+        source.display(),
+        aside.display()
+    );
+    if !atty::is(atty::Stream::Stdin) {
+        bail!("{}; pass `--yes` to restore anyway", question);
+    }
+    if !init::confirm(&format!("{}. Continue?", question), false)? {
+        bail!("Restoring {} was not confirmed", subvolume.display());
+    }
+    Ok(())
+}
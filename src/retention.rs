@@ -0,0 +1,617 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Deciding which snapshots to keep and which to delete.
+
+use crate::{naming::Naming, pkg, size::ByteSize};
+use anyhow::Result;
+use chrono::{DateTime, Datelike as _, FixedOffset, Timelike as _};
+use humantime::format_duration;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// A list of spacing between snapshots for snapshots of a given age, as it
+/// appears in the config.
+pub type Spacings = IndexMap<humantime_serde::Serde<Duration>, humantime_serde::Serde<Duration>>;
+
+/// Get spacings as `(age, spacing)` pairs, sorted by ascending age.
+pub fn sort_spacings(spacings: &Spacings) -> Vec<(Duration, Duration)> {
+    let mut spacings: Vec<_> = spacings
+        .iter()
+        .map(|(age, spacing)| (age.into_inner(), spacing.into_inner()))
+        .collect();
+    spacings.sort_by_key(|&(age, _)| age);
+    trace!("Spacings: {:?}", spacings);
+    spacings
+}
+
+/// Why a snapshot is kept or deleted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Reason {
+    /// The rule responsible, such as `every 1day after 1week` or
+    /// `keep_max = 10`.
+    pub rule: String,
+    /// How the rule applies to the snapshot, such as the spacing to the
+    /// neighbors it was compared against.
+    pub detail: Option<String>,
+}
+
+impl Reason {
+    /// Create a reason without details.
+    pub fn new(rule: impl Into<String>) -> Self {
+        Self {
+            rule: rule.into(),
+            detail: None,
+        }
+    }
+
+    /// Explain how the rule applies to the snapshot.
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// Create a reason for a snapshot subject to a spacing rule.
+    fn spacing((age, spacing): (Duration, Duration)) -> Self {
+        Self::new(format!(
+            "every {} after {}",
+            format_duration(spacing),
+            format_duration(age)
+        ))
+    }
+}
+
+/// Why each of a number of snapshots is kept or deleted, keyed by path.
+pub type Reasons<'a> = IndexMap<&'a Path, Reason>;
+
+/// The number of snapshots to keep per calendar period.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct KeepCounts {
+    /// Keep the newest snapshot of each of the last N hours.
+    pub keep_hourly: Option<usize>,
+    /// Keep the newest snapshot of each of the last N days.
+    pub keep_daily: Option<usize>,
+    /// Keep the newest snapshot of each of the last N weeks.
+    pub keep_weekly: Option<usize>,
+    /// Keep the newest snapshot of each of the last N months.
+    pub keep_monthly: Option<usize>,
+    /// Keep the newest snapshot of each of the last N years.
+    pub keep_yearly: Option<usize>,
+}
+
+impl KeepCounts {
+    /// Check whether no counts are configured.
+    pub fn is_empty(&self) -> bool {
+        self.periods()
+            .iter()
+            .all(|(_, _, count, _)| count.is_none())
+    }
+
+    /// Fill in the counts that are not configured from another config.
+    pub fn inherit(&mut self, other: &KeepCounts) {
+        self.keep_hourly = self.keep_hourly.or(other.keep_hourly);
+        self.keep_daily = self.keep_daily.or(other.keep_daily);
+        self.keep_weekly = self.keep_weekly.or(other.keep_weekly);
+        self.keep_monthly = self.keep_monthly.or(other.keep_monthly);
+        self.keep_yearly = self.keep_yearly.or(other.keep_yearly);
+    }
+
+    /// The configured counts with their config keys and the name of their
+    /// period, together with a function that maps a date to the calendar
+    /// period it falls into.
+    fn periods(&self) -> Vec<(&'static str, &'static str, Option<usize>, PeriodFn)> {
+        vec![
+            ("keep_hourly", "hour", self.keep_hourly, |d| {
+                (d.year(), d.ordinal(), d.hour())
+            }),
+            ("keep_daily", "day", self.keep_daily, |d| {
+                (d.year(), d.ordinal(), 0)
+            }),
+            ("keep_weekly", "week", self.keep_weekly, |d| {
+                let week = d.iso_week();
+                (week.year(), week.week(), 0)
+            }),
+            ("keep_monthly", "month", self.keep_monthly, |d| {
+                (d.year(), d.month(), 0)
+            }),
+            ("keep_yearly", "year", self.keep_yearly, |d| {
+                (d.year(), 0, 0)
+            }),
+        ]
+    }
+}
+
+/// A calendar period that a snapshot falls into.
+type Period = (i32, u32, u32);
+
+/// A function that maps a date to the calendar period it falls into.
+type PeriodFn = fn(&DateTime<FixedOffset>) -> Period;
+
+/// The retention rules for snapshots taken with a specific tag.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TagConfig {
+    /// The spacings applied to snapshots with this tag.
+    pub spacings: Option<Spacings>,
+    /// The number of snapshots with this tag to keep per calendar period.
+    /// Replaces `spacings` if any count is given.
+    #[serde(flatten)]
+    pub keep: KeepCounts,
+}
+
+/// An existing snapshot found in a snapshot directory.
+#[derive(Clone)]
+pub struct SnapshotEntry {
+    /// The date parsed from the snapshot name.
+    pub date: DateTime<FixedOffset>,
+    /// The path of the snapshot subvolume.
+    pub path: PathBuf,
+    /// The age of the snapshot.
+    pub age: Duration,
+    /// The index of the spacing rule that applies to this snapshot, if any.
+    pub rule: Option<usize>,
+    /// The tag the snapshot was taken with, if any.
+    pub tag: Option<String>,
+    /// The sequence number of the snapshot, if the names carry one.
+    pub seq: Option<u64>,
+    /// The suffix telling the snapshot apart from others taken at the same
+    /// time, if any.
+    pub suffix: Option<u64>,
+}
+
+/// Parse the dates of a list of snapshots from their names, sorted by
+/// descending date. Snapshots whose name does not match the format are
+/// ignored.
+pub fn parse_snapshots(
+    files: impl IntoIterator<Item = PathBuf>,
+    naming: &Naming,
+    spacings: &[(Duration, Duration)],
+) -> Result<Vec<SnapshotEntry>> {
+    // Parse the snapshots into proper dates.
+    let now = chrono::Local::now().with_nanosecond(0).unwrap();
+    let mut entries = Vec::new();
+    for file in files {
+        let name = match file.file_name().and_then(|x| x.to_str()) {
+            Some(x) => x,
+            None => continue,
+        };
+        let parsed = match naming.parse(name) {
+            Some(x) => x,
+            // Directories shared with other hosts or configs legitimately
+            // contain snapshots that do not match.
+            None if naming.is_qualified() => {
+                debug!("Ignoring snapshot {} of another config", file.display());
+                continue;
+            }
+            None => {
+                warn!(
+                    "Ignoring snapshot {} because name does not match format `{}`",
+                    file.display(),
+                    naming.template()
+                );
+                continue;
+            }
+        };
+        let date = parsed.date;
+        let age = now.signed_duration_since(date).to_std()?;
+        entries.push(SnapshotEntry {
+            date,
+            path: file,
+            age,
+            rule: find_rule(age, spacings),
+            tag: parsed.tag,
+            seq: parsed.seq,
+            suffix: parsed.suffix,
+        });
+    }
+
+    // Sort the entries by descending date. Of the snapshots taken at the
+    // same time, the ones with a higher suffix are newer.
+    entries.sort_by_key(|e| (e.date, e.suffix));
+    entries.reverse();
+    Ok(entries)
+}
+
+/// Find the index of the spacing rule that applies to a snapshot of a given
+/// age.
+fn find_rule(age: Duration, spacings: &[(Duration, Duration)]) -> Option<usize> {
+    spacings
+        .iter()
+        .enumerate()
+        .filter(|(_, &(a, _))| a <= age)
+        .max_by_key(|(_, &(a, _))| a)
+        .map(|(i, _)| i)
+}
+
+/// Split snapshots into one stream per tag, with the untagged snapshots
+/// first. The order of the snapshots is preserved within each stream. The
+/// snapshots around package manager transactions form a single stream.
+pub fn split_by_tag(entries: &[SnapshotEntry]) -> IndexMap<Option<&str>, Vec<SnapshotEntry>> {
+    let mut streams: IndexMap<_, Vec<_>> = IndexMap::new();
+    streams.insert(None, Vec::new());
+    for entry in entries {
+        streams
+            .entry(pkg::bucket(entry.tag.as_deref()))
+            .or_default()
+            .push(entry.clone());
+    }
+    streams
+}
+
+/// Recompute which spacing rule applies to each snapshot, for snapshots that
+/// are subject to different spacings than the ones they were parsed with.
+pub fn assign_rules(entries: &mut [SnapshotEntry], spacings: &[(Duration, Duration)]) {
+    for entry in entries {
+        entry.rule = find_rule(entry.age, spacings);
+    }
+}
+
+/// Determine which snapshots to delete such that the remaining ones adhere to
+/// the spacings. With `align`, spacings of whole calendar units keep the
+/// first snapshot after each calendar boundary instead. Why the others are
+/// kept is added to `kept`. The entries must be sorted by descending date.
+pub fn plan_rotation<'a>(
+    entries: &'a [SnapshotEntry],
+    spacings: &[(Duration, Duration)],
+    align: bool,
+    kept: &mut Reasons<'a>,
+) -> Result<Reasons<'a>> {
+    // Snapshots younger than the first rule, and the newest and oldest ones,
+    // are never compared against their neighbors.
+    for (index, entry) in entries.iter().enumerate() {
+        let reason = match entry.rule {
+            None if spacings.is_empty() => {
+                Reason::new("keep all").with_detail("no spacings configured")
+            }
+            None => Reason::new("keep all")
+                .with_detail(format!("younger than {}", format_duration(spacings[0].0))),
+            Some(rule) if index == 0 => {
+                Reason::spacing(spacings[rule]).with_detail("newest snapshot")
+            }
+            Some(rule) if index + 1 == entries.len() => {
+                Reason::spacing(spacings[rule]).with_detail("oldest snapshot")
+            }
+            Some(_) => continue,
+        };
+        kept.insert(entry.path.as_path(), reason);
+    }
+
+    // Iterate through the entries newest to oldest and mark the ones that
+    // are too close to the previous entry.
+    let mut delete = Reasons::new();
+    for (rule, &(target_age, target_spacing)) in spacings.iter().enumerate() {
+        if let Some(bucket) = align.then(|| CalendarBucket::new(target_spacing)).flatten() {
+            plan_aligned(entries, rule, spacings[rule], bucket, &mut delete, kept);
+            continue;
+        }
+        trace!(
+            "Purging for rule {}, until age {}, spacing {}",
+            rule,
+            format_duration(target_age),
+            format_duration(target_spacing)
+        );
+        let mut it = entries.iter().zip(entries.iter().skip(1));
+        let mut newest = match it.next() {
+            Some((x, _)) => x,
+            None => return Ok(delete),
+        };
+        trace!("  Initial {}", newest.date);
+        for (current, older) in it {
+            if current.rule > Some(rule) {
+                break;
+            }
+            let applies = current.rule == Some(rule);
+            let to_newer = newest.date.signed_duration_since(current.date).to_std()?;
+            let to_older = current.date.signed_duration_since(older.date).to_std()?;
+            let spacing = std::cmp::max(to_newer, to_older);
+            trace!(
+                "  {} {}, rule {:?}, spacing {}",
+                if applies { "Considering" } else { "Skipping" },
+                current.date,
+                current.rule,
+                format_duration(spacing)
+            );
+
+            let reason = || {
+                Reason::spacing(spacings[rule]).with_detail(format!(
+                    "{} after newer {}, {} before older {}; target spacing {}",
+                    format_duration(to_newer),
+                    newest.date,
+                    format_duration(to_older),
+                    older.date,
+                    format_duration(target_spacing)
+                ))
+            };
+
+            // Drop the snapshot if not adequately spaced.
+            if spacing < target_spacing {
+                if applies {
+                    delete.insert(current.path.as_path(), reason());
+                    debug!("  Dropping {}", current.date);
+                    debug!("    Favoring: {}", newest.date);
+                    debug!("    Spacing:  {}", format_duration(spacing));
+                    debug!("    Intended: {}", format_duration(target_spacing));
+                }
+            } else {
+                if applies {
+                    kept.insert(current.path.as_path(), reason());
+                }
+                newest = current;
+            }
+        }
+    }
+    Ok(delete)
+}
+
+/// A calendar-aligned bucket that a spacing of whole calendar units divides
+/// time into, such as days starting at midnight.
+#[derive(Debug, Clone, Copy)]
+enum CalendarBucket {
+    /// Groups of this many hours, starting at midnight.
+    Hours(u32),
+    /// Groups of this many days, counted from the start of the common era.
+    Days(u32),
+    /// Groups of this many weeks starting on Monday, counted from the start
+    /// of the common era.
+    Weeks(u32),
+    /// Groups of this many months, starting on the first of January.
+    Months(u32),
+    /// Groups of this many years.
+    Years(u32),
+}
+
+impl CalendarBucket {
+    /// Find the calendar bucket for a spacing, which must be a whole number of
+    /// hours, days, weeks, months, or years as `humantime` counts them.
+    fn new(spacing: Duration) -> Option<Self> {
+        const HOUR: u64 = 3600;
+        const DAY: u64 = 24 * HOUR;
+        const WEEK: u64 = 7 * DAY;
+        const MONTH: u64 = 2_630_016;
+        const YEAR: u64 = 31_557_600;
+        let secs = spacing.as_secs();
+        let count = |unit: u64| match secs > 0 && secs.is_multiple_of(unit) {
+            true => u32::try_from(secs / unit).ok(),
+            false => None,
+        };
+        if let Some(n) = count(YEAR) {
+            Some(CalendarBucket::Years(n))
+        } else if let Some(n) = count(MONTH) {
+            Some(CalendarBucket::Months(n))
+        } else if let Some(n) = count(WEEK) {
+            Some(CalendarBucket::Weeks(n))
+        } else if let Some(n) = count(DAY) {
+            Some(CalendarBucket::Days(n))
+        } else {
+            count(HOUR)
+                .filter(|&n| 24u32.is_multiple_of(n))
+                .map(CalendarBucket::Hours)
+        }
+    }
+
+    /// Determine the bucket a date falls into.
+    fn key(self, date: &DateTime<FixedOffset>) -> (i64, i64) {
+        let days = i64::from(date.num_days_from_ce());
+        match self {
+            CalendarBucket::Hours(n) => (days, i64::from(date.hour() / n)),
+            CalendarBucket::Days(n) => (days / i64::from(n), 0),
+            CalendarBucket::Weeks(n) => ((days - 1) / 7 / i64::from(n), 0),
+            CalendarBucket::Months(n) => (
+                (i64::from(date.year()) * 12 + i64::from(date.month0())) / i64::from(n),
+                0,
+            ),
+            CalendarBucket::Years(n) => (i64::from(date.year()) / i64::from(n), 0),
+        }
+    }
+
+    /// The name of the boundary the buckets start at.
+    fn boundary(self) -> &'static str {
+        match self {
+            CalendarBucket::Hours(_) => "the hour",
+            CalendarBucket::Days(_) => "midnight",
+            CalendarBucket::Weeks(_) => "Monday",
+            CalendarBucket::Months(_) => "the first of the month",
+            CalendarBucket::Years(_) => "the first of January",
+        }
+    }
+}
+
+/// Keep only the first snapshot after each calendar boundary among the
+/// snapshots subject to a spacing rule. The newest and oldest snapshots are
+/// always kept.
+fn plan_aligned<'a>(
+    entries: &'a [SnapshotEntry],
+    rule: usize,
+    spacing: (Duration, Duration),
+    bucket: CalendarBucket,
+    delete: &mut Reasons<'a>,
+    kept: &mut Reasons<'a>,
+) {
+    trace!("Purging for rule {} aligned to {:?}", rule, bucket);
+    // Entries are sorted newest first, so the first snapshot of a bucket is
+    // the last one seen.
+    let mut first: IndexMap<(i64, i64), &SnapshotEntry> = IndexMap::new();
+    for entry in entries.iter().filter(|entry| entry.rule == Some(rule)) {
+        first.insert(bucket.key(&entry.date), entry);
+    }
+    let last = entries.len().saturating_sub(1);
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.rule != Some(rule) {
+            continue;
+        }
+        let keep = first[&bucket.key(&entry.date)];
+        if keep.path == entry.path {
+            kept.insert(
+                entry.path.as_path(),
+                Reason::spacing(spacing)
+                    .with_detail(format!("first snapshot after {}", bucket.boundary())),
+            );
+        } else if index != 0 && index != last {
+            debug!("  Dropping {} in favor of {}", entry.date, keep.date);
+            delete.insert(
+                entry.path.as_path(),
+                Reason::spacing(spacing).with_detail(format!(
+                    "{} is the first snapshot after {}",
+                    keep.date,
+                    bucket.boundary()
+                )),
+            );
+        }
+    }
+}
+
+/// Determine which snapshots to delete such that only the newest snapshot in
+/// each of the configured number of calendar periods remains. The newest
+/// snapshot is always kept. Why the others are kept is added to `kept`. The
+/// entries must be sorted by descending date.
+pub fn plan_keep_counts<'a>(
+    entries: &'a [SnapshotEntry],
+    keep: &KeepCounts,
+    kept: &mut Reasons<'a>,
+) -> Reasons<'a> {
+    let mut reasons = vec![None; entries.len()];
+    if let Some(first) = reasons.first_mut() {
+        *first = Some(Reason::new("newest").with_detail("the newest snapshot is always kept"));
+    }
+    for (key, noun, count, period) in keep.periods() {
+        let count = match count {
+            Some(x) => x,
+            None => continue,
+        };
+        let mut last = None;
+        let mut seen = 0;
+        for (entry, reason) in entries.iter().zip(reasons.iter_mut()) {
+            if seen >= count {
+                break;
+            }
+            let p = period(&entry.date);
+            if last != Some(p) {
+                last = Some(p);
+                seen += 1;
+                reason.get_or_insert_with(|| {
+                    Reason::new(format!("{} = {}", key, count))
+                        .with_detail(format!("newest snapshot of {} {} of {}", noun, seen, count))
+                });
+            }
+        }
+    }
+    let mut delete = Reasons::new();
+    for (entry, reason) in entries.iter().zip(reasons) {
+        match reason {
+            Some(reason) => {
+                kept.insert(entry.path.as_path(), reason);
+            }
+            None => {
+                debug!("  Dropping {}", entry.date);
+                delete.insert(
+                    entry.path.as_path(),
+                    Reason::new("outside keep counts")
+                        .with_detail("not the newest snapshot of any kept period"),
+                );
+            }
+        }
+    }
+    delete
+}
+
+/// Delete the oldest snapshots until the exclusive size of the remaining
+/// snapshots is at most `max` bytes. The newest snapshot is always kept. The
+/// entries must be sorted by descending date.
+pub fn limit_size<'a>(
+    entries: &'a [SnapshotEntry],
+    delete: &mut Reasons<'a>,
+    max: u64,
+    exclusive: impl Fn(&Path) -> Result<u64>,
+) -> Result<()> {
+    let mut remaining = Vec::new();
+    let mut total = 0;
+    for entry in entries {
+        if !delete.contains_key(entry.path.as_path()) {
+            let size = exclusive(&entry.path)?;
+            trace!("  Exclusive size of {}: {}", entry.date, size);
+            remaining.push((entry, size));
+            total += size;
+        }
+    }
+    for &(entry, size) in remaining.iter().skip(1).rev() {
+        if total <= max {
+            break;
+        }
+        debug!(
+            "  Dropping {} to reduce total size {} beyond {}",
+            entry.date, total, max
+        );
+        delete.insert(
+            entry.path.as_path(),
+            Reason::new(format!("max_total_size = {}", ByteSize(max))).with_detail(format!(
+                "oldest of snapshots using {} exclusively",
+                ByteSize(total)
+            )),
+        );
+        total -= size;
+    }
+    Ok(())
+}
+
+/// Adjust the snapshots to be deleted such that at least `min` and at most
+/// `max` snapshots remain. Snapshots beyond `max` are deleted oldest first,
+/// and snapshots below `min` are spared newest first. The entries must be
+/// sorted by descending date.
+pub fn limit_count<'a>(
+    entries: &'a [SnapshotEntry],
+    delete: &mut Reasons<'a>,
+    min: Option<usize>,
+    max: Option<usize>,
+) {
+    if let Some(max) = max {
+        let mut remaining = entries.len() - delete.len();
+        for entry in entries.iter().rev() {
+            if remaining <= max {
+                break;
+            }
+            if !delete.contains_key(entry.path.as_path()) {
+                delete.insert(
+                    entry.path.as_path(),
+                    Reason::new(format!("keep_max = {}", max))
+                        .with_detail(format!("oldest of {} remaining snapshots", remaining)),
+                );
+                debug!("  Dropping {} beyond `keep_max` of {}", entry.date, max);
+                remaining -= 1;
+            }
+        }
+    }
+    if let Some(min) = min {
+        let mut remaining = entries.len() - delete.len();
+        for entry in entries {
+            if remaining >= min {
+                break;
+            }
+            if delete.shift_remove(entry.path.as_path()).is_some() {
+                debug!("  Keeping {} due to `keep_min` of {}", entry.date, min);
+                remaining += 1;
+            }
+        }
+    }
+}
+
+/// Delete all snapshots older than `max_age`, regardless of the spacings and
+/// keep counts.
+pub fn limit_age<'a>(entries: &'a [SnapshotEntry], delete: &mut Reasons<'a>, max_age: Duration) {
+    for entry in entries {
+        if entry.age > max_age && !delete.contains_key(entry.path.as_path()) {
+            delete.insert(
+                entry.path.as_path(),
+                Reason::new(format!("max_age = {}", format_duration(max_age)))
+                    .with_detail(format!("{} old", format_duration(entry.age))),
+            );
+            debug!(
+                "  Dropping {} beyond `max_age` of {}",
+                entry.date,
+                format_duration(max_age)
+            );
+        }
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Schedules that determine when the daemon takes snapshots.
+//!
+//! A schedule is either one of the shorthands `hourly`, `daily`, `weekly`,
+//! `monthly`, and `yearly`, or a cron expression with the five fields minute,
+//! hour, day of month, month, and day of week. Schedules are evaluated in the
+//! local time zone.
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+/// How many years ahead to look for the next time a schedule is due.
+const SEARCH_YEARS: i32 = 5;
+
+/// When to take snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    /// The schedule as written in the config.
+    text: String,
+    /// The minutes at which the schedule is due, as a bit set.
+    minutes: u64,
+    /// The hours at which the schedule is due, as a bit set.
+    hours: u64,
+    /// The days of the month on which the schedule is due, as a bit set.
+    days: u64,
+    /// The months in which the schedule is due, as a bit set.
+    months: u64,
+    /// The days of the week on which the schedule is due, as a bit set with
+    /// Sunday as 0.
+    weekdays: u64,
+    /// Whether the day of month field is `*`.
+    any_day: bool,
+    /// Whether the day of week field is `*`.
+    any_weekday: bool,
+}
+
+impl Schedule {
+    /// Determine the first time after `time` at which the schedule is due.
+    /// Returns `None` if it is never due, e.g. on February 30th.
+    pub fn next_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = time.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let end_year = start.year() + SEARCH_YEARS;
+        let mut t = start;
+        while t.year() <= end_year {
+            if !has(self.months, t.month()) {
+                t = start_of_month(t.year(), t.month() + 1)?;
+            } else if !self.day_matches(t) {
+                t = t.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !has(self.hours, t.hour()) {
+                t = t.with_minute(0)? + Duration::hours(1);
+            } else if !has(self.minutes, t.minute()) {
+                t += Duration::minutes(1);
+            } else {
+                // Skip times that do not exist or already passed due to a
+                // daylight saving time change.
+                match Local.from_local_datetime(&t).earliest() {
+                    Some(due) if due > time => return Some(due),
+                    _ => t += Duration::minutes(1),
+                }
+            }
+        }
+        None
+    }
+
+    /// Check whether the schedule is due on the day of a given time. As in
+    /// cron, a day matches either field if both are restricted.
+    fn day_matches(&self, t: NaiveDateTime) -> bool {
+        let day = has(self.days, t.day());
+        let weekday = has(self.weekdays, t.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+/// Check whether a bit set contains a value.
+fn has(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+/// The first minute of a month, where month 13 is January of the next year.
+fn start_of_month(year: i32, month: u32) -> Option<NaiveDateTime> {
+    let (year, month) = if month > 12 {
+        (year + 1, 1)
+    } else {
+        (year, month)
+    };
+    NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)
+}
+
+/// Parse one field of a cron expression into a bit set of the values it
+/// matches. Supports `*`, numbers, ranges `a-b`, steps `*/n` and `a-b/n`, and
+/// comma-separated lists thereof.
+fn parse_field(field: &str, name: &str, min: u32, max: u32) -> Result<u64> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let invalid = || anyhow!("Invalid {} `{}` in schedule", name, part);
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((a, b)) => (
+                    a.parse().map_err(|_| invalid())?,
+                    b.parse().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let a = range.parse().map_err(|_| invalid())?;
+                    (a, if part.contains('/') { max } else { a })
+                }
+            },
+        };
+        if step == 0 || first < min || last > max || first > last {
+            return Err(invalid());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let expr = match s.trim_start_matches('@') {
+            "hourly" => "0 * * * *",
+            "daily" => "0 0 * * *",
+            "weekly" => "0 0 * * 0",
+            "monthly" => "0 0 1 * *",
+            "yearly" => "0 0 1 1 *",
+            _ => s,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            bail!(
+                "Invalid schedule `{}`; expected `hourly`, `daily`, `weekly`, `monthly`, \
+                 `yearly`, or a cron expression with five fields",
+                s
+            );
+        }
+        let mut weekdays = parse_field(fields[4], "day of week", 0, 7)?;
+        // Both 0 and 7 denote Sunday.
+        if has(weekdays, 7) {
+            weekdays |= 1;
+        }
+        Ok(Schedule {
+            text: s.to_owned(),
+            minutes: parse_field(fields[0], "minute", 0, 59)?,
+            hours: parse_field(fields[1], "hour", 0, 23)?,
+            days: parse_field(fields[2], "day of month", 1, 31)?,
+            months: parse_field(fields[3], "month", 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Serialize for Schedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Periodically scrubbing the filesystems that hold snapshots, such that
+//! their data is checked against its checksums.
+
+use crate::{output::OutputFormat, privilege, SnapshotConfig, State};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Timelike as _};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The name of the file within the state directory that holds the outcome of
+/// past scrubs.
+const SCRUB_FILE: &str = "scrub.toml";
+
+/// How often to scrub the filesystem of a snapshot config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrubConfig {
+    /// The time between the start of two scrubs, e.g. `1month`.
+    pub interval: humantime_serde::Serde<Duration>,
+}
+
+/// The outcome of the most recent scrub of each filesystem.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ScrubFile {
+    /// The last scrub of each filesystem, keyed by mount point.
+    #[serde(default)]
+    pub filesystems: IndexMap<PathBuf, ScrubStatus>,
+}
+
+/// The outcome of a single scrub.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrubStatus {
+    /// When the scrub was started.
+    pub time: DateTime<Local>,
+    /// Whether the scrub completed without finding errors.
+    pub success: bool,
+    /// The error summary reported by btrfs, or the error message if the
+    /// scrub failed.
+    pub summary: Option<String>,
+}
+
+impl ScrubFile {
+    /// Load the scrub status from a state directory. Returns no scrubs if the
+    /// file does not exist yet.
+    pub fn load(state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(SCRUB_FILE);
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        debug!("Loading scrub status {}", path.display());
+        let buf = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read scrub status from {}", path.display()))?;
+        toml::de::from_str(&buf)
+            .with_context(|| format!("Failed to parse scrub status from {}", path.display()))
+    }
+
+    /// Write the scrub status into a state directory.
+    pub fn save(&self, state_dir: &Path) -> Result<()> {
+        let path = state_dir.join(SCRUB_FILE);
+        debug!("Saving scrub status {}", path.display());
+        std::fs::create_dir_all(state_dir)
+            .with_context(|| format!("Failed to create state dir {}", state_dir.display()))?;
+        std::fs::write(&path, toml::ser::to_string(self)?)
+            .with_context(|| format!("Failed to write scrub status to {}", path.display()))
+    }
+
+    /// Check whether a filesystem is due for a scrub.
+    fn is_due(&self, mount_point: &Path, interval: Duration) -> bool {
+        match self.filesystems.get(mount_point) {
+            Some(last) => Local::now()
+                .signed_duration_since(last.time)
+                .to_std()
+                .is_ok_and(|age| age >= interval),
+            None => true,
+        }
+    }
+}
+
+impl<'a> State<'a> {
+    /// Scrub the filesystems of the given snapshot configs whose last scrub
+    /// is longer ago than their `scrub` interval, recording the outcome in
+    /// `scrubs`. With `force`, every filesystem is scrubbed, whether it has a
+    /// `scrub` config or not. A failed scrub does not keep the other
+    /// filesystems from being scrubbed; the first error is returned.
+    pub fn scrub_filesystems(
+        &mut self,
+        snapshots: &[&'a SnapshotConfig],
+        scrubs: &mut ScrubFile,
+        force: bool,
+    ) -> Result<()> {
+        // Filesystems shared by several configs are scrubbed as often as the
+        // config with the shortest interval asks for.
+        let mut due: IndexMap<&Path, (&'a SnapshotConfig, Option<Duration>)> = IndexMap::new();
+        for &snapshot in snapshots {
+            let interval = snapshot
+                .scrub
+                .as_ref()
+                .map(|scrub| scrub.interval.into_inner());
+            if interval.is_none() && !force {
+                continue;
+            }
+            let mount_point = snapshot.mount_point.as_deref().unwrap();
+            let entry = due.entry(mount_point).or_insert((snapshot, interval));
+            entry.1 = match (entry.1, interval) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+
+        let mut first_error = None;
+        for (mount_point, (snapshot, interval)) in due {
+            if !force && !scrubs.is_due(mount_point, interval.unwrap()) {
+                debug!("Not scrubbing {}; scrubbed recently", mount_point.display());
+                continue;
+            }
+            let time = Local::now().with_nanosecond(0).unwrap();
+            let result = self.scrub(snapshot, mount_point);
+            if self.dry_run {
+                continue;
+            }
+            scrubs.filesystems.insert(
+                mount_point.to_owned(),
+                ScrubStatus {
+                    time,
+                    success: result.is_ok(),
+                    summary: match &result {
+                        Ok(summary) => summary.clone(),
+                        Err(e) => Some(format!("{:#}", e)),
+                    },
+                },
+            );
+            if let Err(e) = result {
+                self.record_failure(&snapshot.name, "scrub", &e);
+                match first_error {
+                    Some(_) => error!("{:?}", e),
+                    None => first_error = Some(e),
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Scrub a single filesystem and wait for the scrub to finish. Returns
+    /// the error summary reported by btrfs.
+    fn scrub(
+        &mut self,
+        snapshot: &'a SnapshotConfig,
+        mount_point: &Path,
+    ) -> Result<Option<String>> {
+        self.mount_snapshot_fs(snapshot)?;
+        if self.output == OutputFormat::Text {
+            println!("Scrubbing filesystem {}", mount_point.display());
+        }
+        let mut cmd = privilege::command("btrfs");
+        cmd.arg("scrub").arg("start").arg("-B").arg(mount_point);
+        if self.dry_run {
+            self.print_commands(&[&mut cmd]);
+            return Ok(None);
+        }
+        let output = self
+            .executor
+            .run(&mut [&mut cmd])
+            .with_context(|| format!("Scrubbing {} failed", mount_point.display()))?;
+        let summary = output
+            .lines()
+            .map(str::trim)
+            .find(|line| line.starts_with("Error summary:"))
+            .map(String::from);
+        info!(
+            "Scrubbed {}: {}",
+            mount_point.display(),
+            summary.as_deref().unwrap_or("done")
+        );
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::Operation, tests::Fixture};
+
+    #[test]
+    fn scrub_only_when_due() {
+        let fixture = Fixture::new("scrub", "scrub = { interval = \"1week\" }");
+        let (mut state, mock) = fixture.state();
+        let mut scrubs = ScrubFile::default();
+        for _ in 0..2 {
+            state
+                .scrub_filesystems(&[fixture.snapshot()], &mut scrubs, false)
+                .unwrap();
+        }
+        assert_eq!(
+            mock.operations(),
+            vec![Operation::Run(vec![vec![
+                "btrfs".to_string(),
+                "scrub".to_string(),
+                "start".to_string(),
+                "-B".to_string(),
+                "/mnt".to_string(),
+            ]])]
+        );
+        assert!(scrubs.filesystems[Path::new("/mnt")].success);
+    }
+}
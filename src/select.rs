@@ -0,0 +1,80 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Selecting snapshot configs on the command line by name or group, with
+//! patterns that include or exclude them.
+//!
+//! A pattern is either a glob with `*` and `?` wildcards, such as `vm-*`, or a
+//! regular expression enclosed in slashes, such as `/^vm-\d+$/`. A name
+//! without wildcards matches only itself.
+
+use crate::{wildcard_regex, SnapshotConfig};
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Which snapshot configs to operate on.
+#[derive(Debug, Default, Clone)]
+pub struct Selector {
+    /// The patterns of which one must match a config. All enabled configs
+    /// are selected if there are none.
+    include: Vec<Regex>,
+    /// The patterns of which none may match a config.
+    exclude: Vec<Regex>,
+}
+
+impl Selector {
+    /// Create a selector from the patterns of configs to include and exclude.
+    pub fn new<'s>(
+        include: impl IntoIterator<Item = &'s str>,
+        exclude: impl IntoIterator<Item = &'s str>,
+    ) -> Result<Self> {
+        Ok(Self {
+            include: include.into_iter().map(pattern).collect::<Result<_>>()?,
+            exclude: exclude.into_iter().map(pattern).collect::<Result<_>>()?,
+        })
+    }
+
+    /// Check whether a config is selected. Disabled configs are only
+    /// selected if they are included explicitly.
+    pub fn selects(&self, snapshot: &SnapshotConfig) -> bool {
+        let matches = |re: &Regex| re.is_match(&snapshot.name) || re.is_match(&snapshot.group);
+        if self.exclude.iter().any(matches) {
+            return false;
+        }
+        if self.include.is_empty() {
+            return snapshot.enabled != Some(false);
+        }
+        self.include.iter().any(matches)
+    }
+
+    /// Check whether a config name is selected, regardless of whether the
+    /// config still exists or is enabled.
+    pub fn selects_name(&self, name: &str) -> bool {
+        !self.exclude.iter().any(|re| re.is_match(name))
+            && (self.include.is_empty() || self.include.iter().any(|re| re.is_match(name)))
+    }
+}
+
+/// Compile a glob or a regular expression enclosed in slashes.
+fn pattern(s: &str) -> Result<Regex> {
+    match s.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+        Some(re) => Regex::new(&format!("^(?:{})$", re))
+            .with_context(|| format!("Invalid regular expression `{}`", s)),
+        None => Ok(wildcard_regex(s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selector_matches_globs_and_regexes() {
+        let selector = Selector::new(vec!["vm-*", "/db-\\d+/"], vec!["vm-test"]).unwrap();
+        assert!(selector.selects_name("vm-1"));
+        assert!(selector.selects_name("db-42"));
+        assert!(!selector.selects_name("vm-test"));
+        assert!(!selector.selects_name("db-x"));
+        assert!(!selector.selects_name("home"));
+        assert!(Selector::new(vec!["/(/"], vec![]).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Checking that a filesystem has enough free space before taking a snapshot,
+//! and pruning snapshots in an emergency if it does not.
+
+use crate::{
+    color, find_snapshots, notification::EventKind, output::OutputFormat, privilege,
+    retention::SnapshotEntry, size::ByteSize, SnapshotConfig, State,
+};
+use anyhow::{anyhow, Context, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{cmp::Ordering, fmt, str::FromStr, time::Duration};
+
+/// The free space a filesystem must have for a snapshot to be taken, either
+/// as an absolute size such as `20GB` or as a percentage such as `10%`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MinFree {
+    /// A number of bytes.
+    Size(ByteSize),
+    /// A percentage of the size of the filesystem.
+    Percent(f64),
+}
+
+/// What to do if a filesystem has less free space than `min_free`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MinFreeAction {
+    /// Skip the snapshot with a warning.
+    #[default]
+    Skip,
+    /// Delete the oldest snapshots until enough space is free, and skip the
+    /// snapshot if that is not enough.
+    Prune,
+}
+
+/// The size and free space of a filesystem, as estimated by btrfs.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Usage {
+    /// The size of all devices of the filesystem.
+    pub size: u64,
+    /// The estimated free space.
+    pub free: u64,
+}
+
+impl MinFree {
+    /// Determine the number of free bytes required on a filesystem of the
+    /// given size.
+    pub(crate) fn bytes(self, size: u64) -> u64 {
+        match self {
+            MinFree::Size(size) => size.bytes(),
+            MinFree::Percent(percent) => (size as f64 * percent / 100.0) as u64,
+        }
+    }
+}
+
+impl FromStr for MinFree {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().strip_suffix('%') {
+            Some(percent) => match percent.trim().parse() {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(MinFree::Percent(percent)),
+                _ => Err(anyhow!("Invalid percentage `{}`", s)),
+            },
+            None => Ok(MinFree::Size(s.parse()?)),
+        }
+    }
+}
+
+impl fmt::Display for MinFree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MinFree::Size(size) => write!(f, "{}", size),
+            MinFree::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+impl Serialize for MinFree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            MinFree::Size(size) => size.serialize(serializer),
+            MinFree::Percent(_) => serializer.serialize_str(&self.to_string()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MinFree {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = MinFree;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a size such as \"20GB\" or a percentage such as \"10%\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<MinFree, E> {
+                Ok(MinFree::Size(ByteSize(v)))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<MinFree, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// Find the snapshot whose deletion hurts the retention rules the least,
+/// among snapshots sorted newest first. The newest snapshot is never picked.
+///
+/// Snapshots subject to a spacing rule rank by the gap their deletion would
+/// leave between their neighbors, relative to the rule's spacing. They go
+/// before the oldest snapshot, which in turn goes before the snapshots younger
+/// than the first rule, which rank by the gap alone.
+fn least_valuable(entries: &[SnapshotEntry], spacings: &[(Duration, Duration)]) -> Option<usize> {
+    let gap = |index: usize| -> f64 {
+        let newer = entries[index - 1].date;
+        let older = entries
+            .get(index + 1)
+            .map_or(entries[index].date, |e| e.date);
+        newer.signed_duration_since(older).num_seconds() as f64
+    };
+    (1..entries.len())
+        .map(|index| {
+            let rank = match entries[index].rule {
+                _ if index + 1 == entries.len() => (1, 0.0),
+                Some(rule) => (0, gap(index) / spacings[rule].1.as_secs_f64().max(1.0)),
+                None => (2, gap(index)),
+            };
+            (index, rank)
+        })
+        .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+        .map(|(index, _)| index)
+}
+
+/// Parse the output of `btrfs filesystem usage -b`.
+fn parse_usage(output: &str) -> Option<Usage> {
+    let field = |name: &str| {
+        output
+            .lines()
+            .map(str::trim)
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|rest| rest.split_whitespace().next()?.parse().ok())
+    };
+    Some(Usage {
+        size: field("Device size:")?,
+        free: field("Free (estimated):")?,
+    })
+}
+
+impl<'a> State<'a> {
+    /// Make sure the filesystem of a snapshot config has at least `min_free`
+    /// space, pruning the oldest snapshots if the config asks for it. Returns
+    /// whether the snapshot may be taken.
+    pub(crate) fn ensure_free_space(
+        &mut self,
+        snapshot: &'a SnapshotConfig,
+        min_free: MinFree,
+    ) -> Result<bool> {
+        let mut usage = self.filesystem_usage(snapshot)?;
+        let required = min_free.bytes(usage.size);
+        if usage.free >= required {
+            return Ok(true);
+        }
+        debug!(
+            "Only {} free on {}, below `min_free` of {}",
+            ByteSize(usage.free),
+            snapshot.mount_point.as_ref().unwrap().display(),
+            min_free
+        );
+
+        // Delete the oldest snapshots in an emergency.
+        if snapshot.min_free_action == Some(MinFreeAction::Prune) {
+            if self.dry_run {
+                if self.output == OutputFormat::Text {
+                    println!(
+                        "Would delete the oldest snapshots of {} until {} is free",
+                        snapshot.name,
+                        ByteSize(required)
+                    );
+                }
+                return Ok(true);
+            }
+            usage = self
+                .prune_until(snapshot, usage, required, |entries| Some(entries.len() - 1))?
+                .0;
+            if usage.free >= required {
+                return Ok(true);
+            }
+        }
+
+        let message = format!(
+            "Skipping snapshot of {}; only {} free, below `min_free` of {}",
+            snapshot.subvolume().display(),
+            ByteSize(usage.free),
+            min_free
+        );
+        if self.output == OutputFormat::Text {
+            println!("{} {}", color::warn("Low space:"), message);
+        }
+        self.record_event(EventKind::Warning, &snapshot.name, "take", message);
+        Ok(false)
+    }
+
+    /// Delete the least valuable snapshots of a config, ranked by its
+    /// spacings, until the filesystem has at least `required` bytes of free
+    /// space. Returns the number of deleted snapshots.
+    pub(crate) fn free_up(&mut self, snapshot: &'a SnapshotConfig, required: u64) -> Result<usize> {
+        if self.dry_run {
+            if self.output == OutputFormat::Text {
+                println!(
+                    "Would delete the least valuable snapshots of {} until {} is free",
+                    snapshot.name,
+                    ByteSize(required)
+                );
+            }
+            return Ok(0);
+        }
+        let spacings = snapshot.sorted_spacings();
+        let usage = self.filesystem_usage(snapshot)?;
+        let (usage, deleted) = self.prune_until(snapshot, usage, required, |entries| {
+            least_valuable(entries, &spacings)
+        })?;
+        if usage.free < required {
+            let message = format!(
+                "Only {} free on {} after deleting {} snapshot(s) of {}, below the target of {}",
+                ByteSize(usage.free),
+                snapshot.mount_point.as_ref().unwrap().display(),
+                deleted,
+                snapshot.name,
+                ByteSize(required)
+            );
+            warn!("{}", message);
+            self.record_event(EventKind::Warning, &snapshot.name, "rotate", message);
+        }
+        Ok(deleted)
+    }
+
+    /// Determine how many bytes must be free on the filesystem of a snapshot
+    /// config after rotating it, given the `--free-up` amount to reclaim on
+    /// top of what is free now and the config's `free_target`. Returns `None`
+    /// if neither is set.
+    pub(crate) fn free_space_target(&self, snapshot: &SnapshotConfig) -> Result<Option<u64>> {
+        if self.free_up.is_none() && snapshot.free_target.is_none() {
+            return Ok(None);
+        }
+        let usage = self.filesystem_usage(snapshot)?;
+        let reclaim = self.free_up.map(|amount| usage.free + amount.bytes());
+        let target = snapshot.free_target.map(|target| target.bytes(usage.size));
+        Ok(reclaim.max(target))
+    }
+
+    /// Determine the free space on the filesystem of a snapshot config.
+    pub(crate) fn free_space(&self, snapshot: &SnapshotConfig) -> Result<u64> {
+        Ok(self.filesystem_usage(snapshot)?.free)
+    }
+
+    /// Wait for btrfs to clean up the snapshots deleted from a config, and
+    /// report how much space that released compared to `free_before`.
+    pub(crate) fn sync_deletions(
+        &mut self,
+        snapshot: &SnapshotConfig,
+        free_before: u64,
+    ) -> Result<()> {
+        let free = self.free_space(snapshot)?;
+        let reclaimed = free.saturating_sub(free_before);
+        let mount_point = snapshot.mount_point.as_ref().unwrap();
+        if self.output == OutputFormat::Text {
+            println!(
+                "{} {} on {}, {} now free",
+                color::keep("Reclaimed"),
+                ByteSize(reclaimed),
+                mount_point.display(),
+                ByteSize(free)
+            );
+        }
+        self.metrics.record_reclaimed(&snapshot.name, reclaimed);
+        Ok(())
+    }
+
+    /// Delete snapshots one by one, waiting for btrfs to actually free their
+    /// space after each, until at least `required` bytes are free, starting
+    /// from the current `usage`. `pick` chooses which of the remaining
+    /// snapshots, sorted newest first, to delete next. Held snapshots and the
+    /// newest `keep_min` ones, at least one, are never offered. Returns the
+    /// final usage and the number of deleted snapshots.
+    fn prune_until(
+        &mut self,
+        snapshot: &'a SnapshotConfig,
+        mut usage: Usage,
+        required: u64,
+        mut pick: impl FnMut(&[SnapshotEntry]) -> Option<usize>,
+    ) -> Result<(Usage, usize)> {
+        let mut deleted = 0;
+        let keep = snapshot.keep_min.unwrap_or(0).max(1);
+        let readonly = snapshot.readonly != Some(false);
+        let mut entries: Vec<_> = find_snapshots(snapshot, &snapshot.sorted_spacings())?;
+        let held: Vec<_> = entries
+            .iter()
+            .filter(|entry| self.holds.is_held(&entry.path))
+            .map(|entry| entry.path.clone())
+            .collect();
+        while usage.free < required && entries.len() > keep {
+            let index = match pick(&entries) {
+                Some(index) => index,
+                None => break,
+            };
+            if held.contains(&entries[index].path) {
+                // Held snapshots stay, but are out of the running.
+                entries.remove(index);
+                continue;
+            }
+            let entry = entries.remove(index);
+            if !self.is_own_snapshot(snapshot, "rotate", &entry.path, readonly) {
+                continue;
+            }
+            warn!(
+                "Deleting {} to free space on {}",
+                entry.path.display(),
+                snapshot.mount_point.as_ref().unwrap().display()
+            );
+            if snapshot.recursive == Some(true) {
+                self.delete_nested(snapshot, &entry.path)?;
+            }
+            self.delete_subvolume(snapshot, &entry.path)
+                .with_context(|| format!("Deleting snapshot {} failed", entry.path.display()))?;
+            deleted += 1;
+            usage = self.filesystem_usage(snapshot)?;
+        }
+        Ok((usage, deleted))
+    }
+
+    /// Determine the size and free space of the filesystem of a snapshot
+    /// config, after waiting for deleted subvolumes to be cleaned up.
+    pub(crate) fn filesystem_usage(&self, snapshot: &SnapshotConfig) -> Result<Usage> {
+        let mount_point = snapshot.mount_point.as_ref().unwrap();
+        self.executor
+            .run(&mut [privilege::command("btrfs")
+                .arg("subvolume")
+                .arg("sync")
+                .arg(mount_point)])
+            .with_context(|| format!("Waiting for cleanup on {} failed", mount_point.display()))?;
+        let output = self
+            .executor
+            .run(&mut [privilege::command("btrfs")
+                .arg("filesystem")
+                .arg("usage")
+                .arg("-b")
+                .arg(mount_point)])
+            .with_context(|| format!("Querying usage of {} failed", mount_point.display()))?;
+        parse_usage(&output)
+            .ok_or_else(|| anyhow!("Cannot parse usage of {}", mount_point.display()))
+    }
+}
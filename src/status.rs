@@ -0,0 +1,72 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Persistent record of the outcome of previous runs.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Timelike as _};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// The name of the file within the state directory that holds the run status.
+const STATUS_FILE: &str = "status.toml";
+
+/// The outcome of the most recent run for each snapshot config.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StatusFile {
+    /// The last run of each snapshot config, keyed by config name.
+    #[serde(default)]
+    pub snapshots: IndexMap<String, RunStatus>,
+}
+
+/// The outcome of a single run for one snapshot config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunStatus {
+    /// When the run finished.
+    pub time: DateTime<Local>,
+    /// The command that was executed, e.g. `take` or `rotate`.
+    pub command: String,
+    /// Whether the run completed without errors.
+    pub success: bool,
+    /// The error message if the run failed.
+    pub error: Option<String>,
+}
+
+impl StatusFile {
+    /// Load the status file from a state directory. Returns an empty status if
+    /// the file does not exist yet.
+    pub fn load(state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(STATUS_FILE);
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        debug!("Loading status {}", path.display());
+        let buf = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read status from {}", path.display()))?;
+        toml::de::from_str(&buf)
+            .with_context(|| format!("Failed to parse status from {}", path.display()))
+    }
+
+    /// Write the status file into a state directory.
+    pub fn save(&self, state_dir: &Path) -> Result<()> {
+        let path = state_dir.join(STATUS_FILE);
+        debug!("Saving status {}", path.display());
+        std::fs::create_dir_all(state_dir)
+            .with_context(|| format!("Failed to create state dir {}", state_dir.display()))?;
+        std::fs::write(&path, toml::ser::to_string(self)?)
+            .with_context(|| format!("Failed to write status to {}", path.display()))
+    }
+
+    /// Record the outcome of a run for a snapshot config.
+    pub fn record(&mut self, name: &str, command: &str, result: &Result<()>) {
+        self.snapshots.insert(
+            name.to_owned(),
+            RunStatus {
+                time: Local::now().with_nanosecond(0).unwrap(),
+                command: command.to_owned(),
+                success: result.is_ok(),
+                error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            },
+        );
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Querying the properties of btrfs subvolumes.
+
+use crate::{ioctl, privilege, run};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// The properties of a subvolume as reported by `btrfs subvolume show`.
+#[derive(Debug, Clone, Default)]
+pub struct SubvolumeInfo {
+    /// The generation of the most recent transaction that modified the
+    /// subvolume.
+    pub generation: u64,
+    /// The generation at which the subvolume was created.
+    pub gen_at_creation: u64,
+    /// The UUID of the subvolume.
+    pub uuid: Option<String>,
+    /// The UUID of the subvolume this one is a snapshot of.
+    pub parent_uuid: Option<String>,
+    /// The UUID of the subvolume this one was received from, set once it has
+    /// been received completely.
+    pub received_uuid: Option<String>,
+    /// Whether the subvolume is read-only.
+    pub readonly: bool,
+}
+
+/// Query the properties of a subvolume.
+pub fn show(path: &Path) -> Result<SubvolumeInfo> {
+    let output = run(privilege::command("btrfs")
+        .arg("subvolume")
+        .arg("show")
+        .arg(path))
+    .with_context(|| format!("Querying subvolume {} failed", path.display()))?;
+    let text = |name: &str| -> Option<&str> {
+        output
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix(name))
+            .filter_map(|rest| rest.trim_start().strip_prefix(':'))
+            .map(|value| value.trim())
+            .next()
+    };
+    let field = |name: &str| -> Result<u64> {
+        text(name)
+            .ok_or_else(|| anyhow!("No `{}` reported for {}", name, path.display()))?
+            .parse::<u64>()
+            .with_context(|| format!("Invalid `{}` reported for {}", name, path.display()))
+    };
+    let uuid = |name: &str| match text(name) {
+        None | Some("-") => None,
+        Some(uuid) => Some(uuid.to_string()),
+    };
+    Ok(SubvolumeInfo {
+        generation: field("Generation")?,
+        gen_at_creation: field("Gen at creation")?,
+        uuid: uuid("UUID"),
+        parent_uuid: uuid("Parent UUID"),
+        received_uuid: uuid("Received UUID"),
+        readonly: text("Flags").is_some_and(|flags| flags.contains("readonly")),
+    })
+}
+
+/// Make sure that `path` is a snapshot of the subvolume at `source`, or of a
+/// subvolume that was at `source` before it was restored and had one of the
+/// `former_uuids`, and that it is read-only if `readonly` is set. Guards
+/// against deleting a stray directory or subvolume that merely matches the
+/// snapshot name format.
+pub fn verify_snapshot(
+    path: &Path,
+    source: &Path,
+    former_uuids: &[String],
+    readonly: bool,
+) -> Result<()> {
+    let info = show(path)?;
+    let source_info = show(source)?;
+    let is_snapshot = info.parent_uuid.as_ref().is_some_and(|parent| {
+        source_info.uuid.as_ref() == Some(parent) || former_uuids.contains(parent)
+    });
+    if !is_snapshot {
+        bail!(
+            "{} is not a snapshot of {}; delete it manually if it should go",
+            path.display(),
+            source.display()
+        );
+    }
+    if readonly && !info.readonly {
+        bail!(
+            "{} is not read-only, unlike the snapshots taken of {}; delete it manually if it \
+             should go",
+            path.display(),
+            source.display()
+        );
+    }
+    Ok(())
+}
+
+/// Make sure that `path` is a read-only snapshot that was completely received
+/// by `btrfs receive`, which only sets the received UUID once the stream has
+/// been applied in full.
+pub fn verify_received(path: &Path) -> Result<()> {
+    let info = show(path)?;
+    if info.received_uuid.is_none() || !info.readonly {
+        bail!(
+            "{} is not a completely received snapshot; delete it manually if it should go",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Check whether a path is the root of a subvolume, rather than a regular
+/// directory or file, on the remote host if there is one. The root directory
+/// of every subvolume has the same inode number.
+pub fn is_subvolume(path: &Path) -> bool {
+    if privilege::remote().is_some() {
+        return run(privilege::command("stat").arg("-c").arg("%F %i").arg(path)).is_ok_and(
+            |output| output.trim() == format!("directory {}", ioctl::BTRFS_FIRST_FREE_OBJECTID),
+        );
+    }
+    std::fs::symlink_metadata(path)
+        .is_ok_and(|meta| meta.is_dir() && meta.ino() == ioctl::BTRFS_FIRST_FREE_OBJECTID)
+}
+
+/// Determine the UUID of the btrfs filesystem that `path` lives on, on the
+/// remote host if there is one. Fails if it is on another kind of filesystem.
+fn filesystem_uuid(path: &Path) -> Result<String> {
+    if !privilege::via_commands() {
+        let id = ioctl::filesystem_id(path)?;
+        return Ok(id.iter().map(|byte| format!("{:02x}", byte)).collect());
+    }
+    let output = run(privilege::command("findmnt")
+        .arg("-n")
+        .arg("-o")
+        .arg("FSTYPE,UUID")
+        .arg("-T")
+        .arg(path))
+    .with_context(|| format!("Failed to determine the filesystem of {}", path.display()))?;
+    match output.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["btrfs", uuid] => Ok(uuid.to_string()),
+        _ => bail!("{} is not on a btrfs filesystem", path.display()),
+    }
+}
+
+/// Make sure that a snapshot of the subvolume at `source` can be placed in
+/// the directory `dir`, which must be on the same btrfs filesystem.
+pub fn check_same_filesystem(source: &Path, dir: &Path) -> Result<()> {
+    if filesystem_uuid(source)? != filesystem_uuid(dir)? {
+        bail!(
+            "Snapshot dir {} is not on the same btrfs filesystem as subvolume {}; snapshots \
+             can only be placed on the filesystem of their subvolume",
+            dir.display(),
+            source.display()
+        );
+    }
+    Ok(())
+}
+
+/// Check whether a subvolume has been modified since a snapshot of it was
+/// taken.
+pub fn changed_since(subvolume: &Path, snapshot: &Path) -> Result<bool> {
+    let source = show(subvolume)?;
+    let snapshot = show(snapshot)?;
+    trace!(
+        "Source generation {}, snapshot created at generation {}",
+        source.generation,
+        snapshot.gen_at_creation
+    );
+    Ok(source.generation > snapshot.gen_at_creation)
+}
+
+/// Find the subvolumes nested anywhere within a subvolume, as paths relative
+/// to it. Parents are sorted before the subvolumes nested within them.
+pub fn nested(path: &Path) -> Result<Vec<PathBuf>> {
+    if let Some(host) = privilege::remote() {
+        bail!(
+            "Cannot find nested subvolumes in {} on {}; `recursive` only works locally",
+            path.display(),
+            host
+        );
+    }
+    let nested = privilege::explain(
+        ioctl::nested_subvolumes(path),
+        "finding nested subvolumes",
+        None,
+    )?;
+    trace!("Nested subvolumes in {}: {:?}", path.display(), nested);
+    Ok(nested)
+}
+
+/// Create a command that marks a subvolume as read-only or writable. The
+/// command is only reported; the change itself is made with an ioctl.
+pub fn readonly_command(path: &Path, readonly: bool) -> Command {
+    let mut cmd = privilege::command("btrfs");
+    cmd.arg("property")
+        .arg("set")
+        .arg("-ts")
+        .arg(path)
+        .arg("ro")
+        .arg(if readonly { "true" } else { "false" });
+    cmd
+}
+
+/// Create a command that snapshots a subvolume. The command is only reported;
+/// the snapshot itself is taken with an ioctl.
+pub fn snapshot_command(source: &Path, target: &Path, readonly: bool) -> Command {
+    let mut cmd = privilege::command("btrfs");
+    cmd.arg("subvolume").arg("snapshot");
+    if readonly {
+        cmd.arg("-r");
+    }
+    cmd.arg(source).arg(target);
+    cmd
+}
+
+/// Create a command that deletes a subvolume. The command is only reported;
+/// the subvolume itself is deleted with an ioctl.
+pub fn delete_command(path: &Path) -> Command {
+    let mut cmd = privilege::command("btrfs");
+    cmd.arg("subvolume").arg("delete").arg(path);
+    cmd
+}
@@ -0,0 +1,140 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Timeouts for external commands, such that a hung mount of a network
+//! filesystem or a stuck hook cannot wedge a run forever.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    process::{Child, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex, OnceLock,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+/// How long external commands may take before they are killed. Commands
+/// without a timeout may run forever.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timeouts {
+    /// Mounting and unmounting filesystems.
+    #[serde(default, with = "humantime_serde")]
+    pub mount: Option<Duration>,
+    /// Running the pre, post, and action hooks.
+    #[serde(default, with = "humantime_serde")]
+    pub hook: Option<Duration>,
+    /// Sending a snapshot to a replication target.
+    #[serde(default, with = "humantime_serde")]
+    pub send: Option<Duration>,
+}
+
+/// The timeouts in effect, set once from the config.
+static TIMEOUTS: OnceLock<Timeouts> = OnceLock::new();
+
+/// Apply `timeouts` to the external commands run from now on.
+pub fn init(timeouts: Timeouts) {
+    TIMEOUTS.set(timeouts).ok();
+}
+
+/// Get the timeouts in effect.
+pub fn timeouts() -> Timeouts {
+    TIMEOUTS.get().copied().unwrap_or_default()
+}
+
+/// Kills the processes of a command or pipeline if they do not finish within
+/// a timeout. Each command must have been spawned in its own process group,
+/// such that the processes it starts are killed along with it, and must be
+/// reaped through the watchdog, such that a reused process ID is never
+/// killed.
+pub(crate) struct Watchdog {
+    /// The process groups of the commands that have not been reaped yet. Each
+    /// group is led by its command, whose process ID is the group's ID.
+    groups: Arc<Mutex<Vec<libc::pid_t>>>,
+    /// Tells the watchdog thread that the processes have finished.
+    done: mpsc::Sender<()>,
+    /// Whether the processes were killed.
+    fired: Arc<AtomicBool>,
+    /// The watchdog thread.
+    thread: JoinHandle<()>,
+    /// The timeout, for reporting.
+    timeout: Duration,
+}
+
+impl Watchdog {
+    /// Start watching processes. Returns `None` if there is no timeout.
+    pub(crate) fn start<'c>(
+        children: impl IntoIterator<Item = &'c Child>,
+        timeout: Option<Duration>,
+    ) -> Option<Self> {
+        let timeout = timeout?;
+        let groups: Vec<_> = children
+            .into_iter()
+            .map(|child| child.id() as libc::pid_t)
+            .collect();
+        let groups = Arc::new(Mutex::new(groups));
+        let (done, finished) = mpsc::channel();
+        let fired = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let groups = groups.clone();
+            let fired = fired.clone();
+            std::thread::spawn(move || {
+                if let Err(mpsc::RecvTimeoutError::Timeout) = finished.recv_timeout(timeout) {
+                    fired.store(true, Ordering::SeqCst);
+                    for &group in groups.lock().unwrap().iter() {
+                        unsafe { libc::killpg(group, libc::SIGKILL) };
+                    }
+                }
+            })
+        };
+        Some(Self {
+            groups,
+            done,
+            fired,
+            thread,
+            timeout,
+        })
+    }
+
+    /// Wait for a watched command to exit and reap it. The command stays a
+    /// zombie until it is no longer watched, such that its process ID cannot
+    /// be reused while the watchdog may still kill its group.
+    pub(crate) fn reap(&self, child: &mut Child) -> io::Result<ExitStatus> {
+        wait_for_exit(child)?;
+        let mut groups = self.groups.lock().unwrap();
+        groups.retain(|&group| group != child.id() as libc::pid_t);
+        child.wait()
+    }
+
+    /// Stop watching once the processes have been waited for, and fail if
+    /// they were killed for taking too long.
+    pub(crate) fn finish(self, what: impl FnOnce() -> String) -> Result<()> {
+        self.done.send(()).ok();
+        self.thread.join().ok();
+        if self.fired.load(Ordering::SeqCst) {
+            bail!(
+                "{} timed out after {} and was killed",
+                what(),
+                humantime::format_duration(self.timeout)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Block until a child process has exited, without reaping it.
+fn wait_for_exit(child: &Child) -> io::Result<()> {
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, child.id(), &mut info, flags) } == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
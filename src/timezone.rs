@@ -0,0 +1,127 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Time zones in which snapshot names are generated and parsed.
+
+use crate::run;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone as _};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, path::Path, process::Command, str::FromStr};
+
+/// The directory that holds the IANA time zone database.
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+/// A time zone for snapshot names.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum TimeZone {
+    /// The system's local time zone.
+    #[default]
+    Local,
+    /// Coordinated universal time.
+    Utc,
+    /// A fixed offset from UTC, e.g. `+02:00`.
+    Fixed(FixedOffset),
+    /// A zone from the IANA time zone database, e.g. `Europe/Zurich`. Offsets
+    /// are looked up with `date`.
+    Named(String),
+}
+
+impl TimeZone {
+    /// Convert an instant into this time zone.
+    pub fn convert(&self, time: DateTime<Local>) -> Result<DateTime<FixedOffset>> {
+        let offset = match self {
+            TimeZone::Local => *time.offset(),
+            TimeZone::Utc => FixedOffset::east_opt(0).unwrap(),
+            TimeZone::Fixed(offset) => *offset,
+            TimeZone::Named(name) => lookup_offset(name, &format!("@{}", time.timestamp()))?,
+        };
+        Ok(time.with_timezone(&offset))
+    }
+
+    /// Interpret a date and time without offset as a time in this zone.
+    pub fn resolve(&self, time: NaiveDateTime) -> Result<DateTime<FixedOffset>> {
+        let offset = match self {
+            TimeZone::Local => *Local
+                .from_local_datetime(&time)
+                .earliest()
+                .ok_or_else(|| anyhow!("{} does not exist in the local time zone", time))?
+                .offset(),
+            TimeZone::Utc => FixedOffset::east_opt(0).unwrap(),
+            TimeZone::Fixed(offset) => *offset,
+            TimeZone::Named(name) => {
+                lookup_offset(name, &time.format("%Y-%m-%d %H:%M:%S").to_string())?
+            }
+        };
+        offset
+            .from_local_datetime(&time)
+            .single()
+            .ok_or_else(|| anyhow!("{} is ambiguous in time zone {}", time, self))
+    }
+}
+
+/// Look up the UTC offset of a named time zone at a date understood by
+/// `date -d`.
+fn lookup_offset(zone: &str, date: &str) -> Result<FixedOffset> {
+    let output = run(Command::new("date")
+        .env("TZ", zone)
+        .arg("-d")
+        .arg(date)
+        .arg("+%z"))
+    .with_context(|| format!("Looking up offset of time zone {} failed", zone))?;
+    let offset = DateTime::parse_from_str(
+        &format!("1970-01-01 00:00:00 {}", output.trim()),
+        "%Y-%m-%d %H:%M:%S %z",
+    )
+    .with_context(|| format!("Invalid offset `{}` for time zone {}", output.trim(), zone))?;
+    Ok(*offset.offset())
+}
+
+impl FromStr for TimeZone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "local" => return Ok(TimeZone::Local),
+            "UTC" | "utc" | "Z" => return Ok(TimeZone::Utc),
+            _ => (),
+        }
+        if s.starts_with('+') || s.starts_with('-') {
+            let offset = DateTime::parse_from_str(
+                &format!("1970-01-01 00:00:00 {}", s),
+                "%Y-%m-%d %H:%M:%S %z",
+            )
+            .map_err(|_| anyhow!("Invalid UTC offset `{}`", s))?;
+            return Ok(TimeZone::Fixed(*offset.offset()));
+        }
+        if s.split('/').any(|part| part.is_empty() || part == "..")
+            || !Path::new(ZONEINFO_DIR).join(s).is_file()
+        {
+            bail!("Unknown time zone `{}`", s);
+        }
+        Ok(TimeZone::Named(s.to_owned()))
+    }
+}
+
+impl fmt::Display for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeZone::Local => write!(f, "local"),
+            TimeZone::Utc => write!(f, "UTC"),
+            TimeZone::Fixed(offset) => write!(f, "{}", offset),
+            TimeZone::Named(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Serialize for TimeZone {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeZone {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
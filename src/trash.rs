@@ -0,0 +1,127 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Moving rotated snapshots into a trash directory, and only deleting them
+//! for good once a grace period has passed.
+
+use crate::{create_dir_all, list_dir, output::ActionKind, SnapshotConfig, State};
+use anyhow::{Context, Result};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+/// The name of the trash directory within a snapshot directory.
+pub const TRASH_DIR: &str = ".trash";
+
+/// Separates the original name of a trashed snapshot from the time it was
+/// trashed, in seconds since the epoch.
+const TRASHED_SEPARATOR: &str = ".trashed-";
+
+/// Get the trash directory of a snapshot config.
+pub fn trash_dir(snapshot: &SnapshotConfig) -> PathBuf {
+    snapshot.snapshot_dir.as_ref().unwrap().join(TRASH_DIR)
+}
+
+/// Determine the original name of a trashed snapshot and when it was
+/// trashed, in seconds since the epoch.
+pub fn parse_trashed(path: &Path) -> Option<(&str, i64)> {
+    let name = path.file_name()?.to_str()?;
+    let (original, time) = name.rsplit_once(TRASHED_SEPARATOR)?;
+    Some((original, time.parse().ok()?))
+}
+
+impl<'a> State<'a> {
+    /// Move a snapshot into the trash directory of its config. The time it
+    /// was trashed is recorded in its new name.
+    pub(crate) fn trash_snapshot(&mut self, snapshot: &SnapshotConfig, path: &Path) -> Result<()> {
+        let dir = trash_dir(snapshot);
+        let name = path.file_name().unwrap().to_string_lossy();
+        let target = dir.join(format!(
+            "{}{}{}",
+            name,
+            TRASHED_SEPARATOR,
+            chrono::Local::now().timestamp()
+        ));
+        if !self.dry_run {
+            create_dir_all(&dir)
+                .with_context(|| format!("Failed to create trash dir {}", dir.display()))?;
+        }
+        let mut cmd = Command::new("mv");
+        cmd.arg(path).arg(&target);
+        self.perform_with(
+            snapshot,
+            ActionKind::Trash,
+            path,
+            &mut [&mut cmd],
+            |exec, _| exec.rename(path, &target).map(|_| 0),
+        )
+        .with_context(|| format!("Moving snapshot {} to the trash failed", path.display()))
+    }
+
+    /// Permanently delete the snapshots that have been in the trash of a
+    /// snapshot config for longer than `grace`.
+    pub(crate) fn empty_trash(&mut self, snapshot: &SnapshotConfig, grace: Duration) -> Result<()> {
+        let dir = trash_dir(snapshot);
+        let mut paths = list_dir(&dir)
+            .with_context(|| format!("Failed to read trash dir {}", dir.display()))?;
+        paths.sort();
+        let now = chrono::Local::now().timestamp();
+        for path in paths {
+            let trashed = match parse_trashed(&path) {
+                Some((_, trashed)) => trashed,
+                None => {
+                    warn!("Ignoring {} in trash; not trashed by us", path.display());
+                    continue;
+                }
+            };
+            if now.saturating_sub(trashed) < grace.as_secs() as i64 {
+                trace!("Keeping {} in trash", path.display());
+                continue;
+            }
+            if snapshot.recursive == Some(true) {
+                self.delete_nested(snapshot, &path)?;
+            }
+            self.delete_subvolume(snapshot, &path)
+                .with_context(|| format!("Deleting snapshot {} failed", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::Operation, tests::Fixture};
+
+    #[test]
+    fn trashed_names_parsed() {
+        assert_eq!(
+            parse_trashed(Path::new("/s/.trash/2021_01_01.trashed-1609459200")),
+            Some(("2021_01_01", 1609459200))
+        );
+        assert_eq!(
+            parse_trashed(Path::new("a.trashed-1.trashed-2")),
+            Some(("a.trashed-1", 2))
+        );
+        assert_eq!(parse_trashed(Path::new("2021_01_01")), None);
+        assert_eq!(parse_trashed(Path::new("a.trashed-soon")), None);
+    }
+
+    #[test]
+    fn trash_emptied_after_grace_period() {
+        let fixture = Fixture::new("trash", "");
+        let dir = trash_dir(fixture.snapshot());
+        let now = chrono::Local::now().timestamp();
+        let expired = dir.join(format!("a{}{}", TRASHED_SEPARATOR, now - 7200));
+        let recent = dir.join(format!("b{}{}", TRASHED_SEPARATOR, now - 60));
+        for path in [&expired, &recent, &dir.join("stray")] {
+            std::fs::create_dir_all(path).unwrap();
+        }
+        let (mut state, mock) = fixture.state();
+        state
+            .empty_trash(fixture.snapshot(), Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(mock.operations(), vec![Operation::Delete(expired)]);
+    }
+}
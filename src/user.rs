@@ -0,0 +1,159 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Running hooks and quiesce commands as a different user and group, such
+//! that they do not need to run as root.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    ffi::{CStr, CString},
+    os::unix::process::CommandExt,
+    path::PathBuf,
+    process::Command,
+};
+
+/// The user and group a command runs as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunAs {
+    /// The user to switch to, if any.
+    pub user: Option<Account>,
+    /// The group to switch to. Defaults to the primary group of the user.
+    pub gid: Option<u32>,
+}
+
+/// A user account, as found in the password database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    /// The name of the user.
+    pub name: String,
+    /// The user ID.
+    pub uid: u32,
+    /// The ID of the user's primary group.
+    pub gid: u32,
+    /// The user's home directory.
+    pub home: PathBuf,
+}
+
+impl RunAs {
+    /// Look up a user and group by name or numeric ID. Returns `None` if
+    /// neither is given, such that commands run as the current user.
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Self>> {
+        if user.is_none() && group.is_none() {
+            return Ok(None);
+        }
+        let user = user.map(lookup_user).transpose()?;
+        let gid = match group {
+            Some(group) => Some(lookup_group(group)?),
+            None => user.as_ref().map(|user| user.gid),
+        };
+        Ok(Some(Self { user, gid }))
+    }
+
+    /// Make a command drop to the user and group before it is executed. The
+    /// user's home directory and name are exposed in `HOME`, `USER`, and
+    /// `LOGNAME`, such that tools find their per-user configuration.
+    pub fn apply(&self, cmd: &mut Command) {
+        if let Some(gid) = self.gid {
+            cmd.gid(gid);
+        }
+        if let Some(user) = &self.user {
+            cmd.uid(user.uid)
+                .env("HOME", &user.home)
+                .env("USER", &user.name)
+                .env("LOGNAME", &user.name);
+        }
+    }
+}
+
+/// Look up a user by name or numeric ID.
+fn lookup_user(name: &str) -> Result<Account> {
+    let cname = CString::new(name)?;
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut found = std::ptr::null_mut();
+        let result = match name.parse::<u32>() {
+            Ok(uid) => unsafe {
+                libc::getpwuid_r(
+                    uid,
+                    &mut entry,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut found,
+                )
+            },
+            Err(_) => unsafe {
+                libc::getpwnam_r(
+                    cname.as_ptr(),
+                    &mut entry,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut found,
+                )
+            },
+        };
+        match result {
+            0 if found.is_null() => bail!("No user named `{}`", name),
+            0 => break,
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            code => {
+                return Err(std::io::Error::from_raw_os_error(code))
+                    .with_context(|| format!("Failed to look up user `{}`", name))
+            }
+        }
+    }
+    let string = |ptr: *const libc::c_char| unsafe { CStr::from_ptr(ptr) }.to_string_lossy();
+    Ok(Account {
+        name: string(entry.pw_name).into_owned(),
+        uid: entry.pw_uid,
+        gid: entry.pw_gid,
+        home: PathBuf::from(string(entry.pw_dir).into_owned()),
+    })
+}
+
+/// Look up a group by name or numeric ID.
+fn lookup_group(name: &str) -> Result<u32> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let cname = CString::new(name)?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut found = std::ptr::null_mut();
+        let result = unsafe {
+            libc::getgrnam_r(
+                cname.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            )
+        };
+        match result {
+            0 if found.is_null() => return Err(anyhow!("No group named `{}`", name)),
+            0 => return Ok(entry.gr_gid),
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            code => {
+                return Err(std::io::Error::from_raw_os_error(code))
+                    .with_context(|| format!("Failed to look up group `{}`", name))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hook_user_and_group_resolved() {
+        assert_eq!(RunAs::resolve(None, None).unwrap(), None);
+        let root = RunAs::resolve(Some("root"), None).unwrap().unwrap();
+        let account = root.user.unwrap();
+        assert_eq!((account.name.as_str(), account.uid), ("root", 0));
+        assert_eq!(root.gid, Some(account.gid));
+        let group = RunAs::resolve(None, Some("42")).unwrap().unwrap();
+        assert_eq!((group.user, group.gid), (None, Some(42)));
+        assert!(RunAs::resolve(Some("no-such-user-here"), None).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! Detecting silent corruption by comparing the contents of files that should
+//! be identical in two snapshots, or in a snapshot and its live subvolume.
+
+use crate::{
+    find_snapshots,
+    output::{Discrepancy, VerifyReport},
+    privilege, resolve_snapshot, SnapshotConfig, State,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::{File, Metadata},
+    hash::{Hash, Hasher},
+    io::Read,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+impl<'a> State<'a> {
+    /// Compare the files of an older snapshot against a newer one, or against
+    /// the live subvolume if `to` is omitted. The older snapshot defaults to
+    /// the newest one. Files whose size and modification time are the same in
+    /// both must have the same contents; anything else counts as changed on
+    /// purpose and is skipped. Only `sample` percent of the files are compared
+    /// if given, picked at random.
+    pub fn verify_snapshots(
+        &mut self,
+        snapshot: &'a SnapshotConfig,
+        from: Option<&str>,
+        to: Option<&str>,
+        sample: Option<u8>,
+    ) -> Result<VerifyReport> {
+        if let Some(host) = privilege::remote() {
+            bail!(
+                "Cannot verify the snapshots of {} on {}; `verify` only works locally",
+                snapshot.name,
+                host
+            );
+        }
+        self.mount_snapshot_fs(snapshot)?;
+        let from = match from {
+            Some(from) => resolve_snapshot(snapshot, from)?,
+            None => find_snapshots(snapshot, &[])?
+                .into_iter()
+                .next()
+                .map(|entry| entry.path)
+                .ok_or_else(|| anyhow!("{} has no snapshots to verify", snapshot.name))?,
+        };
+        let to = match to {
+            Some(to) => resolve_snapshot(snapshot, to)?,
+            None => snapshot.subvolume().to_owned(),
+        };
+        debug!("Verify {} against {}", from.display(), to.display());
+
+        let mut verifier = Verifier {
+            report: VerifyReport {
+                name: snapshot.name.clone(),
+                from: from.clone(),
+                to: to.clone(),
+                ..Default::default()
+            },
+            sample,
+            seed: chrono::Local::now().timestamp(),
+        };
+        let dev = std::fs::metadata(&from)
+            .with_context(|| format!("Failed to access {}", from.display()))?
+            .dev();
+        verifier.walk(&from, &to, Path::new(""), dev)?;
+        Ok(verifier.report)
+    }
+}
+
+/// Walks two directory trees and compares the files within them.
+struct Verifier {
+    /// The outcome so far.
+    report: VerifyReport,
+    /// The percentage of files to compare.
+    sample: Option<u8>,
+    /// Varies the files picked for the sample from run to run.
+    seed: i64,
+}
+
+impl Verifier {
+    /// Compare the files in a directory below both trees, without descending
+    /// into nested subvolumes, which belong to other devices.
+    fn walk(&mut self, from: &Path, to: &Path, rel: &Path, dev: u64) -> Result<()> {
+        let dir = from.join(rel);
+        let mut entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read directory {}", dir.display()))?
+            .map(|entry| entry.map(|entry| rel.join(entry.file_name())))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort();
+        for rel in entries {
+            let old = std::fs::symlink_metadata(from.join(&rel))?;
+            if old.is_dir() && old.dev() == dev {
+                self.walk(from, to, &rel, dev)?;
+            } else if old.is_file() && self.sampled(&rel) {
+                self.compare(from, to, rel, &old);
+            }
+        }
+        Ok(())
+    }
+
+    /// Decide whether to compare a file.
+    fn sampled(&self, rel: &Path) -> bool {
+        match self.sample {
+            Some(percent) => {
+                let mut hasher = DefaultHasher::new();
+                (self.seed, rel).hash(&mut hasher);
+                hasher.finish() % 100 < percent as u64
+            }
+            None => true,
+        }
+    }
+
+    /// Compare a file in both trees, if it appears unchanged.
+    fn compare(&mut self, from: &Path, to: &Path, rel: PathBuf, old: &Metadata) {
+        let new = match std::fs::symlink_metadata(to.join(&rel)) {
+            Ok(new) => new,
+            Err(_) => {
+                self.report.skipped += 1;
+                return;
+            }
+        };
+        if !new.is_file()
+            || new.len() != old.len()
+            || (new.mtime(), new.mtime_nsec()) != (old.mtime(), old.mtime_nsec())
+        {
+            self.report.skipped += 1;
+            return;
+        }
+        self.report.compared += 1;
+        let problem = match same_contents(&from.join(&rel), &to.join(&rel)) {
+            Ok(true) => return,
+            Ok(false) => "contents differ although size and modification time match".to_string(),
+            Err(e) => format!("{:#}", e),
+        };
+        self.report
+            .discrepancies
+            .push(Discrepancy { path: rel, problem });
+    }
+}
+
+/// Check whether two files of the same size have the same contents.
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let open = |path: &Path| {
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))
+    };
+    let (mut a, mut b) = (open(a)?, open(b)?);
+    let (mut buf_a, mut buf_b) = (vec![0; 1 << 16], vec![0; 1 << 16]);
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(b.read(&mut buf_b)? == 0);
+        }
+        match b.read_exact(&mut buf_b[..n]) {
+            Ok(()) if buf_a[..n] == buf_b[..n] => continue,
+            Ok(()) => return Ok(false),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Fixture;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn verify_finds_corrupted_files() {
+        let fixture = Fixture::new("verify", "");
+        let paths = fixture.add_snapshots(&[2, 1]);
+        let write = |rel: &str, old: &str, new: &str| {
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+            for (path, contents) in paths.iter().zip([old, new]) {
+                std::fs::create_dir_all(path.join(rel).parent().unwrap()).unwrap();
+                std::fs::write(path.join(rel), contents).unwrap();
+                std::fs::File::options()
+                    .write(true)
+                    .open(path.join(rel))
+                    .unwrap()
+                    .set_modified(mtime)
+                    .unwrap();
+            }
+        };
+        write("same", "hello", "hello");
+        write("dir/rotten", "hello", "hellO");
+        write("grown", "hello", "hello world");
+        let (mut state, _) = fixture.state();
+        let name = |path: &PathBuf| path.file_name().unwrap().to_str().unwrap().to_owned();
+        let report = state
+            .verify_snapshots(
+                fixture.snapshot(),
+                Some(&name(&paths[0])),
+                Some(&name(&paths[1])),
+                None,
+            )
+            .unwrap();
+        assert_eq!((report.compared, report.skipped), (2, 1));
+        assert_eq!(report.discrepancies.len(), 1);
+        assert_eq!(report.discrepancies[0].path, Path::new("dir/rotten"));
+    }
+}
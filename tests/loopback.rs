@@ -0,0 +1,308 @@
+// Copyright (c) 2021 Fabian Schuiki
+
+//! End-to-end tests against real btrfs filesystems in loopback images.
+//!
+//! These tests need root privileges, `mkfs.btrfs`, and `btrfs` from
+//! btrfs-progs, and a kernel with btrfs and loop device support. They are
+//! ignored by default; run them with `sudo cargo test -- --ignored`.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+/// The size of each loopback image. `mkfs.btrfs` refuses images much smaller
+/// than this.
+const IMAGE_SIZE: u64 = 256 << 20;
+
+/// Run a command and return its stdout, panicking if it fails.
+fn run(cmd: &mut Command) -> String {
+    let output = cmd
+        .output()
+        .unwrap_or_else(|e| panic!("Failed to execute {:?}: {}", cmd, e));
+    assert!(
+        output.status.success(),
+        "Command {:?} failed: {}",
+        cmd,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// A btrfs filesystem in a loopback image, unmounted and removed when
+/// dropped.
+struct Loopback {
+    /// The directory holding the image and the mount point.
+    dir: PathBuf,
+    /// Where the filesystem is mounted.
+    mount_point: PathBuf,
+}
+
+impl Loopback {
+    /// Create and mount a fresh filesystem.
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "btrfs-snapshot-loopback-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let image = dir.join("image");
+        let mount_point = dir.join("mnt");
+        std::fs::create_dir_all(&mount_point).unwrap();
+        std::fs::File::create(&image)
+            .unwrap()
+            .set_len(IMAGE_SIZE)
+            .unwrap();
+        run(Command::new("mkfs.btrfs").arg("-q").arg(&image));
+        run(Command::new("mount")
+            .arg("-o")
+            .arg("loop")
+            .arg(&image)
+            .arg(&mount_point));
+        Self { dir, mount_point }
+    }
+
+    /// Get the path of a file within the filesystem.
+    fn path(&self, rel: &str) -> PathBuf {
+        self.mount_point.join(rel)
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        Command::new("umount").arg(&self.mount_point).status().ok();
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+/// Create a subvolume.
+fn create_subvolume(path: &Path) {
+    run(Command::new("btrfs")
+        .arg("subvolume")
+        .arg("create")
+        .arg(path));
+}
+
+/// Check whether a subvolume is read-only.
+fn is_readonly(path: &Path) -> bool {
+    run(Command::new("btrfs")
+        .arg("property")
+        .arg("get")
+        .arg("-ts")
+        .arg(path)
+        .arg("ro"))
+    .trim()
+        == "ro=true"
+}
+
+/// List the subvolumes below a path, relative to it and sorted by name.
+fn list_subvolumes(path: &Path) -> Vec<String> {
+    let output = run(Command::new("btrfs")
+        .arg("subvolume")
+        .arg("list")
+        .arg("-o")
+        .arg(path));
+    let top = run(Command::new("btrfs").arg("subvolume").arg("show").arg(path));
+    let own = top
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .trim_matches('/');
+    let prefix = match own {
+        "" | "<FS_TREE>" => String::new(),
+        own => format!("{}/", own),
+    };
+    let mut names: Vec<_> = output
+        .lines()
+        .filter_map(|line| line.split_once(" path ").map(|(_, path)| path.trim()))
+        .filter_map(|path| path.strip_prefix(&prefix))
+        .map(String::from)
+        .collect();
+    names.sort();
+    names
+}
+
+/// List the entries of a directory, sorted by name.
+fn list_dir(path: &Path) -> Vec<String> {
+    let mut names: Vec<_> = std::fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Write a config file into the filesystem's directory and return its path.
+/// Snapshot names carry seconds, such that a test can take several snapshots
+/// in quick succession with `take`.
+fn write_config(fs: &Loopback, snapshots: &str) -> PathBuf {
+    let path = fs.dir.join("config.toml");
+    std::fs::write(
+        &path,
+        format!(
+            "state_dir = \"{dir}/state\"\n\
+             lock_file = \"{dir}/lock\"\n\
+             mount_point = \"{mnt}\"\n\
+             format = \"%Y_%m_%d_%H%M%S\"\n\
+             {snapshots}",
+            dir = fs.dir.display(),
+            mnt = fs.mount_point.display(),
+            snapshots = snapshots,
+        ),
+    )
+    .unwrap();
+    path
+}
+
+/// Take a snapshot, waiting long enough for it to get a name distinct from
+/// the previous one.
+fn take(config: &Path) {
+    std::thread::sleep(Duration::from_millis(1100));
+    btrfs_snapshot(config, &["take"]);
+}
+
+/// Run the tool with a config file.
+fn btrfs_snapshot(config: &Path, args: &[&str]) -> String {
+    run(Command::new(env!("CARGO_BIN_EXE_btrfs-snapshot"))
+        .arg("-c")
+        .arg(config)
+        .args(args))
+}
+
+#[test]
+#[ignore = "requires root and btrfs"]
+fn take_and_rotate() {
+    let fs = Loopback::new("take-rotate");
+    create_subvolume(&fs.path("data"));
+    std::fs::write(fs.path("data/file"), "hello").unwrap();
+    let config = write_config(
+        &fs,
+        &format!(
+            "keep_max = 2\n\
+             [snapshots.data]\n\
+             subvolume = \"{}\"\n\
+             snapshot_dir = \"{}\"\n",
+            fs.path("data").display(),
+            fs.path("snapshots").display(),
+        ),
+    );
+
+    for _ in 0..3 {
+        take(&config);
+    }
+    let snapshots = list_dir(&fs.path("snapshots"));
+    assert_eq!(snapshots.len(), 3);
+    for name in &snapshots {
+        let path = fs.path("snapshots").join(name);
+        assert!(is_readonly(&path), "{} is not read-only", name);
+        assert_eq!(std::fs::read_to_string(path.join("file")).unwrap(), "hello");
+    }
+    assert_eq!(
+        list_subvolumes(&fs.mount_point),
+        ["data"]
+            .iter()
+            .map(|s| s.to_string())
+            .chain(snapshots.iter().map(|name| format!("snapshots/{}", name)))
+            .collect::<Vec<_>>()
+    );
+
+    btrfs_snapshot(&config, &["rotate"]);
+    assert_eq!(list_dir(&fs.path("snapshots")), snapshots[1..]);
+}
+
+#[test]
+#[ignore = "requires root and btrfs"]
+fn recursive_snapshot() {
+    let fs = Loopback::new("recursive");
+    create_subvolume(&fs.path("data"));
+    create_subvolume(&fs.path("data/nested"));
+    std::fs::write(fs.path("data/nested/file"), "nested").unwrap();
+    let config = write_config(
+        &fs,
+        &format!(
+            "keep_max = 1\n\
+             recursive = true\n\
+             [snapshots.data]\n\
+             subvolume = \"{}\"\n\
+             snapshot_dir = \"{}\"\n",
+            fs.path("data").display(),
+            fs.path("snapshots").display(),
+        ),
+    );
+
+    take(&config);
+    let first = fs
+        .path("snapshots")
+        .join(&list_dir(&fs.path("snapshots"))[0]);
+    assert!(is_readonly(&first));
+    assert!(is_readonly(&first.join("nested")));
+    assert_eq!(
+        std::fs::read_to_string(first.join("nested/file")).unwrap(),
+        "nested"
+    );
+
+    // Rotating the first snapshot away must delete its nested snapshot too.
+    std::thread::sleep(Duration::from_millis(1100));
+    btrfs_snapshot(&config, &["run"]);
+    let snapshots = list_dir(&fs.path("snapshots"));
+    assert_eq!(snapshots.len(), 1);
+    assert!(!first.exists());
+    assert_eq!(
+        list_subvolumes(&fs.mount_point),
+        vec![
+            "data".to_string(),
+            "data/nested".to_string(),
+            format!("snapshots/{}", snapshots[0]),
+            format!("snapshots/{}/nested", snapshots[0]),
+        ]
+    );
+}
+
+#[test]
+#[ignore = "requires root and btrfs"]
+fn replicate_to_local_disk() {
+    let fs = Loopback::new("replicate-source");
+    let target = Loopback::new("replicate-target");
+    create_subvolume(&fs.path("data"));
+    std::fs::write(fs.path("data/file"), "first").unwrap();
+    let config = write_config(
+        &fs,
+        &format!(
+            "[snapshots.data]\n\
+             subvolume = \"{}\"\n\
+             snapshot_dir = \"{}\"\n\
+             [snapshots.data.replicate]\n\
+             mount_point = \"{}\"\n\
+             target_dir = \"{}\"\n",
+            fs.path("data").display(),
+            fs.path("snapshots").display(),
+            target.mount_point.display(),
+            target.path("backup").display(),
+        ),
+    );
+
+    // The first snapshot is sent in full, the second one incrementally.
+    take(&config);
+    btrfs_snapshot(&config, &["send"]);
+    std::fs::write(fs.path("data/file"), "second").unwrap();
+    take(&config);
+    btrfs_snapshot(&config, &["send"]);
+
+    let snapshots = list_dir(&fs.path("snapshots"));
+    assert_eq!(snapshots.len(), 2);
+    assert_eq!(list_dir(&target.path("backup")), snapshots);
+    for (name, content) in snapshots.iter().zip(&["first", "second"]) {
+        let path = target.path("backup").join(name);
+        assert!(is_readonly(&path));
+        assert_eq!(
+            std::fs::read_to_string(path.join("file")).unwrap(),
+            *content
+        );
+    }
+
+    // Sending again has nothing left to do.
+    btrfs_snapshot(&config, &["send"]);
+    assert_eq!(list_dir(&target.path("backup")), snapshots);
+}